@@ -0,0 +1,55 @@
+// Copyright (c) 2025 Sébastien Campion, FOSS4. All rights reserved.
+//
+// This software is provided under the Commons Clause License Condition v1.0.
+// See the LICENSE file for full license details.
+
+//! Compare blacklist matching strategies on a model with 500 blacklist words.
+//!
+//! Run with: cargo run --release --example bench_blacklist [iterations]
+
+use aho_corasick::AhoCorasick;
+use std::time::{Duration, Instant};
+
+/// The previous approach: lowercase the whole body once per word
+fn contains_word_case_insensitive(text: &[u8], word: &str) -> bool {
+    let lowercase_word = word.to_lowercase();
+    let lowercase_text = String::from_utf8_lossy(text).to_lowercase();
+    lowercase_text.contains(&lowercase_word)
+}
+
+fn loop_match(text: &[u8], words: &[String]) -> bool {
+    words.iter().any(|word| contains_word_case_insensitive(text, word))
+}
+
+fn time<F: FnMut() -> bool>(iterations: u32, mut f: F) -> Duration {
+    let start = Instant::now();
+    for _ in 0..iterations {
+        assert!(!std::hint::black_box(f()));
+    }
+    start.elapsed() / iterations
+}
+
+fn main() {
+    let iterations = std::env::args().nth(1).and_then(|n| n.parse().ok()).unwrap_or(20);
+
+    let words: Vec<String> = (0..500).map(|i| format!("Secret-Project-{}", i)).collect();
+    let sentence = "Please summarize the quarterly report for the Sales team. ";
+    let body = format!(
+        r#"{{"messages": [{{"role": "user", "content": "{}"}}]}}"#,
+        sentence.repeat(16 * 1024 / sentence.len())
+    );
+    let text = body.as_bytes();
+
+    let matcher = AhoCorasick::builder()
+        .ascii_case_insensitive(true)
+        .build(&words)
+        .unwrap();
+
+    let looped = time(iterations, || loop_match(text, &words));
+    let automaton = time(iterations, || matcher.is_match(text));
+
+    println!("words: {}, body: {} bytes, iterations: {}", words.len(), text.len(), iterations);
+    println!("per word loop: {:?} per request", looped);
+    println!("aho-corasick:  {:?} per request", automaton);
+    println!("speedup:       {:.0}x", looped.as_secs_f64() / automaton.as_secs_f64());
+}
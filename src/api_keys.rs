@@ -0,0 +1,72 @@
+// Copyright (c) 2025 Sébastien Campion, FOSS4. All rights reserved.
+//
+// This software is provided under the Commons Clause License Condition v1.0.
+// See the LICENSE file for full license details.
+
+use crate::config::ModelConfig;
+use log::warn;
+use prometheus::IntCounterVec;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+#[derive(Default)]
+struct Rotation {
+    /// Index of the key in turn
+    next: usize,
+    /// By key index, set while the key cools down after a 429 of the provider
+    cooling_until: Vec<Option<Instant>>,
+}
+
+/// Rotation of the `api_keys` of each model location. Requests take the keys in turn, skipping
+/// the keys the provider answered a 429 to in the last `api_key_cooldown_secs`.
+pub struct ApiKeys {
+    rotations: Mutex<HashMap<String, Rotation>>,
+    /// Requests by location and key index
+    requests: IntCounterVec,
+    /// 429 responses of the provider by location and key index
+    rate_limited: IntCounterVec,
+}
+
+impl ApiKeys {
+    pub fn new(requests: IntCounterVec, rate_limited: IntCounterVec) -> Self {
+        Self {
+            rotations: Mutex::new(HashMap::new()),
+            requests,
+            rate_limited,
+        }
+    }
+
+    /// Index in `api_keys` of the key of the next request: the next one in turn not cooling
+    /// down, or the one whose cooldown ends first when they all are. None without `api_keys`.
+    pub fn pick(&self, model: &ModelConfig) -> Option<usize> {
+        let count = model.api_keys.len();
+        if count == 0 {
+            return None;
+        }
+        let mut rotations = self.rotations.lock().unwrap();
+        let rotation = rotations.entry(model.location.clone()).or_default();
+        // the keys may have changed on reload
+        rotation.cooling_until.resize(count, None);
+        let now = Instant::now();
+        let index = (0..count)
+            .map(|i| (rotation.next + i) % count)
+            .find(|&i| rotation.cooling_until[i].is_none_or(|until| until <= now))
+            .unwrap_or_else(|| (0..count).min_by_key(|&i| rotation.cooling_until[i]).unwrap());
+        rotation.next = index + 1;
+        self.requests.with_label_values(&[&model.location, &index.to_string()]).inc();
+        Some(index)
+    }
+
+    /// The provider answered a 429 to the request sent with the key at `index`
+    pub fn cool_down(&self, model: &ModelConfig, index: usize) {
+        let mut rotations = self.rotations.lock().unwrap();
+        let Some(until) = rotations.get_mut(&model.location).and_then(|r| r.cooling_until.get_mut(index)) else {
+            return;
+        };
+        *until = Some(Instant::now() + Duration::from_secs(model.api_key_cooldown_secs));
+        self.rate_limited.with_label_values(&[&model.location, &index.to_string()]).inc();
+        warn!("Location {}: API key {} rate limited by the provider, set aside for {}s",
+            model.location, index, model.api_key_cooldown_secs);
+    }
+}
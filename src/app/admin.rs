@@ -5,26 +5,33 @@
 use async_trait::async_trait;
 use bytes::Bytes;
 use http::{Response, StatusCode};
-use log::debug;
 use once_cell::sync::Lazy;
 use pingora_timeout::timeout;
+use prometheus::proto::MetricType;
 use prometheus::{register_int_counter, IntCounter};
 use std::sync::Arc;
 use std::time::Duration;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use pingora::prelude::*;
 
 use pingora::apps::http_app::ServeHttp;
-use pingora::apps::ServerApp;
 use pingora::protocols::http::ServerSession;
-use pingora::protocols::Stream;
-use pingora::server::ShutdownWatch;
 use rust_embed::Embed;
 use tree_magic;
 use redb::TableDefinition;
 use redb::ReadableTable;
-use log::{error, info, trace, warn};
+use log::{error, info};
 use std::collections::HashMap;
+use crate::auth;
+use crate::auth::TOKEN_EXPIRY;
+use crate::request_signing;
+use crate::cost::{self, cost_key, COST, COST_CAPS};
+use crate::token_limit::{usage_by_user, usage_window};
+use crate::cache::AuthCache;
+use crate::decisions;
+use crate::maintenance_mode::MaintenanceMode;
+use crate::disabled_models::DisabledModels;
+use crate::config::{ServerConf, UsagePeriod};
+use arc_swap::ArcSwap;
 
 
 
@@ -33,19 +40,25 @@ static REQ_COUNTER: Lazy<IntCounter> =
 
 const TOKENS: TableDefinition<&str, &str> = TableDefinition::new("tokens");
 const USAGE: TableDefinition<&str, u64> = TableDefinition::new("usage");
-const GROUPS: TableDefinition<&str, &str> = TableDefinition::new("groups");
+const CREDENTIALS: TableDefinition<&str, &str> = TableDefinition::new("credentials");
+/// Time the previous tokens of a user keep working after a rotation
+const DEFAULT_ROTATION_GRACE_SECONDS: i64 = 3600;
+
+/// Unix timestamp as an RFC 3339 date
+fn format_timestamp(timestamp: i64) -> String {
+    chrono::DateTime::from_timestamp(timestamp, 0)
+        .map(|date| date.to_rfc3339())
+        .unwrap_or_default()
+}
 
 
 
 pub struct HttpAdminApp {
     pub db: Arc<redb::Database>,
-}
-
-pub fn admin_service_http(db: Arc<redb::Database>) -> pingora_core::services::listening::Service<HttpAdminApp> {
-    pingora_core::services::listening::Service::new(
-        "Admin HTTP Service".to_string(),
-        HttpAdminApp { db },
-    )
+    pub auth_cache: Arc<AuthCache>,
+    pub conf: Arc<ArcSwap<ServerConf>>,
+    pub maintenance_mode: Arc<MaintenanceMode>,
+    pub disabled_models: Arc<DisabledModels>,
 }
 
 
@@ -76,7 +89,7 @@ impl Period {
             Self::Minutely => Some("M:"),
             Self::Hourly => Some("H:"),
             Self::Daily => Some("d:"),
-            Self::Weekly => Some("w:"),
+            Self::Weekly => Some("W:"),
             Self::Monthly => Some("m:"),
             Self::All => None,
         }
@@ -95,13 +108,59 @@ impl ServeHttp for HttpAdminApp {
         
         let uri = http_stream.req_header().uri.path();
         let method = http_stream.req_header().method.as_str();
-        
+
+        // Only the pages of the admin UI are public, they ask for the secret to call the API
+        let public = method == "GET" && (uri == "/" || self.is_embedded(&uri[1..]));
+        if !public && !self.is_authorized(http_stream) {
+            return self.json_response(StatusCode::UNAUTHORIZED, serde_json::json!({"error": "Unauthorized"}));
+        }
+
         match (method, uri) {
             ("GET", "/") => self.handle_static_asset("index.html"),
             ("GET", path) if self.is_embedded(&path[1..]) => self.handle_static_asset(&path[1..]),
             ("GET", "/tokens") => self.handle_get_tokens(),
             ("POST", "/tokens") => self.handle_post_tokens(http_stream).await,
             ("DELETE", "/tokens") => self.handle_delete_tokens(http_stream).await,
+            ("DELETE", path) if path.starts_with("/tokens/") => self.handle_revoke_token(&path["/tokens/".len()..]),
+            ("GET", path) if path.starts_with("/users/") && path.ends_with("/tokens") => {
+                self.handle_get_user_tokens(&path["/users/".len()..path.len() - "/tokens".len()])
+            }
+            ("POST", path) if path.starts_with("/users/") && path.ends_with("/tokens/rotate") => {
+                let user = path["/users/".len()..path.len() - "/tokens/rotate".len()].to_string();
+                self.handle_rotate_tokens(&user, http_stream).await
+            }
+            ("GET", path) if path.starts_with("/users/") && path.ends_with("/cost_cap") => {
+                self.handle_get_cost_cap(&path["/users/".len()..path.len() - "/cost_cap".len()])
+            }
+            ("PUT", path) if path.starts_with("/users/") && path.ends_with("/cost_cap") => {
+                let user = path["/users/".len()..path.len() - "/cost_cap".len()].to_string();
+                self.handle_put_cost_cap(&user, http_stream).await
+            }
+            ("DELETE", path) if path.starts_with("/users/") && path.ends_with("/cost_cap") => {
+                self.handle_delete_cost_cap(&path["/users/".len()..path.len() - "/cost_cap".len()])
+            }
+            ("POST", "/credentials") => self.handle_post_credentials(http_stream).await,
+            ("POST", "/signing_clients") => self.handle_post_signing_clients(http_stream).await,
+            ("DELETE", "/signing_clients") => self.handle_delete_signing_clients(http_stream).await,
+            ("GET", "/cost") => self.handle_get_cost(),
+            ("GET", "/metrics-json") => self.handle_get_metrics_json(),
+            ("GET", "/decisions") => {
+                let query = http_stream.req_header().uri.query().unwrap_or_default().to_string();
+                self.handle_get_decisions(&query)
+            }
+            ("GET", "/maintenance") => self.json_response(StatusCode::OK, serde_json::json!({"maintenance_mode": self.maintenance_mode.is_enabled()})),
+            ("PUT", "/maintenance") => self.handle_put_maintenance(http_stream).await,
+            ("GET", "/models/disabled") => self.json_response(StatusCode::OK, serde_json::json!({"disabled": self.disabled_models.list()})),
+            ("POST", path) if path.starts_with("/models/") && path.ends_with("/disable") => {
+                self.handle_set_model_disabled(&path["/models/".len()..path.len() - "/disable".len()], true)
+            }
+            ("POST", path) if path.starts_with("/models/") && path.ends_with("/enable") => {
+                self.handle_set_model_disabled(&path["/models/".len()..path.len() - "/enable".len()], false)
+            }
+            ("GET", "/usage") if http_stream.req_header().uri.query().is_some() => {
+                let query = http_stream.req_header().uri.query().unwrap_or_default().to_string();
+                self.handle_get_usage_totals(&query)
+            }
             ("GET", "/usage") => self.handle_get_usage("all"),
             ("GET", "/usage/minutely") => self.handle_get_usage("minutely"),
             ("GET", "/usage/hourly") => self.handle_get_usage("hourly"),
@@ -124,6 +183,22 @@ impl ServeHttp for HttpAdminApp {
 }
 
 impl HttpAdminApp {
+    /// Check the request carries the admin secret as a bearer token
+    fn is_authorized(&self, http_stream: &ServerSession) -> bool {
+        let conf = self.conf.load();
+        if conf.admin_secret.is_empty() {
+            return false;
+        }
+        let provided = http_stream.req_header().headers.get("Authorization")
+            .and_then(|v| v.to_str().ok())
+            .and_then(|s| s.strip_prefix("Bearer "))
+            .unwrap_or_default();
+        // constant time comparison
+        let expected = conf.admin_secret.as_bytes();
+        provided.len() == expected.len()
+            && provided.bytes().zip(expected).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+    }
+
     pub fn is_embedded(&self, uri: &str) -> bool {
         Asset::get(uri).is_some()
     }
@@ -144,10 +219,10 @@ impl HttpAdminApp {
                 .header(http::header::CONTENT_LENGTH, body.len())
                 .body(body).unwrap();
         }
-        return Response::builder()
+        Response::builder()
             .status(StatusCode::INTERNAL_SERVER_ERROR)
             .body("Asset can't be loaded".as_bytes().to_vec())
-            .unwrap();
+            .unwrap()
     }
 
     async fn read_json_body(&self, http_stream: &mut ServerSession) -> Result<serde_json::Value, Response<Vec<u8>>> {
@@ -170,8 +245,6 @@ impl HttpAdminApp {
             }
         };
 
-        println!("Body: {:?}", body);
-
         // Attempt to parse the body as JSON
         match serde_json::from_slice(&body) {
             Ok(json_value) => Ok(json_value),
@@ -190,23 +263,42 @@ impl HttpAdminApp {
     async fn handle_post_tokens(&self, http_stream: &mut ServerSession) -> Response<Vec<u8>> {
         let json = match self.read_json_body(http_stream).await {
             Ok(json) => json,
-            Err(response) => {
+            Err(_) => {
                 return self.json_response(StatusCode::BAD_REQUEST, serde_json::json!({"error": "Invalid JSON"}));
             }
         };
+        // {"user": "user"} without tokens generates a random token for the user
+        if let (None, Some(user)) = (json.get("tokens"), json.get("user").and_then(|v| v.as_str())) {
+            return self.handle_generate_token(user, &json);
+        }
         {
             let write_txn = self.db.begin_write().expect("Failed to begin write transaction");
             {
                 let mut table = write_txn.open_table(TOKENS).expect("Failed to open table");
+                let mut expiry_table = write_txn.open_table(TOKEN_EXPIRY).expect("Failed to open table");
                 if let Some(tokens) = json.get("tokens").and_then(|v| v.as_object()) {
-                    for (token, user) in tokens {
-                        if let Some(user_str) = user.as_str() {
+                    for (token, value) in tokens {
+                        // either "token": "user" or "token": {"user": "user", "expires_at": "RFC 3339 date"}
+                        let user = value.as_str().or_else(|| value.get("user").and_then(|v| v.as_str()));
+                        let expires_at = match self.parse_expires_at(value) {
+                            Ok(expires_at) => expires_at,
+                            Err(e) => {
+                                error!("Invalid expiry for token {}: {}", token.as_str(), e);
+                                return self.json_response(StatusCode::BAD_REQUEST, serde_json::json!({"error": "Invalid expires_at, expected an RFC 3339 date"}));
+                            }
+                        };
+                        if let Some(user_str) = user {
                             // test if token length is greater than 32 otherwise return error
                             if token.as_str().len() < 32 {
                                 error!("Token {} is too short", token.as_str());
                                 return self.json_response(StatusCode::BAD_REQUEST, serde_json::json!({"error": "Token is too short"}));
                             } else {
                                 table.insert(token.as_str(), user_str).expect("Failed to insert token");
+                                match expires_at {
+                                    Some(timestamp) => expiry_table.insert(token.as_str(), timestamp).map(|_| ()),
+                                    None => expiry_table.remove(token.as_str()).map(|_| ()),
+                                }.expect("Failed to update token expiry");
+                                self.auth_cache.invalidate_token(token.as_str());
                                 info!("Token {} inserted for user {}", token.as_str(), user_str);
                             }
                         }
@@ -219,10 +311,100 @@ impl HttpAdminApp {
         self.json_response(StatusCode::OK, serde_json::json!({"status": "ok"}))
     }
 
+    fn handle_generate_token(&self, user: &str, json: &serde_json::Value) -> Response<Vec<u8>> {
+        let expires_at = match self.parse_expires_at(json) {
+            Ok(expires_at) => expires_at,
+            Err(e) => {
+                error!("Invalid token expiry: {}", e);
+                return self.json_response(StatusCode::BAD_REQUEST, serde_json::json!({"error": "Invalid expires_at, expected an RFC 3339 date"}));
+            }
+        };
+        let write_txn = self.db.begin_write().expect("Failed to begin write transaction");
+        let token = auth::mint_token(&write_txn, user).expect("Failed to insert token");
+        if let Some(timestamp) = expires_at {
+            let mut expiry_table = write_txn.open_table(TOKEN_EXPIRY).expect("Failed to open table");
+            expiry_table.insert(token.as_str(), timestamp).expect("Failed to update token expiry");
+        }
+        write_txn.commit().expect("Failed to commit write transaction");
+        info!("Token generated for user {}", user);
+        self.json_response(StatusCode::OK, serde_json::json!({"token": token, "user": user}))
+    }
+
+    /// Optional `expires_at` RFC 3339 date of a token, as a unix timestamp
+    fn parse_expires_at(&self, value: &serde_json::Value) -> Result<Option<i64>, chrono::ParseError> {
+        value.get("expires_at")
+            .and_then(|v| v.as_str())
+            .map(|date| chrono::DateTime::parse_from_rfc3339(date).map(|date| date.timestamp()))
+            .transpose()
+    }
+
+    fn handle_revoke_token(&self, token: &str) -> Response<Vec<u8>> {
+        let write_txn = self.db.begin_write().expect("Failed to begin write transaction");
+        let removed = {
+            let mut table = write_txn.open_table(TOKENS).expect("Failed to open table");
+            let mut expiry_table = write_txn.open_table(TOKEN_EXPIRY).expect("Failed to open table");
+            expiry_table.remove(token).expect("Failed to remove token expiry");
+            let removed = table.remove(token).expect("Failed to remove token").is_some();
+            removed
+        };
+        write_txn.commit().expect("Failed to commit write transaction");
+        self.auth_cache.invalidate_token(token);
+        if !removed {
+            return self.json_response(StatusCode::NOT_FOUND, serde_json::json!({"error": "Token not found"}));
+        }
+        info!("Token {} revoked", token);
+        self.json_response(StatusCode::OK, serde_json::json!({"status": "ok"}))
+    }
+
+    /// Tokens of a user with their expiry, to find the ones to rotate
+    fn handle_get_user_tokens(&self, user: &str) -> Response<Vec<u8>> {
+        let read_txn = self.db.begin_read().expect("Failed to begin read transaction");
+        let tokens: Vec<_> = auth::tokens_for_user(&read_txn, user)
+            .expect("Failed to read tokens")
+            .into_iter()
+            .map(|(token, expires_at)| serde_json::json!({"token": token, "expires_at": expires_at.map(format_timestamp)}))
+            .collect();
+        self.json_response(StatusCode::OK, serde_json::json!({"user": user, "tokens": tokens}))
+    }
+
+    /// Mint a new token for the user and expire the current ones after a grace period,
+    /// `grace_seconds` in the body (one hour by default), so clients can switch over.
+    async fn handle_rotate_tokens(&self, user: &str, http_stream: &mut ServerSession) -> Response<Vec<u8>> {
+        let json = match self.read_json_body(http_stream).await {
+            Ok(json) => json,
+            Err(_) => {
+                return self.json_response(StatusCode::BAD_REQUEST, serde_json::json!({"error": "Invalid JSON"}));
+            }
+        };
+        let grace_seconds = match json.get("grace_seconds") {
+            None => DEFAULT_ROTATION_GRACE_SECONDS,
+            Some(value) => match value.as_i64() {
+                Some(seconds) if seconds >= 0 => seconds,
+                _ => {
+                    return self.json_response(StatusCode::BAD_REQUEST, serde_json::json!({"error": "Invalid grace_seconds, expected a positive number of seconds"}));
+                }
+            },
+        };
+        let grace_until = chrono::Utc::now().timestamp() + grace_seconds;
+
+        let write_txn = self.db.begin_write().expect("Failed to begin write transaction");
+        let (token, rotated) = auth::rotate_tokens(&write_txn, user, grace_until).expect("Failed to rotate tokens");
+        write_txn.commit().expect("Failed to commit write transaction");
+        for (old_token, _) in &rotated {
+            self.auth_cache.invalidate_token(old_token);
+        }
+        info!("Rotated {} tokens of user {}", rotated.len(), user);
+
+        let rotated: Vec<_> = rotated.into_iter()
+            .map(|(token, expires_at)| serde_json::json!({"token": token, "expires_at": format_timestamp(expires_at)}))
+            .collect();
+        self.json_response(StatusCode::OK, serde_json::json!({"token": token, "user": user, "rotated": rotated}))
+    }
+
     async fn handle_delete_tokens(&self, http_stream: &mut ServerSession) -> Response<Vec<u8>> {
         let json = match self.read_json_body(http_stream).await {
             Ok(json) => json,
-            Err(response) => {
+            Err(_) => {
                 return self.json_response(StatusCode::BAD_REQUEST, serde_json::json!({"error": "Invalid JSON"}));
             }
         };
@@ -230,9 +412,12 @@ impl HttpAdminApp {
             let write_txn = self.db.begin_write().expect("Failed to begin write transaction");
             {
                 let mut table = write_txn.open_table(TOKENS).expect("Failed to open table");
+                let mut expiry_table = write_txn.open_table(TOKEN_EXPIRY).expect("Failed to open table");
                 for token in json.get("tokens").and_then(|v| v.as_array()).unwrap() {
                     if let Some(token_str) = token.as_str() {
                         table.remove(token_str).expect("Failed to remove token");
+                        expiry_table.remove(token_str).expect("Failed to remove token expiry");
+                        self.auth_cache.invalidate_token(token_str);
                         info!("Token {} removed", token_str);
                     }
                 }
@@ -243,19 +428,107 @@ impl HttpAdminApp {
         self.json_response(StatusCode::OK, serde_json::json!({"status": "ok"}))
     }
 
+    /// Register clients signing their requests, `{"clients": {"<id>": {"user": ..., "secret": ...}}}`
+    async fn handle_post_signing_clients(&self, http_stream: &mut ServerSession) -> Response<Vec<u8>> {
+        let json = match self.read_json_body(http_stream).await {
+            Ok(json) => json,
+            Err(_) => {
+                return self.json_response(StatusCode::BAD_REQUEST, serde_json::json!({"error": "Invalid JSON"}));
+            }
+        };
+        let Some(clients) = json.get("clients").and_then(|v| v.as_object()) else {
+            return self.json_response(StatusCode::BAD_REQUEST, serde_json::json!({"error": "Expected a clients object"}));
+        };
+        let mut entries = Vec::new();
+        for (client_id, client) in clients {
+            let (Some(user), Some(secret)) = (client["user"].as_str(), client["secret"].as_str()) else {
+                return self.json_response(StatusCode::BAD_REQUEST, serde_json::json!({"error": format!("Client {} needs a user and a secret", client_id)}));
+            };
+            if secret.len() < request_signing::MIN_SECRET_LEN {
+                error!("Secret of signing client {} is too short", client_id);
+                return self.json_response(StatusCode::BAD_REQUEST, serde_json::json!({"error": "Secret is too short"}));
+            }
+            entries.push((client_id, user, secret));
+        }
+        {
+            let write_txn = self.db.begin_write().expect("Failed to begin write transaction");
+            for (client_id, user, secret) in entries {
+                request_signing::set_client(&write_txn, client_id, user, secret).expect("Failed to insert signing client");
+                info!("Signing client {} set for user {}", client_id, user);
+            }
+            write_txn.commit().expect("Failed to commit write transaction");
+        }
+
+        self.json_response(StatusCode::OK, serde_json::json!({"status": "ok"}))
+    }
+
+    /// Remove signing clients, `{"clients": ["<id>"]}`
+    async fn handle_delete_signing_clients(&self, http_stream: &mut ServerSession) -> Response<Vec<u8>> {
+        let json = match self.read_json_body(http_stream).await {
+            Ok(json) => json,
+            Err(_) => {
+                return self.json_response(StatusCode::BAD_REQUEST, serde_json::json!({"error": "Invalid JSON"}));
+            }
+        };
+        {
+            let write_txn = self.db.begin_write().expect("Failed to begin write transaction");
+            {
+                let mut table = write_txn.open_table(request_signing::SIGNING_CLIENTS).expect("Failed to open table");
+                for client_id in json.get("clients").and_then(|v| v.as_array()).into_iter().flatten().filter_map(|v| v.as_str()) {
+                    table.remove(client_id).expect("Failed to remove signing client");
+                    info!("Signing client {} removed", client_id);
+                }
+            }
+            write_txn.commit().expect("Failed to commit write transaction");
+        }
+
+        self.json_response(StatusCode::OK, serde_json::json!({"status": "ok"}))
+    }
+
+    async fn handle_post_credentials(&self, http_stream: &mut ServerSession) -> Response<Vec<u8>> {
+        let json = match self.read_json_body(http_stream).await {
+            Ok(json) => json,
+            Err(_) => {
+                return self.json_response(StatusCode::BAD_REQUEST, serde_json::json!({"error": "Invalid JSON"}));
+            }
+        };
+        {
+            let write_txn = self.db.begin_write().expect("Failed to begin write transaction");
+            {
+                let mut table = write_txn.open_table(CREDENTIALS).expect("Failed to open table");
+                if let Some(credentials) = json.get("credentials").and_then(|v| v.as_object()) {
+                    for (user, password) in credentials {
+                        if let Some(password_str) = password.as_str() {
+                            let hash = match auth::hash_password(password_str) {
+                                Ok(hash) => hash,
+                                Err(e) => {
+                                    error!("{}", e);
+                                    return self.json_response(StatusCode::INTERNAL_SERVER_ERROR, serde_json::json!({"error": "Failed to hash password"}));
+                                }
+                            };
+                            table.insert(user.as_str(), hash.as_str()).expect("Failed to insert credentials");
+                            info!("Credentials set for user {}", user);
+                        }
+                    }
+                }
+            }
+            write_txn.commit().expect("Failed to commit write transaction");
+        }
+
+        self.json_response(StatusCode::OK, serde_json::json!({"status": "ok"}))
+    }
+
     fn handle_get_tokens(&self) -> Response<Vec<u8>> {
         let read_txn = self.db.begin_read().expect("Failed to begin read transaction");
         let table = read_txn.open_table(TOKENS).expect("Failed to open table");
         let mut tokens = Vec::new();
 
-        for entry in table.iter().into_iter().flatten() {
-            if let Ok((key, value)) = entry {
-                let token_key = key.value().to_string();
-                let user_value = value.value().to_string();
-                let mut token_map = std::collections::HashMap::new();
-                token_map.insert(token_key, user_value);
-                tokens.push(token_map);
-            }
+        for (key, value) in table.iter().into_iter().flatten().flatten() {
+            let token_key = key.value().to_string();
+            let user_value = value.value().to_string();
+            let mut token_map = std::collections::HashMap::new();
+            token_map.insert(token_key, user_value);
+            tokens.push(token_map);
         }
         self.json_response(StatusCode::OK, &tokens)
     }
@@ -289,6 +562,192 @@ impl HttpAdminApp {
     }
 
 
+    /// Token totals of the current period, `?user=alice&period=month`. Without a user, or with
+    /// `user=all`, every user is listed. Monthly totals also carry the cost in USD.
+    fn handle_get_usage_totals(&self, query: &str) -> Response<Vec<u8>> {
+        let params: HashMap<String, String> = url::form_urlencoded::parse(query.as_bytes()).into_owned().collect();
+        let period_name = params.get("period").map(String::as_str).unwrap_or("month");
+        let Some(period) = UsagePeriod::from_name(period_name) else {
+            return self.json_response(StatusCode::BAD_REQUEST, serde_json::json!({"error": "Invalid period, expected minute, hour, day, week or month"}));
+        };
+        if !self.conf.load().usage_periods.contains(&period) {
+            return self.json_response(StatusCode::BAD_REQUEST, serde_json::json!({"error": format!("Usage per {} is not kept, see usage_periods", period_name)}));
+        }
+
+        let now = chrono::Utc::now();
+        let read_txn = self.db.begin_read().expect("Failed to begin read transaction");
+        let totals = match usage_by_user(&read_txn, period, now) {
+            Ok(totals) => totals,
+            Err(e) => {
+                error!("Failed to read usage totals: {}", e);
+                return self.json_response(StatusCode::INTERNAL_SERVER_ERROR, serde_json::json!({"error": "Failed to read usage"}));
+            }
+        };
+        let cost_table = read_txn.open_table(COST).expect("Failed to open table");
+        let user_totals = |user: &str, (input_tokens, output_tokens): (u64, u64)| {
+            let mut totals = serde_json::json!({"input_tokens": input_tokens, "output_tokens": output_tokens});
+            if period == UsagePeriod::Month {
+                let cents = cost_table.get(cost_key(user, now).as_str()).ok().flatten().map(|v| v.value()).unwrap_or(0.0);
+                totals["cost"] = serde_json::json!(cents / 100.0);
+            }
+            totals
+        };
+
+        let window = usage_window(period, now);
+        match params.get("user").map(String::as_str) {
+            None | Some("all") => {
+                let users: serde_json::Map<String, serde_json::Value> = totals.into_iter()
+                    .map(|(user, user_tokens)| {
+                        let totals = user_totals(&user, user_tokens);
+                        (user, totals)
+                    })
+                    .collect();
+                self.json_response(StatusCode::OK, serde_json::json!({"period": period_name, "window": window, "users": users}))
+            }
+            Some(user) => {
+                let mut body = user_totals(user, totals.get(user).copied().unwrap_or_default());
+                body["user"] = serde_json::json!(user);
+                body["period"] = serde_json::json!(period_name);
+                body["window"] = serde_json::json!(window);
+                self.json_response(StatusCode::OK, body)
+            }
+        }
+    }
+
+    /// Daily spend cap of a user, its own or the global one, with what it spent today
+    fn handle_get_cost_cap(&self, user: &str) -> Response<Vec<u8>> {
+        let now = chrono::Utc::now();
+        let read_txn = self.db.begin_read().expect("Failed to begin read transaction");
+        let user_cap = cost::user_cost_cap(&read_txn, user).expect("Failed to read cost cap");
+        let spent = cost::daily_cost_cents(&read_txn, user, None, now).expect("Failed to read daily cost");
+        self.json_response(StatusCode::OK, serde_json::json!({
+            "user": user,
+            "daily_cost_cap_usd": user_cap.or(self.conf.load().daily_cost_cap_usd),
+            "overridden": user_cap.is_some(),
+            "spent_today_usd": spent / 100.0,
+        }))
+    }
+
+    /// Set the daily spend cap of a user, `{"daily_cost_cap_usd": 20.0}`, in place of the global one
+    async fn handle_put_cost_cap(&self, user: &str, http_stream: &mut ServerSession) -> Response<Vec<u8>> {
+        let json = match self.read_json_body(http_stream).await {
+            Ok(json) => json,
+            Err(_) => {
+                return self.json_response(StatusCode::BAD_REQUEST, serde_json::json!({"error": "Invalid JSON"}));
+            }
+        };
+        let Some(cap) = json.get("daily_cost_cap_usd").and_then(|v| v.as_f64()).filter(|cap| *cap >= 0.0) else {
+            return self.json_response(StatusCode::BAD_REQUEST, serde_json::json!({"error": "Invalid daily_cost_cap_usd, expected a positive number of USD"}));
+        };
+        let write_txn = self.db.begin_write().expect("Failed to begin write transaction");
+        write_txn.open_table(COST_CAPS).expect("Failed to open table")
+            .insert(user, cap).expect("Failed to insert cost cap");
+        write_txn.commit().expect("Failed to commit write transaction");
+        info!("Daily cost cap of user {} set to {} USD", user, cap);
+        self.json_response(StatusCode::OK, serde_json::json!({"status": "ok"}))
+    }
+
+    /// Remove the daily spend cap of a user, the global one applies again
+    fn handle_delete_cost_cap(&self, user: &str) -> Response<Vec<u8>> {
+        let write_txn = self.db.begin_write().expect("Failed to begin write transaction");
+        let removed = write_txn.open_table(COST_CAPS).expect("Failed to open table")
+            .remove(user).expect("Failed to remove cost cap").is_some();
+        write_txn.commit().expect("Failed to commit write transaction");
+        if !removed {
+            return self.json_response(StatusCode::NOT_FOUND, serde_json::json!({"error": "No cost cap set for this user"}));
+        }
+        info!("Daily cost cap of user {} removed", user);
+        self.json_response(StatusCode::OK, serde_json::json!({"status": "ok"}))
+    }
+
+    /// Take a location out of rotation or put it back, given as in the configuration with or
+    /// without its leading slash
+    fn handle_set_model_disabled(&self, location: &str, disabled: bool) -> Response<Vec<u8>> {
+        let location = if location.starts_with('/') { location.to_string() } else { format!("/{}", location) };
+        if !self.conf.load().models.iter().any(|m| m.location == location) {
+            return self.json_response(StatusCode::NOT_FOUND, serde_json::json!({"error": "Unknown location"}));
+        }
+        self.disabled_models.set(&location, disabled);
+        self.json_response(StatusCode::OK, serde_json::json!({"location": location, "disabled": disabled}))
+    }
+
+    /// Switch the maintenance mode, `{"maintenance_mode": true}`
+    async fn handle_put_maintenance(&self, http_stream: &mut ServerSession) -> Response<Vec<u8>> {
+        let json = match self.read_json_body(http_stream).await {
+            Ok(json) => json,
+            Err(_) => {
+                return self.json_response(StatusCode::BAD_REQUEST, serde_json::json!({"error": "Invalid JSON"}));
+            }
+        };
+        let Some(enabled) = json.get("maintenance_mode").and_then(|v| v.as_bool()) else {
+            return self.json_response(StatusCode::BAD_REQUEST, serde_json::json!({"error": "Expected a maintenance_mode boolean"}));
+        };
+        self.maintenance_mode.set(enabled);
+        self.json_response(StatusCode::OK, serde_json::json!({"maintenance_mode": enabled}))
+    }
+
+    fn handle_get_cost(&self) -> Response<Vec<u8>> {
+        let read_txn = self.db.begin_read().expect("Failed to begin read transaction");
+        let table = read_txn.open_table(COST).expect("Failed to open table");
+        let mut cost = HashMap::new();
+        for (key, value) in table.iter().into_iter().flatten().flatten() {
+            // stored in cents, exposed in USD
+            cost.insert(key.value().to_string(), value.value() / 100.0);
+        }
+        self.json_response(StatusCode::OK, &cost)
+    }
+
+    /// Decisions of the refused requests, `?user=alice&from=...&to=...&limit=100`. `from` and
+    /// `to` are RFC 3339 dates, the last day by default, and at most `limit` are listed, 1000
+    /// by default.
+    fn handle_get_decisions(&self, query: &str) -> Response<Vec<u8>> {
+        let params: HashMap<String, String> = url::form_urlencoded::parse(query.as_bytes()).into_owned().collect();
+        let now = chrono::Utc::now();
+        let date = |name: &str, default| params.get(name)
+            .map(|date| chrono::DateTime::parse_from_rfc3339(date).map(|date| date.to_utc()))
+            .unwrap_or(Ok(default));
+        let (Ok(from), Ok(to)) = (date("from", now - chrono::Duration::days(1)), date("to", now)) else {
+            return self.json_response(StatusCode::BAD_REQUEST, serde_json::json!({"error": "Invalid from or to, expected RFC 3339 dates"}));
+        };
+        let Ok(limit) = params.get("limit").map_or(Ok(1000), |limit| limit.parse::<usize>()) else {
+            return self.json_response(StatusCode::BAD_REQUEST, serde_json::json!({"error": "Invalid limit"}));
+        };
+        match decisions::find_decisions(&self.db, params.get("user").map(String::as_str), from, to, limit) {
+            Ok(decisions) => self.json_response(StatusCode::OK, &decisions),
+            Err(e) => {
+                error!("Failed to read decisions: {}", e);
+                self.json_response(StatusCode::INTERNAL_SERVER_ERROR, serde_json::json!({"error": "Failed to read decisions"}))
+            }
+        }
+    }
+
+    /// The metrics of the Prometheus service, by name, with their values by labels. Histograms
+    /// give the count and sum of their observations.
+    fn handle_get_metrics_json(&self) -> Response<Vec<u8>> {
+        let mut metrics = serde_json::Map::new();
+        for family in prometheus::gather() {
+            let values: Vec<_> = family.get_metric().iter().map(|metric| {
+                let labels: HashMap<_, _> = metric.get_label().iter().map(|l| (l.get_name(), l.get_value())).collect();
+                match family.get_field_type() {
+                    MetricType::COUNTER => serde_json::json!({"labels": labels, "value": metric.get_counter().get_value()}),
+                    MetricType::GAUGE => serde_json::json!({"labels": labels, "value": metric.get_gauge().get_value()}),
+                    MetricType::HISTOGRAM => serde_json::json!({
+                        "labels": labels,
+                        "count": metric.get_histogram().get_sample_count(),
+                        "sum": metric.get_histogram().get_sample_sum(),
+                    }),
+                    _ => serde_json::json!({"labels": labels, "value": metric.get_untyped().get_value()}),
+                }
+            }).collect();
+            metrics.insert(family.get_name().to_string(), serde_json::json!({
+                "type": format!("{:?}", family.get_field_type()).to_lowercase(),
+                "help": family.get_help(),
+                "values": values,
+            }));
+        }
+        self.json_response(StatusCode::OK, metrics)
+    }
+
     pub fn json_response(&self, status: StatusCode, body: impl serde::Serialize) -> Response<Vec<u8>> {
         let body = serde_json::to_vec(&body).expect("Failed to serialize JSON");
         Response::builder()
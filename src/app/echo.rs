@@ -4,21 +4,13 @@
 // See the LICENSE file for full license details.
 
 use async_trait::async_trait;
-use bytes::Bytes;
 use http::{Response, StatusCode};
 use log::debug;
-use once_cell::sync::Lazy;
 use pingora_timeout::timeout;
-use prometheus::{register_int_counter, IntCounter};
-use std::sync::Arc;
 use std::time::Duration;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
 
 use pingora::apps::http_app::ServeHttp;
-use pingora::apps::ServerApp;
 use pingora::protocols::http::ServerSession;
-use pingora::protocols::Stream;
-use pingora::server::ShutdownWatch;
 
 // static ECHO_REQ_COUNTER: Lazy<IntCounter> =
 //     Lazy::new(|| register_int_counter!("reg_counter", "Number of requests").unwrap());
@@ -35,29 +27,35 @@ impl ServeHttp for HttpEchoApp {
         debug!("Path: {}", path);
         if path == "/echo" {
             let read_timeout = 2000;
-            let body = match timeout(
-                Duration::from_millis(read_timeout),
-                http_stream.read_request_body(),
-            )
-                .await
-            {
-                Ok(res) => match res.unwrap() {
-                    Some(bytes) => bytes,
-                    None => Bytes::from("no body!"),
-                },
-                Err(_) => {
-                    panic!("Timed out after {:?}ms", read_timeout);
+            // The body arrives in chunks, echo all of them
+            let mut body = Vec::new();
+            loop {
+                match timeout(
+                    Duration::from_millis(read_timeout),
+                    http_stream.read_request_body(),
+                )
+                    .await
+                {
+                    Ok(res) => match res.unwrap() {
+                        Some(bytes) => body.extend_from_slice(&bytes),
+                        None => break,
+                    },
+                    Err(_) => {
+                        panic!("Timed out after {:?}ms", read_timeout);
+                    }
                 }
-            };
+            }
+            if body.is_empty() {
+                body.extend_from_slice(b"no body!");
+            }
 
             Response::builder()
                 .status(StatusCode::OK)
                 .header(http::header::CONTENT_TYPE, "text/html")
                 .header(http::header::CONTENT_LENGTH, body.len())
-                .body(body.to_vec())
+                .body(body)
                 .unwrap()
-        } else
-        {
+        } else {
             Response::builder()
                 .status(StatusCode::NOT_FOUND)
                 .body("Not Found".to_string().into_bytes())
@@ -4,119 +4,729 @@
 // See the LICENSE file for full license details.
 // External crates
 use async_trait::async_trait;
-use base64::engine::general_purpose;
-use base64::Engine;
 use bytes::Bytes;
 use log::{debug, error, info, trace, warn};
-use once_cell::sync::Lazy;
-use prometheus::register_int_counter;
-use redb::{Database, TableDefinition};
-use reqwest::Client;
-use reqwest::Error as ReqwestError;
-use flate2::read::GzDecoder;
+use redb::Database;
 use http::header;
 
 // Pingora-related imports
 use pingora::prelude::*;
-use pingora_http::ResponseHeader;
-use pingora_limits::rate::Rate;
+use pingora_http::{RequestHeader, ResponseHeader};
 use pingora_proxy::{ProxyHttp, Session};
 use pingora::protocols::http::SERVER_NAME;
+use pingora::protocols::Digest;
+use pingora::modules::http::compression::ResponseCompression;
+use pingora::upstreams::peer::ALPN;
 
 // Internal modules
+use crate::auth;
 use crate::config;
 use crate::parsers;
 use crate::pii_protection;
+use crate::pii_protection::{PiiCache, PiiError};
+use crate::prompt_injection::{self, PromptInjectionError};
+use crate::moderation::{self, ModerationError};
+use crate::audit::{AuditLog, AuditRecord};
+use crate::usage_writer::{UsageDelta, UsageWriter};
+use crate::jwt::{JwtAuth, JwtError};
+use crate::introspection::Introspection;
+use crate::request_signing::{RequestSigning, SIGNATURE_HEADER};
+use crate::response_cache::{self, CachedResponse, ResponseCache, CACHE_STATUS_HEADER};
+use crate::coalescing::{self, Flight, Leader, RequestCoalescer, COALESCED_HEADER};
+use crate::stream_blacklist::StreamBlacklist;
+use crate::stream_usage::{self, UsageEventWriter};
+use crate::system_prompt;
+use crate::token_estimate;
 use crate::token_limit;
 use crate::rate_limit;
-use crate::app;
+use crate::load_balancing;
+use crate::cost;
+use crate::cache::AuthCache;
+use crate::sigv4;
+use crate::shadow::{self, ShadowUsage};
+use crate::circuit_breaker::{Admission, CircuitBreakers};
+use crate::api_keys::ApiKeys;
+use crate::concurrency::{ActiveRequests, ActiveRequest, ConcurrencyLimiter, InFlight, ModelRequest, UpstreamSlot, UpstreamSlots};
+use crate::errors::{error_message, insert_request_id, respond_json_error, respond_json_error_retry, REQUEST_ID_HEADER};
+use crate::maintenance_mode::MaintenanceMode;
+use crate::disabled_models::DisabledModels;
+use crate::compression::{self, DecodeError};
+use crate::cors;
+use crate::decisions::{self, Decision};
+use crate::parameter_limits;
+use crate::telemetry;
+use crate::ip_filter::{client_ip, from_trusted_proxy};
 
 // Re-exports from internal modules
-use config::{ModelConfig, QuotaPeriod, ServerConf};
-use parsers::{parse, parser_ollama};
-use token_limit::{check_token_limits, update_usage_periods};
+use config::{LocationMatch, ModelConfig, PiiFailMode, QuotaPeriod, ServerConf, UpstreamProtocol};
+use parsers::{parse, SseUsageParser};
+use token_limit::{find_exceeded_token_limit, reject_token_limit, QuotaStatus};
 use rate_limit::check_rate_limits;
+use load_balancing::select_upstream;
+use cost::request_cost_cents;
 
 // Constants and lazy statics
+use arc_swap::ArcSwap;
 use std::sync::Arc;
-use std::io::Read;
+use std::sync::atomic::AtomicUsize;
+use std::net::IpAddr;
+use std::time::Instant;
+use tracing::{debug_span, field, Span};
 use uuid::Uuid;
 
 
+/// Largest body read before the request is proxied, to check or compute a signature or to look
+/// up the response cache: Pingora's retry buffer, which replays that body
+const BODY_AHEAD_LIMIT: usize = 64 * 1024;
 
-const TOKENS: TableDefinition<&str, &str> = TableDefinition::new("tokens");
-const USAGE: TableDefinition<&str, u64> = TableDefinition::new("usage");
-const GROUPS: TableDefinition<&str, &str> = TableDefinition::new("groups");
+/// Milliseconds from the upstream connection to the last byte of its response, or to its
+/// headers for the responses that are not held
+const UPSTREAM_LATENCY_HEADER: &str = "X-Upstream-Latency-Ms";
+/// Tokens and cost counted for the request, on the held responses
+const INPUT_TOKENS_HEADER: &str = "X-Input-Tokens";
+const OUTPUT_TOKENS_HEADER: &str = "X-Output-Tokens";
+const REQUEST_COST_HEADER: &str = "X-Request-Cost-Usd";
 
-fn check_login(req: &pingora_http::RequestHeader) -> bool {
-    // implement you logic check logic here
-    req.headers.get("Authorization").map(|v| v.as_bytes()) == Some(b"password")
+/// Body of the request read before it is proxied, for the signatures covering it or the
+/// response cache key. Pingora replays it from its retry buffer, which bounds its size.
+async fn read_body_ahead(session: &mut Session, body_ahead: &mut Option<Bytes>, request_id: &str, too_large: &'static str) -> Result<Bytes> {
+    if let Some(body) = body_ahead {
+        return Ok(body.clone());
+    }
+    session.as_mut().enable_retry_buffering();
+    let mut body = Vec::new();
+    while let Some(chunk) = session.read_request_body().await? {
+        body.extend_from_slice(&chunk);
+        if body.len() > BODY_AHEAD_LIMIT {
+            warn!("{} Request body over {} bytes, {}", request_id, BODY_AHEAD_LIMIT, session.req_header().uri.path());
+            return Err(Error::explain(HTTPStatus(413), too_large));
+        }
+    }
+    Ok(body_ahead.insert(Bytes::from(body)).clone())
+}
+
+/// Body of a request sent with a `Content-Encoding`, decoded within the `max_request_bytes` of
+/// the location
+fn decode_request_body(ctx: &GatewayContext, body: Bytes) -> Result<Bytes> {
+    let Some(content_encoding) = &ctx.request_encoding else {
+        return Ok(body);
+    };
+    let max_request_bytes = ctx.model.as_ref()
+        .and_then(|model| model.max_request_bytes)
+        .unwrap_or(ctx.conf.max_request_bytes);
+    compression::decode(content_encoding, &body, max_request_bytes).map_err(|e| match e {
+        DecodeError::TooLarge => {
+            warn!("{} Decoded request body over {} bytes rejected", ctx.request_id, max_request_bytes);
+            Error::explain(HTTPStatus(413), "Request body too large")
+        }
+        DecodeError::Invalid(e) => {
+            warn!("{} Failed to decode the {} request body: {}", ctx.request_id, content_encoding, e);
+            Error::explain(HTTPStatus(400), "Invalid compressed request body")
+        }
+    })
+}
+
+/// Time left of the `request_deadline_ms` of the model since the request arrived, none without a
+/// deadline and zero once it passed
+fn time_left(model: &ModelConfig, ctx: &GatewayContext) -> Option<std::time::Duration> {
+    let deadline = std::time::Duration::from_millis(model.request_deadline_ms?);
+    let elapsed = (chrono::Utc::now() - ctx.time).to_std().unwrap_or_default();
+    Some(deadline.saturating_sub(elapsed))
+}
+
+/// Error ending an event stream whose generated text holds a blacklisted word. The client gets
+/// the stream cut before the line completing the word.
+fn stop_blacklisted_stream(ctx: &mut GatewayContext, word: &str) -> Box<Error> {
+    let user = ctx.user.as_deref().unwrap_or("none");
+    warn!("{} Blacklisted word found in response stream: {} and user {}, stopping it", ctx.request_id, word, user);
+    let message = "Blacklisted word found in response";
+    ctx.rejection = Some(message.to_string());
+    Error::explain(HTTPStatus(403), message)
 }
 
-fn contains_word_case_insensitive(text: &[u8], word: &str) -> bool {
-    // Convert the word to lowercase
-    let lowercase_word = word.to_lowercase();
+/// Answer a refused request with a JSON error, whose message is the reason of its decision
+async fn reject(session: &mut Session, ctx: &mut GatewayContext, status: u16, message: &str) {
+    ctx.rejection = Some(message.to_string());
+    let _ = respond_json_error(session, status, message).await;
+}
+
+/// Accept caller request ids that are short printable ASCII, to keep them safe in headers and logs
+fn is_valid_request_id(id: &str) -> bool {
+    !id.is_empty() && id.len() <= 128 && id.bytes().all(|b| b.is_ascii_graphic())
+}
 
-    // Convert the text to lowercase
-    let lowercase_text = String::from_utf8_lossy(text).to_lowercase();
+/// Whether the groups pass the `allowed_groups` of the location, always true when it is empty
+fn in_allowed_groups(model: &ModelConfig, groups: &[String]) -> bool {
+    let mut allowed_groups = model.allowed_groups.split(',').map(str::trim).filter(|g| !g.is_empty()).peekable();
+    allowed_groups.peek().is_none() || allowed_groups.any(|allowed| groups.iter().any(|g| g == allowed))
+}
+
+/// Whether one of the groups is in the `disabled_groups` of the location
+fn in_disabled_groups(model: &ModelConfig, groups: &[String]) -> bool {
+    model.disabled_groups.split(',').map(str::trim).filter(|g| !g.is_empty())
+        .any(|disabled| groups.iter().any(|g| g == disabled))
+}
 
-    // Check if the lowercase word exists in the lowercase text
-    let result = lowercase_text.contains(&lowercase_word);
-    info!("result: {:?}", result);
-    result
+/// An `Upgrade: websocket` handshake, the token may come among other protocols
+fn is_websocket_upgrade(request: &RequestHeader) -> bool {
+    request.headers.get(header::UPGRADE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.split(',').any(|protocol| protocol.trim().eq_ignore_ascii_case("websocket")))
+}
+
+/// Whether the media type of a `Content-Type` is allowed, `type/*` allowing all the subtypes
+fn content_type_allowed(allowed: &[String], content_type: &str) -> bool {
+    let media_type = content_type.split(';').next().unwrap_or_default().trim().to_ascii_lowercase();
+    allowed.iter().any(|allowed| *allowed == media_type
+        || allowed.strip_suffix("/*").is_some_and(|main_type| media_type.split('/').next() == Some(main_type)))
+}
+
+/// Count the tokens of the request body and of the response text, for upstreams reporting no usage
+fn estimate_tokens(request_body: Option<&Bytes>, response_text: &str, request_id: &str) -> (u64, u64) {
+    let request_text = request_body.map(|body| token_estimate::request_text(body)).unwrap_or_default();
+    let (input_tokens, output_tokens) = (token_estimate::count(&request_text), token_estimate::count(response_text));
+    info!("{} Upstream reported no usage, estimated {} input / {} output tokens", request_id, input_tokens, output_tokens);
+    (input_tokens, output_tokens)
+}
+
+/// Apply the `body_transform` of the model, a body that is not a JSON object is sent untouched
+fn transform_body(model: &ModelConfig, body: &Bytes, request_id: &str) -> Bytes {
+    let Some(transform) = &model.body_transform else {
+        return body.clone();
+    };
+    transform.apply(body).unwrap_or_else(|e| {
+        warn!("{} Request body of {} sent untransformed, {}", request_id, model.location, e);
+        body.clone()
+    })
+}
+
+/// Apply the `system_prompt` of the model, a body that is not a chat request is sent untouched
+fn apply_system_prompt(model: &ModelConfig, body: Bytes, request_id: &str) -> Bytes {
+    let Some(prompt) = &model.system_prompt else {
+        return body;
+    };
+    // Anthropic takes the system prompt apart from the messages
+    let top_level = model.parser == "anthropic";
+    system_prompt::apply(prompt, model.system_prompt_mode, top_level, &body).unwrap_or_else(|e| {
+        warn!("{} Request body of {} sent without the system prompt, {}", request_id, model.location, e);
+        body
+    })
+}
+
+/// Body sent upstream: the `parameter_limits` of the model enforced, then its `body_transform`
+/// and `system_prompt` applied. A parameter refused fails the request with a 400 naming it.
+fn prepare_body(model: &ModelConfig, body: &Bytes, request_id: &str) -> Result<Bytes> {
+    if model.parameter_limits.is_empty() {
+        return Ok(apply_system_prompt(model, transform_body(model, body, request_id), request_id));
+    }
+    let (body, adjustments) = parameter_limits::enforce(&model.parameter_limits, model.parameter_limit_mode, body)
+        .map_err(|reason| {
+            warn!("{} Request to {} refused, {}", request_id, model.location, reason);
+            Error::explain(HTTPStatus(400), reason)
+        })?;
+    for adjustment in adjustments {
+        match adjustment.from {
+            Some(from) => info!("{} {} of {} changed from {} to {}", request_id, adjustment.name, model.location, from, adjustment.to),
+            None => info!("{} {} of {} set to its default {}", request_id, adjustment.name, model.location, adjustment.to),
+        }
+    }
+    Ok(apply_system_prompt(model, transform_body(model, &body, request_id), request_id))
 }
 
 pub struct BurgonetGateway {
+    pub maintenance_mode: Arc<MaintenanceMode>,
+    pub disabled_models: Arc<DisabledModels>,
     pub req_metric: prometheus::IntCounter,
-    pub input_tokens: prometheus::IntCounter,
-    pub output_tokens: prometheus::IntCounter,
-    pub conf: Arc<ServerConf>,
+    /// Tokens by model location, and by user when `token_metrics_by_user` is set
+    pub input_tokens: prometheus::IntCounterVec,
+    pub output_tokens: prometheus::IntCounterVec,
+    pub token_metrics_by_user: bool,
+    pub upstream_requests: prometheus::IntCounterVec,
+    pub response_parse_errors: prometheus::IntCounter,
+    pub response_cache: ResponseCache,
+    /// Requests answered from the response cache and deterministic ones sent upstream, by location
+    pub response_cache_hits: prometheus::IntCounterVec,
+    pub response_cache_misses: prometheus::IntCounterVec,
+    pub coalescer: RequestCoalescer,
+    pub coalesced_requests: prometheus::IntCounterVec,
+    /// Request duration by model location and status class, not by user to bound the cardinality
+    pub request_duration: prometheus::HistogramVec,
+    /// Time from the connection to the upstream to the end of its response, by model location
+    pub upstream_latency: prometheus::HistogramVec,
+    pub upstream_counter: AtomicUsize,
+    pub conf: Arc<ArcSwap<ServerConf>>,
     pub db: Arc<Database>,
+    pub auth_cache: Arc<AuthCache>,
+    pub pii_cache: PiiCache,
+    pub audit_log: Option<Arc<AuditLog>>,
+    pub jwt: JwtAuth,
+    pub introspection: Introspection,
+    pub request_signing: RequestSigning,
+    pub circuit_breakers: CircuitBreakers,
+    pub api_keys: ApiKeys,
+    pub concurrency: ConcurrencyLimiter,
+    pub upstream_slots: UpstreamSlots,
+    /// Requests of each location between their first upstream attempt and their logging
+    pub in_flight_requests: prometheus::IntGaugeVec,
+    /// Requests moved to the fallback location of a model, by location and fallback
+    pub model_fallbacks: prometheus::IntCounterVec,
+    /// Requests in progress, waited for on shutdown
+    pub active_requests: ActiveRequests,
+    pub usage_writer: Arc<UsageWriter>,
 }
 
 
 
+impl BurgonetGateway {
+    /// Identify a bearer token missing from the token table as a JWT, or as an opaque token
+    /// by introspection.
+    /// Returns the user and groups, or the message of the 401 response.
+    async fn external_token(&self, conf: &ServerConf, token: &str) -> std::result::Result<(String, Vec<String>), &'static str> {
+        let mut reason = "not in the token table".to_string();
+        if let Some(jwt_conf) = &conf.jwt {
+            match self.jwt.validate(jwt_conf, token).await {
+                Ok(identity) => return Ok((identity.user, identity.groups)),
+                Err(JwtError::Expired) => return Err("API key expired"),
+                Err(JwtError::Invalid(jwt_reason)) => {
+                    warn!("Invalid JWT, {}", jwt_reason);
+                    return Err("Invalid API key");
+                }
+                Err(JwtError::Malformed) => reason = "not a JWT".to_string(),
+            }
+        }
+        if let Some(introspection_conf) = &conf.introspection {
+            match self.introspection.introspect(introspection_conf, token).await {
+                Ok(Some(introspected)) => return Ok((introspected.user, introspected.groups)),
+                Ok(None) => reason = "inactive at the introspection endpoint".to_string(),
+                Err(e) => {
+                    error!("Token introspection failed: {}", e);
+                    reason = "introspection failed".to_string();
+                }
+            }
+        }
+        warn!("Invalid token, {}", reason);
+        Err("Invalid API key")
+    }
+
+    /// Body sent upstream once the checks of the location pass: blacklists, PII protection and
+    /// `parameter_limits`, then `body_transform`. Kept in the context to be replayed on retries.
+    async fn check_request_body(&self, ctx: &mut GatewayContext, body: Bytes) -> Result<Bytes> {
+        let patterns = ctx.model.as_ref().map(|m| &m.redact_patterns[..]).unwrap_or_default();
+        info!(target: "audit", "{} Request ### {}", ctx.request_id, pii_protection::redact(&body, patterns));
+
+        let Some(model) = &ctx.model else {
+            ctx.request_body = Some(body.clone());
+            return Ok(body);
+        };
+
+        // test if the request body contain a blacklisted word
+        if let Some(found) = model.blacklist_matcher.as_ref().and_then(|m| m.find(&body[..])) {
+            let word = String::from_utf8_lossy(&body[found.range()]);
+            let user = ctx.user.as_ref().unwrap();
+            warn!("Blacklisted word found in request body: {} and user {}", word, user);
+            return Err(Error::explain(HTTPStatus(403), "Blacklisted word found in request body"));
+        }
+
+        if let Some(pattern) = model.blacklist_patterns.iter().find(|p| p.is_match(&body)) {
+            let user = ctx.user.as_ref().unwrap();
+            warn!("Blacklisted pattern {} found in request body and user {}", pattern, user);
+            return Err(Error::explain(HTTPStatus(403), "Blacklisted pattern found in request body"));
+        }
+
+        // Check prompt injection if configured
+        if !model.prompt_injection_url.is_empty() {
+            let timeout = std::time::Duration::from_millis(model.prompt_injection_timeout_ms);
+            match prompt_injection::check_prompt_injection(&model.prompt_injection_url, &body, timeout).await {
+                Ok(()) => {}
+                Err(PromptInjectionError::Detected) => {
+                    warn!("{} Prompt injection detected for user : {}", ctx.request_id, ctx.user.as_ref().unwrap());
+                    return Err(Error::explain(HTTPStatus(403), "Prompt injection found in request body"));
+                }
+                Err(PromptInjectionError::Service(reason)) => match model.prompt_injection_fail_mode {
+                    PiiFailMode::Open => {
+                        warn!("{} Prompt injection check skipped, {}", ctx.request_id, reason);
+                    }
+                    PiiFailMode::Closed => {
+                        error!("{} Prompt injection check failed, {}", ctx.request_id, reason);
+                        return Err(Error::explain(HTTPStatus(503), "Prompt injection detection service unavailable"));
+                    }
+                },
+            }
+        }
+
+        // Check moderation categories if configured
+        if !model.moderation_url.is_empty() {
+            let timeout = std::time::Duration::from_millis(model.moderation_timeout_ms);
+            match moderation::check_moderation(&model.moderation_url, &model.moderation_thresholds, &body, timeout).await {
+                Ok(()) => {}
+                Err(ModerationError::Flagged { category, score }) => {
+                    warn!("{} Moderation category {} scored {} for user : {}", ctx.request_id, category, score, ctx.user.as_ref().unwrap());
+                    return Err(Error::explain(HTTPStatus(403), format!("Request body flagged for {}", category)));
+                }
+                Err(ModerationError::Service(reason)) => match model.moderation_fail_mode {
+                    PiiFailMode::Open => {
+                        warn!("{} Moderation check skipped, {}", ctx.request_id, reason);
+                    }
+                    PiiFailMode::Closed => {
+                        error!("{} Moderation check failed, {}", ctx.request_id, reason);
+                        return Err(Error::explain(HTTPStatus(503), "Moderation service unavailable"));
+                    }
+                },
+            }
+        }
+
+        // Check PII protection if configured
+        if !model.pii_protection_url.is_empty() {
+            let timeout = std::time::Duration::from_millis(model.pii_timeout_ms);
+            match pii_protection::check_pii_protection(&self.pii_cache, &model.pii_protection_url, &body, timeout).await {
+                Ok(()) => {}
+                Err(PiiError::Detected) => {
+                    warn!("PII detected for user : {}", &ctx.user.as_ref().unwrap());
+                    return Err(Error::explain(HTTPStatus(403), "PII found in request body"));
+                }
+                Err(PiiError::Service(reason)) => match model.pii_fail_mode {
+                    PiiFailMode::Open => {
+                        warn!("{} PII check skipped, {}", ctx.request_id, reason);
+                    }
+                    PiiFailMode::Closed => {
+                        error!("{} PII check failed, {}", ctx.request_id, reason);
+                        return Err(Error::explain(HTTPStatus(503), "PII protection service unavailable"));
+                    }
+                },
+            }
+        }
+
+        let body = prepare_body(model, &body, &ctx.request_id)?;
+        ctx.request_body = Some(body.clone());
+        Ok(body)
+    }
+
+    /// Answer with the cached response of an identical deterministic request, or with the
+    /// response of an identical request in flight, if any. The body is read ahead to compute the
+    /// key, so only bodies with a `Content-Length` that fits the retry buffer are looked up; the
+    /// others are proxied as usual.
+    async fn serve_shared_response(&self, session: &mut Session, ctx: &mut GatewayContext) -> Result<bool> {
+        let content_length = session.req_header().headers.get(header::CONTENT_LENGTH)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.parse::<usize>().ok());
+        if content_length.is_none_or(|length| length > BODY_AHEAD_LIMIT) {
+            return Ok(false);
+        }
+        let body = read_body_ahead(session, &mut ctx.body_ahead, &ctx.request_id, "Request body too large").await?;
+        let body = decode_request_body(ctx, body)?;
+        let body = self.check_request_body(ctx, body).await?;
+
+        // The location tells fallbacks apart, the path the endpoints of a prefix location
+        let model = ctx.model.clone().unwrap();
+        let location = &model.location;
+        let path = session.req_header().uri.path_and_query().map_or("", |p| p.as_str());
+        let key = response_cache::key(&[location.as_bytes(), path.as_bytes(), &body]);
+
+        if model.response_cache_ttl_secs.is_some() && response_cache::is_deterministic(&body) {
+            if let Some(cached) = self.response_cache.get(&key) {
+                self.response_cache_hits.with_label_values(&[location]).inc();
+                info!("{} Response of {} served from the response cache", ctx.request_id, location);
+                self.write_shared_response(session, ctx, cached, (CACHE_STATUS_HEADER, "HIT")).await?;
+                return Ok(true);
+            }
+            self.response_cache_misses.with_label_values(&[location]).inc();
+            ctx.response_cache_key = Some(key);
+        }
+
+        if model.coalesce_requests && !response_cache::is_streamed(&body) {
+            match self.coalescer.join(key, &ctx.request_id) {
+                Flight::Leader(leader) => ctx.coalescing = Some(leader),
+                Flight::Follower { leader_id, outcome } => {
+                    debug!("{} Waiting for the identical request {}", ctx.request_id, leader_id);
+                    match coalescing::wait(outcome).await {
+                        Some(Ok(response)) => {
+                            self.coalesced_requests.with_label_values(&[location]).inc();
+                            info!("{} Response of {} shared by request {}", ctx.request_id, location, leader_id);
+                            self.write_shared_response(session, ctx, response, (COALESCED_HEADER, &leader_id)).await?;
+                            return Ok(true);
+                        }
+                        Some(Err(status)) => {
+                            self.coalesced_requests.with_label_values(&[location]).inc();
+                            warn!("{} Identical request {} failed with {}", ctx.request_id, leader_id, status);
+                            return Err(Error::explain(HTTPStatus(status), "Upstream request failed"));
+                        }
+                        None => debug!("{} Request {} has no response to share, proxying", ctx.request_id, leader_id),
+                    }
+                }
+            }
+        }
+
+        self.mirror_request(ctx);
+        Ok(false)
+    }
+
+    /// Answer with a response given by the upstream to an identical request, flagged by `marker`
+    async fn write_shared_response(&self, session: &mut Session, ctx: &mut GatewayContext, response: CachedResponse, marker: (&str, &str)) -> Result<()> {
+        let mut resp = ResponseHeader::build(response.status, Some(6))?;
+        resp.insert_header(header::SERVER, "Burgonet")?;
+        if let Some(content_type) = &response.content_type {
+            resp.insert_header(header::CONTENT_TYPE, content_type)?;
+        }
+        resp.insert_header(header::CONTENT_LENGTH, response.body.len())?;
+        resp.insert_header(marker.0.to_string(), marker.1)?;
+        insert_request_id(session, &mut resp)?;
+        cors::insert_response_headers(&ctx.conf.cors, session.req_header(), &mut resp)?;
+        if self.audit_log.is_some() && ctx.conf.audit_log_bodies {
+            ctx.response_body = Some(response.body.clone());
+        }
+        session.write_response_header(Box::new(resp), false).await?;
+        session.write_response_body(Some(response.body), true).await
+    }
+
+    /// Send the checked body to the shadow upstream of the location, if any
+    fn mirror_request(&self, ctx: &GatewayContext) {
+        let (Some(model), Some(body)) = (&ctx.model, &ctx.request_body) else {
+            return;
+        };
+        if model.shadow_proxy_pass.is_empty() {
+            return;
+        }
+        let mut labels = vec![format!("shadow:{}", model.location)];
+        if self.token_metrics_by_user {
+            labels.push(ctx.user.clone().unwrap_or_else(|| "none".to_string()));
+        }
+        shadow::mirror(model.clone(), body.clone(), ctx.request_id.clone(), ShadowUsage {
+            input_tokens: self.input_tokens.clone(),
+            output_tokens: self.output_tokens.clone(),
+            labels,
+        });
+    }
+
+    /// OpenAI style list of the locations the groups may use
+    async fn handle_models(&self, session: &mut Session, conf: &ServerConf, groups: &[String]) -> Result<bool> {
+        let data: Vec<serde_json::Value> = conf.models.iter()
+            .filter(|model| in_allowed_groups(model, groups) && !in_disabled_groups(model, groups))
+            .filter(|model| !self.disabled_models.is_disabled(&model.location))
+            .map(|model| serde_json::json!({"id": model.location, "object": "model", "created": 0, "owned_by": "burgonet"}))
+            .collect();
+        let body = serde_json::json!({"object": "list", "data": data}).to_string();
+        let mut resp = ResponseHeader::build(200, Some(4)).unwrap();
+        resp.insert_header(header::CONTENT_TYPE, "application/json").unwrap();
+        resp.insert_header(header::CONTENT_LENGTH, body.len()).unwrap();
+        cors::insert_response_headers(&conf.cors, session.req_header(), &mut resp)?;
+        session.write_response_header(Box::new(resp), false).await?;
+        session.write_response_body(Some(Bytes::from(body)), true).await?;
+        Ok(true)
+    }
+
+    /// Exchange a JSON `{"username": ..., "password": ...}` body for a bearer token
+    async fn handle_login(&self, session: &mut Session, ctx: &mut GatewayContext) -> Result<bool> {
+        let mut body = Vec::new();
+        while let Some(chunk) = session.read_request_body().await? {
+            body.extend_from_slice(&chunk);
+        }
+
+        let credentials = serde_json::from_slice::<serde_json::Value>(&body).ok();
+        let username = credentials.as_ref().and_then(|c| c["username"].as_str());
+        let password = credentials.as_ref().and_then(|c| c["password"].as_str());
+        let (Some(username), Some(password)) = (username, password) else {
+            reject(session, ctx, 400, "Expected a JSON body with username and password").await;
+            return Ok(true);
+        };
+
+        let verified = auth::verify_credentials(&self.db, username, password).unwrap_or_else(|e| {
+            error!("Failed to verify credentials: {}", e);
+            false
+        });
+        if !verified {
+            warn!("Invalid credentials for user {}", username);
+            reject(session, ctx, 401, "Invalid username or password").await;
+            return Ok(true);
+        }
+
+        let token = self.db.begin_write().map_err(anyhow::Error::from)
+            .and_then(|write_txn| auth::mint_token(&write_txn, username)
+                .and_then(|token| write_txn.commit().map(|_| token).map_err(Into::into)))
+            .map_err(|e| {
+                error!("Failed to mint token: {}", e);
+                Error::explain(HTTPStatus(500), "Internal server error")
+            })?;
+        info!(target: "audit", "User {} logged in", username);
+
+        let body = serde_json::json!({"token": token, "user": username}).to_string();
+        let mut resp = ResponseHeader::build(200, Some(4)).unwrap();
+        resp.insert_header(header::CONTENT_TYPE, "application/json").unwrap();
+        resp.insert_header(header::CONTENT_LENGTH, body.len()).unwrap();
+        cors::insert_response_headers(&self.conf.load().cors, session.req_header(), &mut resp)?;
+        session.write_response_header(Box::new(resp), false).await?;
+        session.write_response_body(Some(Bytes::from(body)), true).await?;
+        Ok(true)
+    }
+}
+
+/// State of one request. Pingora moves it between threads across await points, so it must be
+/// Send + Sync: database transactions are opened where they are used instead of kept here.
 pub struct GatewayContext {
+    pub conf: Arc<ServerConf>,
     pub model: Option<Arc<ModelConfig>>,
-    pub read_txn: Option<redb::ReadTransaction>,
-    pub write_txn: Option<redb::WriteTransaction>,
     buffer: Vec<u8>,
+    request_body: Option<Bytes>,
+    /// Buffered response kept for the audit log
+    response_body: Option<Bytes>,
+    pub retries: usize,
+    pub tried_upstreams: Vec<usize>,
     token: Option<String>,
     pub user: Option<String>,
+    pub groups: Vec<String>,
     pub time: chrono::DateTime<chrono::Utc>,
     pub input_tokens: u64,
     pub output_tokens: u64,
     pub usage_input: QuotaPeriod,
     pub usage_output: QuotaPeriod,
+    /// Set by check_token_limits when the location has token quotas
+    pub token_quota: Option<QuotaStatus>,
     pub upstream_headers: ResponseHeader,
-    pub request_id: Uuid,
-
+    /// SHA-256 of the request body, for upstreams signing their requests
+    payload_hash: Option<String>,
+    /// Body read before the request is proxied, to check or compute a signature
+    body_ahead: Option<Bytes>,
+    /// Key under which the response is cached, set when the request missed the response cache
+    response_cache_key: Option<[u8; 32]>,
+    /// Set when identical requests wait for the response of this one
+    coalescing: Option<Leader>,
+    /// Address of the client, from `X-Forwarded-For` behind trusted proxies
+    pub client_ip: Option<IpAddr>,
+    /// Literal prefix of the location matched by the client path, when a token quota moved the
+    /// request to a fallback
+    client_location: Option<String>,
+    /// URI sent by the client, before it is rewritten for the upstream
+    pub client_uri: Option<http::Uri>,
+    /// Status of the final upstream response, None when no upstream answered
+    pub upstream_status: Option<u16>,
+    /// This request is the half-open probe of the model circuit breaker
+    circuit_probe: bool,
+    /// Counted against `max_concurrent_requests` until released in logging
+    in_flight: Option<InFlight>,
+    /// One of the `upstream_max_connections` of the location, kept across retries
+    upstream_slot: Option<UpstreamSlot>,
+    /// Counted in the `in_flight_requests` of the location from the first upstream attempt until
+    /// released in logging
+    model_request: Option<ModelRequest>,
+    /// Index in `api_keys` of the key of the current upstream attempt
+    api_key: Option<usize>,
+    pub event_stream: Option<SseUsageParser>,
+    /// Set when the client asked for the usage of an event stream
+    usage_event: Option<UsageEventWriter>,
+    /// Set when the event stream is scanned for the `blacklist_words` of the location
+    stream_blacklist: Option<StreamBlacklist>,
+    /// Set once the response outgrew max_response_buffer_bytes and is no longer buffered
+    response_passthrough: bool,
+    /// Headers of a response that is not streamed, held until its body is buffered and counted
+    /// and sent with it from logging
+    held_response: Option<Box<ResponseHeader>>,
+    /// Body of the held response, as it goes to the client
+    held_body: Option<Bytes>,
+    /// WebSocket connection of a `websocket` location, its frames are passed through both ways
+    websocket: bool,
+    /// Reason of the error the gateway answered, for the decision of a refused request
+    rejection: Option<String>,
+    /// `Content-Encoding` of the request body, decoded before it is checked and proxied
+    request_encoding: Option<String>,
+    /// Decodes the upstream response body, for the encodings of `compression::ACCEPTED_ENCODINGS`
+    response_decoder: Option<compression::Decoder>,
+    pub request_id: String,
+    /// Span of the whole request, parent of the phase spans below
+    pub span: Span,
+    /// Span of the current upstream attempt, until the request is logged
+    upstream_span: Span,
+    /// When the current upstream attempt got its connection, for `X-Upstream-Latency-Ms` and the
+    /// `upstream_latency_seconds` histogram
+    upstream_connected: Option<Instant>,
+    /// Span of the response, from the upstream response header until the request is logged
+    response_span: Span,
+    /// Counted until the context is dropped, after logging committed the usage
+    _active: ActiveRequest,
 }
 
 
-// Implement Send and Sync since all fields are thread-safe
-unsafe impl Send for GatewayContext {}
-unsafe impl Sync for GatewayContext {}
-
+/// Send the response held by response_filter with its body, once counted: the headers tell the
+/// tokens and cost of the request and the upstream latency up to the last byte
+async fn write_held_response(session: &mut Session, ctx: &mut GatewayContext, mut held: Box<ResponseHeader>) {
+    let body = ctx.held_body.take().unwrap_or_default();
+    if let Some(connected) = ctx.upstream_connected {
+        let _ = held.insert_header(UPSTREAM_LATENCY_HEADER, connected.elapsed().as_millis().to_string());
+    }
+    if let Some(model) = &ctx.model {
+        let cost_usd = request_cost_cents(model, ctx.input_tokens, ctx.output_tokens) / 100.0;
+        let _ = held.insert_header(INPUT_TOKENS_HEADER, ctx.input_tokens.to_string());
+        let _ = held.insert_header(OUTPUT_TOKENS_HEADER, ctx.output_tokens.to_string());
+        let _ = held.insert_header(REQUEST_COST_HEADER, format!("{:.6}", cost_usd));
+
+        // compressed now that the compression module sees the final header
+        let level = model.response_compression_level.unwrap_or(ctx.conf.response_compression_level);
+        if let Some(compression) = session.downstream_modules_ctx.get_mut::<ResponseCompression>().filter(|_| level > 0) {
+            compression.adjust_level(level);
+        }
+    }
+    held.remove_header(&header::TRANSFER_ENCODING);
+    let _ = held.insert_header(header::CONTENT_LENGTH, body.len().to_string());
+
+    let written = match session.write_response_header(held, body.is_empty()).await {
+        Ok(()) if !body.is_empty() => session.write_response_body(Some(body), true).await,
+        result => result,
+    };
+    if let Err(e) = written {
+        warn!("{} Failed to send the response: {}", ctx.request_id, e);
+    }
+}
 
 #[async_trait]
 impl ProxyHttp for BurgonetGateway {
     type CTX = GatewayContext;
+
     fn new_ctx(&self) -> Self::CTX {
         GatewayContext {
+            _active: self.active_requests.start(),
+            conf: self.conf.load_full(),
             model: None,
-            read_txn: Some(self.db.begin_read().expect("Failed to begin read transaction")),
-            write_txn: Some(self.db.begin_write().expect("Failed to begin write transaction")),
             buffer: Vec::new(),
+            request_body: None,
+            response_body: None,
+            retries: 0,
+            tried_upstreams: Vec::new(),
             token: None,
             user: None,
+            groups: Vec::new(),
             time: chrono::Utc::now(),
             input_tokens: 0,
             output_tokens: 0,
             usage_input: QuotaPeriod::new(),
             usage_output: QuotaPeriod::new(),
+            token_quota: None,
             upstream_headers: ResponseHeader::build_no_case(200, Some(0)).expect("Failed to build response header"),
-            request_id: Uuid::new_v4(),
+            client_uri: None,
+            client_location: None,
+            client_ip: None,
+            payload_hash: None,
+            body_ahead: None,
+            response_cache_key: None,
+            coalescing: None,
+            upstream_status: None,
+            circuit_probe: false,
+            in_flight: None,
+            upstream_slot: None,
+            model_request: None,
+            api_key: None,
+            event_stream: None,
+            usage_event: None,
+            stream_blacklist: None,
+            response_passthrough: false,
+            held_response: None,
+            held_body: None,
+            websocket: false,
+            rejection: None,
+            request_encoding: None,
+            response_decoder: None,
+            request_id: Uuid::new_v4().to_string(),
+            span: Span::none(),
+            upstream_span: Span::none(),
+            upstream_connected: None,
+            response_span: Span::none(),
         }
     }
 
@@ -125,14 +735,49 @@ impl ProxyHttp for BurgonetGateway {
         info!("request_filter");
         trace!("Start of request_filter: {:?}", session.req_header().uri.path());
 
-        // Handle OPTIONS preflight requests
+        // Keep the caller's request id, or tag the request with the generated one
+        if let Some(request_id) = session.req_header().headers.get(REQUEST_ID_HEADER)
+            .and_then(|v| v.to_str().ok())
+            .filter(|id| is_valid_request_id(id))
+        {
+            ctx.request_id = request_id.to_string();
+        }
+        session.req_header_mut().insert_header(REQUEST_ID_HEADER, &ctx.request_id)?;
+
+        let request = session.req_header();
+        ctx.span = debug_span!(
+            "request",
+            otel.kind = "server",
+            request_id = %ctx.request_id,
+            http.request.method = %request.method,
+            url.path = request.uri.path(),
+            user = field::Empty,
+            model = field::Empty,
+            http.response.status_code = field::Empty,
+            input_tokens = field::Empty,
+            output_tokens = field::Empty,
+        );
+        telemetry::set_parent(&ctx.span, request);
+        let _phase = debug_span!(parent: &ctx.span, "request_filter");
+
+        // Reject the clients outside the allowed networks before any auth work
+        ctx.client_ip = client_ip(session, &ctx.conf.trusted_proxy_networks);
+        if !ctx.conf.ip_filter.allows(ctx.client_ip) {
+            warn!("{} Client address {:?} not allowed", ctx.request_id, ctx.client_ip);
+            reject(session, ctx, 403, "Client address not allowed").await;
+            return Ok(true);
+        }
+
+        // Answer OPTIONS preflight requests, allowing only the configured origins
         if session.req_header().method == http::Method::OPTIONS {
-            let mut resp = ResponseHeader::build(200, None).unwrap();
-            resp.insert_header("Access-Control-Allow-Origin", "*").unwrap();
-            resp.insert_header("Access-Control-Allow-Methods", "GET, POST, PUT, DELETE, OPTIONS").unwrap();
-            resp.insert_header("Access-Control-Allow-Headers", "Content-Type, Authorization, Accept").unwrap();
-            resp.insert_header("Access-Control-Max-Age", "86400").unwrap(); // 24 hours
-            session.write_response_header(Box::new(resp), true).await;
+            let mut resp = ResponseHeader::build(204, Some(5))?;
+            if let Some(cors) = &ctx.conf.cors {
+                if let Some(origin) = cors.allow_origin(session.req_header()) {
+                    cors.insert_preflight_headers(&mut resp, origin)?;
+                }
+            }
+            insert_request_id(session, &mut resp)?;
+            session.write_response_header(Box::new(resp), true).await?;
             return Ok(true);
         }
 
@@ -142,6 +787,45 @@ impl ProxyHttp for BurgonetGateway {
             return Ok(true);
         }
 
+        if session.req_header().uri.path() == "/login" && session.req_header().method == http::Method::POST {
+            return self.handle_login(session, ctx).await;
+        }
+
+        // A location ending with a slash, or matched as a prefix or a regex, serves several paths.
+        // An exact match wins, then the longest literal prefix, then the first listed.
+        let path = session.req_header().uri.path();
+        let model = ctx.conf.models.iter()
+            .find(|m| m.location_match != LocationMatch::Regex && m.location == path)
+            .or_else(|| ctx.conf.models.iter()
+                .filter(|m| m.matches_path(path))
+                .rev()
+                .max_by_key(|m| m.location_prefix.len()))
+            .cloned()
+            .map(Arc::new);
+
+        if let Some(model) = &model {
+            if !model.ip_filter.allows(ctx.client_ip) {
+                warn!("{} Client address {:?} not allowed on {}", ctx.request_id, ctx.client_ip, model.location);
+                reject(session, ctx, 403, "Client address not allowed").await;
+                return Ok(true);
+            }
+        }
+
+        // Model requests wait out the maintenance, before any authentication touches the database
+        if model.is_some() && self.maintenance_mode.is_enabled() {
+            debug!("{} Rejected during maintenance: {}", ctx.request_id, session.req_header().uri.path());
+            ctx.rejection = Some("Gateway under maintenance".to_string());
+            let _ = respond_json_error_retry(session, 503, "Gateway under maintenance, try again later", ctx.conf.maintenance_retry_after_secs).await;
+            return Ok(true);
+        }
+
+        // Locations taken out of rotation, until enabled again or the configuration is reloaded
+        if let Some(model) = model.as_ref().filter(|m| self.disabled_models.is_disabled(&m.location)) {
+            debug!("{} Rejected on disabled location {}", ctx.request_id, model.location);
+            reject(session, ctx, 503, "Model disabled by the administrator").await;
+            return Ok(true);
+        }
+
         // test if the request contain a bearer token
         let token = session.req_header().headers.get("Authorization")
             .and_then(|v| v.to_str().ok())
@@ -150,25 +834,46 @@ impl ProxyHttp for BurgonetGateway {
         debug!("token: {:?}", token);
 
 
-        if let Some(token) = token {
-            if let Some(read_txn) = &ctx.read_txn {
-                let table = read_txn.open_table(TOKENS).expect("Failed to open table");
-
-                match table.get(token) {
-                    Ok(Some(value)) if !value.value().is_empty() => {
-                        trace!("Token is valid");
+        // Groups from the claims of a JWT, instead of the groups table
+        let mut claim_groups = None;
+        if session.req_header().headers.contains_key(SIGNATURE_HEADER) {
+            let body = read_body_ahead(session, &mut ctx.body_ahead, &ctx.request_id, "Request body too large for a signed request").await?;
+            match self.request_signing.verify(&self.db, session.req_header(), &body, ctx.conf.signature_max_age_secs, ctx.time.timestamp()) {
+                Ok(user) => ctx.user = Some(user),
+                Err(message) => {
+                    warn!("{} {}, request : {:?}", ctx.request_id, message, session.req_header().uri.path());
+                    reject(session, ctx, 401, message).await;
+                    return Ok(true);
+                }
+            }
+        } else if let Some(token) = token {
+            match self.auth_cache.token_record(&self.db, token) {
+                Some(record) if record.is_expired(ctx.time) => {
+                    warn!("Expired token for user {}, expired at {}", record.user, record.expires_at.unwrap().to_rfc3339());
+                    reject(session, ctx, 401, "API key expired").await;
+                    return Ok(true);
+                }
+                Some(record) => {
+                    trace!("Token is valid");
+                    ctx.token = Some(token.to_string());
+                    ctx.user = Some(record.user);
+                }
+                None => match self.external_token(&ctx.conf, token).await {
+                    Ok((user, groups)) => {
                         ctx.token = Some(token.to_string());
-                        ctx.user = Some(value.value().to_string());
+                        ctx.user = Some(user);
+                        claim_groups = Some(groups);
                     }
-                    _ => {
-                        warn!("Invalid token, request : {:?}", session.req_header().uri.path());
-                        let _ = session.respond_error(401).await;
+                    Err(message) => {
+                        warn!("{}, request : {:?}", message, session.req_header().uri.path());
+                        reject(session, ctx, 401, message).await;
                         return Ok(true);
                     }
-                }
+                },
             }
-        } else if self.conf.trust_header_authentication.iter().any(|h| session.req_header().headers.contains_key(h)) {
-            let user = self.conf.trust_header_authentication.iter()
+        } else if ctx.conf.trust_header_authentication.iter().any(|h| session.req_header().headers.contains_key(h))
+            && from_trusted_proxy(session, &ctx.conf.trusted_proxy_networks) {
+            let user = ctx.conf.trust_header_authentication.iter()
                 .find_map(|h| session.req_header().headers.get(h))
                 .and_then(|v| v.to_str().ok());
 
@@ -177,71 +882,196 @@ impl ProxyHttp for BurgonetGateway {
             }
             debug!("User from trusted header: {:?}", ctx.user);
         } else  {
-            let _ = session.respond_error(401).await;
+            if ctx.conf.trust_header_authentication.iter().any(|h| session.req_header().headers.contains_key(h)) {
+                warn!("{} Identity header from {:?} ignored, not a trusted proxy", ctx.request_id, session.client_addr());
+            }
+            reject(session, ctx, 401, "Missing API key, expected an Authorization: Bearer header").await;
             return Ok(true);
         }
 
         trace!("request: {:?}", session.req_header().uri.path());
 
-        let model = self.conf.models.iter()
-            .find(|m| m.location == session.req_header().uri.path())
-            .cloned()
-            .map(Arc::new);
-
         println!("URI {}", session.req_header().uri.path());
 
         if model.is_none() {
-            let _ = session.respond_error(404).await;
+            if session.req_header().uri.path() == "/models" && session.req_header().method == http::Method::GET {
+                let groups = claim_groups.or_else(|| ctx.user.as_ref().and_then(|user| self.auth_cache.groups_for_user(&self.db, user)))
+                    .unwrap_or_default();
+                return self.handle_models(session, &ctx.conf, &groups).await;
+            }
+            let message = format!("No model configured for location {}", session.req_header().uri.path());
+            reject(session, ctx, 404, &message).await;
             return Ok(true);
         }
-        trace!("model: {:?}", model);
+        // not the whole model, it holds the api key
+        trace!("model: {}", model.as_ref().map_or("", |m| m.location.as_str()));
 
         ctx.model = model;
-        // Skip quota check if no user is set
-        let Some(user) = &ctx.user else {
-            return Ok(false);
-        };
 
-        // Check rate limits
-        if let Err(response) = check_rate_limits(&ctx, session).await {
-            return Err(response);
+        // WebSocket frames are bridged as they come, only the locations expecting them take them
+        if is_websocket_upgrade(session.req_header()) {
+            if !ctx.model.as_ref().unwrap().websocket {
+                warn!("{} WebSocket upgrade refused on {}", ctx.request_id, session.req_header().uri.path());
+                reject(session, ctx, 400, "WebSocket not enabled for this location").await;
+                return Ok(true);
+            }
+            ctx.websocket = true;
+        }
+
+        // Reject the bodies the upstream cannot parse before buffering them
+        let allowed_content_types = &ctx.model.as_ref().unwrap().allowed_content_types;
+        if let Some(content_type) = session.req_header().headers.get(header::CONTENT_TYPE) {
+            let content_type = content_type.to_str().unwrap_or_default();
+            if !content_type_allowed(allowed_content_types, content_type) {
+                warn!("{} Content-Type {:?} not allowed on {}", ctx.request_id, content_type, session.req_header().uri.path());
+                let message = format!("Unsupported Content-Type, expected one of: {}", allowed_content_types.join(", "));
+                reject(session, ctx, 415, &message).await;
+                return Ok(true);
+            }
         }
 
-        //
-        // get the usage of the user for quota check
-        let read_txn = self.db.begin_read().expect("Failed to begin read transaction");
+        // Compressed bodies are decoded once read, the upstream gets them plain
+        if let Some(content_encoding) = session.req_header().headers.get(header::CONTENT_ENCODING) {
+            let content_encoding = content_encoding.to_str().unwrap_or_default().trim().to_ascii_lowercase();
+            if !ctx.websocket && content_encoding != "identity" {
+                if !compression::is_supported(&content_encoding) {
+                    warn!("{} Content-Encoding {:?} not supported on {}", ctx.request_id, content_encoding, session.req_header().uri.path());
+                    reject(session, ctx, 415, "Unsupported Content-Encoding, expected gzip or br").await;
+                    return Ok(true);
+                }
+                ctx.request_encoding = Some(content_encoding);
+            }
+        }
 
+        // Pingora's compression module is off until the location sets a level, it has skipped
+        // the Accept-Encoding of the request so far
+        let model = ctx.model.as_ref().unwrap();
+        let level = model.response_compression_level.unwrap_or(ctx.conf.response_compression_level);
+        if level > 0 && !ctx.websocket {
+            if let Some(compression) = session.downstream_modules_ctx.get_mut::<ResponseCompression>() {
+                compression.adjust_level(level);
+                compression.request_filter(session.downstream_session.req_header());
+            }
+        }
+
+        // Fail fast while the upstreams of the model keep failing
+        match self.circuit_breakers.admit(ctx.model.as_ref().unwrap()) {
+            Admission::Allowed => {}
+            Admission::Probe => ctx.circuit_probe = true,
+            Admission::Rejected => {
+                warn!("{} Circuit breaker open for {}", ctx.request_id, session.req_header().uri.path());
+                reject(session, ctx, 503, "Model temporarily unavailable after repeated upstream failures").await;
+                return Ok(true);
+            }
+        }
+
+        // Skip quota check if no user is set
+        let Some(user) = &ctx.user else {
+            return Ok(false);
+        };
 
         // Check groups are allowed to access the location
-        let table = read_txn.open_table(GROUPS).expect("Failed to open table");
-        let groups = match table.get(user.as_ref() as &str) {
-            Ok(Some(access_guard)) => access_guard.value().split(',').map(|s| s.trim().to_string()).collect::<Vec<String>>(),
-            _ => {
+        let groups = claim_groups.or_else(|| self.auth_cache.groups_for_user(&self.db, user))
+            .unwrap_or_else(|| {
                 warn!("User {} not found in groups table", user);
                 Vec::new() // Return empty vector if user not found
-            }
-        };
+            });
 
         let model = ctx.model.as_ref().unwrap();
-        let disabled_groups = model.disabled_groups.split(',').map(str::trim).collect::<Vec<&str>>();
-        // find if the user group is in the disabled groups
-        if groups.iter().any(|g| disabled_groups.contains(&g.as_str())) {
+        // allowed_groups is checked first, then disabled_groups can still exclude an allowed user
+        if !in_allowed_groups(model, &groups) {
+            warn!("User {} not in an allowed group for {}", user, model.location);
+            reject(session, ctx, 403, "User is not allowed to access this model").await;
+            return Ok(true);
+        }
+
+        if in_disabled_groups(model, &groups) {
             let error_message = format!("User {} in a disabled group", user);
             warn!("{}", error_message);
-            //return Err(Error::explain(HTTPStatus(403), error_message));
-            let _ = session.respond_error(401).await;
+            reject(session, ctx, 401, "User group is disabled for this model").await;
             return Ok(true);
 
         }
-        // Check token limits
-        if let Err(response) = check_token_limits(ctx, session).await {
-            return Err(response);
+        ctx.groups = groups;
+
+        // Check user and group rate limits
+        check_rate_limits(ctx, session).await?;
+
+        // Check token limits, moving on to the fallback location of an exhausted model
+        let mut hops = 0;
+        while let Some(exceeded) = find_exceeded_token_limit(&self.db, ctx)? {
+            let model = ctx.model.clone().unwrap();
+            let fallback = model.fallback_model_location.as_ref()
+                .and_then(|location| ctx.conf.models.iter().find(|m| m.location == *location))
+                .filter(|fallback| hops < ctx.conf.models.len() && fallback.ip_filter.allows(ctx.client_ip)
+                    && in_allowed_groups(fallback, &ctx.groups) && !in_disabled_groups(fallback, &ctx.groups))
+                .cloned()
+                .map(Arc::new);
+            let Some(fallback) = fallback else {
+                return reject_token_limit(session, exceeded).await;
+            };
+            let admission = self.circuit_breakers.admit(&fallback);
+            if let Admission::Rejected = admission {
+                warn!("{} Circuit breaker open for the fallback {} of {}", ctx.request_id, fallback.location, model.location);
+                return reject_token_limit(session, exceeded).await;
+            }
+            if ctx.circuit_probe {
+                self.circuit_breakers.release_probe(&model);
+            }
+            ctx.circuit_probe = matches!(admission, Admission::Probe);
+            warn!("{} User {:?} over a token quota of {}, falling back to {}", ctx.request_id, ctx.user, model.location, fallback.location);
+            self.model_fallbacks.with_label_values(&[&model.location, &fallback.location]).inc();
+            ctx.client_location.get_or_insert_with(|| model.location_prefix.clone());
+            ctx.model = Some(fallback);
+            hops += 1;
+        }
+
+        // Stop the requests of a user whose spend of the day reached a cap
+        let (user, model) = (ctx.user.as_ref().unwrap(), ctx.model.as_ref().unwrap());
+        match cost::reached_cost_cap(&self.db, &ctx.conf, model, user, ctx.time) {
+            Ok(None) => {}
+            Ok(Some(message)) => {
+                warn!("{} User {} reached a daily spend cap on {}", ctx.request_id, user, model.location);
+                reject(session, ctx, 402, message).await;
+                return Ok(true);
+            }
+            Err(e) => {
+                error!("Failed to read the daily costs: {}", e);
+                return Err(Error::explain(HTTPStatus(500), "Internal server error"));
+            }
+        }
+
+        // Count the request in flight until the context is dropped, whatever ends the request
+        let (user, model) = (ctx.user.as_ref().unwrap(), ctx.model.as_ref().unwrap());
+        let limit = match model.max_concurrent_requests {
+            Some(limit) => Some(((user.clone(), model.location.clone()), limit)),
+            None => ctx.conf.max_concurrent_requests.map(|limit| ((user.clone(), String::new()), limit)),
+        };
+        if let Some((key, limit)) = limit {
+            match self.concurrency.acquire(key, limit) {
+                Some(in_flight) => ctx.in_flight = Some(in_flight),
+                None => {
+                    warn!("{} User {} has {} requests in flight on {}", ctx.request_id, user, limit, model.location);
+                    reject(session, ctx, 429, "Too many concurrent requests").await;
+                    return Ok(true);
+                }
+            }
+        }
+
+        info!(target: "audit", "{} User {:?} accessed location {}", ctx.request_id, ctx.user, session.req_header().uri.path());
+        if ctx.websocket {
+            return Ok(false);
         }
 
         // change the accept header to  "text/plain"
         let _ = session.req_header_mut().insert_header("Accept", "text/plain");
 
-        info!(target: "audit", "{} User {:?} accessed location {}", ctx.request_id, ctx.user, session.req_header().uri.path());
+        // Answer repeated deterministic requests and identical requests in flight without
+        // calling the upstream again
+        let model = ctx.model.as_ref().unwrap();
+        if (model.response_cache_ttl_secs.is_some() || model.coalesce_requests) && self.serve_shared_response(session, ctx).await? {
+            return Ok(true);
+        }
         trace!("End of request_filter: {:?}", session.req_header().uri.path());
         Ok(false)
     }
@@ -261,39 +1091,36 @@ impl ProxyHttp for BurgonetGateway {
             return Ok(());
         }
 
+        // Frames of a WebSocket connection, from the client to the upstream
+        if _ctx.websocket {
+            return Ok(());
+        }
+
+        // On a retry the body is replayed: send the one already checked
+        if let Some(request_body) = &_ctx.request_body {
+            *_body = if _end_of_stream { Some(request_body.clone()) } else { None };
+            return Ok(());
+        }
+
         if let Some(b) = _body {
             _ctx.buffer.extend(&b[..]);
             b.clear();
+            let max_request_bytes = _ctx.model.as_ref()
+                .and_then(|model| model.max_request_bytes)
+                .unwrap_or(_ctx.conf.max_request_bytes);
+            if _ctx.buffer.len() > max_request_bytes {
+                warn!("Request body over {} bytes rejected for user {:?}", max_request_bytes, _ctx.user);
+                return Err(Error::explain(HTTPStatus(413), "Request body too large"));
+            }
         }
         if _end_of_stream {
-            *_body = Some(Bytes::from(std::mem::take(&mut _ctx.buffer)));
-            info!(target: "audit", "{} Request ### {}", _ctx.request_id, String::from_utf8_lossy(_body.as_ref().unwrap()));
-
-            if let Some(model) = &_ctx.model {
-                if let Some(text) = _body.as_ref() {
-
-                    // test if the request body contain a blacklisted word
-                    for word in model.blacklist_words.split(',').map(str::trim).filter(|w| !w.is_empty()) {
-                        trace!("word: {}", word);
-                        trace!("text: {:?}", text);
-                        if contains_word_case_insensitive(text, word) {
-                            let user = _ctx.user.as_ref().unwrap();
-                            warn!("Blacklisted word found in request body: {} and user {}", word, user);
-                            return Err(Error::explain(HTTPStatus(403), "Blacklisted word found in request body"));
-                        }
-                    }
-
-                    // Check PII protection if configured
-                    if !model.pii_protection_url.is_empty() {
-                        if let Err(e) = pii_protection::check_pii_protection(&model.pii_protection_url, text).await {
-                            warn!("PII detected for user : {}", &_ctx.user.as_ref().unwrap());
-                            return Err(e);
-                        }
-                    }
-                }
-            }
+            let body = Bytes::from(std::mem::take(&mut _ctx.buffer));
+            let body = decode_request_body(_ctx, body)?;
+            *_body = Some(self.check_request_body(_ctx, body).await?);
+            // Mirror the checked body once, retries replay it without coming back here
+            self.mirror_request(_ctx);
         }
-        return Ok(());
+        Ok(())
     }
 
     async fn upstream_peer(
@@ -301,52 +1128,242 @@ impl ProxyHttp for BurgonetGateway {
         session: &mut Session,
         ctx: &mut Self::CTX,
     ) -> Result<Box<HttpPeer>> {
-
+        let _phase = debug_span!(parent: &ctx.span, "upstream_peer");
 
         let model = ctx.model.as_ref().ok_or_else(|| {
-            anyhow::anyhow!("No model found for request")
-        }).unwrap();
+            error!("No model found for request");
+            Error::explain(InternalError, "No model found for request")
+        })?;
+
+        // not the whole model, it holds the api key
+        trace!("model: {} ({})", model.location, model.model_name);
+
+        // The signature covers the body, so it is read before the request is sent.
+        // Pingora replays it from its retry buffer, which bounds its size.
+        if model.aws_signer.is_some() && ctx.payload_hash.is_none() {
+            let body = read_body_ahead(session, &mut ctx.body_ahead, &ctx.request_id, "Request body too large for a signed upstream").await?;
+            // request_body_filter decodes and prepares the replayed body the same way
+            let body = decode_request_body(ctx, body)?;
+            let body = prepare_body(model, &body, &ctx.request_id)?;
+            if model.rewrites_body() || ctx.request_encoding.is_some() {
+                session.req_header_mut().remove_header(&header::TRANSFER_ENCODING);
+                let _ = session.req_header_mut().insert_header(header::CONTENT_LENGTH, body.len());
+            }
+            ctx.payload_hash = Some(sigv4::payload_hash(&body));
+        }
 
-        trace!("model: {:?}", model);
+        if ctx.model_request.is_none() {
+            ctx.model_request = Some(ModelRequest::start(self.in_flight_requests.with_label_values(&[&model.location])));
+        }
 
-        let proxy_url = url::Url::parse(&model.proxy_pass)
-            .map_err(|e| anyhow::anyhow!("Invalid proxy_pass URL: {}", e));
+        // Every attempt, retries included, ends by the deadline of the request
+        let left = time_left(model, ctx);
+        if left.is_some_and(|left| left.is_zero()) {
+            warn!("{} Request deadline of {}ms exceeded after {} retries", ctx.request_id, model.request_deadline_ms.unwrap_or_default(), ctx.retries);
+            return Err(Error::explain(HTTPStatus(504), "Request deadline exceeded"));
+        }
+        let within_deadline = |timeout: Option<std::time::Duration>| match (timeout, left) {
+            (Some(timeout), Some(left)) => Some(timeout.min(left)),
+            (timeout, left) => timeout.or(left),
+        };
 
-        // extract uri from the proxy_url
-        let uri = proxy_url.as_ref().map(|u| u.path()).unwrap().to_string();
+        // Wait for a connection to the upstreams of the location when they are all in use
+        if let (Some(limit), None) = (model.upstream_max_connections, &ctx.upstream_slot) {
+            let queue_timeout = std::time::Duration::from_millis(model.upstream_queue_timeout_ms);
+            let timeout = within_deadline(Some(queue_timeout)).unwrap_or(queue_timeout);
+            match self.upstream_slots.acquire(&model.location, limit, timeout).await {
+                Some(slot) => ctx.upstream_slot = Some(slot),
+                None if timeout < queue_timeout => {
+                    warn!("{} Request deadline exceeded waiting for an upstream connection of {}", ctx.request_id, model.location);
+                    return Err(Error::explain(HTTPStatus(504), "Request deadline exceeded"));
+                }
+                None => {
+                    warn!("{} No upstream connection of {} freed within {}ms", ctx.request_id, model.location, model.upstream_queue_timeout_ms);
+                    return Err(Error::explain(HTTPStatus(503), "Upstream connection limit reached"));
+                }
+            }
+        }
 
-        // replace the uri with the path from the request
-        session.req_header_mut().set_uri(uri.as_str().parse().unwrap());
+        // pick one of the model upstreams (weighted round-robin)
+        let index = select_upstream(&model.upstreams, &self.upstream_counter, &ctx.tried_upstreams)
+            .ok_or_else(|| {
+                error!("No valid upstream for location {}", model.location);
+                Error::explain(HTTPStatus(502), "No valid upstream")
+            })?;
+        ctx.tried_upstreams.push(index);
+        let upstream = &model.upstreams[index];
+        self.upstream_requests.with_label_values(&[&upstream.proxy_pass]).inc();
+
+        // join the client path after the location and its query to the proxy_pass,
+        // from the client URI kept on the first attempt since the header is rewritten
+        let target = &upstream.target;
+        let client_uri = ctx.client_uri.get_or_insert_with(|| session.req_header().uri.clone());
+        let location = ctx.client_location.as_deref().unwrap_or(&model.location_prefix);
+        let suffix = client_uri.path().strip_prefix(location).unwrap_or_default();
+        let uri = target.request_uri(suffix, client_uri.query()).map_err(|e| {
+            warn!("Invalid upstream URI for {}: {}", client_uri, e);
+            Error::explain(HTTPStatus(400), "Invalid request path")
+        })?;
+        session.req_header_mut().set_uri(uri);
+
+        trace!("connecting to {}:{}, tls: {}", target.host, target.port, target.tls);
+        let sni = model.sni.clone().unwrap_or_else(|| target.host.clone());
+        let mut peer = Box::new(HttpPeer::new((target.host.as_str(), target.port), target.tls, sni));
+        peer.options.connection_timeout = within_deadline(model.connect_timeout_ms.map(std::time::Duration::from_millis));
+        peer.options.total_connection_timeout = left;
+        peer.options.read_timeout = within_deadline(model.read_timeout_ms.map(std::time::Duration::from_millis));
+        peer.options.write_timeout = left;
+        peer.options.alpn = match model.upstream_protocol {
+            // The upgrade handshake only exists in HTTP/1.1
+            _ if ctx.websocket => ALPN::H1,
+            UpstreamProtocol::Http1 => ALPN::H1,
+            UpstreamProtocol::Http2 => ALPN::H2,
+            UpstreamProtocol::Auto => ALPN::H2H1,
+        };
+        trace!("peer: {:?}", peer);
 
-        let host = proxy_url.as_ref().map(|u| u.host_str().unwrap());
+        // send the api key in the auth header of the provider, never the gateway token of the client
+        ctx.api_key = self.api_keys.pick(model);
+        let api_key = ctx.api_key.map_or(&model.api_key, |index| &model.api_keys[index]);
+        let auth_value = if model.auth_scheme.is_empty() {
+            api_key.clone()
+        } else {
+            format!("{} {}", model.auth_scheme, api_key)
+        };
+        session.req_header_mut().remove_header(&header::AUTHORIZATION);
+        if model.aws_signer.is_none() {
+            let _ = session.req_header_mut().insert_header(model.auth_header_name.clone(), auth_value);
+        }
+        // add Content-Type: application/json, and ask for the encodings decoded in response_body_filter
+        if !ctx.websocket {
+            let _ = session.req_header_mut().insert_header("Content-Type", "application/json");
+            let _ = session.req_header_mut().insert_header(header::ACCEPT_ENCODING, compression::ACCEPTED_ENCODINGS);
+        }
+        // correlate the upstream call with the gateway logs, and with the trace of the request
+        let _ = session.req_header_mut().insert_header(REQUEST_ID_HEADER, &ctx.request_id);
+        ctx.upstream_span = debug_span!(
+            parent: &ctx.span,
+            "upstream",
+            otel.kind = "client",
+            server.address = %target.host,
+            server.port = target.port,
+            attempt = ctx.tried_upstreams.len(),
+            http.response.status_code = field::Empty,
+        );
+        telemetry::inject(&ctx.upstream_span, session.req_header_mut());
 
-        trace!("host: {:?}", host);
+        // add host header
+        let host_header = model.upstream_host_header.as_ref().unwrap_or(&target.host);
+        let _ = session.req_header_mut().insert_header("Host", host_header);
 
-        let port = proxy_url.as_ref().map(|u| u.port().unwrap_or(443));
-        // unwrap is safe because we have already checked the host
-        let addr = (host.unwrap(), port.unwrap());
+        // provider specific headers, validated when the configuration is loaded
+        for name in &model.upstream_headers_remove {
+            session.req_header_mut().remove_header(name);
+        }
+        for (name, value) in &model.upstream_headers_add {
+            let _ = session.req_header_mut().insert_header(name.clone(), value);
+        }
 
-        //let addr = (host.unwrap(), 443);
-        trace!("connecting to {addr:?}");
+        // sign last, over the final path and Host
+        if let (Some(signer), Some(payload_hash)) = (&model.aws_signer, &ctx.payload_hash) {
+            let request = session.req_header();
+            let headers = signer.sign(request.method.as_str(), &request.uri, host_header, payload_hash, chrono::Utc::now());
+            for (name, value) in headers {
+                let _ = session.req_header_mut().insert_header(name, value);
+            }
+        }
 
-        let tls = proxy_url.as_ref().map(|u| u.scheme() == "https").unwrap();
-        trace!("tls: {:?}", tls);
-        let peer = Box::new(HttpPeer::new(addr, tls, host.unwrap().to_string()));
-        trace!("peer: {:?}", peer);
+        trace!("session.req_header_mut(): {:?}", session.req_header_mut());
+        Ok(peer)
+    }
 
-        // add header Authorization to the request for the peer with the api key
-        let api_key = model.api_key.clone();
-        let _ = session.req_header_mut().insert_header("Authorization", "Bearer ".to_string() + &api_key);
-        // add Content-Type: application/json
-        let _ = session.req_header_mut().insert_header("Content-Type", "application/json");
+    /// A transformed or decoded body changes length, so it goes out chunked unless it was read
+    /// and measured to be signed. Only the upstream request is changed: the client body is still
+    /// read with the framing the client sent. HTTP/2 has no chunked encoding, its frames
+    /// delimit the body.
+    async fn upstream_request_filter(
+        &self,
+        _session: &mut Session,
+        upstream_request: &mut RequestHeader,
+        ctx: &mut Self::CTX,
+    ) -> Result<()> {
+        let Some(model) = &ctx.model else {
+            return Ok(());
+        };
+        if ctx.request_encoding.is_some() {
+            upstream_request.remove_header(&header::CONTENT_ENCODING);
+        }
+        if (model.rewrites_body() || ctx.request_encoding.is_some()) && model.aws_signer.is_none()
+            && upstream_request.headers.contains_key(header::CONTENT_LENGTH) {
+            upstream_request.remove_header(&header::CONTENT_LENGTH);
+            if upstream_request.version != http::Version::HTTP_2 {
+                upstream_request.insert_header(header::TRANSFER_ENCODING, "chunked")?;
+            }
+        }
+        Ok(())
+    }
 
-        // add host header
-        let _ = session.req_header_mut().insert_header("Host", host.unwrap());
 
-        trace!("session.req_header_mut(): {:?}", session.req_header_mut());
-        Ok(peer)
+    /// Errors returned by the filters, like the blacklist 403, get a JSON body unless
+    /// a response was already written
+    async fn fail_to_proxy(&self, session: &mut Session, e: &Error, _ctx: &mut Self::CTX) -> u16 {
+        let code = match e.etype() {
+            HTTPStatus(code) => *code,
+            ConnectTimedout | ReadTimedout if *e.esource() == ErrorSource::Upstream => 504,
+            _ => match e.esource() {
+                ErrorSource::Upstream => 502,
+                ErrorSource::Downstream => match e.etype() {
+                    WriteError | ReadError | ConnectionClosed => 0,
+                    _ => 400,
+                },
+                ErrorSource::Internal | ErrorSource::Unset => 500,
+            },
+        };
+        // Identical requests waiting for this one fail alike, but for a client gone away
+        if let Some(leader) = _ctx.coalescing.take() {
+            if code > 0 {
+                leader.complete(Err(code));
+            }
+        }
+        if code > 0 && session.response_written().is_none() {
+            let _ = respond_json_error(session, code, &error_message(e, code)).await;
+        }
+        code
     }
 
+    fn fail_to_connect(
+        &self,
+        _session: &mut Session,
+        peer: &HttpPeer,
+        ctx: &mut Self::CTX,
+        mut e: Box<Error>,
+    ) -> Box<Error> {
+        if let Some(model) = &ctx.model {
+            if ctx.retries < model.max_retries {
+                ctx.retries += 1;
+                warn!("Failed to connect to {}, retrying ({}/{})", peer, ctx.retries, model.max_retries);
+                e.set_retry(true);
+            }
+        }
+        e
+    }
+
+    async fn connected_to_upstream(
+        &self,
+        _session: &mut Session,
+        _reused: bool,
+        _peer: &HttpPeer,
+        #[cfg(unix)] _fd: std::os::unix::io::RawFd,
+        #[cfg(windows)] _sock: std::os::windows::io::RawSocket,
+        _digest: Option<&Digest>,
+        ctx: &mut Self::CTX,
+    ) -> Result<()>
+    where
+        Self::CTX: Send + Sync,
+    {
+        ctx.upstream_connected = Some(Instant::now());
+        Ok(())
+    }
 
     async fn response_filter(
         &self,
@@ -357,22 +1374,87 @@ impl ProxyHttp for BurgonetGateway {
     where
         Self::CTX:  Send + Sync,
     {
+        // Fail over to another upstream on gateway errors, as long as the
+        // request body can still be replayed
+        let status = upstream_response.status.as_u16();
+        _ctx.upstream_span.record("http.response.status_code", status);
+        _ctx.response_span = debug_span!(parent: &_ctx.span, "response", http.response.status_code = status);
+        if let (429, Some(model), Some(index)) = (status, &_ctx.model, _ctx.api_key) {
+            self.api_keys.cool_down(model, index);
+        }
+        if matches!(status, 502..=504) {
+            if let Some(model) = &_ctx.model {
+                if _ctx.retries < model.max_retries && !_session.as_ref().retry_buffer_truncated() {
+                    _ctx.retries += 1;
+                    warn!("Upstream returned {}, retrying ({}/{})", status, _ctx.retries, model.max_retries);
+                    let mut e = Error::explain(HTTPStatus(status), "Upstream error");
+                    e.set_retry(true);
+                    return Err(e);
+                }
+            }
+        }
+
 
         _ctx.upstream_headers = upstream_response.clone();
+        _ctx.upstream_status = Some(status);
 
-        // Add CORS headers for all responses
-        upstream_response
-            .insert_header("Access-Control-Allow-Origin", "*")
-            .unwrap();
-        upstream_response
-            .insert_header("Access-Control-Allow-Methods", "GET, POST, PUT, DELETE, OPTIONS")
-            .unwrap();
-        upstream_response
-            .insert_header("Access-Control-Allow-Headers", "Content-Type, Authorization, Accept")
-            .unwrap();
-        upstream_response
-            .insert_header("Access-Control-Expose-Headers", "Content-Length, Content-Range")
-            .unwrap();
+        // The gateway reads the bodies it asked in gzip or br decoded, like the client does
+        _ctx.response_decoder = upstream_response.headers.get(header::CONTENT_ENCODING)
+            .and_then(|v| v.to_str().ok())
+            .and_then(compression::response_decoder);
+        let is_decoded = _ctx.response_decoder.is_some();
+
+        // Server-Sent Events and NDJSON streams (Ollama) are counted as they stream instead of
+        // being buffered
+        let content_type = upstream_response.headers.get(header::CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or_default();
+        let is_sse = content_type.starts_with("text/event-stream");
+        let is_ndjson = content_type.starts_with("application/x-ndjson");
+        let is_event_stream = is_sse || is_ndjson;
+        if is_event_stream {
+            let mut event_stream = SseUsageParser::default();
+            event_stream.ndjson = is_ndjson;
+            let matcher = _ctx.model.as_ref()
+                .filter(|m| m.blacklist_stream_responses)
+                .and_then(|m| m.blacklist_matcher.as_ref());
+            _ctx.stream_blacklist = matcher.map(StreamBlacklist::new);
+            if _ctx.stream_blacklist.is_some() || _ctx.model.as_ref().is_some_and(|m| m.estimate_tokens) {
+                event_stream.text = Some(String::new());
+            }
+            _ctx.event_stream = Some(event_stream);
+            let is_encoded = upstream_response.headers.contains_key(header::CONTENT_ENCODING) && !is_decoded;
+            if is_sse && !is_encoded && _ctx.request_body.as_ref().is_some_and(|body| stream_usage::is_requested(body)) {
+                _ctx.usage_event = Some(UsageEventWriter::default());
+            }
+        }
+
+        upstream_response.insert_header(REQUEST_ID_HEADER, &_ctx.request_id)?;
+        // Responses that are not streamed are held until their body is counted, the others leave
+        // before their body, the latency header then stops at the upstream response headers
+        let no_body = _session.req_header().method == http::Method::HEAD || matches!(status, 204 | 304);
+        let too_large = upstream_response.headers.get(header::CONTENT_LENGTH)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.parse::<usize>().ok())
+            .is_some_and(|length| length > _ctx.conf.max_response_buffer_bytes);
+        let holds = !is_event_stream && !_ctx.websocket && !no_body && !too_large;
+        if let (false, Some(connected)) = (holds, _ctx.upstream_connected) {
+            upstream_response.insert_header(UPSTREAM_LATENCY_HEADER, connected.elapsed().as_millis().to_string())?;
+        }
+        if _ctx.response_cache_key.is_some() {
+            upstream_response.insert_header(CACHE_STATUS_HEADER, "MISS")?;
+        }
+
+        // let clients back off before they hit their token quota
+        if let Some(quota) = &_ctx.token_quota {
+            upstream_response.insert_header("X-Quota-Limit", quota.limit.to_string())?;
+            upstream_response.insert_header("X-Quota-Used", quota.used.to_string())?;
+            upstream_response.insert_header("X-Quota-Reset", quota.reset_seconds.to_string())?;
+        }
+
+        // CORS headers for the allowed origins only, whatever the upstream answered
+        cors::remove_headers(upstream_response);
+        cors::insert_response_headers(&_ctx.conf.cors, _session.req_header(), upstream_response)?;
 
         // Replace existing header if any
         upstream_response
@@ -381,13 +1463,43 @@ impl ProxyHttp for BurgonetGateway {
         // Because we don't support h3
         upstream_response.remove_header("alt-svc");
 
-        upstream_response.remove_header("Content-Encoding");
-        upstream_response.remove_header("content-encoding");
+        // A decoded body changes length, so it goes out chunked, and compressed again by
+        // `response_compression_level` for the clients accepting it. Other responses are
+        // forwarded byte for byte and keep the upstream framing. Pingora itself sends the body
+        // of HTTP/2 upstreams chunked when it has no length.
+        if is_decoded {
+            upstream_response.remove_header(&header::CONTENT_ENCODING);
+        }
+        // a compressor holds back the events until it has enough of them
+        if is_event_stream {
+            if let Some(compression) = _session.downstream_modules_ctx.get_mut::<ResponseCompression>() {
+                compression.adjust_level(0);
+            }
+        }
 
-        upstream_response.remove_header("Content-Length");
-        upstream_response
-            .insert_header("Transfer-Encoding", "Chunked")
-            .unwrap();
+        let adds_usage_event = _ctx.usage_event.is_some() && upstream_response.headers.contains_key(header::CONTENT_LENGTH);
+        let transforms = !is_event_stream && upstream_response.status.is_success()
+            && _ctx.model.as_ref().is_some_and(|m| m.response_transform.is_some());
+        if is_decoded || adds_usage_event || transforms {
+            upstream_response.remove_header("Content-Length");
+            if upstream_response.version != http::Version::HTTP_2 {
+                upstream_response
+                    .insert_header("Transfer-Encoding", "Chunked")
+                    .unwrap();
+            }
+        }
+
+        // Pingora writes the header it is left with at once: an informational one, which it skips
+        // when told to. The body chunks are dropped until logging writes the held response, the
+        // compression stays undecided until then.
+        if holds {
+            if let Some(compression) = _session.downstream_modules_ctx.get_mut::<ResponseCompression>() {
+                compression.adjust_level(0);
+            }
+            _session.set_ignore_info_resp(true);
+            let placeholder = ResponseHeader::build(103, Some(0))?;
+            _ctx.held_response = Some(Box::new(std::mem::replace(upstream_response, placeholder)));
+        }
 
         Ok(())
     }
@@ -402,35 +1514,164 @@ impl ProxyHttp for BurgonetGateway {
     where
         Self::CTX: Send + Sync,
     {
+        if let Some(decoder) = _ctx.response_decoder.as_mut() {
+            let encoded = body.take().unwrap_or_default();
+            match decoder.encode(&encoded, end_of_stream) {
+                Ok(decoded) => *body = Some(decoded),
+                Err(e) => {
+                    warn!("{} Failed to decode the upstream response: {}", _ctx.request_id, e);
+                    return Err(Error::explain(HTTPStatus(502), "Invalid compressed upstream response"));
+                }
+            }
+        }
+        if let Some(event_stream) = _ctx.event_stream.as_mut() {
+            let parser = _ctx.model.as_ref().map(|m| m.parser.as_str()).unwrap_or_default();
+            if let Some(b) = body {
+                event_stream.feed(b, parser);
+                if let Some(blacklist) = _ctx.stream_blacklist.as_mut() {
+                    if let Some(word) = blacklist.scan(event_stream.text.as_deref().unwrap_or_default()) {
+                        return Err(stop_blacklisted_stream(_ctx, &word));
+                    }
+                    *b = blacklist.filter(b);
+                }
+                if let Some(writer) = _ctx.usage_event.as_mut() {
+                    *b = writer.filter(b);
+                }
+            }
+            if end_of_stream {
+                event_stream.finish(parser);
+                if let Some(blacklist) = _ctx.stream_blacklist.as_mut() {
+                    if let Some(word) = blacklist.scan(event_stream.text.as_deref().unwrap_or_default()) {
+                        return Err(stop_blacklisted_stream(_ctx, &word));
+                    }
+                    // the line held back goes through the usage event writer like the others
+                    let rest = blacklist.finish();
+                    let rest = match _ctx.usage_event.as_mut() {
+                        Some(writer) => writer.filter(&rest),
+                        None => rest,
+                    };
+                    let mut last = body.take().map(Vec::from).unwrap_or_default();
+                    last.extend_from_slice(&rest);
+                    *body = Some(Bytes::from(last));
+                }
+                _ctx.input_tokens = event_stream.input_tokens;
+                _ctx.output_tokens = event_stream.output_tokens;
+                let estimates = _ctx.model.as_ref().is_some_and(|m| m.estimate_tokens);
+                if let Some(text) = event_stream.text.take().filter(|_| estimates && _ctx.input_tokens + _ctx.output_tokens == 0) {
+                    (_ctx.input_tokens, _ctx.output_tokens) = estimate_tokens(_ctx.request_body.as_ref(), &text, &_ctx.request_id);
+                }
+                info!(target: "audit", "{} Response ### event stream, {} input / {} output tokens", _ctx.request_id, _ctx.input_tokens, _ctx.output_tokens);
+
+                // Give the client the usage it asked for when the upstream did not send it
+                if let Some(writer) = _ctx.usage_event.as_mut() {
+                    let counted = _ctx.input_tokens > 0 || _ctx.output_tokens > 0;
+                    let usage = (counted && !event_stream.usage_reported).then_some((_ctx.input_tokens, _ctx.output_tokens));
+                    let mut last = body.take().map(Vec::from).unwrap_or_default();
+                    last.extend_from_slice(&writer.finish(usage));
+                    *body = Some(Bytes::from(last));
+                }
+            }
+            return Ok(None);
+        }
+
+        if _ctx.response_passthrough || _ctx.websocket {
+            return Ok(None);
+        }
         if let Some(b) = body {
+            if _ctx.buffer.len() + b.len() > _ctx.conf.max_response_buffer_bytes {
+                // the headers of a held response are gone, it can only fail
+                if _ctx.held_response.take().is_some() {
+                    warn!("{} Response over {} bytes, too large to be held", _ctx.request_id, _ctx.conf.max_response_buffer_bytes);
+                    return Err(Error::explain(HTTPStatus(502), "Upstream response too large"));
+                }
+                warn!("{} Response over {} bytes, passing it through and skipping token accounting",
+                    _ctx.request_id, _ctx.conf.max_response_buffer_bytes);
+                let mut buffered = std::mem::take(&mut _ctx.buffer);
+                buffered.extend(&b[..]);
+                *b = Bytes::from(buffered);
+                _ctx.response_passthrough = true;
+                return Ok(None);
+            }
             _ctx.buffer.extend(&b[..]);
             b.clear();
         }
         if end_of_stream {
-
-            // test if _ctx.upstream_headers contains the header "content-encoding" with value "gzip"
-            if let Some(content_encoding) = _ctx.upstream_headers.headers.get("content-encoding") {
-                if content_encoding == "gzip" {
-                    let mut decoder = flate2::read::GzDecoder::new(&_ctx.buffer[..]);
-                    let mut decoded = Vec::new();
-                    decoder.read_to_end(&mut decoded).unwrap();
-                    _ctx.buffer = decoded;
+            let json_body = serde_json::de::from_slice::<serde_json::Value>(&_ctx.buffer);
+            // The client of a response_transform gets the envelope, the tokens are still counted
+            // below from the upstream response
+            let transform = _ctx.model.as_ref().and_then(|m| m.response_transform.as_ref())
+                .filter(|_| _ctx.upstream_status.is_some_and(|status| (200..300).contains(&status)));
+            *body = Some(match (transform, &json_body) {
+                (Some(transform), Ok(json)) => {
+                    _ctx.buffer.clear();
+                    transform.apply(json)
                 }
+                _ => Bytes::from(std::mem::take(&mut _ctx.buffer)),
+            });
+            if _ctx.held_response.is_some() {
+                _ctx.held_body = body.clone();
             }
-            let json_body = serde_json::de::from_slice(&_ctx.buffer).unwrap();
-            *body = Some(Bytes::from(std::mem::take(&mut _ctx.buffer)));
+            if let Some(leader) = _ctx.coalescing.take() {
+                leader.complete(Ok(CachedResponse {
+                    status: _ctx.upstream_status.unwrap_or(200),
+                    content_type: _ctx.upstream_headers.headers.get(header::CONTENT_TYPE)
+                        .and_then(|v| v.to_str().ok())
+                        .map(String::from),
+                    body: body.clone().unwrap_or_default(),
+                }));
+            }
+            if self.audit_log.is_some() && _ctx.conf.audit_log_bodies {
+                _ctx.response_body = body.clone();
+            }
+
+            // Forward unparsable responses (HTML error pages, truncated JSON) untouched
+            let json_body = match json_body {
+                Ok(json_body) => json_body,
+                Err(e) => {
+                    warn!("{} Invalid JSON response, skipping token accounting: {}", _ctx.request_id, e);
+                    self.response_parse_errors.inc();
+                    return Ok(None);
+                }
+            };
 
-            info!(target: "audit", "{} Response ### {}", _ctx.request_id, json_body);
+            let patterns = _ctx.model.as_ref().map(|m| &m.redact_patterns[..]).unwrap_or_default();
+            info!(target: "audit", "{} Response ### {}", _ctx.request_id, pii_protection::redact(json_body.to_string().as_bytes(), patterns));
 
             if let Some(model) = &_ctx.model {
                 match parse(&json_body, &model.parser) {
+                    Ok((0, 0)) if model.estimate_tokens => {
+                        let mut text = String::new();
+                        token_estimate::collect_response_text(&json_body, &mut text);
+                        (_ctx.input_tokens, _ctx.output_tokens) = estimate_tokens(_ctx.request_body.as_ref(), &text, &_ctx.request_id);
+                    }
                     Ok((input_tokens, output_tokens)) => {
                         _ctx.input_tokens = input_tokens;
                         _ctx.output_tokens = output_tokens;
                     }
+                    Err(e) if model.estimate_tokens => {
+                        debug!("{} No usage in the response: {}", _ctx.request_id, e);
+                        let mut text = String::new();
+                        token_estimate::collect_response_text(&json_body, &mut text);
+                        (_ctx.input_tokens, _ctx.output_tokens) = estimate_tokens(_ctx.request_body.as_ref(), &text, &_ctx.request_id);
+                    }
+                    // Upstream error bodies (429, 4xx) are valid JSON without usage, forward them
                     Err(e) => {
-                        error!("Error parsing response: {}", e);
-                        return Err(Error::explain(ErrorType::InternalError, "Error parsing response"));
+                        warn!("{} No usage in the response, skipping token accounting: {}", _ctx.request_id, e);
+                        self.response_parse_errors.inc();
+                        return Ok(None);
+                    }
+                }
+
+                // Keep the successful answers of deterministic requests for the identical ones
+                if let (Some(key), Some(ttl)) = (_ctx.response_cache_key.take(), model.response_cache_ttl_secs) {
+                    if _ctx.upstream_status == Some(200) {
+                        self.response_cache.insert(key, CachedResponse {
+                            status: 200,
+                            content_type: _ctx.upstream_headers.headers.get(header::CONTENT_TYPE)
+                                .and_then(|v| v.to_str().ok())
+                                .map(String::from),
+                            body: body.clone().unwrap_or_default(),
+                        }, std::time::Duration::from_secs(ttl));
                     }
                 }
             }
@@ -447,7 +1688,7 @@ impl ProxyHttp for BurgonetGateway {
     ) {
         debug!("logging uri path: {:?}", session.req_header().uri.path());
         if session.req_header().uri.path() == "/" {
-            let models: std::collections::HashMap<String, std::collections::HashMap<String, String>> = self.conf.models.iter().map(|m| {
+            let models: std::collections::HashMap<String, std::collections::HashMap<String, String>> = ctx.conf.models.iter().map(|m| {
                 let mut model_info = std::collections::HashMap::new();
                 model_info.insert("parser".to_string(), m.parser.clone());
                 model_info.insert("location".to_string(), m.location.clone());
@@ -459,31 +1700,122 @@ impl ProxyHttp for BurgonetGateway {
             let mut resp = ResponseHeader::build(200, Some(4)).unwrap();
             resp.insert_header(header::SERVER, &SERVER_NAME[..]).unwrap();
             resp.insert_header(header::CONTENT_TYPE, "application/json").unwrap();
-            // Add CORS headers
-            resp.insert_header("Access-Control-Allow-Origin", "*").unwrap();
-            resp.insert_header("Access-Control-Allow-Methods", "GET, POST, PUT, DELETE, OPTIONS").unwrap();
-            resp.insert_header("Access-Control-Allow-Headers", "Content-Type, Authorization, Accept").unwrap();
-            resp.insert_header("Access-Control-Expose-Headers", "Content-Length, Content-Range").unwrap();
-            session.write_response_header(Box::new(resp), true).await;
-            session.write_response_body(Some(Bytes::from(json_conf.into_bytes())), true).await;
+            let _ = cors::insert_response_headers(&ctx.conf.cors, session.req_header(), &mut resp);
+            let _ = session.write_response_header(Box::new(resp), true).await;
+            let _ = session.write_response_body(Some(Bytes::from(json_conf.into_bytes())), true).await;
             debug!("Returning configuration from logging");
 
         } else {
+            if let Some(held) = ctx.held_response.take() {
+                if session.response_written().is_none() {
+                    write_held_response(session, ctx, held).await;
+                }
+            }
             let response_code = session
                 .response_written()
                 .map_or(0, |resp| resp.status.as_u16());
-            info!("{} response code: {response_code}", self.request_summary(session, ctx));
+            tracing::info!(
+                request_id = %ctx.request_id,
+                request = %self.request_summary(session, ctx),
+                user = ctx.user.as_deref(),
+                model = ctx.model.as_ref().map(|m| m.model_name.as_str()),
+                status = response_code,
+                upstream_status = ctx.upstream_status,
+                input_tokens = ctx.input_tokens,
+                output_tokens = ctx.output_tokens,
+                duration_ms = (chrono::Utc::now() - ctx.time).num_milliseconds(),
+                "request completed"
+            );
+            ctx.span.record("http.response.status_code", response_code);
+            ctx.span.record("user", ctx.user.as_deref());
+            ctx.span.record("model", ctx.model.as_ref().map(|m| m.model_name.as_str()));
+            ctx.span.record("input_tokens", ctx.input_tokens);
+            ctx.span.record("output_tokens", ctx.output_tokens);
+            ctx.in_flight = None;
+            ctx.upstream_slot = None;
+            ctx.model_request = None;
+
+            // Only requests that got an upstream answer or an upstream error count for the breaker
+            if let Some(model) = &ctx.model {
+                let upstream_error = _e.is_some_and(|e| *e.esource() == ErrorSource::Upstream);
+                match ctx.upstream_status {
+                    _ if upstream_error => self.circuit_breakers.record(model, false),
+                    Some(status) => self.circuit_breakers.record(model, status < 500),
+                    None if ctx.circuit_probe => self.circuit_breakers.release_probe(model),
+                    None => {}
+                }
+            }
+
+            if let Some(audit_log) = &self.audit_log {
+                let bodies = ctx.conf.audit_log_bodies;
+                let patterns = ctx.model.as_ref().map(|m| &m.redact_patterns[..]).unwrap_or_default();
+                let body_text = |body: &Option<Bytes>| body.as_ref().filter(|_| bodies).map(|b| pii_protection::redact(b, patterns));
+                audit_log.record(AuditRecord {
+                    timestamp: ctx.time.to_rfc3339(),
+                    request_id: ctx.request_id.clone(),
+                    user: ctx.user.clone(),
+                    model: ctx.model.as_ref().map(|m| m.model_name.clone()),
+                    location: ctx.model.as_ref().map(|m| m.location.clone()),
+                    status: response_code,
+                    input_tokens: ctx.input_tokens,
+                    output_tokens: ctx.output_tokens,
+                    request_body: body_text(&ctx.request_body),
+                    response_body: body_text(&ctx.response_body),
+                });
+            }
+
+            // Why the gateway itself refused the request: the errors it answered before any
+            // upstream did, but for its own failures
+            let refused = _e.filter(|e| ctx.upstream_status.is_none() && matches!(e.etype(), HTTPStatus(400..=499)));
+            if let Some(reason) = ctx.rejection.take().or_else(|| refused.map(|e| error_message(e, response_code))) {
+                self.usage_writer.record_decision(Decision {
+                    timestamp: decisions::timestamp(ctx.time),
+                    request_id: ctx.request_id.clone(),
+                    user: ctx.user.clone(),
+                    model: ctx.model.as_ref().map(|m| m.model_name.clone()),
+                    location: ctx.model.as_ref().map(|m| m.location.clone()),
+                    path: ctx.client_uri.as_ref().unwrap_or(&session.req_header().uri).path().to_string(),
+                    client_ip: ctx.client_ip.map(|ip| ip.to_string()),
+                    decision: "deny".to_string(),
+                    status: response_code,
+                    reason,
+                }).await;
+            }
 
             self.req_metric.inc();
-            self.input_tokens.inc_by(ctx.input_tokens);
-            self.output_tokens.inc_by(ctx.output_tokens);
-
-            //get the current time in hour
-            let current_time = std::time::SystemTime::now();
-            //format the time to get the current hour YYYY-MM-DD-HH
-            let current_hour = chrono::Utc::now().format("%Y%m%d%H").to_string();
-            // store in the table usage the number of tokens used by the user with key current_hour:user:input_tokens
-            update_usage_periods(ctx);
+            let location = ctx.model.as_ref().map_or("none", |m| m.location.as_str());
+            let status_class = match response_code {
+                100..=599 => format!("{}xx", response_code / 100),
+                _ => "none".to_string(),
+            };
+            let elapsed = (chrono::Utc::now() - ctx.time).to_std().unwrap_or_default();
+            self.request_duration.with_label_values(&[location, &status_class]).observe(elapsed.as_secs_f64());
+            if let (Some(model), Some(connected)) = (&ctx.model, ctx.upstream_connected) {
+                if ctx.upstream_status.is_some() {
+                    self.upstream_latency.with_label_values(&[&model.location]).observe(connected.elapsed().as_secs_f64());
+                }
+            }
+            let user = ctx.user.as_deref().unwrap_or("none");
+            let token_labels = if self.token_metrics_by_user { vec![location, user] } else { vec![location] };
+            self.input_tokens.with_label_values(&token_labels).inc_by(ctx.input_tokens);
+            self.output_tokens.with_label_values(&token_labels).inc_by(ctx.output_tokens);
+
+            // committed in batches by the usage writer, weighted for the quotas; the metrics
+            // above and the cost count the tokens of the upstream
+            if let (Some(model), Some(user)) = (&ctx.model, &ctx.user) {
+                if ctx.input_tokens + ctx.output_tokens > 0 {
+                    let (input_tokens, output_tokens) = model.weighted_tokens(ctx.input_tokens, ctx.output_tokens);
+                    self.usage_writer.record(UsageDelta {
+                        user: user.clone(),
+                        location: model.location.clone(),
+                        time: ctx.time,
+                        periods: ctx.conf.usage_periods.clone(),
+                        input_tokens,
+                        output_tokens,
+                        cents: request_cost_cents(model, ctx.input_tokens, ctx.output_tokens),
+                    }).await;
+                }
+            }
         }
     }
 }
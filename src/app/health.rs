@@ -0,0 +1,107 @@
+// Copyright (c) 2025 Sébastien Campion, FOSS4. All rights reserved.
+//
+// This software is provided under the Commons Clause License Condition v1.0.
+// See the LICENSE file for full license details.
+
+use arc_swap::ArcSwap;
+use async_trait::async_trait;
+use http::{Response, StatusCode};
+use log::{error, warn};
+use pingora_timeout::timeout;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::TcpStream;
+
+use pingora::apps::http_app::ServeHttp;
+use pingora::protocols::http::ServerSession;
+
+use crate::config::ServerConf;
+
+const UPSTREAM_CONNECT_TIMEOUT: Duration = Duration::from_millis(500);
+
+pub struct HttpHealthApp {
+    pub db: Arc<redb::Database>,
+    pub conf: Arc<ArcSwap<ServerConf>>,
+}
+
+#[async_trait]
+impl ServeHttp for HttpHealthApp {
+    async fn response(&self, http_stream: &mut ServerSession) -> Response<Vec<u8>> {
+        match http_stream.req_header().uri.path() {
+            "/healthz" => {
+                let db_ok = self.check_database();
+                self.health_response(db_ok, serde_json::json!({"database": db_ok}))
+            }
+            "/readyz" => {
+                let db_ok = self.check_database();
+                let upstreams = self.check_upstreams().await;
+                let ready = db_ok && upstreams.values().all(|ok| *ok);
+                self.health_response(ready, serde_json::json!({"database": db_ok, "upstreams": upstreams}))
+            }
+            _ => Response::builder()
+                .status(StatusCode::NOT_FOUND)
+                .header(http::header::CONTENT_LENGTH, "Not Found".len())
+                .body("Not Found".to_string().into_bytes())
+                .unwrap(),
+        }
+    }
+}
+
+impl HttpHealthApp {
+    fn check_database(&self) -> bool {
+        match self.db.begin_read() {
+            Ok(_) => true,
+            Err(e) => {
+                error!("Health check: failed to open read transaction: {}", e);
+                false
+            }
+        }
+    }
+
+    /// Try a TCP connect to every distinct upstream host, all in parallel
+    async fn check_upstreams(&self) -> BTreeMap<String, bool> {
+        let conf = self.conf.load_full();
+        let mut addresses: Vec<String> = conf.models.iter()
+            .flat_map(|m| m.upstreams.iter())
+            .map(|u| format!("{}:{}", u.target.host, u.target.port))
+            .collect();
+        addresses.sort();
+        addresses.dedup();
+
+        let checks: Vec<_> = addresses.into_iter()
+            .map(|address| tokio::spawn(async move {
+                let ok = matches!(timeout(UPSTREAM_CONNECT_TIMEOUT, TcpStream::connect(&address)).await, Ok(Ok(_)));
+                if !ok {
+                    warn!("Readiness check: upstream {} is unreachable", address);
+                }
+                (address, ok)
+            }))
+            .collect();
+
+        let mut results = BTreeMap::new();
+        for check in checks {
+            if let Ok((address, ok)) = check.await {
+                results.insert(address, ok);
+            }
+        }
+        results
+    }
+
+    fn health_response(&self, healthy: bool, details: serde_json::Value) -> Response<Vec<u8>> {
+        let (status, label) = if healthy {
+            (StatusCode::OK, "ok")
+        } else {
+            (StatusCode::SERVICE_UNAVAILABLE, "unavailable")
+        };
+        let mut body = details;
+        body["status"] = serde_json::json!(label);
+        let body = serde_json::to_vec(&body).expect("Failed to serialize JSON");
+        Response::builder()
+            .status(status)
+            .header(http::header::CONTENT_TYPE, "application/json")
+            .header(http::header::CONTENT_LENGTH, body.len())
+            .body(body)
+            .unwrap()
+    }
+}
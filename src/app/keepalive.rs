@@ -0,0 +1,47 @@
+// Copyright (c) 2025 Sébastien Campion, FOSS4. All rights reserved.
+//
+// This software is provided under the Commons Clause License Condition v1.0.
+// See the LICENSE file for full license details.
+
+use arc_swap::ArcSwap;
+use async_trait::async_trait;
+use pingora::server::ShutdownWatch;
+use pingora::services::background::BackgroundService;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::config::ServerConf;
+use crate::keepalive;
+
+/// How often the models are checked for a keep-alive request due
+const TICK: Duration = Duration::from_secs(1);
+
+/// Sends the keep-alive requests of the models with a `keepalive_interval_secs`, from the current
+/// configuration so a reload adds or removes them
+pub struct KeepAlive {
+    pub conf: Arc<ArcSwap<ServerConf>>,
+}
+
+#[async_trait]
+impl BackgroundService for KeepAlive {
+    async fn start(&self, mut shutdown: ShutdownWatch) {
+        // the first requests go out at startup, to load the models before the clients come
+        let mut last_sent: HashMap<String, Instant> = HashMap::new();
+        loop {
+            let conf = self.conf.load();
+            let now = Instant::now();
+            for model in conf.models.iter().filter(|m| m.keepalive_interval_secs > 0) {
+                let interval = Duration::from_secs(model.keepalive_interval_secs);
+                if last_sent.get(&model.location).is_none_or(|sent| now.duration_since(*sent) >= interval) {
+                    last_sent.insert(model.location.clone(), now);
+                    keepalive::ping(Arc::new(model.clone()));
+                }
+            }
+            tokio::select! {
+                _ = shutdown.changed() => return,
+                _ = tokio::time::sleep(TICK) => {}
+            }
+        }
+    }
+}
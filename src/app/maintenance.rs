@@ -0,0 +1,56 @@
+// Copyright (c) 2025 Sébastien Campion, FOSS4. All rights reserved.
+//
+// This software is provided under the Commons Clause License Condition v1.0.
+// See the LICENSE file for full license details.
+
+use arc_swap::ArcSwap;
+use async_trait::async_trait;
+use log::{error, info};
+use pingora::server::ShutdownWatch;
+use pingora::services::background::BackgroundService;
+use redb::Database;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::config::ServerConf;
+use crate::cost::{prune_cost, prune_daily_cost};
+use crate::decisions::prune_decisions;
+use crate::token_limit::prune_usage;
+
+/// Removes expired usage totals, costs and decisions at startup and then every
+/// `db_maintenance_interval_secs`, so the database stays bounded
+pub struct DbMaintenance {
+    pub db: Arc<Database>,
+    pub conf: Arc<ArcSwap<ServerConf>>,
+}
+
+impl DbMaintenance {
+    fn prune(db: &Database, conf: &ServerConf) -> anyhow::Result<(u64, u64, u64)> {
+        let now = chrono::Utc::now();
+        let usage = prune_usage(db, &conf.usage_periods, conf.usage_retention_months, now)?;
+        let cost = prune_cost(db, conf.usage_retention_months, now)? + prune_daily_cost(db, now)?;
+        let decisions = prune_decisions(db, conf.decision_retention_days, now)?;
+        Ok((usage, cost, decisions))
+    }
+}
+
+#[async_trait]
+impl BackgroundService for DbMaintenance {
+    async fn start(&self, mut shutdown: ShutdownWatch) {
+        loop {
+            let conf = self.conf.load_full();
+            let db = self.db.clone();
+            // redb blocks while it waits for the write lock and writes
+            let interval = Duration::from_secs(conf.db_maintenance_interval_secs);
+            match tokio::task::spawn_blocking(move || Self::prune(&db, &conf)).await {
+                Ok(Ok((usage, cost, decisions))) => info!("Pruned {} expired usage totals, {} costs and {} decisions", usage, cost, decisions),
+                Ok(Err(e)) => error!("Failed to prune the database: {}", e),
+                Err(e) => error!("Database maintenance task failed: {}", e),
+            }
+            tokio::select! {
+                _ = shutdown.changed() => return,
+                _ = tokio::time::sleep(interval) => {}
+            }
+        }
+    }
+}
@@ -0,0 +1,40 @@
+// Copyright (c) 2025 Sébastien Campion, FOSS4. All rights reserved.
+//
+// This software is provided under the Commons Clause License Condition v1.0.
+// See the LICENSE file for full license details.
+
+use async_trait::async_trait;
+use log::{error, info};
+use pingora::server::ShutdownWatch;
+use pingora::services::background::BackgroundService;
+use std::sync::Arc;
+use tokio::signal::unix::{signal, SignalKind};
+
+use crate::maintenance_mode::MaintenanceMode;
+
+/// Switches the maintenance mode on SIGUSR1
+pub struct MaintenanceSignal {
+    pub maintenance_mode: Arc<MaintenanceMode>,
+}
+
+#[async_trait]
+impl BackgroundService for MaintenanceSignal {
+    async fn start(&self, mut shutdown: ShutdownWatch) {
+        let mut user_signal = match signal(SignalKind::user_defined1()) {
+            Ok(user_signal) => user_signal,
+            Err(e) => {
+                error!("Failed to install SIGUSR1 handler: {}", e);
+                return;
+            }
+        };
+        loop {
+            tokio::select! {
+                _ = shutdown.changed() => return,
+                _ = user_signal.recv() => {
+                    let enabled = self.maintenance_mode.toggle();
+                    info!("SIGUSR1 received, maintenance mode is now {}", if enabled { "on" } else { "off" });
+                }
+            }
+        }
+    }
+}
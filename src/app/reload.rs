@@ -0,0 +1,52 @@
+// Copyright (c) 2025 Sébastien Campion, FOSS4. All rights reserved.
+//
+// This software is provided under the Commons Clause License Condition v1.0.
+// See the LICENSE file for full license details.
+
+use arc_swap::ArcSwap;
+use async_trait::async_trait;
+use log::{error, info};
+use pingora::server::ShutdownWatch;
+use pingora::services::background::BackgroundService;
+use std::sync::Arc;
+use tokio::signal::unix::{signal, SignalKind};
+
+use crate::config::ServerConf;
+use crate::disabled_models::DisabledModels;
+
+/// Reloads the configuration file on SIGHUP, and enables the locations disabled with the admin API.
+/// Requests in flight keep the snapshot they started with; listeners are not rebound.
+pub struct ConfigReloader {
+    pub conf_path: String,
+    pub conf: Arc<ArcSwap<ServerConf>>,
+    pub disabled_models: Arc<DisabledModels>,
+}
+
+#[async_trait]
+impl BackgroundService for ConfigReloader {
+    async fn start(&self, mut shutdown: ShutdownWatch) {
+        let mut hangup = match signal(SignalKind::hangup()) {
+            Ok(hangup) => hangup,
+            Err(e) => {
+                error!("Failed to install SIGHUP handler: {}", e);
+                return;
+            }
+        };
+        loop {
+            tokio::select! {
+                _ = shutdown.changed() => return,
+                _ = hangup.recv() => {
+                    info!("SIGHUP received, reloading configuration from {}", self.conf_path);
+                    match ServerConf::load(&self.conf_path) {
+                        Ok(conf) => {
+                            info!("Configuration reloaded with {} models 👌", conf.models.len());
+                            self.conf.store(Arc::new(conf));
+                            self.disabled_models.clear();
+                        }
+                        Err(e) => error!("Configuration reload failed, keeping previous configuration: {:#}", e),
+                    }
+                }
+            }
+        }
+    }
+}
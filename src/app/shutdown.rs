@@ -0,0 +1,64 @@
+// Copyright (c) 2025 Sébastien Campion, FOSS4. All rights reserved.
+//
+// This software is provided under the Commons Clause License Condition v1.0.
+// See the LICENSE file for full license details.
+
+use async_trait::async_trait;
+use log::{info, warn};
+use pingora::server::ShutdownWatch;
+use pingora::services::background::BackgroundService;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::audit::AuditLog;
+use crate::concurrency::ActiveRequests;
+use crate::telemetry;
+use crate::usage_writer::UsageWriter;
+
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Drains the gateway on SIGTERM. Pingora stops accepting connections and waits the whole
+/// `grace_period_seconds` before dropping the requests left; this waits for the requests in
+/// flight to finish, their usage to be committed and the audit log to be flushed, then returns
+/// and leaves the exit to Pingora at the end of the grace period.
+pub struct GracefulShutdown {
+    pub requests: ActiveRequests,
+    pub usage_writer: Arc<UsageWriter>,
+    pub audit_log: Option<Arc<AuditLog>>,
+    pub grace_period: Duration,
+}
+
+impl GracefulShutdown {
+    fn drained(&self) -> bool {
+        self.requests.count() == 0
+            && self.usage_writer.pending() == 0
+            && self.audit_log.as_ref().is_none_or(|audit_log| audit_log.pending() == 0)
+    }
+}
+
+#[async_trait]
+impl BackgroundService for GracefulShutdown {
+    async fn start(&self, mut shutdown: ShutdownWatch) {
+        if shutdown.changed().await.is_err() {
+            return;
+        }
+        let deadline = Instant::now() + self.grace_period;
+        info!("Shutting down, waiting for {} requests in flight", self.requests.count());
+        // the requests read before the listeners closed only get their context now
+        tokio::time::sleep(POLL_INTERVAL).await;
+        while !self.drained() {
+            if Instant::now() >= deadline {
+                warn!(
+                    "{} requests still in flight after the {}s grace period, they are dropped",
+                    self.requests.count(),
+                    self.grace_period.as_secs()
+                );
+                telemetry::shutdown();
+                return;
+            }
+            tokio::time::sleep(POLL_INTERVAL).await;
+        }
+        info!("All requests completed, exiting at the end of the grace period");
+        telemetry::shutdown();
+    }
+}
@@ -0,0 +1,109 @@
+// Copyright (c) 2025 Sébastien Campion, FOSS4. All rights reserved.
+//
+// This software is provided under the Commons Clause License Condition v1.0.
+// See the LICENSE file for full license details.
+
+use anyhow::{Context, Result};
+use log::{error, warn};
+use once_cell::sync::Lazy;
+use prometheus::{register_int_counter, IntCounter};
+use serde::Serialize;
+use std::fs::OpenOptions;
+use std::io::{BufWriter, Write};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender, TryRecvError, TrySendError};
+
+/// Records waiting to be written before new ones are dropped
+const QUEUE_SIZE: usize = 10_000;
+
+static AUDIT_RECORDS_DROPPED: Lazy<IntCounter> = Lazy::new(|| {
+    register_int_counter!("audit_records_dropped", "Audit records dropped because the writer fell behind").unwrap()
+});
+
+/// One line of the audit log
+#[derive(Debug, Serialize)]
+pub struct AuditRecord {
+    pub timestamp: String,
+    pub request_id: String,
+    pub user: Option<String>,
+    pub model: Option<String>,
+    pub location: Option<String>,
+    pub status: u16,
+    pub input_tokens: u64,
+    pub output_tokens: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub request_body: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub response_body: Option<String>,
+}
+
+/// Newline-delimited JSON audit log written by a dedicated thread,
+/// so a slow disk never stalls the requests.
+pub struct AuditLog {
+    sender: SyncSender<AuditRecord>,
+    /// Records queued and not yet flushed to the file
+    pending: Arc<AtomicUsize>,
+}
+
+impl AuditLog {
+    /// Open the file in append mode and start the writer thread
+    pub fn open(path: &str) -> Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .with_context(|| format!("Unable to open audit log {}", path))?;
+        let (sender, receiver) = sync_channel(QUEUE_SIZE);
+        let pending = Arc::new(AtomicUsize::new(0));
+        let written = pending.clone();
+        std::thread::Builder::new()
+            .name("audit-log".to_string())
+            .spawn(move || write_records(BufWriter::new(file), receiver, &written))?;
+        Ok(Self { sender, pending })
+    }
+
+    /// Number of records not yet flushed to the file
+    pub fn pending(&self) -> usize {
+        self.pending.load(Ordering::SeqCst)
+    }
+
+    /// Queue the record without waiting, dropping it if the queue is full
+    pub fn record(&self, record: AuditRecord) {
+        self.pending.fetch_add(1, Ordering::SeqCst);
+        match self.sender.try_send(record) {
+            Ok(()) => return,
+            Err(TrySendError::Full(record)) => {
+                AUDIT_RECORDS_DROPPED.inc();
+                warn!("Audit log queue full, dropping record {}", record.request_id);
+            }
+            Err(TrySendError::Disconnected(record)) => {
+                AUDIT_RECORDS_DROPPED.inc();
+                error!("Audit log writer stopped, dropping record {}", record.request_id);
+            }
+        }
+        self.pending.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Write the records as they come, flushing whenever the queue is empty
+fn write_records(mut writer: BufWriter<std::fs::File>, receiver: Receiver<AuditRecord>, pending: &AtomicUsize) {
+    while let Ok(mut record) = receiver.recv() {
+        let mut written = 0;
+        loop {
+            written += 1;
+            let line = serde_json::to_string(&record).expect("Audit record serializes to JSON");
+            if let Err(e) = writeln!(writer, "{}", line) {
+                error!("Failed to write audit record {}: {}", record.request_id, e);
+            }
+            match receiver.try_recv() {
+                Ok(next) => record = next,
+                Err(TryRecvError::Empty) | Err(TryRecvError::Disconnected) => break,
+            }
+        }
+        if let Err(e) = writer.flush() {
+            error!("Failed to flush audit log: {}", e);
+        }
+        pending.fetch_sub(written, Ordering::SeqCst);
+    }
+}
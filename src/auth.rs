@@ -0,0 +1,88 @@
+// Copyright (c) 2025 Sébastien Campion, FOSS4. All rights reserved.
+//
+// This software is provided under the Commons Clause License Condition v1.0.
+// See the LICENSE file for full license details.
+
+use anyhow::{anyhow, Result};
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::Argon2;
+use base64::engine::general_purpose;
+use base64::Engine;
+use redb::{Database, ReadTransaction, ReadableTable, TableDefinition, WriteTransaction};
+
+const TOKENS: TableDefinition<&str, &str> = TableDefinition::new("tokens");
+const CREDENTIALS: TableDefinition<&str, &str> = TableDefinition::new("credentials");
+/// Optional expiry of a token as a unix timestamp, tokens without an entry never expire
+pub const TOKEN_EXPIRY: TableDefinition<&str, i64> = TableDefinition::new("token_expiry");
+
+/// Hash a password with argon2 and a random salt, in PHC string format
+pub fn hash_password(password: &str) -> Result<String> {
+    let salt = SaltString::generate(&mut OsRng);
+    Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map(|hash| hash.to_string())
+        .map_err(|e| anyhow!("Failed to hash password: {}", e))
+}
+
+/// Check a username/password pair against the credentials table
+pub fn verify_credentials(db: &Database, username: &str, password: &str) -> Result<bool> {
+    let read_txn = db.begin_read()?;
+    let table = read_txn.open_table(CREDENTIALS)?;
+    let Some(stored) = table.get(username)? else {
+        return Ok(false);
+    };
+    let hash = PasswordHash::new(stored.value())
+        .map_err(|e| anyhow!("Invalid stored hash for {}: {}", username, e))?;
+    Ok(Argon2::default().verify_password(password.as_bytes(), &hash).is_ok())
+}
+
+/// Generate a random bearer token and register it for the user.
+/// The caller is responsible for committing the transaction.
+pub fn mint_token(write_txn: &WriteTransaction, username: &str) -> Result<String> {
+    let mut bytes = [0u8; 32];
+    OsRng.fill_bytes(&mut bytes);
+    let token = general_purpose::URL_SAFE_NO_PAD.encode(bytes);
+
+    let mut table = write_txn.open_table(TOKENS)?;
+    table.insert(token.as_str(), username)?;
+    Ok(token)
+}
+
+/// Tokens of the user with their optional expiry as a unix timestamp
+pub fn tokens_for_user(read_txn: &ReadTransaction, username: &str) -> Result<Vec<(String, Option<i64>)>> {
+    let table = read_txn.open_table(TOKENS)?;
+    let expiry_table = read_txn.open_table(TOKEN_EXPIRY)?;
+    let mut tokens = Vec::new();
+    for entry in table.iter()? {
+        let (token, user) = entry?;
+        if user.value() == username {
+            let expires_at = expiry_table.get(token.value())?.map(|v| v.value());
+            tokens.push((token.value().to_string(), expires_at));
+        }
+    }
+    Ok(tokens)
+}
+
+/// Mint a new token for the user and make the current ones expire at `grace_until`,
+/// unless they already expire before. Returns the new token and the rotated ones.
+/// The caller is responsible for committing the transaction.
+pub fn rotate_tokens(write_txn: &WriteTransaction, username: &str, grace_until: i64) -> Result<(String, Vec<(String, i64)>)> {
+    let mut rotated = Vec::new();
+    {
+        let table = write_txn.open_table(TOKENS)?;
+        let mut expiry_table = write_txn.open_table(TOKEN_EXPIRY)?;
+        for entry in table.iter()? {
+            let (token, user) = entry?;
+            if user.value() != username {
+                continue;
+            }
+            let current = expiry_table.get(token.value())?.map(|v| v.value());
+            let expires_at = current.map_or(grace_until, |current| current.min(grace_until));
+            expiry_table.insert(token.value(), expires_at)?;
+            rotated.push((token.value().to_string(), expires_at));
+        }
+    }
+    let token = mint_token(write_txn, username)?;
+    Ok((token, rotated))
+}
@@ -0,0 +1,87 @@
+// Copyright (c) 2025 Sébastien Campion, FOSS4. All rights reserved.
+//
+// This software is provided under the Commons Clause License Condition v1.0.
+// See the LICENSE file for full license details.
+
+use bytes::Bytes;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::collections::BTreeMap;
+
+/// Changes applied to the top-level fields of JSON request bodies before they are sent upstream,
+/// in order: the `max_tokens` cap, then `rename`, then `defaults`, then `set`
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+pub struct BodyTransform {
+    /// Fields renamed from the key to the value, e.g. `max_completion_tokens: max_tokens`
+    #[serde(default)]
+    pub rename: BTreeMap<String, String>,
+    /// Fields added when the client did not send them
+    #[serde(default)]
+    pub defaults: BTreeMap<String, Value>,
+    /// Fields always overwritten, e.g. `stream: false`
+    #[serde(default)]
+    pub set: BTreeMap<String, Value>,
+    /// `max_output_tokens_cap` of the model, filled in at load time
+    #[serde(skip)]
+    pub max_tokens_cap: Option<u64>,
+}
+
+/// Output token limits of the OpenAI and Anthropic APIs
+const MAX_TOKENS_FIELDS: [&str; 2] = ["max_tokens", "max_completion_tokens"];
+
+impl BodyTransform {
+    /// Transformed body, or an error when the body is not a JSON object
+    pub fn apply(&self, body: &[u8]) -> Result<Bytes, String> {
+        let mut json = serde_json::from_slice::<Value>(body).map_err(|e| e.to_string())?;
+        let object = json.as_object_mut().ok_or("body is not a JSON object")?;
+        if let Some(cap) = self.max_tokens_cap {
+            cap_max_tokens(object, cap);
+        }
+        for (from, to) in &self.rename {
+            if let Some(value) = object.remove(from) {
+                object.insert(to.clone(), value);
+            }
+        }
+        for (name, value) in &self.defaults {
+            object.entry(name.clone()).or_insert_with(|| value.clone());
+        }
+        for (name, value) in &self.set {
+            object.insert(name.clone(), value.clone());
+        }
+        serde_json::to_vec(&json).map(Bytes::from).map_err(|e| e.to_string())
+    }
+}
+
+/// Lower the output token limits above `cap` to it, and ask for `cap` when the client sent none
+fn cap_max_tokens(object: &mut Map<String, Value>, cap: u64) {
+    let mut found = false;
+    for field in MAX_TOKENS_FIELDS {
+        if let Some(value) = object.get_mut(field).filter(|value| !value.is_null()) {
+            found = true;
+            if !value.as_f64().is_some_and(|requested| requested <= cap as f64) {
+                *value = Value::from(cap);
+            }
+        }
+    }
+    if !found {
+        object.insert("max_tokens".to_string(), Value::from(cap));
+    }
+}
+
+/// Simpler JSON envelope built from the fields of the successful JSON responses, for the clients
+/// not speaking the API of the upstream
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+pub struct ResponseTransform {
+    /// Fields of the envelope, with the JSON pointer of their value in the upstream response,
+    /// e.g. `text: /choices/0/message/content`. Values missing from the response are null.
+    pub fields: BTreeMap<String, String>,
+}
+
+impl ResponseTransform {
+    pub fn apply(&self, response: &Value) -> Bytes {
+        let envelope: Map<String, Value> = self.fields.iter()
+            .map(|(name, pointer)| (name.clone(), response.pointer(pointer).cloned().unwrap_or(Value::Null)))
+            .collect();
+        Bytes::from(Value::Object(envelope).to_string())
+    }
+}
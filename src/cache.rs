@@ -0,0 +1,71 @@
+// Copyright (c) 2025 Sébastien Campion, FOSS4. All rights reserved.
+//
+// This software is provided under the Commons Clause License Condition v1.0.
+// See the LICENSE file for full license details.
+
+use crate::auth::TOKEN_EXPIRY;
+use moka::sync::Cache;
+use redb::{Database, TableDefinition};
+use std::time::Duration;
+
+const TOKENS: TableDefinition<&str, &str> = TableDefinition::new("tokens");
+const GROUPS: TableDefinition<&str, &str> = TableDefinition::new("groups");
+
+const MAX_CAPACITY: u64 = 100_000;
+
+#[derive(Clone, Debug)]
+pub struct TokenRecord {
+    pub user: String,
+    pub expires_at: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+impl TokenRecord {
+    pub fn is_expired(&self, now: chrono::DateTime<chrono::Utc>) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= now)
+    }
+}
+
+/// In-memory cache of token→user and user→groups in front of redb.
+/// Only hits are cached, so unknown tokens always fall through to the database.
+pub struct AuthCache {
+    tokens: Cache<String, TokenRecord>,
+    groups: Cache<String, Vec<String>>,
+}
+
+impl AuthCache {
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            tokens: Cache::builder().max_capacity(MAX_CAPACITY).time_to_live(ttl).build(),
+            groups: Cache::builder().max_capacity(MAX_CAPACITY).time_to_live(ttl).build(),
+        }
+    }
+
+    /// User and expiry of the token, reading the tokens tables only on a cache miss
+    pub fn token_record(&self, db: &Database, token: &str) -> Option<TokenRecord> {
+        self.tokens.optionally_get_with_by_ref(token, || {
+            let read_txn = db.begin_read().ok()?;
+            let table = read_txn.open_table(TOKENS).ok()?;
+            let user = table.get(token).ok()??.value().to_string();
+            if user.is_empty() {
+                return None;
+            }
+            let expires_at = read_txn.open_table(TOKEN_EXPIRY).ok()
+                .and_then(|table| table.get(token).ok().flatten())
+                .and_then(|timestamp| chrono::DateTime::from_timestamp(timestamp.value(), 0));
+            Some(TokenRecord { user, expires_at })
+        })
+    }
+
+    /// Groups of the user, reading the groups table only on a cache miss
+    pub fn groups_for_user(&self, db: &Database, user: &str) -> Option<Vec<String>> {
+        self.groups.optionally_get_with_by_ref(user, || {
+            let table = db.begin_read().ok()?.open_table(GROUPS).ok()?;
+            let groups = table.get(user).ok()??;
+            Some(groups.value().split(',').map(|s| s.trim().to_string()).collect())
+        })
+    }
+
+    pub fn invalidate_token(&self, token: &str) {
+        self.tokens.invalidate(token);
+    }
+}
@@ -0,0 +1,107 @@
+// Copyright (c) 2025 Sébastien Campion, FOSS4. All rights reserved.
+//
+// This software is provided under the Commons Clause License Condition v1.0.
+// See the LICENSE file for full license details.
+
+use crate::config::ModelConfig;
+use log::{info, warn};
+use prometheus::IntGaugeVec;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Values of the `circuit_breaker_state` gauge
+const CLOSED: i64 = 0;
+const OPEN: i64 = 1;
+const HALF_OPEN: i64 = 2;
+
+/// Whether a request may be sent to the upstreams of a model
+#[derive(Debug, PartialEq)]
+pub enum Admission {
+    Allowed,
+    /// The cooldown is over, this request tests the upstreams for the others
+    Probe,
+    Rejected,
+}
+
+#[derive(Default)]
+struct Breaker {
+    consecutive_failures: u32,
+    /// Set while the circuit is open, requests are rejected until then
+    open_until: Option<Instant>,
+    /// A probe is in flight, other requests are still rejected
+    probing: bool,
+}
+
+/// Circuit breaker of each model location. After `circuit_breaker_threshold` consecutive
+/// failed requests the circuit opens and requests are rejected without reaching the
+/// upstreams. Once `circuit_breaker_cooldown_secs` have passed a single probe request is let
+/// through: it closes the circuit if it succeeds and opens it again otherwise.
+pub struct CircuitBreakers {
+    breakers: Mutex<HashMap<String, Breaker>>,
+    /// State by location: 0 closed, 1 open, 2 half-open
+    state: IntGaugeVec,
+}
+
+impl CircuitBreakers {
+    pub fn new(state: IntGaugeVec) -> Self {
+        Self {
+            breakers: Mutex::new(HashMap::new()),
+            state,
+        }
+    }
+
+    pub fn admit(&self, model: &ModelConfig) -> Admission {
+        if model.circuit_breaker_threshold == 0 {
+            return Admission::Allowed;
+        }
+        let mut breakers = self.breakers.lock().unwrap();
+        let Some(breaker) = breakers.get_mut(&model.location) else {
+            return Admission::Allowed;
+        };
+        match breaker.open_until {
+            None => Admission::Allowed,
+            Some(until) if breaker.probing || Instant::now() < until => Admission::Rejected,
+            Some(_) => {
+                breaker.probing = true;
+                self.state.with_label_values(&[&model.location]).set(HALF_OPEN);
+                info!("Circuit breaker of {} half-open, sending a probe request", model.location);
+                Admission::Probe
+            }
+        }
+    }
+
+    /// Record the outcome of a request that reached the upstreams of the model
+    pub fn record(&self, model: &ModelConfig, success: bool) {
+        if model.circuit_breaker_threshold == 0 {
+            return;
+        }
+        let mut breakers = self.breakers.lock().unwrap();
+        let breaker = breakers.entry(model.location.clone()).or_default();
+        if success {
+            if breaker.open_until.is_some() {
+                info!("Circuit breaker of {} closed", model.location);
+            }
+            *breaker = Breaker::default();
+            self.state.with_label_values(&[&model.location]).set(CLOSED);
+            return;
+        }
+
+        breaker.consecutive_failures += 1;
+        let reopen = breaker.probing
+            || (breaker.open_until.is_none() && breaker.consecutive_failures >= model.circuit_breaker_threshold);
+        if reopen {
+            warn!("Circuit breaker of {} open after {} consecutive failures", model.location, breaker.consecutive_failures);
+            breaker.open_until = Some(Instant::now() + Duration::from_secs(model.circuit_breaker_cooldown_secs));
+            breaker.probing = false;
+            self.state.with_label_values(&[&model.location]).set(OPEN);
+        }
+    }
+
+    /// Let another request probe the upstreams, the probe was rejected before reaching them
+    pub fn release_probe(&self, model: &ModelConfig) {
+        if let Some(breaker) = self.breakers.lock().unwrap().get_mut(&model.location) {
+            breaker.probing = false;
+        }
+    }
+}
@@ -0,0 +1,79 @@
+// Copyright (c) 2025 Sébastien Campion, FOSS4. All rights reserved.
+//
+// This software is provided under the Commons Clause License Condition v1.0.
+// See the LICENSE file for full license details.
+
+use dashmap::mapref::entry::Entry;
+use dashmap::DashMap;
+use std::sync::Arc;
+use tokio::sync::watch;
+
+use crate::response_cache::CachedResponse;
+
+pub const COALESCED_HEADER: &str = "X-Coalesced-With";
+
+/// What the upstream request of a leader gave: its response, or the status of the error
+/// returned to its client when the upstream could not be reached
+pub type Outcome = Result<CachedResponse, u16>;
+
+/// Request sent upstream, waited for by the identical requests arriving meanwhile
+struct InFlightRequest {
+    leader_id: String,
+    outcome: watch::Receiver<Option<Outcome>>,
+}
+
+/// Identical requests in flight, by `response_cache::key`
+#[derive(Default)]
+pub struct RequestCoalescer {
+    in_flight: Arc<DashMap<[u8; 32], InFlightRequest>>,
+}
+
+/// Role of a request among the identical requests in flight
+pub enum Flight {
+    /// First of them, it goes upstream
+    Leader(Leader),
+    /// Waits for the outcome of the request with id `leader_id`
+    Follower { leader_id: String, outcome: watch::Receiver<Option<Outcome>> },
+}
+
+/// The request going upstream for the others, in flight until it is completed or dropped
+pub struct Leader {
+    key: [u8; 32],
+    sender: watch::Sender<Option<Outcome>>,
+    in_flight: Arc<DashMap<[u8; 32], InFlightRequest>>,
+}
+
+impl RequestCoalescer {
+    pub fn join(&self, key: [u8; 32], request_id: &str) -> Flight {
+        match self.in_flight.entry(key) {
+            Entry::Occupied(entry) => Flight::Follower {
+                leader_id: entry.get().leader_id.clone(),
+                outcome: entry.get().outcome.clone(),
+            },
+            Entry::Vacant(entry) => {
+                let (sender, outcome) = watch::channel(None);
+                entry.insert(InFlightRequest { leader_id: request_id.to_string(), outcome });
+                Flight::Leader(Leader { key, sender, in_flight: self.in_flight.clone() })
+            }
+        }
+    }
+}
+
+impl Leader {
+    /// Hand the outcome to the followers, later identical requests start a new flight
+    pub fn complete(self, outcome: Outcome) {
+        self.sender.send_replace(Some(outcome));
+    }
+}
+
+impl Drop for Leader {
+    fn drop(&mut self) {
+        // Followers of a leader dropped without an outcome go upstream themselves
+        self.in_flight.remove(&self.key);
+    }
+}
+
+/// Outcome of the leader, None when it ended without one to share
+pub async fn wait(mut outcome: watch::Receiver<Option<Outcome>>) -> Option<Outcome> {
+    outcome.wait_for(Option::is_some).await.ok().and_then(|outcome| outcome.clone())
+}
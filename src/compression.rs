@@ -0,0 +1,50 @@
+// Copyright (c) 2025 Sébastien Campion, FOSS4. All rights reserved.
+//
+// This software is provided under the Commons Clause License Condition v1.0.
+// See the LICENSE file for full license details.
+
+use bytes::Bytes;
+use pingora::protocols::http::compression::{Algorithm, Encode};
+use std::io::Read;
+
+/// Encodings asked from the upstreams, those the gateway can decode
+pub const ACCEPTED_ENCODINGS: &str = "gzip, br";
+
+#[derive(Debug)]
+pub enum DecodeError {
+    /// Decoded, the body is larger than the limit
+    TooLarge,
+    Invalid(std::io::Error),
+}
+
+/// Whether the gateway can decode a body with this `Content-Encoding`
+pub fn is_supported(content_encoding: &str) -> bool {
+    matches!(Algorithm::from(content_encoding), Algorithm::Gzip | Algorithm::Brotli)
+}
+
+/// Decode a whole request body, reading at most `limit` decoded bytes so that a small
+/// compressed body cannot fill the memory
+pub fn decode(content_encoding: &str, body: &[u8], limit: usize) -> Result<Bytes, DecodeError> {
+    let reader: Box<dyn Read> = match Algorithm::from(content_encoding) {
+        Algorithm::Gzip => Box::new(flate2::read::GzDecoder::new(body)),
+        Algorithm::Brotli => Box::new(brotli::Decompressor::new(body, 4096)),
+        _ => return Ok(Bytes::copy_from_slice(body)),
+    };
+    let mut decoded = Vec::new();
+    reader.take(limit as u64 + 1).read_to_end(&mut decoded).map_err(DecodeError::Invalid)?;
+    if decoded.len() > limit {
+        return Err(DecodeError::TooLarge);
+    }
+    Ok(Bytes::from(decoded))
+}
+
+/// Incremental decoder of a response body
+pub type Decoder = Box<dyn Encode + Send + Sync>;
+
+/// Decoder of an upstream response body, None for the encodings the gateway leaves untouched
+pub fn response_decoder(content_encoding: &str) -> Option<Decoder> {
+    match Algorithm::from(content_encoding) {
+        algorithm @ (Algorithm::Gzip | Algorithm::Brotli) => algorithm.decompressor(true),
+        _ => None,
+    }
+}
@@ -0,0 +1,156 @@
+// Copyright (c) 2025 Sébastien Campion, FOSS4. All rights reserved.
+//
+// This software is provided under the Commons Clause License Condition v1.0.
+// See the LICENSE file for full license details.
+
+use prometheus::{IntGauge, IntGaugeVec};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+/// User and location of a count, the location is empty for the limit shared by all locations
+type Key = (String, String);
+
+/// Requests in flight by user, across all locations or for one location with its own limit
+#[derive(Default)]
+pub struct ConcurrencyLimiter {
+    in_flight: Arc<Mutex<HashMap<Key, usize>>>,
+}
+
+/// A request counted in flight until it is dropped
+pub struct InFlight {
+    key: Key,
+    in_flight: Arc<Mutex<HashMap<Key, usize>>>,
+}
+
+impl ConcurrencyLimiter {
+    /// Count one more request for the key, or None when `limit` are already in flight
+    pub fn acquire(&self, key: Key, limit: usize) -> Option<InFlight> {
+        let mut in_flight = self.in_flight.lock().unwrap();
+        let count = in_flight.entry(key.clone()).or_default();
+        if *count >= limit {
+            return None;
+        }
+        *count += 1;
+        Some(InFlight {
+            key,
+            in_flight: self.in_flight.clone(),
+        })
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        let mut in_flight = self.in_flight.lock().unwrap();
+        if let Some(count) = in_flight.get_mut(&self.key) {
+            *count -= 1;
+            if *count == 0 {
+                in_flight.remove(&self.key);
+            }
+        }
+    }
+}
+
+/// Gateway requests in progress, from their context creation to its drop, counted by the
+/// `active_requests` gauge.
+#[derive(Clone)]
+pub struct ActiveRequests {
+    count: Arc<AtomicUsize>,
+    gauge: IntGauge,
+}
+
+/// A request counted as active until it is dropped
+pub struct ActiveRequest {
+    count: Arc<AtomicUsize>,
+    gauge: IntGauge,
+}
+
+impl ActiveRequests {
+    pub fn new(gauge: IntGauge) -> Self {
+        Self { count: Arc::new(AtomicUsize::new(0)), gauge }
+    }
+
+    pub fn start(&self) -> ActiveRequest {
+        self.count.fetch_add(1, Ordering::SeqCst);
+        self.gauge.inc();
+        ActiveRequest { count: self.count.clone(), gauge: self.gauge.clone() }
+    }
+
+    pub fn count(&self) -> usize {
+        self.count.load(Ordering::SeqCst)
+    }
+}
+
+impl Drop for ActiveRequest {
+    fn drop(&mut self) {
+        self.count.fetch_sub(1, Ordering::SeqCst);
+        self.gauge.dec();
+    }
+}
+
+/// A request counted in the in-flight gauge of its location until it is dropped
+pub struct ModelRequest {
+    gauge: IntGauge,
+}
+
+impl ModelRequest {
+    pub fn start(gauge: IntGauge) -> Self {
+        gauge.inc();
+        Self { gauge }
+    }
+}
+
+impl Drop for ModelRequest {
+    fn drop(&mut self) {
+        self.gauge.dec();
+    }
+}
+
+/// Upstream requests in flight by location, for the locations with `upstream_max_connections`.
+/// Requests over the limit wait for a slot instead of opening more connections.
+pub struct UpstreamSlots {
+    /// Slots of each location, with the limit they were created for
+    slots: Mutex<HashMap<String, (usize, Arc<Semaphore>)>>,
+    in_use: IntGaugeVec,
+    max: IntGaugeVec,
+}
+
+/// A slot used until it is dropped
+pub struct UpstreamSlot {
+    _permit: OwnedSemaphorePermit,
+    in_use: IntGauge,
+}
+
+impl UpstreamSlots {
+    pub fn new(in_use: IntGaugeVec, max: IntGaugeVec) -> Self {
+        Self { slots: Mutex::new(HashMap::new()), in_use, max }
+    }
+
+    /// Wait up to `timeout` for one of the `limit` slots of the location, None when none
+    /// freed in time. A new limit, after a reload, starts with free slots.
+    pub async fn acquire(&self, location: &str, limit: usize, timeout: Duration) -> Option<UpstreamSlot> {
+        let semaphore = {
+            let mut slots = self.slots.lock().unwrap();
+            let (slots_limit, semaphore) = slots.entry(location.to_string())
+                .or_insert_with(|| (limit, Arc::new(Semaphore::new(limit))));
+            if *slots_limit != limit {
+                *slots_limit = limit;
+                *semaphore = Arc::new(Semaphore::new(limit));
+            }
+            self.max.with_label_values(&[location]).set(limit as i64);
+            semaphore.clone()
+        };
+        let permit = tokio::time::timeout(timeout, semaphore.acquire_owned()).await.ok()?.ok()?;
+        let in_use = self.in_use.with_label_values(&[location]);
+        in_use.inc();
+        Some(UpstreamSlot { _permit: permit, in_use })
+    }
+}
+
+impl Drop for UpstreamSlot {
+    fn drop(&mut self) {
+        self.in_use.dec();
+    }
+}
@@ -5,10 +5,24 @@
 
 use serde::Deserialize;
 use serde::Serialize;
+use std::collections::BTreeMap;
 use std::fs;
 use std::path::Path;
-use anyhow::{Context, Result};
-use pingora::prelude::*;
+use anyhow::{anyhow, Context, Result};
+use aho_corasick::AhoCorasick;
+use regex::bytes::Regex;
+use regex_syntax::hir::literal::{ExtractKind, Extractor};
+use url::Url;
+use crate::jwt::{self, StaticKey};
+use crate::body_transform::{BodyTransform, ResponseTransform};
+use crate::system_prompt::SystemPromptMode;
+use crate::parameter_limits::{ParameterLimitMode, ParameterRange};
+use crate::cors::CorsConf;
+use crate::ip_filter::{self, IpFilter};
+use crate::logging::LogFormat;
+use crate::telemetry::TracingConf;
+use ipnet::IpNet;
+use crate::sigv4::AwsSigner;
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct QuotaPeriod {
@@ -28,34 +42,530 @@ pub struct QuotaPeriod {
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct Quota {
+    /// Input and output tokens together
     pub max_tokens: Option<QuotaPeriod>,
+    #[serde(default)]
+    pub max_input_tokens: Option<QuotaPeriod>,
+    #[serde(default)]
+    pub max_output_tokens: Option<QuotaPeriod>,
     pub max_requests: Option<QuotaPeriod>,
 }
 
+impl Quota {
+    /// Token quotas with their name: `max_tokens`, `max_input_tokens` and `max_output_tokens`
+    pub fn token_limits(&self) -> impl Iterator<Item = (&'static str, &QuotaPeriod)> {
+        [("max_tokens", &self.max_tokens), ("max_input_tokens", &self.max_input_tokens), ("max_output_tokens", &self.max_output_tokens)]
+            .into_iter()
+            .filter_map(|(name, limit)| limit.as_ref().map(|limit| (name, limit)))
+    }
+}
+
+/// Calendar period of the token usage totals kept for each user
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum UsagePeriod {
+    Minute,
+    Hour,
+    Day,
+    Week,
+    Month,
+}
+
+impl UsagePeriod {
+    pub const ALL: [UsagePeriod; 5] = [Self::Minute, Self::Hour, Self::Day, Self::Week, Self::Month];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Minute => "minute",
+            Self::Hour => "hour",
+            Self::Day => "day",
+            Self::Week => "week",
+            Self::Month => "month",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|period| period.name() == name)
+    }
+
+    /// Limit or usage of this period in a `QuotaPeriod`
+    pub fn of(self, quota: &QuotaPeriod) -> u64 {
+        match self {
+            Self::Minute => quota.minute,
+            Self::Hour => quota.hour,
+            Self::Day => quota.day,
+            Self::Week => quota.week,
+            Self::Month => quota.month,
+        }
+    }
+
+    pub fn of_mut(self, quota: &mut QuotaPeriod) -> &mut u64 {
+        match self {
+            Self::Minute => &mut quota.minute,
+            Self::Hour => &mut quota.hour,
+            Self::Day => &mut quota.day,
+            Self::Week => &mut quota.week,
+            Self::Month => &mut quota.month,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum RateLimitAlgorithm {
+    #[default]
+    Fixed,
+    Sliding,
+}
+
+/// How the `location` of a model matches the request paths
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum LocationMatch {
+    /// The path itself, and the paths below it when the location ends with a slash
+    #[default]
+    Exact,
+    /// The location and the paths below it
+    Prefix,
+    /// Regular expression matching the whole path
+    Regex,
+}
+
+/// HTTP version spoken with the upstreams of a location
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum UpstreamProtocol {
+    #[default]
+    Http1,
+    /// HTTP/2 only: negotiated by ALPN over TLS, prior knowledge (h2c) over plain TCP
+    Http2,
+    /// HTTP/2 when the upstream offers it by ALPN, else HTTP/1.1. Plain TCP always uses HTTP/1.1.
+    Auto,
+}
+
+/// How requests are addressed to the upstreams of a location
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Provider {
+    /// `proxy_pass` is used as is
+    #[default]
+    Generic,
+    /// Azure OpenAI: `api-key` header, deployment in the path and `api-version` query
+    Azure,
+    /// AWS Bedrock: requests signed with SigV4 instead of an API key
+    Bedrock,
+}
+
+/// Path of Azure OpenAI upstreams whose `proxy_pass` is only the resource endpoint
+const AZURE_PATH_TEMPLATE: &str = "/openai/deployments/{deployment}/chat/completions";
+
+/// Bearer JWTs accepted besides the tokens of the database
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct JwtConf {
+    /// URL of the JSON Web Key Set of the identity provider
+    #[serde(default)]
+    pub jwks_url: String,
+    /// PEM public key, instead of `jwks_url`
+    #[serde(default)]
+    pub public_key: String,
+    /// Signing algorithm of `public_key`
+    #[serde(default = "default_jwt_algorithm")]
+    pub algorithm: jsonwebtoken::Algorithm,
+    /// Expected `iss` claim, not checked when empty
+    #[serde(default)]
+    pub issuer: String,
+    /// Expected `aud` claim, not checked when empty
+    #[serde(default)]
+    pub audience: String,
+    #[serde(default = "default_jwt_user_claim")]
+    pub user_claim: String,
+    /// Claim holding the groups of the user, as a list or a comma separated string
+    #[serde(default = "default_jwt_groups_claim")]
+    pub groups_claim: String,
+    /// Seconds the downloaded JWKS is used before being downloaded again
+    #[serde(default = "default_jwks_refresh_secs")]
+    pub jwks_refresh_secs: u64,
+    #[serde(skip)]
+    pub static_key: Option<StaticKey>,
+}
+
+/// OAuth2 token introspection (RFC 7662) of opaque bearer tokens
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct IntrospectionConf {
+    pub url: String,
+    pub client_id: String,
+    /// Either a literal or `$VAR` to read it from the environment
+    pub client_secret: String,
+    /// Seconds an introspection answer is reused. Only read at startup.
+    #[serde(default = "default_introspection_cache_ttl")]
+    pub cache_ttl: u64,
+}
+
+/// What to do with a request when the PII protection, prompt injection or moderation service
+/// cannot be reached
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum PiiFailMode {
+    /// Let the request through with a warning
+    Open,
+    /// Reject the request
+    #[default]
+    Closed,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct Upstream {
+    pub proxy_pass: String,
+    #[serde(default = "default_upstream_weight")]
+    pub weight: u32,
+    /// `proxy_pass` parsed at load time
+    #[serde(skip)]
+    pub target: UpstreamTarget,
+}
+
+/// Address and path of an upstream, taken from its `proxy_pass` URL
+#[derive(Debug, Clone, Default)]
+pub struct UpstreamTarget {
+    pub host: String,
+    pub port: u16,
+    pub tls: bool,
+    /// Base path the request path is joined to
+    pub path: String,
+    /// Query of the `proxy_pass`, such as an `api-version`, kept before the client's query
+    pub query: Option<String>,
+}
+
+impl UpstreamTarget {
+    fn parse(proxy_pass: &str) -> Result<Self> {
+        let url = Url::parse(proxy_pass)?;
+        let tls = match url.scheme() {
+            "https" => true,
+            "http" => false,
+            scheme => return Err(anyhow!("unsupported scheme {}", scheme)),
+        };
+        let host = url.host_str().ok_or_else(|| anyhow!("no host"))?.to_string();
+        let port = url.port_or_known_default().ok_or_else(|| anyhow!("no port"))?;
+        let target = Self { host, port, tls, path: url.path().to_string(), query: url.query().map(str::to_string) };
+        target.request_uri("", None)?;
+        Ok(target)
+    }
+
+    /// Upstream URI of a request: the base path joined with the part of the client path after
+    /// a prefix location, then the query of the `proxy_pass` followed by the client's
+    pub fn request_uri(&self, suffix: &str, query: Option<&str>) -> std::result::Result<http::Uri, http::uri::InvalidUri> {
+        let mut uri = self.path.clone();
+        let suffix = suffix.trim_start_matches('/');
+        if !suffix.is_empty() {
+            if !uri.ends_with('/') {
+                uri.push('/');
+            }
+            uri.push_str(suffix);
+        }
+        let queries: Vec<&str> = self.query.as_deref().into_iter().chain(query).filter(|q| !q.is_empty()).collect();
+        if !queries.is_empty() {
+            uri.push('?');
+            uri.push_str(&queries.join("&"));
+        }
+        uri.parse()
+    }
+}
+
 #[derive(Debug, Deserialize, Serialize,  Clone)]
 pub struct ModelConfig {
     pub location: String,
+    /// How `location` matches the request paths
+    #[serde(default)]
+    pub location_match: LocationMatch,
+    /// Compiled `location` of a regex location, anchored to the whole path
+    #[serde(skip)]
+    pub location_regex: Option<Regex>,
+    /// Literal start of the paths the location matches, the location itself unless it is a
+    /// regex. Stripped from the client path before it is joined to the upstream path.
+    #[serde(skip)]
+    pub location_prefix: String,
     pub model_name: String,
+    #[serde(default)]
     pub proxy_pass: String,
     #[serde(default)]
+    pub upstreams: Vec<Upstream>,
+    #[serde(default)]
     pub api_key: String,
+    /// Environment variable holding the API key, instead of `api_key`
+    #[serde(default)]
+    pub api_key_env: Option<String>,
+    /// File holding the API key, e.g. a mounted Kubernetes secret, instead of `api_key`
+    #[serde(default)]
+    pub api_key_file: Option<String>,
+    /// Keys taken in turn instead of `api_key`, to spread the rate limits of the provider. Each
+    /// may be `$VAR`.
+    #[serde(default)]
+    pub api_keys: Vec<String>,
+    /// How long a key the provider answered a 429 to is set aside
+    #[serde(default = "default_api_key_cooldown_secs")]
+    pub api_key_cooldown_secs: u64,
+    #[serde(default)]
+    pub provider: Provider,
+    /// Deployment substituted for `{deployment}` in the path of Azure upstreams
+    #[serde(default)]
+    pub azure_deployment: String,
+    /// `api-version` query parameter of Azure upstreams
+    #[serde(default)]
+    pub azure_api_version: String,
+    /// Region and credentials signing Bedrock requests, `AWS_REGION`, `AWS_ACCESS_KEY_ID`,
+    /// `AWS_SECRET_ACCESS_KEY` and `AWS_SESSION_TOKEN` from the environment when unset
+    #[serde(default)]
+    pub aws_region: String,
+    #[serde(default)]
+    pub aws_access_key_id: String,
+    #[serde(default)]
+    pub aws_secret_access_key: String,
+    #[serde(default)]
+    pub aws_session_token: String,
+    /// Built at load time for the bedrock provider
+    #[serde(skip)]
+    pub aws_signer: Option<AwsSigner>,
+    /// Header carrying `api_key` upstream, e.g. `api-key` for Azure or `x-api-key` for Anthropic
+    #[serde(default = "default_auth_header_name")]
+    pub auth_header_name: String,
+    /// Scheme before the key in the auth header, empty to send the bare key
+    #[serde(default = "default_auth_scheme")]
+    pub auth_scheme: String,
+    /// Endpoint receiving a copy of each request, its responses only counted in the metrics
+    #[serde(default)]
+    pub shadow_proxy_pass: String,
+    /// API key of the shadow endpoint, `api_key` when unset. May be `$VAR`.
+    #[serde(default)]
+    pub shadow_api_key: Option<String>,
+    /// Seconds between the keep-alive requests sent to each upstream, to keep a local model
+    /// loaded. 0 sends none.
+    #[serde(default)]
+    pub keepalive_interval_secs: u64,
+    /// JSON body of the keep-alive requests, a one token completion of `model_name` when unset
+    #[serde(default)]
+    pub keepalive_body: Option<serde_json::Value>,
+    /// Headers set on upstream requests after the defaults, e.g. `anthropic-version`
+    #[serde(default)]
+    pub upstream_headers_add: BTreeMap<String, String>,
+    /// Headers removed from upstream requests, before `upstream_headers_add` is applied
+    #[serde(default)]
+    pub upstream_headers_remove: Vec<String>,
+    /// Renames and defaults applied to JSON request bodies before they are sent upstream
+    #[serde(default)]
+    pub body_transform: Option<BodyTransform>,
+    /// Envelope replacing the successful JSON responses sent to the client, off by default
+    #[serde(default)]
+    pub response_transform: Option<ResponseTransform>,
+    /// Send `"stream": false` upstream so every response carries its usage block
+    #[serde(default)]
+    pub force_non_stream: bool,
+    /// Highest `max_tokens` sent upstream, set on JSON requests asking for more or not saying
+    #[serde(default)]
+    pub max_output_tokens_cap: Option<u64>,
+    /// System prompt sent upstream with every chat request
+    #[serde(default)]
+    pub system_prompt: Option<String>,
+    /// Whether `system_prompt` goes before the client's system prompt or replaces it
+    #[serde(default)]
+    pub system_prompt_mode: SystemPromptMode,
+    /// Seconds the response to a request with `temperature: 0` is served again to identical
+    /// requests, no response cache when unset
+    #[serde(default)]
+    pub response_cache_ttl_secs: Option<u64>,
+    /// Send one of the identical non-streamed requests in flight upstream and give its response
+    /// to all of them
+    #[serde(default)]
+    pub coalesce_requests: bool,
+    /// Allowed ranges of numeric body fields, e.g. `temperature: {max: 1.0}`
+    #[serde(default)]
+    pub parameter_limits: BTreeMap<String, ParameterRange>,
+    /// Whether a parameter out of range is clamped to it or rejected with a 400
+    #[serde(default)]
+    pub parameter_limit_mode: ParameterLimitMode,
+    #[serde(default)]
+    pub allowed_groups: String,
     #[serde(default)]
     pub disabled_groups: String,
     #[serde(default)]
     pub blacklist_words: String,
+    /// `blacklist_words` compiled at load time to find any of them in one pass
+    #[serde(skip)]
+    pub blacklist_matcher: Option<AhoCorasick>,
+    /// Also stop the event streams whose generated text holds one of the `blacklist_words`
+    #[serde(default)]
+    pub blacklist_stream_responses: bool,
+    /// Regular expressions rejected in request bodies, e.g. card or SSN shapes
+    #[serde(default)]
+    pub blacklist_regex: Vec<String>,
+    /// `blacklist_regex` compiled at load time
+    #[serde(skip)]
+    pub blacklist_patterns: Vec<Regex>,
+    /// Regular expressions masked in the request and response bodies written to the logs
+    #[serde(default)]
+    pub redact_regex: Vec<String>,
+    /// `redact_regex` and `blacklist_regex` compiled at load time
+    #[serde(skip)]
+    pub redact_patterns: Vec<Regex>,
+    /// Client networks allowed on this location, besides the global `ip_allowlist`
+    #[serde(default)]
+    pub ip_allowlist: Vec<String>,
+    /// Client networks rejected on this location, besides the global `ip_denylist`
+    #[serde(default)]
+    pub ip_denylist: Vec<String>,
+    /// `ip_allowlist` and `ip_denylist` compiled at load time
+    #[serde(skip)]
+    pub ip_filter: IpFilter,
     #[serde(default)]
     pub pii_protection_url: String,
     #[serde(default)]
+    pub pii_fail_mode: PiiFailMode,
+    /// Timeout of the call to the PII protection service
+    #[serde(default = "default_pii_timeout_ms")]
+    pub pii_timeout_ms: u64,
+    /// Service checking request bodies for prompt injections, called like the PII service
+    #[serde(default)]
+    pub prompt_injection_url: String,
+    #[serde(default)]
+    pub prompt_injection_fail_mode: PiiFailMode,
+    /// Timeout of the call to the prompt injection service
+    #[serde(default = "default_pii_timeout_ms")]
+    pub prompt_injection_timeout_ms: u64,
+    /// Service scoring request bodies by moderation category
+    #[serde(default)]
+    pub moderation_url: String,
+    /// Highest score allowed by category, e.g. `violence: 0.8`, from 0 to 1. Categories without
+    /// a threshold are not checked.
+    #[serde(default)]
+    pub moderation_thresholds: BTreeMap<String, f64>,
+    #[serde(default)]
+    pub moderation_fail_mode: PiiFailMode,
+    /// Timeout of the call to the moderation service
+    #[serde(default = "default_pii_timeout_ms")]
+    pub moderation_timeout_ms: u64,
+    #[serde(default)]
     pub parser: String,
     #[serde(default)]
     pub quotas: Option<Vec<Quota>>,
+    /// Location serving the requests of users over a token quota of this one, instead of a 429
+    #[serde(default)]
+    pub fallback_model_location: Option<String>,
+    #[serde(default)]
+    pub rate_limit_algorithm: RateLimitAlgorithm,
+    /// Requests per second/minute shared by all the members of each group of the user
+    #[serde(default)]
+    pub group_rate_limit: Option<QuotaPeriod>,
+    /// Requests over a rate limit held until it frees up instead of rejected at once, 0 rejects
+    /// them all
+    #[serde(default)]
+    pub rate_limit_queue_size: usize,
+    /// How long a request is held for a rate limit before the 429
+    #[serde(default = "default_rate_limit_queue_timeout_ms")]
+    pub rate_limit_queue_timeout_ms: u64,
+    #[serde(default)]
+    pub max_retries: usize,
+    /// Host header sent upstream, the host of `proxy_pass` when unset
+    #[serde(default)]
+    pub upstream_host_header: Option<String>,
+    /// TLS server name sent to https upstreams, the host of `proxy_pass` when unset
+    #[serde(default)]
+    pub sni: Option<String>,
+    #[serde(default)]
+    pub upstream_protocol: UpstreamProtocol,
+    /// Timeout to connect to an upstream, Pingora's default when unset
+    #[serde(default)]
+    pub connect_timeout_ms: Option<u64>,
+    /// Timeout of each read from an upstream, a slower response gets a 504
+    #[serde(default)]
+    pub read_timeout_ms: Option<u64>,
+    /// Time from the arrival of a request to the start of the upstream response, retries
+    /// included, a request over it gets a 504. No deadline when unset.
+    #[serde(default)]
+    pub request_deadline_ms: Option<u64>,
+    /// Consecutive failed requests that open the circuit breaker of the location, 0 disables it
+    #[serde(default)]
+    pub circuit_breaker_threshold: u32,
+    /// Seconds the circuit stays open before a probe request is let through
+    #[serde(default = "default_circuit_breaker_cooldown_secs")]
+    pub circuit_breaker_cooldown_secs: u64,
+    /// Requests of a user in flight at once on this location, counted apart from the global
+    /// `max_concurrent_requests`
+    #[serde(default)]
+    pub max_concurrent_requests: Option<usize>,
+    /// Spend of a user on this location per day in USD, on top of the global `daily_cost_cap_usd`
+    #[serde(default)]
+    pub daily_cost_cap_usd: Option<f64>,
+    /// Requests sent to the upstreams of this location at once, and so connections open to
+    /// them. The others wait for one to end. Unlimited when unset.
+    #[serde(default)]
+    pub upstream_max_connections: Option<usize>,
+    /// How long a request waits for one of the `upstream_max_connections` before a 503
+    #[serde(default = "default_upstream_queue_timeout_ms")]
+    pub upstream_queue_timeout_ms: u64,
+    /// Overrides the global `max_request_bytes` for this location
+    #[serde(default)]
+    pub max_request_bytes: Option<usize>,
+    /// Overrides the global `response_compression_level` for this location
+    #[serde(default)]
+    pub response_compression_level: Option<u32>,
+    /// Media types accepted in the request `Content-Type`, `type/*` for all the subtypes.
+    /// Others get a 415, requests without a `Content-Type` are let through.
+    #[serde(default = "default_allowed_content_types")]
+    pub allowed_content_types: Vec<String>,
+    #[serde(default)]
+    pub input_price_per_1k: f64,
+    #[serde(default)]
+    pub output_price_per_1k: f64,
+    /// Multiplier of the input tokens counted towards quotas and usage totals, e.g. 2 for a
+    /// model costing twice as much against a budget shared with others
+    #[serde(default = "default_token_weight")]
+    pub token_weight_input: f64,
+    /// Multiplier of the output tokens counted towards quotas and usage totals
+    #[serde(default = "default_token_weight")]
+    pub token_weight_output: f64,
+    /// Count the tokens of requests whose response reports no usage with a local tokenizer
+    #[serde(default)]
+    pub estimate_tokens: bool,
+    /// Accept `Upgrade: websocket` requests, whose frames are passed through without being
+    /// checked or counted
+    #[serde(default)]
+    pub websocket: bool,
+}
+
+impl ModelConfig {
+    /// Whether the location serves the path, besides the exact match of its location
+    pub fn matches_path(&self, path: &str) -> bool {
+        match self.location_match {
+            LocationMatch::Exact => self.location.ends_with('/') && path.starts_with(&self.location),
+            LocationMatch::Prefix => path.strip_prefix(&self.location).is_some_and(|rest| {
+                self.location.ends_with('/') || rest.is_empty() || rest.starts_with('/')
+            }),
+            LocationMatch::Regex => self.location_regex.as_ref().is_some_and(|regex| regex.is_match(path.as_bytes())),
+        }
+    }
+
+    /// Whether the request body sent upstream may differ from the client's, and so its length
+    pub fn rewrites_body(&self) -> bool {
+        self.body_transform.is_some() || !self.parameter_limits.is_empty() || self.system_prompt.is_some()
+    }
+
+    /// Input and output tokens counted towards quotas, rounded to the nearest token
+    pub fn weighted_tokens(&self, input_tokens: u64, output_tokens: u64) -> (u64, u64) {
+        (
+            (input_tokens as f64 * self.token_weight_input).round() as u64,
+            (output_tokens as f64 * self.token_weight_output).round() as u64,
+        )
+    }
 }
 
 #[derive(Debug, Deserialize, Serialize)]
 pub struct ServerConf {
     pub models: Vec<ModelConfig>,
-    #[serde(default = "default_db_filepath")]
-    pub db_filepath: String,
+    /// redb file of the tokens, usage and costs, locked by the gateway while it runs; unset
+    /// when left out so that setting it along with `db_filepath` is refused, see `database_path()`
+    #[serde(default)]
+    pub database_path: Option<String>,
+    /// Former name of `database_path`, still read with a warning
+    #[serde(default, skip_serializing)]
+    pub db_filepath: Option<String>,
     #[serde(default = "default_port")]
     pub port: u16,
     #[serde(default = "default_host")]
@@ -64,6 +574,9 @@ pub struct ServerConf {
     pub prometheus_host: String,
     #[serde(default = "default_prometheus_port")]
     pub prometheus_port: u16,
+    /// Serve the admin API, which needs `admin_secret`
+    #[serde(default = "default_true")]
+    pub admin_enabled: bool,
     #[serde(default = "default_admin_host")]
     pub admin_host: String,
     #[serde(default = "default_admin_port")]
@@ -72,14 +585,133 @@ pub struct ServerConf {
     pub chat_host: String,
     #[serde(default = "default_chat_port")]
     pub chat_port: u16,
+    /// Serve the echo upstream used to try the gateway, best turned off in production
+    #[serde(default = "default_true")]
+    pub echo_enabled: bool,
     #[serde(default = "default_echo_host")]
     pub echo_host: String,
     #[serde(default = "default_echo_port")]
     pub echo_port: u16,
+    #[serde(default = "default_health_host")]
+    pub health_host: String,
+    #[serde(default = "default_health_port")]
+    pub health_port: u16,
+    /// Headers giving the user authenticated by a proxy of `trusted_proxies`
     #[serde(default = "default_trust_headers")]
     pub trust_header_authentication: Vec<String>,
     #[serde(default = "default_log_config_file")]
     pub log_config_file: String,
+    /// `text` or `json` log lines, overridden by `BURGONET_LOG_FORMAT`. Read at startup only.
+    #[serde(default)]
+    pub log_format: LogFormat,
+    /// Shared secret required as a bearer token on every admin API endpoint, either a literal or
+    /// `$VAR` to read it from the environment. Required while `admin_enabled` is set.
+    #[serde(default)]
+    pub admin_secret: String,
+    /// Seconds a token→user or user→groups lookup stays cached
+    #[serde(default = "default_auth_cache_ttl")]
+    pub auth_cache_ttl: u64,
+    /// Largest request body accepted, in bytes, unless a location overrides it
+    #[serde(default = "default_max_request_bytes")]
+    pub max_request_bytes: usize,
+    /// Requests of a user in flight at once across the locations without their own limit,
+    /// unlimited when unset
+    #[serde(default)]
+    pub max_concurrent_requests: Option<usize>,
+    /// Spend of a user per day in USD across all locations, the admin API can set another cap
+    /// for a user. Unlimited when unset.
+    #[serde(default)]
+    pub daily_cost_cap_usd: Option<f64>,
+    /// Periods whose token usage totals are kept, every token quota must use one of them
+    #[serde(default = "default_usage_periods")]
+    pub usage_periods: Vec<UsagePeriod>,
+    /// Months of past monthly usage totals and costs kept, older ones and ended shorter periods
+    /// are pruned
+    #[serde(default = "default_usage_retention_months")]
+    pub usage_retention_months: u32,
+    /// Seconds between two prunings of the expired usage totals and costs
+    #[serde(default = "default_db_maintenance_interval_secs")]
+    pub db_maintenance_interval_secs: u64,
+    /// Days the decisions of the refused requests are kept
+    #[serde(default = "default_decision_retention_days")]
+    pub decision_retention_days: u32,
+    /// Milliseconds the usage of the requests is gathered before it is committed at once.
+    /// Read at startup only.
+    #[serde(default = "default_usage_flush_interval_ms")]
+    pub usage_flush_interval_ms: u64,
+    /// Largest number of requests whose usage is committed in one write transaction.
+    /// Read at startup only.
+    #[serde(default = "default_usage_flush_batch_size")]
+    pub usage_flush_batch_size: usize,
+    /// Largest response buffered for token accounting, in bytes.
+    /// Bigger responses are passed through as they arrive and not counted.
+    #[serde(default = "default_max_response_buffer_bytes")]
+    pub max_response_buffer_bytes: usize,
+    /// Level at which responses are compressed for the clients accepting gzip, br or zstd, 0
+    /// leaves them uncompressed. Locations can override it.
+    #[serde(default)]
+    pub response_compression_level: u32,
+    /// Number of PII protection answers kept for identical request bodies, 0 disables the cache
+    #[serde(default = "default_pii_cache_size")]
+    pub pii_cache_size: u64,
+    /// Seconds a PII protection answer stays cached
+    #[serde(default = "default_pii_cache_ttl")]
+    pub pii_cache_ttl: u64,
+    /// Total size of the response bodies kept by the response cache. Only read at startup.
+    #[serde(default = "default_response_cache_max_bytes")]
+    pub response_cache_max_bytes: u64,
+    /// File receiving one JSON line per request, empty disables the audit log.
+    /// Only read at startup.
+    #[serde(default)]
+    pub audit_log_path: String,
+    /// Also write the request and response bodies to the audit log
+    #[serde(default)]
+    pub audit_log_bodies: bool,
+    /// Also label the token counters by user, one series per user and location.
+    /// Only read at startup.
+    #[serde(default)]
+    pub token_metrics_by_user: bool,
+    /// Start with the model requests rejected, the admin API and SIGUSR1 switch it afterwards.
+    /// Only read at startup.
+    #[serde(default)]
+    pub maintenance_mode: bool,
+    /// `Retry-After` of the requests rejected during maintenance
+    #[serde(default = "default_maintenance_retry_after_secs")]
+    pub maintenance_retry_after_secs: u64,
+    /// Validate bearer tokens missing from the database as JWTs
+    #[serde(default)]
+    pub jwt: Option<JwtConf>,
+    /// Introspect bearer tokens missing from the database, and not JWTs, at the identity provider
+    #[serde(default)]
+    pub introspection: Option<IntrospectionConf>,
+    /// Origins of the browser clients allowed to call the gateway, none when unset
+    #[serde(default)]
+    pub cors: Option<CorsConf>,
+    /// OpenTelemetry export of the request spans, off when unset. Read at startup only.
+    #[serde(default)]
+    pub tracing: Option<TracingConf>,
+    /// Client networks allowed to call the gateway, any when empty
+    #[serde(default)]
+    pub ip_allowlist: Vec<String>,
+    /// Client networks rejected, even when in `ip_allowlist`
+    #[serde(default)]
+    pub ip_denylist: Vec<String>,
+    /// `ip_allowlist` and `ip_denylist` compiled at load time
+    #[serde(skip)]
+    pub ip_filter: IpFilter,
+    /// Seconds a signed request is accepted before or after its `X-Timestamp`
+    #[serde(default = "default_signature_max_age_secs")]
+    pub signature_max_age_secs: u64,
+    /// Proxies whose `X-Forwarded-For` and `trust_header_authentication` headers are believed
+    #[serde(default)]
+    pub trusted_proxies: Vec<String>,
+    /// `trusted_proxies` compiled at load time
+    #[serde(skip)]
+    pub trusted_proxy_networks: Vec<IpNet>,
+}
+
+fn default_signature_max_age_secs() -> u64 {
+    300
 }
 
 fn default_trust_headers() -> Vec<String> {
@@ -102,8 +734,8 @@ fn default_prometheus_port() -> u16 {
     6192
 }
 
-fn default_db_filepath() -> String {
-    "database.redb".to_string()
+fn default_true() -> bool {
+    true
 }
 
 fn default_admin_host() -> String {
@@ -122,6 +754,14 @@ fn default_echo_port() -> u16 {
     6193
 }
 
+fn default_health_port() -> u16 {
+    6194
+}
+
+fn default_health_host() -> String {
+    "127.0.0.1".to_string()
+}
+
 fn default_chat_host() -> String {
     "127.0.0.1".to_string()
 }
@@ -134,6 +774,184 @@ fn default_log_config_file() -> String {
     "log4rs.yml".to_string()
 }
 
+fn default_auth_cache_ttl() -> u64 {
+    60
+}
+
+fn default_usage_periods() -> Vec<UsagePeriod> {
+    UsagePeriod::ALL.to_vec()
+}
+
+fn default_maintenance_retry_after_secs() -> u64 {
+    60
+}
+
+fn default_usage_retention_months() -> u32 {
+    12
+}
+
+fn default_decision_retention_days() -> u32 {
+    90
+}
+
+fn default_db_maintenance_interval_secs() -> u64 {
+    3600
+}
+
+fn default_usage_flush_interval_ms() -> u64 {
+    100
+}
+
+fn default_usage_flush_batch_size() -> usize {
+    1000
+}
+
+fn default_allowed_content_types() -> Vec<String> {
+    vec!["application/json".to_string()]
+}
+
+fn default_max_request_bytes() -> usize {
+    10 * 1024 * 1024
+}
+
+fn default_max_response_buffer_bytes() -> usize {
+    10 * 1024 * 1024
+}
+
+fn default_jwt_algorithm() -> jsonwebtoken::Algorithm {
+    jsonwebtoken::Algorithm::RS256
+}
+
+fn default_jwt_user_claim() -> String {
+    "sub".to_string()
+}
+
+fn default_jwt_groups_claim() -> String {
+    "groups".to_string()
+}
+
+fn default_jwks_refresh_secs() -> u64 {
+    300
+}
+
+fn default_introspection_cache_ttl() -> u64 {
+    30
+}
+
+fn default_pii_cache_size() -> u64 {
+    10_000
+}
+
+fn default_pii_cache_ttl() -> u64 {
+    60
+}
+
+fn default_response_cache_max_bytes() -> u64 {
+    64 * 1024 * 1024
+}
+
+fn default_pii_timeout_ms() -> u64 {
+    2000
+}
+
+fn default_auth_header_name() -> String {
+    "Authorization".to_string()
+}
+
+fn default_api_key_cooldown_secs() -> u64 {
+    60
+}
+
+fn default_auth_scheme() -> String {
+    "Bearer".to_string()
+}
+
+fn default_circuit_breaker_cooldown_secs() -> u64 {
+    30
+}
+
+fn default_upstream_weight() -> u32 {
+    1
+}
+
+fn default_token_weight() -> f64 {
+    1.0
+}
+
+fn default_rate_limit_queue_timeout_ms() -> u64 {
+    5000
+}
+
+fn default_upstream_queue_timeout_ms() -> u64 {
+    5000
+}
+
+
+/// Literal path every match of the regular expression starts with, up to its last slash so that
+/// the rest of the path keeps whole segments
+fn regex_literal_prefix(pattern: &str) -> String {
+    let Ok(hir) = regex_syntax::parse(pattern) else {
+        return String::new();
+    };
+    let prefixes = Extractor::new().kind(ExtractKind::Prefix).extract(&hir);
+    let prefix = prefixes.longest_common_prefix().unwrap_or_default();
+    let end = prefix.iter().rposition(|b| *b == b'/').map_or(0, |slash| slash + 1);
+    String::from_utf8_lossy(&prefix[..end]).into_owned()
+}
+
+/// Address Azure upstreams: the deployment goes in the path, `api-version` in the query and the
+/// key in the `api-key` header
+fn apply_azure(model: &mut ModelConfig) -> Result<()> {
+    let api_key = model.api_keys.first().unwrap_or(&model.api_key);
+    for (field, value) in [("azure_deployment", &model.azure_deployment), ("azure_api_version", &model.azure_api_version), ("api_key", api_key)] {
+        if value.is_empty() {
+            return Err(anyhow!("Location {}: {} is required with provider azure", model.location, field));
+        }
+    }
+    for upstream in &mut model.upstreams {
+        let mut url = Url::parse(&upstream.proxy_pass.replace("{deployment}", &model.azure_deployment))
+            .map_err(|e| anyhow!("Location {}: invalid proxy_pass {:?}: {}", model.location, upstream.proxy_pass, e))?;
+        if url.path() == "/" {
+            url.set_path(&AZURE_PATH_TEMPLATE.replace("{deployment}", &model.azure_deployment));
+        }
+        if !url.query_pairs().any(|(name, _)| name == "api-version") {
+            url.query_pairs_mut().append_pair("api-version", &model.azure_api_version);
+        }
+        upstream.proxy_pass = url.to_string();
+    }
+    model.auth_header_name = "api-key".to_string();
+    model.auth_scheme = String::new();
+    Ok(())
+}
+
+/// Build the SigV4 signer of a Bedrock location from its settings or the AWS environment
+fn apply_bedrock(model: &mut ModelConfig) -> Result<()> {
+    let setting = |value: &str, default_var: &str| -> Result<String> {
+        match value.strip_prefix('$') {
+            Some(var_name) => std::env::var(var_name)
+                .map_err(|_| anyhow!("Location {}: environment variable {} not found", model.location, var_name)),
+            None if value.is_empty() => Ok(std::env::var(default_var).unwrap_or_default()),
+            None => Ok(value.to_string()),
+        }
+    };
+    let region = setting(&model.aws_region, "AWS_REGION")?;
+    let access_key_id = setting(&model.aws_access_key_id, "AWS_ACCESS_KEY_ID")?;
+    let secret_access_key = setting(&model.aws_secret_access_key, "AWS_SECRET_ACCESS_KEY")?;
+    let session_token = setting(&model.aws_session_token, "AWS_SESSION_TOKEN")?;
+    for (field, value) in [("aws_region", &region), ("aws_access_key_id", &access_key_id), ("aws_secret_access_key", &secret_access_key)] {
+        if value.is_empty() {
+            return Err(anyhow!("Location {}: {} is required with provider bedrock", model.location, field));
+        }
+    }
+    model.aws_signer = Some(AwsSigner {
+        access_key_id,
+        secret_access_key,
+        session_token: Some(session_token).filter(|t| !t.is_empty()),
+        region,
+        service: "bedrock".to_string(),
+    });
+    Ok(())
+}
 
 impl QuotaPeriod {
 
@@ -148,18 +966,14 @@ impl QuotaPeriod {
             month: 0,
         }
     }
-
-    // fucntion display the quota period
-    pub fn display(&self) {
-        println!("QuotaPeriod: second: {}, minute: {}, hour: {}, day: {}, week: {}, month: {}", self.second, self.minute, self.hour, self.day, self.week, self.month);
-    }
-
-    pub fn to_string(&self) -> String {
-        format!("second: {}, minute: {}, hour: {}, day: {}, week: {}, month: {}", self.second, self.minute, self.hour, self.day, self.week, self.month)
-    }
 }
 
 impl ServerConf {
+    /// redb file of the gateway, `database.redb` when `database_path` is not set
+    pub fn database_path(&self) -> &str {
+        self.database_path.as_deref().unwrap_or("database.redb")
+    }
+
     /// Load configuration from a YAML file
     pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self> {
         let conf_str = fs::read_to_string(&path)
@@ -173,33 +987,245 @@ impl ServerConf {
 
     /// Load configuration from a YAML file with error handling for server startup
     pub fn from_file_or_exit<P: AsRef<Path>>(path: P) -> Self {
-        let mut conf = Self::from_file(&path).unwrap_or_else(|e| {
+        Self::load(path).unwrap_or_else(|e| {
             log::error!("Configuration error: {}", e);
             std::process::exit(1);
-        });
+        })
+    }
+
+    /// Load and validate configuration from a YAML file, resolving upstreams and API keys
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let mut conf = Self::from_file(&path)?;
 
         // Process each model's API key
         let mut processed_models = Vec::new();
-        for model in conf.models {
-            let processed_model = if model.api_key.starts_with('$') {
-                let var_name = &model.api_key[1..];
-                let api_key = std::env::var(var_name).unwrap_or_else(|_| {
-                    log::error!("Environment variable {} not found", var_name);
-                    "".to_string()
+        for mut model in conf.models {
+            // A single proxy_pass is shorthand for one upstream of weight 1
+            if model.upstreams.is_empty() {
+                if model.proxy_pass.is_empty() {
+                    return Err(anyhow!("Location {}: either proxy_pass or upstreams must be set", model.location));
+                }
+                model.upstreams.push(Upstream {
+                    proxy_pass: model.proxy_pass.clone(),
+                    weight: default_upstream_weight(),
+                    target: UpstreamTarget::default(),
                 });
-                log::info!("Location {}: using API key from environment variable {}", model.location,var_name);
-                ModelConfig {
-                    api_key,
-                    ..model
+            }
+            if !model.shadow_proxy_pass.is_empty() {
+                UpstreamTarget::parse(&model.shadow_proxy_pass)
+                    .map_err(|e| anyhow!("Location {}: invalid shadow_proxy_pass {:?}: {}", model.location, model.shadow_proxy_pass, e))?;
+            }
+            if let Some(var_name) = model.shadow_api_key.as_deref().and_then(|key| key.strip_prefix('$')) {
+                model.shadow_api_key = Some(std::env::var(var_name)
+                    .map_err(|_| anyhow!("Location {}: environment variable {} for shadow_api_key not found", model.location, var_name))?);
+            }
+            if model.parser.is_empty() {
+                return Err(anyhow!("Location {}: parser not set, expected one of {}",
+                    model.location, crate::parsers::PARSERS.join(", ")));
+            }
+            if !crate::parsers::PARSERS.contains(&model.parser.as_str()) {
+                return Err(anyhow!("Location {}: unknown parser {:?}, expected one of {}",
+                    model.location, model.parser, crate::parsers::PARSERS.join(", ")));
+            }
+            let key_sources = [!model.api_key.is_empty(), model.api_key_env.is_some(), model.api_key_file.is_some(), !model.api_keys.is_empty()];
+            if key_sources.iter().filter(|set| **set).count() > 1 {
+                return Err(anyhow!("Location {}: api_key, api_key_env, api_key_file and api_keys are exclusive", model.location));
+            }
+            if let Some(var_name) = &model.api_key_env {
+                model.api_key = std::env::var(var_name)
+                    .map_err(|_| anyhow!("Location {}: environment variable {} for api_key_env not found", model.location, var_name))?;
+            } else if let Some(var_name) = model.api_key.strip_prefix('$') {
+                let api_key = std::env::var(var_name)
+                    .map_err(|_| anyhow!("Location {}: environment variable {} for api_key not found", model.location, var_name))?;
+                log::info!("Location {}: using API key from environment variable {}", model.location, var_name);
+                model.api_key = api_key;
+            }
+            if let Some(path) = &model.api_key_file {
+                // secrets mounted from Kubernetes or written by editors often end with a newline
+                model.api_key = std::fs::read_to_string(path)
+                    .map_err(|e| anyhow!("Location {}: unable to read api_key_file {}: {}", model.location, path, e))?
+                    .trim_end()
+                    .to_string();
+                if model.api_key.is_empty() {
+                    return Err(anyhow!("Location {}: api_key_file {} is empty", model.location, path));
+                }
+            }
+            for key in &mut model.api_keys {
+                if let Some(var_name) = key.strip_prefix('$') {
+                    *key = std::env::var(var_name)
+                        .map_err(|_| anyhow!("Location {}: environment variable {} for api_keys not found", model.location, var_name))?;
                 }
-            } else {
-                model
-            };
-            processed_models.push(processed_model);
+            }
+            match model.provider {
+                Provider::Generic => {}
+                Provider::Azure => apply_azure(&mut model)?,
+                Provider::Bedrock => apply_bedrock(&mut model)?,
+            }
+            if model.keepalive_interval_secs > 0 && model.provider == Provider::Bedrock {
+                return Err(anyhow!("Location {}: keepalive_interval_secs is not supported by the bedrock provider, its requests are signed", model.location));
+            }
+            for content_type in &mut model.allowed_content_types {
+                *content_type = content_type.trim().to_ascii_lowercase();
+                if content_type.split('/').filter(|part| !part.is_empty()).count() != 2 {
+                    return Err(anyhow!("Location {}: invalid allowed_content_types entry {:?}, expected type/subtype", model.location, content_type));
+                }
+            }
+            if model.force_non_stream {
+                model.body_transform.get_or_insert_with(BodyTransform::default)
+                    .set.insert("stream".to_string(), serde_json::Value::Bool(false));
+            }
+            if let Some(cap) = model.max_output_tokens_cap {
+                if cap == 0 {
+                    return Err(anyhow!("Location {}: max_output_tokens_cap must be at least 1", model.location));
+                }
+                model.body_transform.get_or_insert_with(BodyTransform::default).max_tokens_cap = Some(cap);
+            }
+            for (name, pointer) in model.response_transform.iter().flat_map(|transform| &transform.fields) {
+                if !pointer.is_empty() && !pointer.starts_with('/') {
+                    return Err(anyhow!("Location {}: response_transform field {} must be a JSON pointer like /choices/0/message/content, got {:?}",
+                        model.location, name, pointer));
+                }
+            }
+            if model.websocket && model.aws_signer.is_some() {
+                return Err(anyhow!("Location {}: websocket cannot be used with a signed upstream", model.location));
+            }
+            if model.daily_cost_cap_usd.is_some_and(|cap| !cap.is_finite() || cap < 0.0) {
+                return Err(anyhow!("Location {}: daily_cost_cap_usd must be a positive number", model.location));
+            }
+            if model.upstream_max_connections == Some(0) {
+                return Err(anyhow!("Location {}: upstream_max_connections must be at least 1", model.location));
+            }
+            if model.response_cache_ttl_secs == Some(0) {
+                return Err(anyhow!("Location {}: response_cache_ttl_secs must be at least 1", model.location));
+            }
+            if !model.moderation_url.is_empty() && model.moderation_thresholds.is_empty() {
+                return Err(anyhow!("Location {}: moderation_url needs moderation_thresholds", model.location));
+            }
+            if let Some((category, threshold)) = model.moderation_thresholds.iter().find(|(_, t)| !(0.0..=1.0).contains(*t)) {
+                return Err(anyhow!("Location {}: moderation_thresholds {} is {}, expected between 0 and 1", model.location, category, threshold));
+            }
+            for (name, weight) in [("token_weight_input", model.token_weight_input), ("token_weight_output", model.token_weight_output)] {
+                if !weight.is_finite() || weight < 0.0 {
+                    return Err(anyhow!("Location {}: {} must be a positive number", model.location, name));
+                }
+            }
+            for (name, range) in &model.parameter_limits {
+                range.validate().map_err(|e| anyhow!("Location {}: parameter_limits {}: {}", model.location, name, e))?;
+            }
+            for upstream in &mut model.upstreams {
+                upstream.target = UpstreamTarget::parse(&upstream.proxy_pass)
+                    .map_err(|e| anyhow!("Location {}: invalid proxy_pass {:?}: {}", model.location, upstream.proxy_pass, e))?;
+            }
+            let words: Vec<&str> = model.blacklist_words.split(',').map(str::trim).filter(|w| !w.is_empty()).collect();
+            if !words.is_empty() {
+                model.blacklist_matcher = Some(AhoCorasick::builder()
+                    .ascii_case_insensitive(true)
+                    .build(&words)
+                    .map_err(|e| anyhow!("Location {}: invalid blacklist_words: {}", model.location, e))?);
+            }
+            model.location_prefix = model.location.clone();
+            if model.location_match == LocationMatch::Regex {
+                let anchored = format!("^(?:{})$", model.location);
+                model.location_regex = Some(Regex::new(&anchored)
+                    .map_err(|e| anyhow!("Location {}: invalid location regex: {}", model.location, e))?);
+                model.location_prefix = regex_literal_prefix(&anchored);
+            }
+            model.blacklist_patterns = model.blacklist_regex.iter()
+                .map(|pattern| Regex::new(pattern)
+                    .map_err(|e| anyhow!("Location {}: invalid blacklist_regex {:?}: {}", model.location, pattern, e)))
+                .collect::<Result<_>>()?;
+            model.redact_patterns = model.redact_regex.iter()
+                .map(|pattern| Regex::new(pattern)
+                    .map_err(|e| anyhow!("Location {}: invalid redact_regex {:?}: {}", model.location, pattern, e)))
+                .collect::<Result<Vec<_>>>()?;
+            model.redact_patterns.extend(model.blacklist_patterns.iter().cloned());
+            model.ip_filter = IpFilter::new(&model.ip_allowlist, &model.ip_denylist)
+                .map_err(|e| anyhow!("Location {}: {}", model.location, e))?;
+            for (name, max_tokens) in model.quotas.iter().flatten().flat_map(Quota::token_limits) {
+                if let Some(period) = UsagePeriod::ALL.into_iter()
+                    .find(|period| period.of(max_tokens) > 0 && !conf.usage_periods.contains(period)) {
+                    return Err(anyhow!("Location {}: {} per {} needs {} in usage_periods", model.location, name, period.name(), period.name()));
+                }
+            }
+            let header_names = model.upstream_headers_remove.iter().chain(model.upstream_headers_add.keys())
+                .chain(std::iter::once(&model.auth_header_name));
+            for name in header_names {
+                http::HeaderName::from_bytes(name.as_bytes())
+                    .map_err(|_| anyhow!("Location {}: invalid header name {:?}", model.location, name))?;
+            }
+            for (name, value) in &model.upstream_headers_add {
+                http::HeaderValue::from_str(value)
+                    .map_err(|_| anyhow!("Location {}: invalid value for header {}", model.location, name))?;
+            }
+            processed_models.push(model);
         }
 
         conf.models = processed_models;
-        conf
+
+        for model in &conf.models {
+            if let Some(fallback) = &model.fallback_model_location {
+                if *fallback == model.location || !conf.models.iter().any(|m| m.location == *fallback) {
+                    return Err(anyhow!("Location {}: fallback_model_location {} is not another configured location", model.location, fallback));
+                }
+            }
+        }
+
+        if let Some(path) = conf.db_filepath.take() {
+            if conf.database_path.is_some() {
+                return Err(anyhow!("db_filepath and database_path are the same setting, keep database_path"));
+            }
+            log::warn!("db_filepath is deprecated, rename it to database_path");
+            conf.database_path = Some(path);
+        }
+
+        if let Some(var_name) = conf.admin_secret.strip_prefix('$') {
+            conf.admin_secret = std::env::var(var_name)
+                .map_err(|_| anyhow!("Environment variable {} for admin_secret not found", var_name))?;
+        }
+        if let Some(jwt_conf) = conf.jwt.as_mut() {
+            match (jwt_conf.jwks_url.is_empty(), jwt_conf.public_key.is_empty()) {
+                (false, true) => {}
+                (true, false) => {
+                    let key = jwt::parse_public_key(&jwt_conf.public_key, jwt_conf.algorithm)
+                        .map_err(|e| anyhow!("jwt: {}", e))?;
+                    jwt_conf.static_key = Some(StaticKey(key));
+                }
+                _ => return Err(anyhow!("jwt: exactly one of jwks_url or public_key must be set")),
+            }
+        }
+        if let Some(introspection) = conf.introspection.as_mut() {
+            if let Some(var_name) = introspection.client_secret.strip_prefix('$') {
+                introspection.client_secret = std::env::var(var_name)
+                    .map_err(|_| anyhow!("Environment variable {} for introspection client_secret not found", var_name))?;
+            }
+        }
+        conf.ip_filter = IpFilter::new(&conf.ip_allowlist, &conf.ip_denylist).map_err(|e| anyhow!(e))?;
+        conf.trusted_proxy_networks = ip_filter::parse_networks(&conf.trusted_proxies)
+            .map_err(|e| anyhow!("trusted_proxies: {}", e))?;
+        for origin in conf.cors.iter().flat_map(|cors| &cors.allowed_origins) {
+            if origin != "*" && Url::parse(origin).map_or(true, |url| url.origin().ascii_serialization() != *origin) {
+                return Err(anyhow!("cors: invalid allowed_origins entry {:?}, expected scheme://host[:port]", origin));
+            }
+        }
+        if let Some(tracing) = &conf.tracing {
+            tracing.validate()?;
+        }
+        if conf.daily_cost_cap_usd.is_some_and(|cap| !cap.is_finite() || cap < 0.0) {
+            return Err(anyhow!("daily_cost_cap_usd must be a positive number"));
+        }
+        if conf.db_maintenance_interval_secs == 0 {
+            return Err(anyhow!("db_maintenance_interval_secs must be at least 1"));
+        }
+        if conf.usage_flush_batch_size == 0 {
+            return Err(anyhow!("usage_flush_batch_size must be at least 1"));
+        }
+        if !conf.trust_header_authentication.is_empty() && conf.trusted_proxies.is_empty() {
+            log::warn!("trust_header_authentication is ignored until trusted_proxies lists the proxies setting the headers");
+        }
+        if conf.admin_enabled && conf.admin_secret.is_empty() {
+            return Err(anyhow!("admin_secret must be set to serve the admin API, or set admin_enabled: false"));
+        }
+        Ok(conf)
     }
 
 
@@ -0,0 +1,94 @@
+// Copyright (c) 2025 Sébastien Campion, FOSS4. All rights reserved.
+//
+// This software is provided under the Commons Clause License Condition v1.0.
+// See the LICENSE file for full license details.
+
+use pingora::http::{RequestHeader, ResponseHeader};
+use pingora::Result;
+use serde::{Deserialize, Serialize};
+
+/// Cross-origin requests of browser clients
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct CorsConf {
+    /// Origins allowed to call the gateway, e.g. `https://chat.example.com`, `*` for any
+    pub allowed_origins: Vec<String>,
+    #[serde(default = "default_allowed_methods")]
+    pub allowed_methods: Vec<String>,
+    /// Request headers a browser may send, besides the ones always allowed
+    #[serde(default = "default_allowed_headers")]
+    pub allowed_headers: Vec<String>,
+    /// Response headers readable by the browser scripts
+    #[serde(default = "default_expose_headers")]
+    pub expose_headers: Vec<String>,
+    /// How long browsers may cache a preflight answer
+    #[serde(default = "default_max_age_secs")]
+    pub max_age_secs: u64,
+}
+
+fn default_allowed_methods() -> Vec<String> {
+    vec!["GET".to_string(), "POST".to_string(), "OPTIONS".to_string()]
+}
+
+fn default_allowed_headers() -> Vec<String> {
+    vec!["Authorization".to_string(), "Content-Type".to_string(), "Accept".to_string()]
+}
+
+fn default_expose_headers() -> Vec<String> {
+    ["X-Request-Id", "X-Quota-Limit", "X-Quota-Used", "X-Quota-Reset", "Retry-After"]
+        .map(String::from).to_vec()
+}
+
+fn default_max_age_secs() -> u64 {
+    86400
+}
+
+impl CorsConf {
+    /// `Access-Control-Allow-Origin` answered to the `Origin` of the request, None when not allowed
+    pub fn allow_origin<'a>(&self, request: &'a RequestHeader) -> Option<&'a str> {
+        let origin = request.headers.get("Origin")?.to_str().ok()?;
+        if self.allowed_origins.iter().any(|allowed| allowed == "*") {
+            return Some("*");
+        }
+        self.allowed_origins.iter().any(|allowed| allowed.eq_ignore_ascii_case(origin)).then_some(origin)
+    }
+
+    /// Headers of the answer to a preflight `OPTIONS` request from an allowed origin
+    pub fn insert_preflight_headers(&self, resp: &mut ResponseHeader, origin: &str) -> Result<()> {
+        self.insert_headers(resp, origin)?;
+        resp.insert_header("Access-Control-Allow-Methods", self.allowed_methods.join(", "))?;
+        resp.insert_header("Access-Control-Allow-Headers", self.allowed_headers.join(", "))?;
+        resp.insert_header("Access-Control-Max-Age", self.max_age_secs.to_string())?;
+        Ok(())
+    }
+
+    /// Headers of a response to an allowed origin
+    pub fn insert_headers(&self, resp: &mut ResponseHeader, origin: &str) -> Result<()> {
+        resp.insert_header("Access-Control-Allow-Origin", origin)?;
+        if origin != "*" {
+            resp.append_header("Vary", "Origin")?;
+        }
+        if !self.expose_headers.is_empty() {
+            resp.insert_header("Access-Control-Expose-Headers", self.expose_headers.join(", "))?;
+        }
+        Ok(())
+    }
+}
+
+/// CORS headers of a response, when the request comes from an allowed origin
+pub fn insert_response_headers(cors: &Option<CorsConf>, request: &RequestHeader, resp: &mut ResponseHeader) -> Result<()> {
+    match cors.as_ref().and_then(|cors| Some((cors, cors.allow_origin(request)?))) {
+        Some((cors, origin)) => cors.insert_headers(resp, origin),
+        None => Ok(()),
+    }
+}
+
+/// Remove the CORS headers of an upstream response, the gateway decides which origins are allowed
+pub fn remove_headers(resp: &mut ResponseHeader) {
+    let names: Vec<_> = resp.headers.keys()
+        .filter(|name| name.as_str().starts_with("access-control-"))
+        .cloned()
+        .collect();
+    for name in names {
+        resp.remove_header(&name);
+    }
+}
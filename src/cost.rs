@@ -0,0 +1,121 @@
+// Copyright (c) 2025 Sébastien Campion, FOSS4. All rights reserved.
+//
+// This software is provided under the Commons Clause License Condition v1.0.
+// See the LICENSE file for full license details.
+
+use crate::config::{ModelConfig, ServerConf};
+use anyhow::Result;
+use once_cell::sync::Lazy;
+use prometheus::{register_gauge_vec, GaugeVec};
+use crate::maintenance::{delete_in_batches, oldest_kept_month};
+use redb::{Database, ReadTransaction, ReadableTable, TableDefinition, WriteTransaction};
+
+/// Accumulated spend in cents, keyed by `YYYYMM:user`
+pub const COST: TableDefinition<&str, f64> = TableDefinition::new("cost");
+
+/// Spend of the current day in cents, keyed by `YYYYMMDD:user` and `YYYYMMDD:user:location`
+pub const DAILY_COST: TableDefinition<&str, f64> = TableDefinition::new("daily_cost");
+
+/// Daily spend caps in USD set for some users through the admin API, replacing
+/// `daily_cost_cap_usd`
+pub const COST_CAPS: TableDefinition<&str, f64> = TableDefinition::new("cost_caps");
+
+static USER_MONTHLY_COST: Lazy<GaugeVec> = Lazy::new(|| {
+    register_gauge_vec!("user_monthly_cost_usd", "Running monthly cost per user in USD", &["user"]).unwrap()
+});
+
+pub fn cost_key(user: &str, current_time: chrono::DateTime<chrono::Utc>) -> String {
+    format!("{}:{}", current_time.format("%Y%m"), user)
+}
+
+/// Daily spend of the user, on one location or on all of them
+pub fn daily_cost_key(user: &str, location: Option<&str>, current_time: chrono::DateTime<chrono::Utc>) -> String {
+    match location {
+        Some(location) => format!("{}:{}:{}", current_time.format("%Y%m%d"), user, location),
+        None => format!("{}:{}", current_time.format("%Y%m%d"), user),
+    }
+}
+
+/// Spend of the user today in cents, on one location or on all of them
+pub fn daily_cost_cents(read_txn: &ReadTransaction, user: &str, location: Option<&str>, current_time: chrono::DateTime<chrono::Utc>) -> Result<f64> {
+    let table = read_txn.open_table(DAILY_COST)?;
+    let key = daily_cost_key(user, location, current_time);
+    Ok(table.get(key.as_str())?.map(|v| v.value()).unwrap_or(0.0))
+}
+
+/// Daily spend cap in USD set for the user through the admin API
+pub fn user_cost_cap(read_txn: &ReadTransaction, user: &str) -> Result<Option<f64>> {
+    Ok(read_txn.open_table(COST_CAPS)?.get(user)?.map(|v| v.value()))
+}
+
+/// Daily spend cap the user reached, across all locations or on the location of `model`,
+/// None while the user spent less than both today
+pub fn reached_cost_cap(db: &Database, conf: &ServerConf, model: &ModelConfig, user: &str, current_time: chrono::DateTime<chrono::Utc>) -> Result<Option<&'static str>> {
+    let read_txn = db.begin_read()?;
+    if let Some(cap) = user_cost_cap(&read_txn, user)?.or(conf.daily_cost_cap_usd) {
+        if daily_cost_cents(&read_txn, user, None, current_time)? >= cap * 100.0 {
+            return Ok(Some("Daily spend cap reached"));
+        }
+    }
+    if let Some(cap) = model.daily_cost_cap_usd {
+        if daily_cost_cents(&read_txn, user, Some(&model.location), current_time)? >= cap * 100.0 {
+            return Ok(Some("Daily spend cap of this model reached"));
+        }
+    }
+    Ok(None)
+}
+
+/// Cost of a request in cents, from the model prices per 1k tokens in USD
+pub fn request_cost_cents(model: &ModelConfig, input_tokens: u64, output_tokens: u64) -> f64 {
+    (input_tokens as f64 * model.input_price_per_1k + output_tokens as f64 * model.output_price_per_1k) / 10.0
+}
+
+/// Add the cost to the user's monthly total, and to its daily totals on all locations and on
+/// `location`, and return the new monthly total in cents.
+/// The caller is responsible for committing the transaction.
+pub fn add_cost(
+    write_txn: &WriteTransaction,
+    user: &str,
+    location: &str,
+    current_time: chrono::DateTime<chrono::Utc>,
+    cents: f64,
+) -> Result<f64> {
+    let key = cost_key(user, current_time);
+    let mut table = write_txn.open_table(COST)?;
+    let total = table.get(key.as_str())?.map(|v| v.value()).unwrap_or(0.0) + cents;
+    table.insert(key.as_str(), total)?;
+    USER_MONTHLY_COST.with_label_values(&[user]).set(total / 100.0);
+
+    let mut daily = write_txn.open_table(DAILY_COST)?;
+    for key in [daily_cost_key(user, None, current_time), daily_cost_key(user, Some(location), current_time)] {
+        let day_total = daily.get(key.as_str())?.map(|v| v.value()).unwrap_or(0.0) + cents;
+        daily.insert(key.as_str(), day_total)?;
+    }
+    Ok(total)
+}
+
+/// Remove the monthly costs older than `retention_months`, returns the number removed
+pub fn prune_cost(db: &Database, retention_months: u32, now: chrono::DateTime<chrono::Utc>) -> Result<u64> {
+    let oldest = oldest_kept_month(now, retention_months)?.format("%Y%m").to_string();
+    let mut expired = Vec::new();
+    for entry in db.begin_read()?.open_table(COST)?.iter()? {
+        let (key, _) = entry?;
+        if key.value().split(':').next().is_some_and(|month| month < oldest.as_str()) {
+            expired.push(key.value().to_string());
+        }
+    }
+    delete_in_batches(db, COST, &expired)
+}
+
+/// Remove the daily costs of the days before today, returns the number removed
+pub fn prune_daily_cost(db: &Database, now: chrono::DateTime<chrono::Utc>) -> Result<u64> {
+    let today = now.format("%Y%m%d").to_string();
+    let mut expired = Vec::new();
+    for entry in db.begin_read()?.open_table(DAILY_COST)?.iter()? {
+        let (key, _) = entry?;
+        if key.value().split(':').next().is_some_and(|day| day < today.as_str()) {
+            expired.push(key.value().to_string());
+        }
+    }
+    delete_in_batches(db, DAILY_COST, &expired)
+}
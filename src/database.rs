@@ -0,0 +1,35 @@
+// Copyright (c) 2025 Sébastien Campion, FOSS4. All rights reserved.
+//
+// This software is provided under the Commons Clause License Condition v1.0.
+// See the LICENSE file for full license details.
+
+use anyhow::{anyhow, Result};
+use log::warn;
+use redb::{Database, DatabaseError, StorageError};
+
+/// Open or create the database at `path`. A file left dirty by a crash is repaired first, the
+/// errors redb cannot recover from explain what the operator can do about them.
+pub fn open(path: &str) -> Result<Database> {
+    Database::builder()
+        .set_repair_callback(|session| warn!("Repairing the database: {:.0}%", session.progress() * 100.0))
+        .create(path)
+        .map_err(|e| match e {
+            DatabaseError::DatabaseAlreadyOpen => anyhow!(
+                "Database {} is locked by another process, most likely a gateway still running: stop it or set another database_path",
+                path
+            ),
+            DatabaseError::UpgradeRequired(version) => anyhow!(
+                "Database {} is in the file format {} of an older redb, upgrade it or move it aside to start empty",
+                path, version
+            ),
+            DatabaseError::Storage(StorageError::Corrupted(cause)) => anyhow!(
+                "Database {} is corrupted and could not be repaired ({}), restore it from a backup or move it aside to start empty",
+                path, cause
+            ),
+            DatabaseError::Storage(StorageError::Io(e)) if e.kind() == std::io::ErrorKind::InvalidData => anyhow!(
+                "Database {} is not a redb database or its header is corrupted, restore it from a backup or move it aside to start empty",
+                path
+            ),
+            e => anyhow!("Unable to open the database {}: {}", path, e),
+        })
+}
@@ -0,0 +1,76 @@
+// Copyright (c) 2025 Sébastien Campion, FOSS4. All rights reserved.
+//
+// This software is provided under the Commons Clause License Condition v1.0.
+// See the LICENSE file for full license details.
+
+use anyhow::Result;
+use chrono::{DateTime, SecondsFormat, Utc};
+use redb::{Database, TableDefinition, WriteTransaction};
+use serde::{Deserialize, Serialize};
+
+use crate::maintenance::delete_in_batches;
+
+/// Requests the gateway refused, JSON records keyed by `<time>:<request id>`. Times are RFC 3339
+/// in UTC to the microsecond, fixed width, so the keys sort by time.
+pub const DECISIONS: TableDefinition<&str, &str> = TableDefinition::new("decisions");
+
+/// Why the gateway refused a request, for the compliance audit trail
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Decision {
+    /// From `timestamp`, which the keys start with
+    pub timestamp: String,
+    pub request_id: String,
+    pub user: Option<String>,
+    pub model: Option<String>,
+    pub location: Option<String>,
+    pub path: String,
+    pub client_ip: Option<String>,
+    /// Always `deny`, allowed requests are in the audit log
+    pub decision: String,
+    pub status: u16,
+    /// Message of the error returned to the client
+    pub reason: String,
+}
+
+/// Time of a decision, RFC 3339 in UTC to the microsecond
+pub fn timestamp(time: DateTime<Utc>) -> String {
+    time.to_rfc3339_opts(SecondsFormat::Micros, true)
+}
+
+/// Add the decision to the table, the caller is responsible for committing the transaction
+pub fn write_decision(write_txn: &WriteTransaction, decision: &Decision) -> Result<()> {
+    let key = format!("{}:{}", decision.timestamp, decision.request_id);
+    let value = serde_json::to_string(decision)?;
+    write_txn.open_table(DECISIONS)?.insert(key.as_str(), value.as_str())?;
+    Ok(())
+}
+
+/// Decisions from `from` included to `to` excluded, of one user or of all, the oldest first
+/// and at most `limit`
+pub fn find_decisions(db: &Database, user: Option<&str>, from: DateTime<Utc>, to: DateTime<Utc>, limit: usize) -> Result<Vec<Decision>> {
+    let read_txn = db.begin_read()?;
+    let table = read_txn.open_table(DECISIONS)?;
+    let mut decisions = Vec::new();
+    for entry in table.range(timestamp(from).as_str()..timestamp(to).as_str())? {
+        let (_, value) = entry?;
+        let decision: Decision = serde_json::from_str(value.value())?;
+        if user.is_none_or(|user| decision.user.as_deref() == Some(user)) {
+            decisions.push(decision);
+            if decisions.len() == limit {
+                break;
+            }
+        }
+    }
+    Ok(decisions)
+}
+
+/// Remove the decisions older than `retention_days`, returns the number removed
+pub fn prune_decisions(db: &Database, retention_days: u32, now: DateTime<Utc>) -> Result<u64> {
+    let oldest = timestamp(now - chrono::Duration::days(retention_days as i64));
+    let mut expired = Vec::new();
+    for entry in db.begin_read()?.open_table(DECISIONS)?.range(..oldest.as_str())? {
+        let (key, _) = entry?;
+        expired.push(key.value().to_string());
+    }
+    delete_in_batches(db, DECISIONS, &expired)
+}
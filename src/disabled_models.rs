@@ -0,0 +1,57 @@
+// Copyright (c) 2025 Sébastien Campion, FOSS4. All rights reserved.
+//
+// This software is provided under the Commons Clause License Condition v1.0.
+// See the LICENSE file for full license details.
+
+use arc_swap::ArcSwap;
+use log::warn;
+use once_cell::sync::Lazy;
+use prometheus::{register_int_gauge, IntGauge};
+use std::collections::BTreeSet;
+use std::sync::Arc;
+
+static DISABLED_MODELS: Lazy<IntGauge> = Lazy::new(|| {
+    register_int_gauge!("disabled_models", "Locations whose requests are rejected until enabled again").unwrap()
+});
+
+/// Locations taken out of rotation with the admin API, the gateway answers their requests with a
+/// 503. They stay disabled until enabled again or the configuration is reloaded.
+/// Shared by the gateway, the admin API and the configuration reloader.
+#[derive(Default)]
+pub struct DisabledModels {
+    locations: ArcSwap<BTreeSet<String>>,
+}
+
+impl DisabledModels {
+    pub fn is_disabled(&self, location: &str) -> bool {
+        self.locations.load().contains(location)
+    }
+
+    /// Disable or enable the location, return whether it changed
+    pub fn set(&self, location: &str, disabled: bool) -> bool {
+        let mut changed = false;
+        self.locations.rcu(|locations| {
+            let mut locations = BTreeSet::clone(locations);
+            changed = if disabled { locations.insert(location.to_string()) } else { locations.remove(location) };
+            locations
+        });
+        if changed {
+            DISABLED_MODELS.set(self.locations.load().len() as i64);
+            warn!("Location {} {}", location, if disabled { "disabled, rejecting its requests" } else { "enabled" });
+        }
+        changed
+    }
+
+    pub fn list(&self) -> Vec<String> {
+        self.locations.load().iter().cloned().collect()
+    }
+
+    /// Enable all the locations, the configuration they were disabled for is gone
+    pub fn clear(&self) {
+        let locations = self.locations.swap(Arc::new(BTreeSet::new()));
+        DISABLED_MODELS.set(0);
+        if !locations.is_empty() {
+            warn!("Locations {:?} enabled with the new configuration", locations);
+        }
+    }
+}
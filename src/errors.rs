@@ -0,0 +1,94 @@
+// Copyright (c) 2025 Sébastien Campion, FOSS4. All rights reserved.
+//
+// This software is provided under the Commons Clause License Condition v1.0.
+// See the LICENSE file for full license details.
+
+use bytes::Bytes;
+use http::{header, StatusCode};
+use pingora::http::ResponseHeader;
+use pingora::prelude::*;
+use pingora_proxy::Session;
+
+/// OpenAI error `type` and `code` for a status
+fn error_kind(status: u16) -> (&'static str, &'static str) {
+    match status {
+        400 => ("invalid_request_error", "bad_request"),
+        401 => ("invalid_request_error", "invalid_api_key"),
+        402 => ("insufficient_quota", "spend_cap_reached"),
+        403 => ("invalid_request_error", "forbidden"),
+        404 => ("invalid_request_error", "not_found"),
+        413 => ("invalid_request_error", "request_too_large"),
+        415 => ("invalid_request_error", "unsupported_media_type"),
+        429 => ("rate_limit_error", "rate_limit_exceeded"),
+        502..=504 => ("api_error", "upstream_error"),
+        _ => ("api_error", "internal_error"),
+    }
+}
+
+/// JSON body following the OpenAI error schema
+pub fn error_body(status: u16, message: &str) -> String {
+    let (error_type, code) = error_kind(status);
+    serde_json::json!({
+        "error": {
+            "message": message,
+            "type": error_type,
+            "code": code,
+        }
+    })
+    .to_string()
+}
+
+/// Correlation id of a request, set on the request by the gateway and echoed on the response
+pub const REQUEST_ID_HEADER: &str = "X-Request-Id";
+
+/// Echo the request id of the request on a response written by the gateway itself
+pub fn insert_request_id(session: &Session, resp: &mut ResponseHeader) -> Result<()> {
+    if let Some(request_id) = session.req_header().headers.get(REQUEST_ID_HEADER) {
+        resp.insert_header(REQUEST_ID_HEADER, request_id.clone())?;
+    }
+    Ok(())
+}
+
+/// Write an error response with a JSON body shaped like OpenAI errors
+pub async fn respond_json_error(session: &mut Session, status: u16, message: &str) -> Result<()> {
+    write_json_error(session, status, message, None).await
+}
+
+/// Write a JSON error telling the client to try again after `retry_after` seconds
+pub async fn respond_json_error_retry(session: &mut Session, status: u16, message: &str, retry_after: u64) -> Result<()> {
+    write_json_error(session, status, message, Some(retry_after)).await
+}
+
+async fn write_json_error(session: &mut Session, status: u16, message: &str, retry_after: Option<u64>) -> Result<()> {
+    let body = error_body(status, message);
+    let mut resp = ResponseHeader::build(status, Some(5))?;
+    resp.insert_header(header::CONTENT_TYPE, "application/json")?;
+    if let Some(retry_after) = retry_after {
+        resp.insert_header(header::RETRY_AFTER, retry_after.to_string())?;
+    }
+    resp.insert_header(header::CONTENT_LENGTH, body.len())?;
+    insert_request_id(session, &mut resp)?;
+    session.set_keepalive(None);
+    session.write_response_header(Box::new(resp), false).await?;
+    session.write_response_body(Some(Bytes::from(body)), true).await?;
+    // Compressed for the client the body goes out chunked, and Pingora does not end it after
+    // fail_to_proxy
+    session.finish_body().await
+}
+
+/// Message for a proxy error: the explanation of HTTP status errors, the status reason otherwise.
+/// Pingora wraps filter errors with the peer as context, so the explanation is the root cause.
+pub fn error_message(e: &Error, status: u16) -> String {
+    let mut root = e;
+    while let Some(cause) = root.cause.as_deref().and_then(|c| c.downcast_ref::<BError>()) {
+        root = cause;
+    }
+    match (root.etype(), &root.context) {
+        (ErrorType::HTTPStatus(_), Some(context)) => context.as_str().to_string(),
+        _ => StatusCode::from_u16(status)
+            .ok()
+            .and_then(|s| s.canonical_reason())
+            .unwrap_or("Error")
+            .to_string(),
+    }
+}
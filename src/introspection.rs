@@ -0,0 +1,83 @@
+// Copyright (c) 2025 Sébastien Campion, FOSS4. All rights reserved.
+//
+// This software is provided under the Commons Clause License Condition v1.0.
+// See the LICENSE file for full license details.
+
+use crate::config::IntrospectionConf;
+use anyhow::{anyhow, Result};
+use moka::sync::Cache;
+use serde::Deserialize;
+use std::time::Duration;
+
+const MAX_CAPACITY: u64 = 100_000;
+const INTROSPECTION_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// RFC 7662 introspection response, only the fields the gateway uses
+#[derive(Debug, Deserialize)]
+struct IntrospectionResponse {
+    active: bool,
+    #[serde(default)]
+    username: Option<String>,
+    #[serde(default)]
+    sub: Option<String>,
+    #[serde(default)]
+    scope: Option<String>,
+    #[serde(default)]
+    exp: Option<i64>,
+}
+
+/// User of an active opaque token, its scopes standing for groups
+#[derive(Clone, Debug)]
+pub struct IntrospectedToken {
+    pub user: String,
+    pub groups: Vec<String>,
+    pub expires_at: Option<i64>,
+}
+
+/// Client of the OAuth2 token introspection endpoint. Answers, inactive tokens included,
+/// are cached briefly so a client does not cost one introspection call per request.
+pub struct Introspection {
+    client: reqwest::Client,
+    tokens: Cache<String, Option<IntrospectedToken>>,
+}
+
+impl Introspection {
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            client: reqwest::Client::new(),
+            tokens: Cache::builder().max_capacity(MAX_CAPACITY).time_to_live(ttl).build(),
+        }
+    }
+
+    /// The user and scopes of the token, None when it is not active
+    pub async fn introspect(&self, conf: &IntrospectionConf, token: &str) -> Result<Option<IntrospectedToken>> {
+        let now = chrono::Utc::now().timestamp();
+        if let Some(cached) = self.tokens.get(token) {
+            // an active answer does not outlive the token
+            return Ok(cached.filter(|t| t.expires_at.is_none_or(|exp| exp > now)));
+        }
+
+        let response = self.client
+            .post(&conf.url)
+            .basic_auth(&conf.client_id, Some(&conf.client_secret))
+            .form(&[("token", token), ("token_type_hint", "access_token")])
+            .timeout(INTROSPECTION_TIMEOUT)
+            .send()
+            .await?;
+        if !response.status().is_success() {
+            return Err(anyhow!("introspection endpoint returned {}", response.status()));
+        }
+        let answer: IntrospectionResponse = response.json().await?;
+
+        let introspected = match answer {
+            IntrospectionResponse { active: true, username, sub, scope, exp } => {
+                let user = username.or(sub).ok_or_else(|| anyhow!("active token without username or sub"))?;
+                let groups = scope.unwrap_or_default().split_whitespace().map(str::to_string).collect();
+                Some(IntrospectedToken { user, groups, expires_at: exp })
+            }
+            _ => None,
+        };
+        self.tokens.insert(token.to_string(), introspected.clone());
+        Ok(introspected.filter(|t| t.expires_at.is_none_or(|exp| exp > now)))
+    }
+}
@@ -0,0 +1,84 @@
+// Copyright (c) 2025 Sébastien Campion, FOSS4. All rights reserved.
+//
+// This software is provided under the Commons Clause License Condition v1.0.
+// See the LICENSE file for full license details.
+
+use std::net::IpAddr;
+use ipnet::IpNet;
+use pingora_proxy::Session;
+
+/// Client addresses allowed and denied, compiled from the CIDR lists of the configuration
+#[derive(Debug, Clone, Default)]
+pub struct IpFilter {
+    /// Networks allowed, any address when empty
+    pub allow: Vec<IpNet>,
+    /// Networks rejected, even when allowed
+    pub deny: Vec<IpNet>,
+}
+
+impl IpFilter {
+    pub fn new(allowlist: &[String], denylist: &[String]) -> Result<Self, String> {
+        Ok(Self {
+            allow: parse_networks(allowlist).map_err(|e| format!("ip_allowlist: {}", e))?,
+            deny: parse_networks(denylist).map_err(|e| format!("ip_denylist: {}", e))?,
+        })
+    }
+
+    /// Whether the client may be served, an unknown address only passes without an allowlist
+    pub fn allows(&self, ip: Option<IpAddr>) -> bool {
+        match ip {
+            Some(ip) => !contains(&self.deny, ip) && (self.allow.is_empty() || contains(&self.allow, ip)),
+            None => self.allow.is_empty(),
+        }
+    }
+}
+
+/// CIDR networks, a bare address being a network of its own, e.g. `10.0.0.0/8` or `::1`
+pub fn parse_networks(entries: &[String]) -> Result<Vec<IpNet>, String> {
+    entries.iter().map(|entry| {
+        let entry = entry.trim();
+        entry.parse::<IpNet>()
+            .or_else(|_| entry.parse::<IpAddr>().map(IpNet::from))
+            .map(|net| net.trunc())
+            .map_err(|_| format!("invalid network {:?}, expected an address or CIDR", entry))
+    }).collect()
+}
+
+pub fn contains(networks: &[IpNet], ip: IpAddr) -> bool {
+    networks.iter().any(|net| net.contains(&ip))
+}
+
+/// Address of the connection, of a proxy or of the client itself
+fn peer_ip(session: &Session) -> Option<IpAddr> {
+    Some(session.client_addr()?.as_inet()?.ip().to_canonical())
+}
+
+/// Whether the request comes straight from one of `trusted_proxies`, whose headers can be believed
+pub fn from_trusted_proxy(session: &Session, trusted_proxies: &[IpNet]) -> bool {
+    peer_ip(session).is_some_and(|ip| contains(trusted_proxies, ip))
+}
+
+/// Address of the client. Behind `trusted_proxies`, it is the last `X-Forwarded-For` hop not added
+/// by a trusted proxy, since the hops before it can be written by the client.
+pub fn client_ip(session: &Session, trusted_proxies: &[IpNet]) -> Option<IpAddr> {
+    let mut ip = peer_ip(session)?;
+    if !contains(trusted_proxies, ip) {
+        return Some(ip);
+    }
+    let forwarded: Vec<&str> = session.req_header().headers.get_all("X-Forwarded-For").iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(str::trim)
+        .collect();
+    for hop in forwarded.into_iter().rev() {
+        // A hop that is not an address ends the chain the proxies vouch for
+        let Ok(hop) = hop.parse::<IpAddr>() else {
+            break;
+        };
+        ip = hop.to_canonical();
+        if !contains(trusted_proxies, ip) {
+            break;
+        }
+    }
+    Some(ip)
+}
@@ -0,0 +1,188 @@
+// Copyright (c) 2025 Sébastien Campion, FOSS4. All rights reserved.
+//
+// This software is provided under the Commons Clause License Condition v1.0.
+// See the LICENSE file for full license details.
+
+use crate::config::JwtConf;
+use anyhow::{anyhow, Result};
+use arc_swap::ArcSwapOption;
+use jsonwebtoken::jwk::{AlgorithmParameters, EllipticCurve, Jwk, JwkSet};
+use jsonwebtoken::{decode, decode_header, Algorithm, DecodingKey, Validation};
+use log::{error, info};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Shortest delay between two JWKS downloads, even for tokens signed with an unknown key
+const MIN_REFETCH_INTERVAL: Duration = Duration::from_secs(10);
+const JWKS_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// User and groups read from the claims of a valid token
+#[derive(Debug)]
+pub struct JwtIdentity {
+    pub user: String,
+    pub groups: Vec<String>,
+}
+
+#[derive(Debug)]
+pub enum JwtError {
+    /// Not a JWT at all, it may be an opaque token
+    Malformed,
+    Expired,
+    Invalid(String),
+}
+
+struct FetchedJwks {
+    url: String,
+    keys: JwkSet,
+    fetched_at: Instant,
+}
+
+/// Validates bearer JWTs against a static public key or a JWKS downloaded from the identity
+/// provider. The JWKS is kept for `jwks_refresh_secs` and downloaded again when it is stale
+/// or a token is signed with a key it does not contain.
+pub struct JwtAuth {
+    client: reqwest::Client,
+    jwks: ArcSwapOption<FetchedJwks>,
+    /// Time of the last download attempt, held while downloading
+    last_fetch: tokio::sync::Mutex<Option<Instant>>,
+}
+
+impl JwtAuth {
+    pub fn new() -> Self {
+        Self {
+            client: reqwest::Client::new(),
+            jwks: ArcSwapOption::empty(),
+            last_fetch: tokio::sync::Mutex::new(None),
+        }
+    }
+
+    pub async fn validate(&self, conf: &JwtConf, token: &str) -> Result<JwtIdentity, JwtError> {
+        let header = decode_header(token).map_err(|_| JwtError::Malformed)?;
+
+        let (key, algorithms) = match &conf.static_key {
+            Some(key) => (key.0.clone(), vec![conf.algorithm]),
+            None => {
+                let kid = header.kid.as_deref().ok_or_else(|| JwtError::Invalid("token has no kid".to_string()))?;
+                let jwk = self.find_key(conf, kid).await
+                    .ok_or_else(|| JwtError::Invalid(format!("no key {} in the JWKS", kid)))?;
+                let key = DecodingKey::from_jwk(&jwk).map_err(|e| JwtError::Invalid(format!("unusable key {}: {}", kid, e)))?;
+                (key, jwk_algorithms(&jwk))
+            }
+        };
+        if !algorithms.contains(&header.alg) {
+            return Err(JwtError::Invalid(format!("algorithm {:?} not allowed for the key", header.alg)));
+        }
+
+        let mut validation = Validation::new(header.alg);
+        validation.algorithms = algorithms;
+        if !conf.issuer.is_empty() {
+            validation.set_issuer(&[&conf.issuer]);
+        }
+        if conf.audience.is_empty() {
+            validation.validate_aud = false;
+        } else {
+            validation.set_audience(&[&conf.audience]);
+        }
+
+        let claims = decode::<serde_json::Value>(token, &key, &validation)
+            .map_err(|e| match e.kind() {
+                jsonwebtoken::errors::ErrorKind::ExpiredSignature => JwtError::Expired,
+                _ => JwtError::Invalid(e.to_string()),
+            })?
+            .claims;
+
+        let user = claims.get(&conf.user_claim)
+            .and_then(|v| v.as_str())
+            .ok_or_else(|| JwtError::Invalid(format!("missing {} claim", conf.user_claim)))?
+            .to_string();
+        let groups = match claims.get(&conf.groups_claim) {
+            Some(serde_json::Value::Array(groups)) => groups.iter().filter_map(|g| g.as_str()).map(str::to_string).collect(),
+            Some(serde_json::Value::String(groups)) => groups.split(',').map(str::trim).filter(|g| !g.is_empty()).map(str::to_string).collect(),
+            _ => Vec::new(),
+        };
+        Ok(JwtIdentity { user, groups })
+    }
+
+    async fn find_key(&self, conf: &JwtConf, kid: &str) -> Option<Jwk> {
+        let max_age = Duration::from_secs(conf.jwks_refresh_secs);
+        let fresh = |jwks: &Option<Arc<FetchedJwks>>| {
+            jwks.as_ref().filter(|jwks| jwks.url == conf.jwks_url && jwks.fetched_at.elapsed() < max_age).cloned()
+        };
+        if let Some(jwk) = fresh(&self.jwks.load_full()).and_then(|jwks| jwks.keys.find(kid).cloned()) {
+            return Some(jwk);
+        }
+
+        // Stale, missing or without the key: download it again, once at a time
+        let mut last_fetch = self.last_fetch.lock().await;
+        if let Some(jwk) = fresh(&self.jwks.load_full()).and_then(|jwks| jwks.keys.find(kid).cloned()) {
+            return Some(jwk);
+        }
+        if last_fetch.is_none_or(|at| at.elapsed() >= MIN_REFETCH_INTERVAL) {
+            *last_fetch = Some(Instant::now());
+            match self.fetch(&conf.jwks_url).await {
+                Ok(keys) => {
+                    info!("Fetched {} keys from {}", keys.keys.len(), conf.jwks_url);
+                    self.jwks.store(Some(Arc::new(FetchedJwks { url: conf.jwks_url.clone(), keys, fetched_at: Instant::now() })));
+                }
+                Err(e) => error!("Failed to fetch JWKS from {}: {}", conf.jwks_url, e),
+            }
+        }
+        // A stale JWKS is still better than none while the identity provider is unreachable
+        self.jwks.load_full()
+            .filter(|jwks| jwks.url == conf.jwks_url)
+            .and_then(|jwks| jwks.keys.find(kid).cloned())
+    }
+
+    async fn fetch(&self, url: &str) -> Result<JwkSet> {
+        let response = self.client.get(url).timeout(JWKS_TIMEOUT).send().await?;
+        if !response.status().is_success() {
+            return Err(anyhow!("status {}", response.status()));
+        }
+        Ok(response.json::<JwkSet>().await?)
+    }
+}
+
+/// Algorithms a JWK may verify: the one it declares, or those of its key type
+fn jwk_algorithms(jwk: &Jwk) -> Vec<Algorithm> {
+    if let Some(algorithm) = jwk.common.key_algorithm {
+        return algorithm.to_string().parse().ok().into_iter().collect();
+    }
+    match &jwk.algorithm {
+        AlgorithmParameters::RSA(_) => vec![
+            Algorithm::RS256, Algorithm::RS384, Algorithm::RS512,
+            Algorithm::PS256, Algorithm::PS384, Algorithm::PS512,
+        ],
+        AlgorithmParameters::EllipticCurve(ec) => match ec.curve {
+            EllipticCurve::P256 => vec![Algorithm::ES256],
+            EllipticCurve::P384 => vec![Algorithm::ES384],
+            _ => Vec::new(),
+        },
+        AlgorithmParameters::OctetKeyPair(_) => vec![Algorithm::EdDSA],
+        // Shared secrets are not accepted from a JWKS
+        AlgorithmParameters::OctetKey(_) => Vec::new(),
+    }
+}
+
+/// Public key of the static key mode, parsed when the configuration is loaded
+#[derive(Clone)]
+pub struct StaticKey(pub DecodingKey);
+
+impl std::fmt::Debug for StaticKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("StaticKey")
+    }
+}
+
+/// Parse the PEM public key of the static key mode
+pub fn parse_public_key(pem: &str, algorithm: Algorithm) -> Result<DecodingKey> {
+    let key = match algorithm {
+        Algorithm::RS256 | Algorithm::RS384 | Algorithm::RS512
+        | Algorithm::PS256 | Algorithm::PS384 | Algorithm::PS512 => DecodingKey::from_rsa_pem(pem.as_bytes()),
+        Algorithm::ES256 | Algorithm::ES384 => DecodingKey::from_ec_pem(pem.as_bytes()),
+        Algorithm::EdDSA => DecodingKey::from_ed_pem(pem.as_bytes()),
+        Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512 => {
+            return Err(anyhow!("algorithm {:?} needs a shared secret, not a public key", algorithm));
+        }
+    };
+    key.map_err(|e| anyhow!("invalid public key: {}", e))
+}
@@ -0,0 +1,77 @@
+// Copyright (c) 2025 Sébastien Campion, FOSS4. All rights reserved.
+//
+// This software is provided under the Commons Clause License Condition v1.0.
+// See the LICENSE file for full license details.
+
+use crate::config::{ModelConfig, Upstream};
+use anyhow::{anyhow, Result};
+use log::{debug, warn};
+use once_cell::sync::Lazy;
+use prometheus::{register_int_counter_vec, IntCounterVec};
+use serde_json::json;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Longest wait for the answer to a keep-alive request, a model being loaded included
+const MAX_KEEPALIVE_TIMEOUT: Duration = Duration::from_secs(60);
+
+static CLIENT: Lazy<reqwest::Client> = Lazy::new(reqwest::Client::new);
+
+static KEEPALIVE_REQUESTS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!("keepalive_requests", "Keep-alive requests sent to the upstreams, by location and result", &["location", "result"]).unwrap()
+});
+
+/// Send a keep-alive request to each upstream of the model in the background. Nothing of it is
+/// counted with the requests of the clients: no usage, quota, cost or token metric.
+pub fn ping(model: Arc<ModelConfig>) {
+    for index in 0..model.upstreams.len() {
+        let model = model.clone();
+        tokio::spawn(async move {
+            let upstream = &model.upstreams[index];
+            let result = match send(&model, upstream).await {
+                Ok(()) => {
+                    debug!("Keep-alive request to {} answered", upstream.proxy_pass);
+                    "ok"
+                }
+                Err(e) => {
+                    warn!("Keep-alive request of {} to {} failed: {}", model.location, upstream.proxy_pass, e);
+                    "error"
+                }
+            };
+            KEEPALIVE_REQUESTS.with_label_values(&[&model.location, result]).inc();
+        });
+    }
+}
+
+async fn send(model: &ModelConfig, upstream: &Upstream) -> Result<()> {
+    let target = &upstream.target;
+    let url = format!("{}://{}:{}{}", if target.tls { "https" } else { "http" }, target.host, target.port, target.request_uri("", None)?);
+    let body = model.keepalive_body.clone().unwrap_or_else(|| json!({
+        "model": model.model_name,
+        "messages": [{"role": "user", "content": "ping"}],
+        "max_tokens": 1,
+        "stream": false,
+    }));
+    let mut request = CLIENT.post(url)
+        .json(&body)
+        .timeout(Duration::from_secs(model.keepalive_interval_secs).min(MAX_KEEPALIVE_TIMEOUT));
+    let api_key = model.api_keys.first().unwrap_or(&model.api_key);
+    if !api_key.is_empty() {
+        let auth_value = if model.auth_scheme.is_empty() {
+            api_key.clone()
+        } else {
+            format!("{} {}", model.auth_scheme, api_key)
+        };
+        request = request.header(model.auth_header_name.as_str(), auth_value);
+    }
+    for (name, value) in &model.upstream_headers_add {
+        request = request.header(name.as_str(), value.as_str());
+    }
+    let response = request.send().await?;
+    if !response.status().is_success() {
+        return Err(anyhow!("status {}", response.status()));
+    }
+    // read to the end, so the connection goes back to the pool
+    response.bytes().await?;
+    Ok(())
+}
@@ -0,0 +1,45 @@
+// Copyright (c) 2025 Sébastien Campion, FOSS4. All rights reserved.
+//
+// This software is provided under the Commons Clause License Condition v1.0.
+// See the LICENSE file for full license details.
+
+use crate::config::Upstream;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Selects an upstream using weighted round-robin over a shared counter.
+///
+/// Each call advances the counter by one; an upstream with weight `w` owns `w`
+/// consecutive slots out of the total weight. Upstreams with a weight of 0 are
+/// never selected.
+///
+/// Indexes listed in `skip` (upstreams already tried for this request) are
+/// avoided as long as another candidate remains.
+pub fn select_upstream(upstreams: &[Upstream], counter: &AtomicUsize, skip: &[usize]) -> Option<usize> {
+    let skip = if upstreams.iter().enumerate().all(|(i, u)| u.weight == 0 || skip.contains(&i)) {
+        &[]
+    } else {
+        skip
+    };
+
+    let total_weight: usize = upstreams.iter().enumerate()
+        .filter(|(i, _)| !skip.contains(i))
+        .map(|(_, u)| u.weight as usize)
+        .sum();
+    if total_weight == 0 {
+        return None;
+    }
+
+    let mut slot = counter.fetch_add(1, Ordering::Relaxed) % total_weight;
+    upstreams.iter().enumerate().position(|(i, u)| {
+        if skip.contains(&i) {
+            return false;
+        }
+        let weight = u.weight as usize;
+        if slot < weight {
+            true
+        } else {
+            slot -= weight;
+            false
+        }
+    })
+}
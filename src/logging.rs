@@ -0,0 +1,122 @@
+// Copyright (c) 2025 Sébastien Campion, FOSS4. All rights reserved.
+//
+// This software is provided under the Commons Clause License Condition v1.0.
+// See the LICENSE file for full license details.
+
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io::IsTerminal;
+use std::path::Path;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{EnvFilter, Layer, Registry};
+
+use crate::telemetry::{self, TracingConf};
+
+/// Environment variable overriding `log_format`
+pub const LOG_FORMAT_ENV: &str = "BURGONET_LOG_FORMAT";
+
+/// Format of the gateway log lines
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// Plain lines, formatted by `env_logger` in debug builds and `log4rs.yml` in release builds
+    #[default]
+    Text,
+    /// One JSON object per line on stderr, the fields of structured events as keys
+    Json,
+}
+
+impl LogFormat {
+    fn from_name(name: &str) -> Option<Self> {
+        match name.trim().to_ascii_lowercase().as_str() {
+            "text" => Some(Self::Text),
+            "json" => Some(Self::Json),
+            _ => None,
+        }
+    }
+}
+
+/// The logging keys of the configuration, read on their own before the configuration is
+/// loaded so that its errors are logged in the chosen format
+#[derive(Deserialize, Default)]
+struct LogSettings {
+    #[serde(default)]
+    log_format: LogFormat,
+    #[serde(default)]
+    log_config_file: Option<String>,
+    #[serde(default)]
+    tracing: Option<TracingConf>,
+}
+
+impl LogSettings {
+    /// Settings of the configuration file, the defaults when it cannot be read: loading it
+    /// again afterwards reports why
+    fn read(conf_path: Option<&Path>) -> Self {
+        conf_path
+            .and_then(|path| fs::read_to_string(path).ok())
+            .and_then(|conf| serde_yaml::from_str(&conf).ok())
+            .unwrap_or_default()
+    }
+}
+
+/// Install the logger, in the format of `BURGONET_LOG_FORMAT` or else of the configuration's
+/// `log_format`, and the span exporter when `tracing` is configured. Both `log` records and
+/// `tracing` events go to the same output; `RUST_LOG` filters them in either format.
+pub fn init(conf_path: Option<&Path>) {
+    let settings = LogSettings::read(conf_path);
+    let env_format = std::env::var(LOG_FORMAT_ENV).ok();
+    let format = env_format.as_deref().and_then(LogFormat::from_name).unwrap_or(settings.log_format);
+    let exporter = settings.tracing.as_ref().map(telemetry::layer);
+
+    match (format, exporter) {
+        (LogFormat::Text, None) => init_text(settings.log_config_file),
+        (format, exporter) => {
+            let output = match format {
+                LogFormat::Json => tracing_subscriber::fmt::layer()
+                    .json()
+                    .flatten_event(true)
+                    .with_current_span(false)
+                    .with_span_list(false)
+                    .with_writer(std::io::stderr)
+                    .boxed(),
+                LogFormat::Text => tracing_subscriber::fmt::layer()
+                    .with_ansi(std::io::stderr().is_terminal())
+                    .with_writer(std::io::stderr)
+                    .boxed(),
+            };
+            let mut layers = vec![output.with_filter(EnvFilter::from_default_env()).boxed()];
+            let exporter_error = match exporter {
+                Some(Ok(layer)) => {
+                    layers.push(layer);
+                    None
+                }
+                Some(Err(e)) => Some(e),
+                None => None,
+            };
+            // Also routes the `log` records of the gateway and its dependencies to the subscriber
+            Registry::default().with(layers).init();
+            if let Some(e) = exporter_error {
+                log::error!("{:#}, spans are not exported", e);
+            }
+        }
+    }
+
+    if let Some(name) = env_format.filter(|name| LogFormat::from_name(name).is_none()) {
+        log::warn!("Ignoring {}={:?}, expected text or json", LOG_FORMAT_ENV, name);
+    }
+}
+
+#[cfg(debug_assertions)]
+fn init_text(_log_config_file: Option<String>) {
+    env_logger::init();
+}
+
+#[cfg(not(debug_assertions))]
+fn init_text(log_config_file: Option<String>) {
+    let file = log_config_file.unwrap_or_else(|| "log4rs.yml".to_string());
+    if let Err(e) = log4rs::init_file(&file, Default::default()) {
+        env_logger::init();
+        log::error!("Failed to load the log configuration {}: {}", file, e);
+    }
+}
@@ -4,82 +4,145 @@
 // See the LICENSE file for full license details.
 //
 // External crates
-use async_trait::async_trait;
-use base64::engine::general_purpose;
-use base64::Engine;
-use bytes::Bytes;
-//use log::{debug, error, info, trace, warn};
-use once_cell::sync::Lazy;
-use prometheus::register_int_counter;
-use redb::{Database, TableDefinition};
-use reqwest::Client;
-use reqwest::Error as ReqwestError;
-use log::{debug, error, info, trace, warn, LevelFilter, SetLoggerError};
-use log4rs::{
-    append::{
-        console::{ConsoleAppender, Target},
-        file::FileAppender,
-    },
-    config::{Appender, Config, Root},
-    encode::pattern::PatternEncoder,
-    filter::threshold::ThresholdFilter,
-};
-use std::io::stdout;
+use prometheus::{register_histogram_vec, register_int_counter, register_int_counter_vec, register_int_gauge, register_int_gauge_vec};
+use redb::TableDefinition;
+use clap::Parser;
+use log::{info, warn};
 
 // Pingora-related imports
 use pingora::prelude::*;
-use pingora_http::ResponseHeader;
-use pingora_limits::rate::Rate;
-use pingora_proxy::{ProxyHttp, Session};
 
 // Internal modules
+mod api_keys;
+mod audit;
+mod auth;
+mod body_transform;
+mod cache;
+mod circuit_breaker;
+mod concurrency;
+mod cors;
+mod compression;
+mod database;
+mod decisions;
+mod disabled_models;
+mod jwt;
+mod keepalive;
+mod logging;
+mod introspection;
+mod ip_filter;
+mod cost;
 mod config;
+mod maintenance;
+mod maintenance_mode;
+mod moderation;
+mod errors;
+mod parameter_limits;
 mod parsers;
 mod pii_protection;
+mod prompt_injection;
 mod app;
+mod load_balancing;
 mod rate_limit;
+mod request_signing;
+mod response_cache;
+mod coalescing;
+mod token_estimate;
 mod token_limit;
+mod usage_writer;
 mod service;
+mod shadow;
+mod sigv4;
+mod stream_blacklist;
+mod stream_usage;
+mod system_prompt;
+mod telemetry;
 
 use crate::app::gateway::BurgonetGateway;
+use crate::cache::AuthCache;
+use crate::api_keys::ApiKeys;
+use crate::circuit_breaker::CircuitBreakers;
+use crate::concurrency::{ActiveRequests, ConcurrencyLimiter, UpstreamSlots};
+use crate::pii_protection::PiiCache;
+use crate::audit::AuditLog;
+use crate::usage_writer::UsageWriter;
+use crate::jwt::JwtAuth;
+use crate::introspection::Introspection;
+use crate::request_signing::RequestSigning;
+use crate::response_cache::ResponseCache;
+use crate::coalescing::RequestCoalescer;
+use crate::maintenance_mode::MaintenanceMode;
+use crate::disabled_models::DisabledModels;
 
 // Re-exports from internal modules
-use config::{ModelConfig, QuotaPeriod, ServerConf};
-use parsers::{parse, parser_ollama};
-//use quota::{check_quota, extract_usage_keys, get_usage_periods};
-use rate_limit::check_rate_limits;
+use config::ServerConf;
 
 // Constants and lazy statics
+use arc_swap::ArcSwap;
 use std::sync::Arc;
+use std::sync::atomic::AtomicUsize;
+use std::time::Duration;
 
+/// Pingora's grace period when `grace_period_seconds` is not set
+const DEFAULT_GRACE_PERIOD: u64 = 300;
+
+/// Pingora's command line options and the gateway's
+#[derive(Parser)]
+struct Args {
+    #[clap(flatten)]
+    pingora: Opt,
+    /// Validate the configuration file, print the problems found and exit, with status 1 if any
+    #[clap(long)]
+    check_config: bool,
+}
+
+/// Validate the configuration like at startup
+fn check_config(conf_path: &str) -> ! {
+    match ServerConf::load(conf_path) {
+        Ok(_) => {
+            println!("Configuration {} is valid", conf_path);
+            std::process::exit(0);
+        }
+        Err(e) => println!("Configuration {} is invalid: {}", conf_path, e),
+    }
+    std::process::exit(1);
+}
 
 fn main() {
-    #[cfg(debug_assertions)]
-    env_logger::init();
-
-    #[cfg(not(debug_assertions))]
-    let conf = ServerConf::from_file_or_exit(
-        Opt::parse_args().conf.unwrap_or_else(|| {
-            log::error!("Error: No configuration file provided");
-            //std::process::exit(1);
-            env_logger::init();
-        })
-    );
-    
-    #[cfg(not(debug_assertions))]
-    log4rs::init_file(&conf.log_config_file, Default::default()).unwrap();
+    let args = Args::parse();
+    logging::init(args.pingora.conf.as_deref().map(std::path::Path::new));
+
+    let conf_path = args.pingora.conf.clone().unwrap_or_else(|| {
+        log::error!("Error: No configuration file provided");
+        std::process::exit(1);
+    });
+    if args.check_config {
+        check_config(&conf_path);
+    }
+    let conf = ServerConf::from_file_or_exit(&conf_path);
 
+    info!("Configuration loaded with {} models 👌", conf.models.len());
 
-    let db = Arc::new(Database::create("database.redb").expect("Failed to create database"));
+    let mut db = database::open(conf.database_path()).unwrap_or_else(|e| {
+        log::error!("{}", e);
+        std::process::exit(1);
+    });
     // create table if not exists
     let write_txn = db.begin_write().expect("Failed to begin write transaction");
     {
         const TOKENS: TableDefinition<&str, &str> = TableDefinition::new("tokens");
         const GROUPS: TableDefinition<&str, &str> = TableDefinition::new("groups");
         const USAGE: TableDefinition<&str, u64> = TableDefinition::new("usage");
-        write_txn.open_table(TOKENS);
-        write_txn.open_table(GROUPS);
-        write_txn.open_table(USAGE);
+        const CREDENTIALS: TableDefinition<&str, &str> = TableDefinition::new("credentials");
+        write_txn.open_table(TOKENS).expect("Failed to open table");
+        write_txn.open_table(GROUPS).expect("Failed to open table");
+        write_txn.open_table(USAGE).expect("Failed to open table");
+        write_txn.open_table(CREDENTIALS).expect("Failed to open table");
+        write_txn.open_table(cost::COST).expect("Failed to open table");
+        write_txn.open_table(cost::DAILY_COST).expect("Failed to open table");
+        write_txn.open_table(cost::COST_CAPS).expect("Failed to open table");
+        write_txn.open_table(auth::TOKEN_EXPIRY).expect("Failed to open table");
+        write_txn.open_table(request_signing::SIGNING_CLIENTS).expect("Failed to open table");
+        write_txn.open_table(decisions::DECISIONS).expect("Failed to open table");
     }
     write_txn.commit().expect("Failed to commit write transaction");
 
@@ -93,34 +156,146 @@ fn main() {
             table.insert("your_token_here", "alice").expect("Failed to insert token");
             let mut table = write_txn.open_table(GROUPS).expect("Failed to open table");
             table.insert("alice", "admin, it, hr").expect("Failed to insert group");
+            const CREDENTIALS: TableDefinition<&str, &str> = TableDefinition::new("credentials");
+            let mut table = write_txn.open_table(CREDENTIALS).expect("Failed to open table");
+            let hash = auth::hash_password("alice").expect("Failed to hash password");
+            table.insert("alice", hash.as_str()).expect("Failed to insert credentials");
         }
         write_txn.commit().expect("Failed to commit write transaction");
     }
 
-    let conf = ServerConf::from_file_or_exit(
-        Opt::parse_args().conf.unwrap_or_else(|| {
-            log::error!("Error: No configuration file provided");
-            std::process::exit(1);
-        })
-    );
-
-    info!("Configuration loaded with {} models 👌", conf.models.len());
+    // Give back the space of the entries pruned by the previous run, while nothing else uses
+    // the database: compaction needs it exclusively
+    match db.compact() {
+        Ok(true) => info!("Database compacted"),
+        Ok(false) => {}
+        Err(e) => warn!("Database compaction failed: {}", e),
+    }
+    let db = Arc::new(db);
 
     // Services
 
-    let mut bgn_server = Server::new(Some(Opt::parse_args())).unwrap();
+    let mut bgn_server = Server::new(Some(args.pingora)).unwrap();
     bgn_server.bootstrap();
 
     let conf = Arc::new(conf);
+    let live_conf = Arc::new(ArcSwap::new(conf.clone()));
+    let auth_cache = Arc::new(AuthCache::new(Duration::from_secs(conf.auth_cache_ttl)));
+    let audit_log = (!conf.audit_log_path.is_empty()).then(|| {
+        Arc::new(AuditLog::open(&conf.audit_log_path).unwrap_or_else(|e| {
+            log::error!("{:#}", e);
+            std::process::exit(1);
+        }))
+    });
+    let active_requests = ActiveRequests::new(register_int_gauge!(
+        "active_requests",
+        "Client requests being served by the gateway, whatever their location"
+    ).unwrap());
+    let usage_writer = Arc::new(UsageWriter::start(
+        db.clone(),
+        Duration::from_millis(conf.usage_flush_interval_ms),
+        conf.usage_flush_batch_size,
+    ).unwrap_or_else(|e| {
+        log::error!("Unable to start the usage writer: {}", e);
+        std::process::exit(1);
+    }));
 
+    let maintenance_mode = Arc::new(MaintenanceMode::new(conf.maintenance_mode));
+    let disabled_models = Arc::new(DisabledModels::default());
+    let token_labels: &[&str] = if conf.token_metrics_by_user { &["location", "user"] } else { &["location"] };
     let mut bgn_gateway = pingora_proxy::http_proxy_service(
         &bgn_server.configuration,
         BurgonetGateway {
+            maintenance_mode: maintenance_mode.clone(),
+            disabled_models: disabled_models.clone(),
             req_metric: register_int_counter!("req_counter", "Number of requests").unwrap(),
-            conf: conf.clone(),
+            conf: live_conf.clone(),
             db: db.clone(),
-            input_tokens: register_int_counter!("input_tokens", "Number of input tokens").unwrap(),
-            output_tokens: register_int_counter!("output_tokens", "Number of output tokens").unwrap(),
+            auth_cache: auth_cache.clone(),
+            audit_log: audit_log.clone(),
+            jwt: JwtAuth::new(),
+            introspection: Introspection::new(Duration::from_secs(
+                conf.introspection.as_ref().map_or(0, |i| i.cache_ttl),
+            )),
+            request_signing: RequestSigning::new(),
+            pii_cache: PiiCache::new(conf.pii_cache_size, Duration::from_secs(conf.pii_cache_ttl)),
+            input_tokens: register_int_counter_vec!("input_tokens", "Number of input tokens", &token_labels).unwrap(),
+            output_tokens: register_int_counter_vec!("output_tokens", "Number of output tokens", &token_labels).unwrap(),
+            token_metrics_by_user: conf.token_metrics_by_user,
+            upstream_requests: register_int_counter_vec!("upstream_requests", "Number of requests sent to each upstream endpoint", &["endpoint"]).unwrap(),
+            upstream_counter: AtomicUsize::new(0),
+            request_duration: register_histogram_vec!(
+                "request_duration_seconds",
+                "Time from the start of a request to its logging, by model location and status class",
+                &["location", "status"],
+                vec![0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0, 120.0]
+            ).unwrap(),
+            upstream_latency: register_histogram_vec!(
+                "upstream_latency_seconds",
+                "Time from the connection to the upstream to the last byte of its response, by model location",
+                &["location"],
+                vec![0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0, 120.0]
+            ).unwrap(),
+            circuit_breakers: CircuitBreakers::new(register_int_gauge_vec!(
+                "circuit_breaker_state",
+                "Circuit breaker of each model location: 0 closed, 1 open, 2 half-open",
+                &["location"]
+            ).unwrap()),
+            api_keys: ApiKeys::new(
+                register_int_counter_vec!(
+                    "api_key_requests",
+                    "Requests sent with each of the api_keys of a location, by key index",
+                    &["location", "key"]
+                ).unwrap(),
+                register_int_counter_vec!(
+                    "api_key_rate_limited",
+                    "429 responses of the provider to each of the api_keys of a location, by key index",
+                    &["location", "key"]
+                ).unwrap(),
+            ),
+            concurrency: ConcurrencyLimiter::default(),
+            in_flight_requests: register_int_gauge_vec!(
+                "in_flight_requests",
+                "Requests of a location sent upstream and not yet logged, retries included",
+                &["location"]
+            ).unwrap(),
+            upstream_slots: UpstreamSlots::new(
+                register_int_gauge_vec!(
+                    "upstream_connections_in_use",
+                    "Requests sent to the upstreams of a location with upstream_max_connections",
+                    &["location"]
+                ).unwrap(),
+                register_int_gauge_vec!(
+                    "upstream_connections_max",
+                    "upstream_max_connections of a location",
+                    &["location"]
+                ).unwrap(),
+            ),
+            model_fallbacks: register_int_counter_vec!(
+                "model_fallbacks",
+                "Requests moved to the fallback location of a model after a token quota was exceeded",
+                &["location", "fallback"]
+            ).unwrap(),
+            active_requests: active_requests.clone(),
+            usage_writer: usage_writer.clone(),
+            response_cache: ResponseCache::new(conf.response_cache_max_bytes),
+            response_cache_hits: register_int_counter_vec!(
+                "response_cache_hits",
+                "Requests answered from the response cache, without calling the upstream",
+                &["location"]
+            ).unwrap(),
+            response_cache_misses: register_int_counter_vec!(
+                "response_cache_misses",
+                "Deterministic requests of a cached location that were sent upstream",
+                &["location"]
+            ).unwrap(),
+            coalescer: RequestCoalescer::default(),
+            coalesced_requests: register_int_counter_vec!(
+                "coalesced_requests",
+                "Requests answered with the response of an identical request in flight",
+                &["location"]
+            ).unwrap(),
+            response_parse_errors: register_int_counter!("response_parse_errors", "Number of upstream responses without parsable JSON or usage").unwrap(),
         },
     );
     bgn_gateway.add_tcp(&format!("{}:{}", conf.host, conf.port));
@@ -132,21 +307,43 @@ fn main() {
     bgn_server.add_service(prometheus_service_http);
     info!("Prometheus service started on port {}", conf.prometheus_port);
 
-    let mut echo_service_http = service::echo::echo_service_http();
-    echo_service_http.add_tcp(&format!("{}:{}", conf.echo_host, conf.echo_port));
-    bgn_server.add_service(echo_service_http);
-    info!("Echo service started on http://{}:{}", conf.echo_host, conf.echo_port);
+    if conf.echo_enabled {
+        let mut echo_service_http = service::echo::echo_service_http();
+        echo_service_http.add_tcp(&format!("{}:{}", conf.echo_host, conf.echo_port));
+        bgn_server.add_service(echo_service_http);
+        info!("Echo service started on http://{}:{}", conf.echo_host, conf.echo_port);
+    }
 
-    let mut chat_service_http = service::chat::chat_service_http(db.clone());
+    let mut chat_service_http = service::chat::chat_service_http(db.clone(), auth_cache.clone(), live_conf.clone(), maintenance_mode.clone(), disabled_models.clone());
     chat_service_http.add_tcp(&format!("{}:{}", conf.chat_host, conf.chat_port));
     bgn_server.add_service(chat_service_http);
     info!("Chat service started on http://{}:{}", conf.chat_host, conf.chat_port);
 
-    let mut admin_service_http = service::admin::admin_service_http(db);
-    admin_service_http.add_tcp(&format!("{}:{}", conf.admin_host, conf.admin_port));
-    bgn_server.add_service(admin_service_http);
-    info!("Admin service started on http://{}:{}", conf.admin_host, conf.admin_port);
+    let mut health_service_http = service::health::health_service_http(db.clone(), live_conf.clone());
+    health_service_http.add_tcp(&format!("{}:{}", conf.health_host, conf.health_port));
+    bgn_server.add_service(health_service_http);
+    info!("Health service started on http://{}:{}", conf.health_host, conf.health_port);
+
+    bgn_server.add_service(service::maintenance::maintenance_service(db.clone(), live_conf.clone()));
+    bgn_server.add_service(service::keepalive::keepalive_service(live_conf.clone()));
+
+    if conf.admin_enabled {
+        let mut admin_service_http = service::admin::admin_service_http(db, auth_cache, live_conf.clone(), maintenance_mode.clone(), disabled_models.clone());
+        admin_service_http.add_tcp(&format!("{}:{}", conf.admin_host, conf.admin_port));
+        bgn_server.add_service(admin_service_http);
+        info!("Admin service started on http://{}:{}", conf.admin_host, conf.admin_port);
+    }
+
+
+    bgn_server.add_service(service::reload::reload_service(conf_path, live_conf, disabled_models));
+    info!("Configuration reload enabled on SIGHUP");
+
+    bgn_server.add_service(service::maintenance_mode::maintenance_signal_service(maintenance_mode));
+    info!("Maintenance mode switched on SIGUSR1");
 
+    let grace_period = Duration::from_secs(bgn_server.configuration.grace_period_seconds.unwrap_or(DEFAULT_GRACE_PERIOD));
+    bgn_server.add_service(service::shutdown::shutdown_service(active_requests, usage_writer, audit_log, grace_period));
+    info!("Graceful shutdown on SIGTERM, requests in flight get {}s to finish", grace_period.as_secs());
 
     bgn_server.run_forever();
 
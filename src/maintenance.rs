@@ -0,0 +1,37 @@
+// Copyright (c) 2025 Sébastien Campion, FOSS4. All rights reserved.
+//
+// This software is provided under the Commons Clause License Condition v1.0.
+// See the LICENSE file for full license details.
+
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Datelike, NaiveDate, Utc};
+use redb::{Database, TableDefinition, Value};
+
+/// Keys deleted per write transaction, requests waiting for the write lock are only held back
+/// for one batch
+const DELETE_BATCH: usize = 1000;
+
+/// First day of the oldest month kept, `retention_months` before the month of `now`
+pub fn oldest_kept_month(now: DateTime<Utc>, retention_months: u32) -> Result<NaiveDate> {
+    let months = now.year() * 12 + now.month0() as i32 - retention_months as i32;
+    NaiveDate::from_ymd_opt(months.div_euclid(12), months.rem_euclid(12) as u32 + 1, 1)
+        .ok_or_else(|| anyhow!("usage_retention_months out of range"))
+}
+
+/// Delete the keys of a table in batches, returns the number deleted
+pub fn delete_in_batches<V: Value + 'static>(db: &Database, definition: TableDefinition<&str, V>, keys: &[String]) -> Result<u64> {
+    let mut deleted = 0;
+    for batch in keys.chunks(DELETE_BATCH) {
+        let write_txn = db.begin_write()?;
+        {
+            let mut table = write_txn.open_table(definition)?;
+            for key in batch {
+                if table.remove(key.as_str())?.is_some() {
+                    deleted += 1;
+                }
+            }
+        }
+        write_txn.commit()?;
+    }
+    Ok(deleted)
+}
@@ -0,0 +1,48 @@
+// Copyright (c) 2025 Sébastien Campion, FOSS4. All rights reserved.
+//
+// This software is provided under the Commons Clause License Condition v1.0.
+// See the LICENSE file for full license details.
+
+use log::warn;
+use once_cell::sync::Lazy;
+use prometheus::{register_int_gauge, IntGauge};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+static MAINTENANCE_MODE: Lazy<IntGauge> = Lazy::new(|| {
+    register_int_gauge!("maintenance_mode", "1 while the model requests are rejected for maintenance").unwrap()
+});
+
+/// Set during maintenance windows, the gateway then answers the model requests with a 503.
+/// Shared by the gateway, the admin API and the SIGUSR1 handler.
+pub struct MaintenanceMode {
+    enabled: AtomicBool,
+}
+
+impl MaintenanceMode {
+    pub fn new(enabled: bool) -> Self {
+        MAINTENANCE_MODE.set(enabled as i64);
+        Self { enabled: AtomicBool::new(enabled) }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled.load(Ordering::Relaxed)
+    }
+
+    pub fn set(&self, enabled: bool) {
+        if self.enabled.swap(enabled, Ordering::Relaxed) != enabled {
+            Self::report(enabled);
+        }
+    }
+
+    /// Switch the mode and return the new one
+    pub fn toggle(&self) -> bool {
+        let enabled = !self.enabled.fetch_xor(true, Ordering::Relaxed);
+        Self::report(enabled);
+        enabled
+    }
+
+    fn report(enabled: bool) {
+        MAINTENANCE_MODE.set(enabled as i64);
+        warn!("Maintenance mode {}", if enabled { "enabled, rejecting model requests" } else { "disabled" });
+    }
+}
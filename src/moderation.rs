@@ -0,0 +1,100 @@
+// Copyright (c) 2025 Sébastien Campion, FOSS4. All rights reserved.
+//
+// This software is provided under the Commons Clause License Condition v1.0.
+// See the LICENSE file for full license details.
+
+use base64::engine::general_purpose;
+use base64::Engine;
+use bytes::Bytes;
+use once_cell::sync::Lazy;
+use prometheus::{register_int_counter, register_int_counter_vec, IntCounter, IntCounterVec};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::time::Duration;
+use url::Url;
+
+static CLIENT: Lazy<reqwest::Client> = Lazy::new(reqwest::Client::new);
+
+pub static MODERATION_FLAGS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!("moderation_flags", "Requests rejected because a moderation category was over its threshold", &["category"]).unwrap()
+});
+
+pub static MODERATION_SERVICE_ERRORS: Lazy<IntCounter> = Lazy::new(|| {
+    register_int_counter!("moderation_service_errors", "Failed calls to the moderation service").unwrap()
+});
+
+/// Answer of the moderation service: a score by category, e.g. `{"categories": {"hate": 0.02}}`
+#[derive(Debug, Deserialize)]
+pub struct ModerationScores {
+    pub categories: BTreeMap<String, f64>,
+}
+
+#[derive(Debug)]
+pub enum ModerationError {
+    /// The score of a category is over its threshold, the highest one when several are
+    Flagged { category: String, score: f64 },
+    /// The service could not give an answer
+    Service(String),
+}
+
+impl ModerationScores {
+    /// Category whose score is over its threshold by the most, categories without a threshold
+    /// are ignored
+    pub fn flagged(&self, thresholds: &BTreeMap<String, f64>) -> Option<(&str, f64)> {
+        self.categories.iter()
+            .filter_map(|(category, &score)| {
+                let threshold = *thresholds.get(category)?;
+                (score > threshold).then_some((category.as_str(), score, score - threshold))
+            })
+            .max_by(|a, b| a.2.total_cmp(&b.2))
+            .map(|(category, score, _)| (category, score))
+    }
+}
+
+pub async fn check_moderation(
+    url: &str,
+    thresholds: &BTreeMap<String, f64>,
+    request_body: &Bytes,
+    timeout: Duration,
+) -> Result<(), ModerationError> {
+    let result = match call_moderation_service(url, request_body, timeout).await {
+        Ok(scores) => match scores.flagged(thresholds) {
+            Some((category, score)) => Err(ModerationError::Flagged { category: category.to_string(), score }),
+            None => Ok(()),
+        },
+        Err(reason) => Err(ModerationError::Service(reason)),
+    };
+    match &result {
+        Ok(()) => {}
+        Err(ModerationError::Flagged { category, .. }) => MODERATION_FLAGS.with_label_values(&[category]).inc(),
+        Err(ModerationError::Service(_)) => MODERATION_SERVICE_ERRORS.inc(),
+    }
+    result
+}
+
+/// The body is sent in base64 as `text`, like to the PII protection service, and scored in a
+/// 200 answer
+async fn call_moderation_service(
+    url: &str,
+    request_body: &Bytes,
+    timeout: Duration,
+) -> Result<ModerationScores, String> {
+    let url = Url::parse(url).map_err(|e| format!("invalid moderation URL {}: {}", url, e))?;
+    let body_base64 = general_purpose::STANDARD.encode(request_body);
+    let json_payload = format!(r#"{{"text": "{}"}}"#, body_base64);
+
+    let response = CLIENT
+        .post(url)
+        .header("Content-Type", "application/json")
+        .timeout(timeout)
+        .body(json_payload)
+        .send()
+        .await
+        .map_err(|e| format!("failed to contact moderation service: {}", e))?;
+
+    match response.status().as_u16() {
+        200 => response.json::<ModerationScores>().await
+            .map_err(|e| format!("invalid moderation service answer: {}", e)),
+        status => Err(format!("moderation service returned {}", status)),
+    }
+}
@@ -0,0 +1,111 @@
+// Copyright (c) 2025 Sébastien Campion, FOSS4. All rights reserved.
+//
+// This software is provided under the Commons Clause License Condition v1.0.
+// See the LICENSE file for full license details.
+
+use bytes::Bytes;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::BTreeMap;
+
+/// Allowed values of a numeric top-level field of JSON request bodies, e.g. `temperature`
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+pub struct ParameterRange {
+    #[serde(default)]
+    pub min: Option<f64>,
+    #[serde(default)]
+    pub max: Option<f64>,
+    /// Value sent when the client did not set the parameter
+    #[serde(default)]
+    pub default: Option<f64>,
+}
+
+/// What to do with a parameter outside its range
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ParameterLimitMode {
+    /// Send the nearest bound instead
+    #[default]
+    Clamp,
+    /// Answer 400 naming the parameter
+    Reject,
+}
+
+/// Parameter value changed or added by `enforce`
+pub struct Adjustment {
+    pub name: String,
+    /// Value sent by the client, None when the default was added
+    pub from: Option<Value>,
+    pub to: f64,
+}
+
+impl ParameterRange {
+    pub fn validate(&self) -> Result<(), String> {
+        if let (Some(min), Some(max)) = (self.min, self.max) {
+            if min > max {
+                return Err(format!("min {} is above max {}", min, max));
+            }
+        }
+        if let Some(default) = self.default {
+            if self.clamp(default) != default {
+                return Err(format!("default {} is out of range", default));
+            }
+        }
+        Ok(())
+    }
+
+    fn clamp(&self, value: f64) -> f64 {
+        let value = self.min.map_or(value, |min| value.max(min));
+        self.max.map_or(value, |max| value.min(max))
+    }
+
+    /// Why the value is refused, None when it is in range
+    fn violation(&self, name: &str, value: f64) -> Option<String> {
+        match (self.min, self.max) {
+            (Some(min), Some(max)) if value < min || value > max => Some(format!("{} must be between {} and {}", name, min, max)),
+            (Some(min), None) if value < min => Some(format!("{} must be at least {}", name, min)),
+            (None, Some(max)) if value > max => Some(format!("{} must be at most {}", name, max)),
+            _ => None,
+        }
+    }
+}
+
+/// Body with its limited parameters in range and their defaults added, with the changes made.
+/// Fails with the reason when a parameter is not a number, or out of range in `reject` mode.
+/// Bodies that are not JSON objects are left to the upstream to refuse.
+pub fn enforce(ranges: &BTreeMap<String, ParameterRange>, mode: ParameterLimitMode, body: &Bytes) -> Result<(Bytes, Vec<Adjustment>), String> {
+    let Ok(mut json) = serde_json::from_slice::<Value>(body) else {
+        return Ok((body.clone(), Vec::new()));
+    };
+    let Some(object) = json.as_object_mut() else {
+        return Ok((body.clone(), Vec::new()));
+    };
+
+    let mut adjustments = Vec::new();
+    for (name, range) in ranges {
+        match object.get(name).filter(|value| !value.is_null()) {
+            None => {
+                if let Some(default) = range.default {
+                    object.insert(name.clone(), Value::from(default));
+                    adjustments.push(Adjustment { name: name.clone(), from: None, to: default });
+                }
+            }
+            Some(value) => {
+                let requested = value.as_f64().ok_or_else(|| format!("{} must be a number", name))?;
+                if let Some(reason) = range.violation(name, requested) {
+                    if mode == ParameterLimitMode::Reject {
+                        return Err(reason);
+                    }
+                    let clamped = range.clamp(requested);
+                    adjustments.push(Adjustment { name: name.clone(), from: Some(value.clone()), to: clamped });
+                    object.insert(name.clone(), Value::from(clamped));
+                }
+            }
+        }
+    }
+    if adjustments.is_empty() {
+        return Ok((body.clone(), adjustments));
+    }
+    let body = serde_json::to_vec(&json).map(Bytes::from).map_err(|e| e.to_string())?;
+    Ok((body, adjustments))
+}
@@ -6,15 +6,25 @@
 use serde_json::Value;
 use anyhow::{Result, anyhow};
 
+use crate::token_estimate;
+
 pub fn parser_ollama(response: &Value) -> Result<(u64, u64)> {
-    let tokens_input = response["prompt_eval_count"]
-        .as_u64()
-        .ok_or_else(|| anyhow!("Missing or invalid prompt_eval_count"))?;
-        
+    // /api/chat and /api/generate both count tokens in their final message, the only one of a
+    // stream with "done": true
+    if response["done"] == false {
+        return Ok((0, 0));
+    }
+
     let tokens_output = response["eval_count"]
         .as_u64()
         .ok_or_else(|| anyhow!("Missing or invalid eval_count"))?;
-        
+
+    // left out when the whole prompt was in Ollama's cache
+    let tokens_input = match &response["prompt_eval_count"] {
+        Value::Null => 0,
+        count => count.as_u64().ok_or_else(|| anyhow!("Invalid prompt_eval_count"))?,
+    };
+
     Ok((tokens_input, tokens_output))
 }
 
@@ -43,6 +53,11 @@ pub fn parser_llamacpp(response: &Value) -> Result<(u64, u64)> {
 }
 
 pub fn parser_openai(response: &Value) -> Result<(u64, u64)> {
+    // usage is null on streamed chunks unless stream_options.include_usage is set
+    if response["usage"].is_null() {
+        return Ok((0, 0));
+    }
+
     //   "usage": {
     //     "prompt_tokens": 28,
     let tokens_input = response["usage"]["prompt_tokens"]
@@ -57,42 +72,159 @@ pub fn parser_openai(response: &Value) -> Result<(u64, u64)> {
     Ok((tokens_input, tokens_output))
 }
 
+pub fn parser_anthropic(response: &Value) -> Result<(u64, u64)> {
+    // Streamed responses report input tokens in message_start and the final
+    // output tokens in message_delta
+    let event_type = response["type"].as_str();
+    let usage = match event_type {
+        Some("message_start") => &response["message"]["usage"],
+        _ => &response["usage"],
+    };
+
+    //   "usage": {
+    //     "input_tokens": 25,
+    let tokens_input = match usage["input_tokens"].as_u64() {
+        Some(tokens) => tokens,
+        None if event_type == Some("message_delta") => 0,
+        None => return Err(anyhow!("Missing or invalid input_tokens")),
+    };
+
+    //     "output_tokens": 15
+    let tokens_output = usage["output_tokens"]
+        .as_u64()
+        .ok_or_else(|| anyhow!("Missing or invalid output_tokens"))?;
+
+    Ok((tokens_input, tokens_output))
+}
+
+pub fn parser_embeddings(response: &Value) -> Result<(u64, u64)> {
+    // embeddings have no completion, some providers only report total_tokens
+    //   "usage": {
+    //     "prompt_tokens": 8,
+    //     "total_tokens": 8
+    let usage = &response["usage"];
+    let tokens_input = usage["prompt_tokens"]
+        .as_u64()
+        .or_else(|| usage["total_tokens"].as_u64())
+        .ok_or_else(|| anyhow!("Missing or invalid prompt_tokens"))?;
+
+    Ok((tokens_input, 0))
+}
+
 pub fn parser_echo(_response: &Value) -> Result<(u64, u64)> {
     Ok((0, 0))
 }
 
+/// Names of the token parsers a model may use in `parser`
+pub const PARSERS: &[&str] = &["echo", "ollama", "deepseek", "llamacpp", "openai", "anthropic", "embeddings"];
+
 pub fn parse(
     json_body: &Value,
     parser: &str,
 ) -> Result<(u64, u64)> {
+    let (input_tokens, output_tokens) = parse_tokens(json_body, parser)?;
+    log_tokens(parser, input_tokens, output_tokens);
+    Ok((input_tokens, output_tokens))
+}
+
+/// Tokens counted by the parser, without logging them, for the events of a stream
+fn parse_tokens(json_body: &Value, parser: &str) -> Result<(u64, u64)> {
     match parser {
-        "echo" => {
-            let (input_tokens, output_tokens) = parser_echo(&json_body)?;
-            log::info!("Echo tokens - input: {}, output: {}", input_tokens, output_tokens);
-            Ok((input_tokens, output_tokens))
+        "echo" => parser_echo(json_body),
+        "ollama" => parser_ollama(json_body),
+        "deepseek" => parser_deepseek(json_body),
+        "llamacpp" => parser_llamacpp(json_body),
+        "openai" => parser_openai(json_body),
+        "anthropic" => parser_anthropic(json_body),
+        "embeddings" => parser_embeddings(json_body),
+        _ => {
+            Err(anyhow!("Parser not set for model"))
+        }
+    }
+}
+
+fn log_tokens(parser: &str, input_tokens: u64, output_tokens: u64) {
+    let name = match parser {
+        "echo" => "Echo",
+        "ollama" => "OLLaMA",
+        "deepseek" => "Deepseek",
+        "llamacpp" => "LLamaCPP",
+        "openai" => "OpenAI",
+        "anthropic" => "Anthropic",
+        "embeddings" => "Embeddings",
+        _ => parser,
+    };
+    log::info!("{} tokens - input: {}, output: {}", name, input_tokens, output_tokens);
+}
+
+/// Incremental token counter for `text/event-stream` and `application/x-ndjson` responses.
+///
+/// Chunks are fed as they arrive; complete `data:` lines, or every line of NDJSON
+/// streams, are decoded as JSON and handed to the model parser. Events without
+/// usage are ignored, and the highest counts seen are kept since providers report
+/// usage in the final event(s). A line split across chunks is held until its
+/// newline arrives.
+#[derive(Debug, Default)]
+pub struct SseUsageParser {
+    pending: Vec<u8>,
+    pub input_tokens: u64,
+    pub output_tokens: u64,
+    /// Whether an event carried an OpenAI `usage` block
+    pub usage_reported: bool,
+    /// Generated text of the events, collected when set to estimate the output tokens
+    pub text: Option<String>,
+    /// Whether each line is a JSON event, as sent by Ollama, rather than `data:` lines
+    pub ndjson: bool,
+    /// Whether the stream ended with `[DONE]` and its tokens were logged
+    done: bool,
+}
+
+impl SseUsageParser {
+    pub fn feed(&mut self, chunk: &[u8], parser: &str) {
+        self.pending.extend_from_slice(chunk);
+        while let Some(pos) = self.pending.iter().position(|b| *b == b'\n') {
+            let line: Vec<u8> = self.pending.drain(..=pos).collect();
+            self.handle_line(&line, parser);
         }
-        "ollama" => {
-            let (input_tokens, output_tokens) = parser_ollama(&json_body)?;
-            log::info!("OLLaMA tokens - input: {}, output: {}", input_tokens, output_tokens);
-            Ok((input_tokens, output_tokens))
+    }
+
+    /// Handle a trailing event that was not terminated by a newline, and log the tokens of
+    /// streams without `[DONE]`
+    pub fn finish(&mut self, parser: &str) {
+        let line = std::mem::take(&mut self.pending);
+        self.handle_line(&line, parser);
+        if !self.done {
+            self.done = true;
+            log_tokens(parser, self.input_tokens, self.output_tokens);
         }
-        "deepseek" => {
-            let (input_tokens, output_tokens) = parser_deepseek(&json_body)?;
-            log::info!("Deepseek tokens - input: {}, output: {}", input_tokens, output_tokens);
-            Ok((input_tokens, output_tokens))
+    }
+
+    fn handle_line(&mut self, line: &[u8], parser: &str) {
+        let line = line.trim_ascii();
+        let Some(data) = line.strip_prefix(b"data:").or(Some(line).filter(|_| self.ndjson)) else {
+            return;
+        };
+        let data = data.trim_ascii();
+        if data == b"[DONE]" && !self.done {
+            self.done = true;
+            log_tokens(parser, self.input_tokens, self.output_tokens);
         }
-        "llamacpp" => {
-            let (input_tokens, output_tokens) = parser_llamacpp(&json_body)?;
-            log::info!("LLamaCPP tokens - input: {}, output: {}", input_tokens, output_tokens);
-            Ok((input_tokens, output_tokens))
+        if data.is_empty() || data == b"[DONE]" {
+            return;
         }
-        "openai" => {
-            let (input_tokens, output_tokens) = parser_openai(&json_body)?;
-            log::info!("OpenAI tokens - input: {}, output: {}", input_tokens, output_tokens);
-            Ok((input_tokens, output_tokens))
+        let Ok(event) = serde_json::from_slice::<Value>(data) else {
+            log::debug!("Ignoring non-JSON event: {}", String::from_utf8_lossy(data));
+            return;
+        };
+        if let Some(text) = self.text.as_mut() {
+            token_estimate::collect_response_text(&event, text);
         }
-        _ => {
-            Err(anyhow!("Parser not set for model"))
+        if event["usage"]["prompt_tokens"].is_u64() {
+            self.usage_reported = true;
+        }
+        if let Ok((input_tokens, output_tokens)) = parse_tokens(&event, parser) {
+            self.input_tokens = self.input_tokens.max(input_tokens);
+            self.output_tokens = self.output_tokens.max(output_tokens);
         }
     }
 }
@@ -3,52 +3,111 @@
 // This software is provided under the Commons Clause License Condition v1.0.
 // See the LICENSE file for full license details.
 
-use anyhow::Result;
 use base64::engine::general_purpose;
 use base64::Engine;
 use bytes::Bytes;
-use log::{info, warn, error};
-use pingora::prelude::*;
-use reqwest::Client;
+use moka::sync::Cache;
+use once_cell::sync::Lazy;
+use prometheus::{register_int_counter, IntCounter};
+use regex::bytes::Regex;
+use std::borrow::Cow;
+use std::time::Duration;
 use url::Url;
+use xxhash_rust::xxh3::xxh3_64;
 
-pub async fn check_pii_protection(
-    pii_url: &str,
-    request_body: &Bytes
-) -> pingora::Result<()> {
+static CLIENT: Lazy<reqwest::Client> = Lazy::new(reqwest::Client::new);
+
+pub static PII_DETECTIONS: Lazy<IntCounter> = Lazy::new(|| {
+    register_int_counter!("pii_detections", "Requests rejected because PII was found").unwrap()
+});
+
+pub static PII_SERVICE_ERRORS: Lazy<IntCounter> = Lazy::new(|| {
+    register_int_counter!("pii_service_errors", "Failed calls to the PII protection service").unwrap()
+});
+
+/// Recent answers of the PII services, keyed by service URL and body hash.
+/// Only clean and PII-found answers are kept, service errors are retried.
+pub struct PiiCache {
+    results: Cache<(String, u64), bool>,
+}
+
+impl PiiCache {
+    pub fn new(max_capacity: u64, ttl: Duration) -> Self {
+        Self {
+            results: Cache::builder().max_capacity(max_capacity).time_to_live(ttl).build(),
+        }
+    }
+}
 
+const REDACTED: &[u8] = b"[REDACTED]";
 
-    let url = match Url::parse(pii_url) {
-        Ok(url) => url,
-        Err(e) => {
-            return Err(Error::explain(HTTPStatus(403), "Invalid PII protection URL"));
+/// Mask the spans of the body matching any of the patterns, for logging
+pub fn redact(body: &[u8], patterns: &[Regex]) -> String {
+    let mut redacted = Cow::Borrowed(body);
+    for pattern in patterns {
+        if let Cow::Owned(replaced) = pattern.replace_all(&redacted, REDACTED) {
+            redacted = Cow::Owned(replaced);
+        }
+    }
+    String::from_utf8_lossy(&redacted).into_owned()
+}
+
+#[derive(Debug)]
+pub enum PiiError {
+    /// The service found PII in the request body
+    Detected,
+    /// The service could not give an answer
+    Service(String),
+}
+
+pub async fn check_pii_protection(
+    cache: &PiiCache,
+    pii_url: &str,
+    request_body: &Bytes,
+    timeout: Duration,
+) -> Result<(), PiiError> {
+    let key = (pii_url.to_string(), xxh3_64(request_body));
+    let result = match cache.results.get(&key) {
+        Some(true) => Err(PiiError::Detected),
+        Some(false) => Ok(()),
+        None => {
+            let result = call_pii_service(pii_url, request_body, timeout).await;
+            match &result {
+                Ok(()) => cache.results.insert(key, false),
+                Err(PiiError::Detected) => cache.results.insert(key, true),
+                Err(PiiError::Service(_)) => PII_SERVICE_ERRORS.inc(),
+            }
+            result
         }
     };
+    if let Err(PiiError::Detected) = result {
+        PII_DETECTIONS.inc();
+    }
+    result
+}
+
+async fn call_pii_service(
+    pii_url: &str,
+    request_body: &Bytes,
+    timeout: Duration,
+) -> Result<(), PiiError> {
+    let url = Url::parse(pii_url)
+        .map_err(|e| PiiError::Service(format!("invalid PII protection URL {}: {}", pii_url, e)))?;
     let body_base64 = general_purpose::STANDARD.encode(request_body);
     let json_payload = format!(r#"{{"text": "{}"}}"#, body_base64);
 
-    let client = reqwest::Client::new();
-    let response = match client
+    let response = CLIENT
         .post(url)
         .header("Content-Type", "application/json")
+        .timeout(timeout)
         .body(json_payload)
         .send()
         .await
-    {
-        Ok(resp) => resp,
-        Err(_) => {
-            return Err(Error::explain(HTTPStatus(500), "Failed to contact PII protection service"));
-        }
-    };
+        .map_err(|e| PiiError::Service(format!("failed to contact PII protection service: {}", e)))?;
 
     match response.status().as_u16() {
         200 => Ok(()),
-        400 => {
-            return Err(Error::explain(HTTPStatus(403), "PII found in request body"))
-
-        }
-        _ => {
-            return Err(Error::explain(HTTPStatus(403), "PII protection service error"))
-        }
+        400 => Err(PiiError::Detected),
+        status => Err(PiiError::Service(format!("PII protection service returned {}", status))),
     }
 }
@@ -0,0 +1,72 @@
+// Copyright (c) 2025 Sébastien Campion, FOSS4. All rights reserved.
+//
+// This software is provided under the Commons Clause License Condition v1.0.
+// See the LICENSE file for full license details.
+
+use base64::engine::general_purpose;
+use base64::Engine;
+use bytes::Bytes;
+use once_cell::sync::Lazy;
+use prometheus::{register_int_counter, IntCounter};
+use std::time::Duration;
+use url::Url;
+
+static CLIENT: Lazy<reqwest::Client> = Lazy::new(reqwest::Client::new);
+
+pub static PROMPT_INJECTION_DETECTIONS: Lazy<IntCounter> = Lazy::new(|| {
+    register_int_counter!("prompt_injection_detections", "Requests rejected because a prompt injection was found").unwrap()
+});
+
+pub static PROMPT_INJECTION_SERVICE_ERRORS: Lazy<IntCounter> = Lazy::new(|| {
+    register_int_counter!("prompt_injection_service_errors", "Failed calls to the prompt injection detection service").unwrap()
+});
+
+#[derive(Debug)]
+pub enum PromptInjectionError {
+    /// The service flagged the request body as a prompt injection
+    Detected,
+    /// The service could not give an answer
+    Service(String),
+}
+
+pub async fn check_prompt_injection(
+    url: &str,
+    request_body: &Bytes,
+    timeout: Duration,
+) -> Result<(), PromptInjectionError> {
+    let result = call_prompt_injection_service(url, request_body, timeout).await;
+    match &result {
+        Ok(()) => {}
+        Err(PromptInjectionError::Detected) => PROMPT_INJECTION_DETECTIONS.inc(),
+        Err(PromptInjectionError::Service(_)) => PROMPT_INJECTION_SERVICE_ERRORS.inc(),
+    }
+    result
+}
+
+/// Same protocol as the PII protection service: the body in base64 as `text`, 200 when it is
+/// clean and 400 when it is flagged
+async fn call_prompt_injection_service(
+    url: &str,
+    request_body: &Bytes,
+    timeout: Duration,
+) -> Result<(), PromptInjectionError> {
+    let url = Url::parse(url)
+        .map_err(|e| PromptInjectionError::Service(format!("invalid prompt injection URL {}: {}", url, e)))?;
+    let body_base64 = general_purpose::STANDARD.encode(request_body);
+    let json_payload = format!(r#"{{"text": "{}"}}"#, body_base64);
+
+    let response = CLIENT
+        .post(url)
+        .header("Content-Type", "application/json")
+        .timeout(timeout)
+        .body(json_payload)
+        .send()
+        .await
+        .map_err(|e| PromptInjectionError::Service(format!("failed to contact prompt injection service: {}", e)))?;
+
+    match response.status().as_u16() {
+        200 => Ok(()),
+        400 => Err(PromptInjectionError::Detected),
+        status => Err(PromptInjectionError::Service(format!("prompt injection service returned {}", status))),
+    }
+}
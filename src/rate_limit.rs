@@ -5,61 +5,177 @@
 
 use pingora::prelude::*;
 use pingora_proxy::Session;
-use std::time::Duration;
-use crate::config::QuotaPeriod;
+use prometheus::{register_int_gauge_vec, IntGaugeVec};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
 use pingora_limits::rate::Rate;
 use once_cell::sync::Lazy;
-use pingora::prelude::*;
 use pingora::http::ResponseHeader;
 use crate::app::gateway::GatewayContext;
+use crate::config::{QuotaPeriod, RateLimitAlgorithm};
+use crate::errors::insert_request_id;
 
 static RATE_LIMITER_PER_SECOND: Lazy<Rate> = Lazy::new(|| Rate::new(Duration::from_secs(1)));
 static RATE_LIMITER_PER_MINUTE: Lazy<Rate> = Lazy::new(|| Rate::new(Duration::from_secs(60)));
+static GROUP_RATE_LIMITER_PER_SECOND: Lazy<Rate> = Lazy::new(|| Rate::new(Duration::from_secs(1)));
+static GROUP_RATE_LIMITER_PER_MINUTE: Lazy<Rate> = Lazy::new(|| Rate::new(Duration::from_secs(60)));
+
+static RATE_LIMIT_QUEUE_DEPTH: Lazy<IntGaugeVec> = Lazy::new(|| {
+    register_int_gauge_vec!("rate_limit_queue_depth", "Requests of a location held until a rate limit frees up", &["location"]).unwrap()
+});
+/// Requests held by location, bounded by `rate_limit_queue_size`
+static QUEUED: Lazy<Mutex<HashMap<String, usize>>> = Lazy::new(Mutex::default);
+
+/// Place of a request in the rate limit queue of its location, freed when dropped
+struct QueueSlot {
+    location: String,
+}
+
+impl QueueSlot {
+    /// None when the queue is full
+    fn join(location: &str, size: usize) -> Option<Self> {
+        let mut queued = QUEUED.lock().unwrap();
+        let depth = queued.entry(location.to_string()).or_default();
+        if *depth >= size {
+            return None;
+        }
+        *depth += 1;
+        RATE_LIMIT_QUEUE_DEPTH.with_label_values(&[location]).set(*depth as i64);
+        Some(Self { location: location.to_string() })
+    }
+}
+
+impl Drop for QueueSlot {
+    fn drop(&mut self) {
+        let mut queued = QUEUED.lock().unwrap();
+        if let Some(depth) = queued.get_mut(&self.location) {
+            *depth -= 1;
+            RATE_LIMIT_QUEUE_DEPTH.with_label_values(&[&self.location]).set(*depth as i64);
+        }
+    }
+}
+
+/// Sliding window estimate from the two fixed window buckets of `Rate`: the previous
+/// window count weighted by the share of it still inside the interval, plus the current count
+fn sliding_count(rate: &Rate, key: &String) -> isize {
+    rate.rate_with(key, |c| {
+        (c.prev_samples as f64 * (1.0 - c.current_interval_fraction) + c.curr_samples as f64).ceil() as isize
+    })
+}
 
+/// Time until the current window of the key resets
+fn until_reset(rate: &Rate, key: &String) -> Duration {
+    rate.rate_with(key, |c| c.interval.mul_f64(1.0 - c.current_interval_fraction))
+}
+
+/// Record the request for the key and return the (per second, per minute) counts
+fn observe(per_second: &Rate, per_minute: &Rate, key: &String, algorithm: RateLimitAlgorithm) -> (isize, isize) {
+    let fixed_second = per_second.observe(key, 1);
+    let fixed_minute = per_minute.observe(key, 1);
+    match algorithm {
+        RateLimitAlgorithm::Fixed => (fixed_second, fixed_minute),
+        RateLimitAlgorithm::Sliding => (sliding_count(per_second, key), sliding_count(per_minute, key)),
+    }
+}
 
 /// Represents rate limit configuration
 struct RateLimitConfig {
     limit: isize,
     remaining: isize,
-    reset_seconds: u64,
+    /// Time until the window of the limit resets
+    reset: Duration,
+    /// `user` or `group:<name>`
+    scope: String,
 }
 
-/// Checks all rate limits for the request
+/// Checks all rate limits for the request. With a `rate_limit_queue_size`, a request over a
+/// limit waits for its window to reset and is checked again, as long as the queue of the
+/// location has room and the reset comes before `rate_limit_queue_timeout_ms`.
 pub async fn check_rate_limits(
     ctx: &GatewayContext,
     session: &mut Session
 ) -> pingora::Result<()> {
-    let curr_second = RATE_LIMITER_PER_SECOND.observe(&ctx.user.as_ref().unwrap(), 1);
-    let curr_minute = RATE_LIMITER_PER_MINUTE.observe(&ctx.user.as_ref().unwrap(), 1);
+    let model = ctx.model.as_ref().unwrap();
+    let deadline = Instant::now() + Duration::from_millis(model.rate_limit_queue_timeout_ms);
+    let mut slot = None;
+    loop {
+        let mut counted = Vec::new();
+        let Some((config, message)) = first_exceeded(ctx, &mut counted) else {
+            return Ok(());
+        };
+        let in_time = Instant::now() + config.reset <= deadline;
+        if model.rate_limit_queue_size > 0 && in_time && slot.is_none() {
+            slot = QueueSlot::join(&model.location, model.rate_limit_queue_size);
+        }
+        if slot.is_none() || !in_time {
+            handle_rate_limit_exceeded(session, config).await?;
+            return Err(Error::explain(HTTPStatus(429), message));
+        }
+        // a held request counts once, when it is checked again
+        for (per_second, per_minute, key) in counted {
+            per_second.observe(&key, -1);
+            per_minute.observe(&key, -1);
+        }
+        // the windows of Rate are counted in whole milliseconds
+        tokio::time::sleep(config.reset + Duration::from_millis(1)).await;
+    }
+}
 
-    if let Some(quotas) = &ctx.model.as_ref().unwrap().quotas {
+/// Count the request and return the first rate limit it exceeds, with the error message.
+/// The rates and keys the request was counted in are added to `counted`.
+fn first_exceeded(ctx: &GatewayContext, counted: &mut Vec<(&'static Rate, &'static Rate, String)>) -> Option<(RateLimitConfig, String)> {
+    let user = ctx.user.as_ref().unwrap();
+    let model = ctx.model.as_ref().unwrap();
+    let counts = observe(&RATE_LIMITER_PER_SECOND, &RATE_LIMITER_PER_MINUTE, user, model.rate_limit_algorithm);
+    counted.push((&RATE_LIMITER_PER_SECOND, &RATE_LIMITER_PER_MINUTE, user.clone()));
+
+    if let Some(quotas) = &model.quotas {
         for quota in quotas {
             if let Some(max_requests) = &quota.max_requests {
-                // Check per-second rate limit
-                if let Some(config) = get_rate_limit_config(max_requests.second, curr_second, 1) {
-                    handle_rate_limit_exceeded(session, config).await?;
-                    return Err(Error::explain(HTTPStatus(429), "Rate limit exceeded"));
+                if let Some(config) = exceeded(max_requests, (&RATE_LIMITER_PER_SECOND, &RATE_LIMITER_PER_MINUTE), user, counts, "user") {
+                    return Some((config, "User rate limit exceeded".to_string()));
                 }
+            }
+        }
+    }
 
-                // Check per-minute rate limit
-                if let Some(config) = get_rate_limit_config(max_requests.minute, curr_minute, 60) {
-                    handle_rate_limit_exceeded(session, config).await?;
-                    return Err(Error::explain(HTTPStatus(429), "Rate limit exceeded"));
-                }
+    // Every group of the user shares one budget across its members, separate at each location
+    if let Some(group_limit) = &model.group_rate_limit {
+        for group in &ctx.groups {
+            let rates = (&*GROUP_RATE_LIMITER_PER_SECOND, &*GROUP_RATE_LIMITER_PER_MINUTE);
+            let key = format!("{}:{}", model.location, group);
+            let counts = observe(rates.0, rates.1, &key, model.rate_limit_algorithm);
+            counted.push((rates.0, rates.1, key.clone()));
+            if let Some(config) = exceeded(group_limit, rates, &key, counts, &format!("group:{}", group)) {
+                return Some((config, format!("Group {} rate limit exceeded", group)));
             }
         }
     }
-    Ok(())
+    None
+}
+
+/// First of the per second and per minute limits exceeded by the counts
+fn exceeded(
+    max_requests: &QuotaPeriod,
+    (per_second, per_minute): (&Rate, &Rate),
+    key: &String,
+    (curr_second, curr_minute): (isize, isize),
+    scope: &str,
+) -> Option<RateLimitConfig> {
+    get_rate_limit_config(max_requests.second, curr_second, scope, || until_reset(per_second, key))
+        .or_else(|| get_rate_limit_config(max_requests.minute, curr_minute, scope, || until_reset(per_minute, key)))
 }
 
 /// Creates rate limit configuration if limit is exceeded
-fn get_rate_limit_config(limit: u64, current: isize, reset_seconds: u64) -> Option<RateLimitConfig> {
+fn get_rate_limit_config(limit: u64, current: isize, scope: &str, reset: impl FnOnce() -> Duration) -> Option<RateLimitConfig> {
     let limit = limit as isize;
     if limit > 0 && current > limit {
         Some(RateLimitConfig {
             limit,
             remaining: 0,
-            reset_seconds,
+            reset: reset(),
+            scope: scope.to_string(),
         })
     } else {
         None
@@ -68,6 +184,8 @@ fn get_rate_limit_config(limit: u64, current: isize, reset_seconds: u64) -> Opti
 
 /// Handles rate limit exceeded response
 async fn handle_rate_limit_exceeded(session: &mut Session, config: RateLimitConfig) -> pingora::Result<bool> {
+    // whole seconds, at least 1
+    let reset_seconds = (config.reset.as_secs_f64().ceil() as u64).max(1);
     let mut header = ResponseHeader::build(429, None).unwrap();
     header
         .insert_header("X-Rate-Limit-Limit", config.limit.to_string())
@@ -76,8 +194,18 @@ async fn handle_rate_limit_exceeded(session: &mut Session, config: RateLimitConf
         .insert_header("X-Rate-Limit-Remaining", config.remaining.to_string())
         .unwrap();
     header
-        .insert_header("X-Rate-Limit-Reset", config.reset_seconds.to_string())
+        .insert_header("X-Rate-Limit-Reset", reset_seconds.to_string())
+        .unwrap();
+    header
+        .insert_header("Retry-After", reset_seconds.to_string())
+        .unwrap();
+    header
+        .insert_header("X-Rate-Limit-Scope", config.scope)
+        .unwrap();
+    header
+        .insert_header("Content-Length", "0")
         .unwrap();
+    insert_request_id(session, &mut header)?;
     
     session.set_keepalive(None);
     session
@@ -0,0 +1,95 @@
+// Copyright (c) 2025 Sébastien Campion, FOSS4. All rights reserved.
+//
+// This software is provided under the Commons Clause License Condition v1.0.
+// See the LICENSE file for full license details.
+
+use anyhow::Result;
+use moka::sync::Cache;
+use moka::Expiry;
+use pingora::http::RequestHeader;
+use redb::{Database, TableDefinition, WriteTransaction};
+use ring::hmac;
+use std::time::{Duration, Instant};
+
+/// Signing clients: client id to the user it acts as and its shared secret
+pub const SIGNING_CLIENTS: TableDefinition<&str, (&str, &str)> = TableDefinition::new("signing_clients");
+
+pub const CLIENT_ID_HEADER: &str = "X-Client-Id";
+pub const TIMESTAMP_HEADER: &str = "X-Timestamp";
+pub const SIGNATURE_HEADER: &str = "X-Signature";
+
+/// Shortest secret accepted, as for bearer tokens
+pub const MIN_SECRET_LEN: usize = 32;
+
+const MAX_CAPACITY: u64 = 100_000;
+
+/// Signatures are remembered until their timestamp is too old to be accepted anyway
+struct UntilStale;
+
+impl Expiry<Vec<u8>, Duration> for UntilStale {
+    fn expire_after_create(&self, _signature: &Vec<u8>, remaining: &Duration, _created_at: Instant) -> Option<Duration> {
+        Some(*remaining)
+    }
+}
+
+/// Register the clients, replacing the secret of known ones.
+/// The caller is responsible for committing the transaction.
+pub fn set_client(write_txn: &WriteTransaction, client_id: &str, user: &str, secret: &str) -> Result<()> {
+    let mut table = write_txn.open_table(SIGNING_CLIENTS)?;
+    table.insert(client_id, (user, secret))?;
+    Ok(())
+}
+
+/// User and secret of a signing client
+fn client(db: &Database, client_id: &str) -> Result<Option<(String, String)>> {
+    let read_txn = db.begin_read()?;
+    let table = read_txn.open_table(SIGNING_CLIENTS)?;
+    Ok(table.get(client_id)?.map(|entry| {
+        let (user, secret) = entry.value();
+        (user.to_string(), secret.to_string())
+    }))
+}
+
+/// Verifies requests signed with HMAC-SHA256 over `<X-Timestamp>.<body>` by a signing client.
+/// A signature is accepted once, and only while its timestamp is within the allowed age.
+pub struct RequestSigning {
+    seen: Cache<Vec<u8>, Duration>,
+}
+
+impl RequestSigning {
+    pub fn new() -> Self {
+        Self { seen: Cache::builder().max_capacity(MAX_CAPACITY).expire_after(UntilStale).build() }
+    }
+
+    /// User of the client that signed the request, or why the signature is refused
+    pub fn verify(&self, db: &Database, req: &RequestHeader, body: &[u8], max_age_secs: u64, now: i64) -> Result<String, &'static str> {
+        let header = |name| req.headers.get(name).and_then(|v| v.to_str().ok());
+        let (Some(client_id), Some(timestamp), Some(signature)) = (header(CLIENT_ID_HEADER), header(TIMESTAMP_HEADER), header(SIGNATURE_HEADER)) else {
+            return Err("Signed requests need X-Client-Id, X-Timestamp and X-Signature headers");
+        };
+        let timestamp_secs = timestamp.parse::<i64>().map_err(|_| "Invalid X-Timestamp, expected unix seconds")?;
+        if now.abs_diff(timestamp_secs) > max_age_secs {
+            return Err("Stale request timestamp");
+        }
+        let signature = hex::decode(signature).map_err(|_| "Invalid X-Signature, expected hex")?;
+
+        let (user, secret) = client(db, client_id)
+            .map_err(|e| {
+                log::error!("Failed to read signing client {}: {}", client_id, e);
+                "Invalid signature"
+            })?
+            .ok_or("Invalid signature")?;
+        let key = hmac::Key::new(hmac::HMAC_SHA256, secret.as_bytes());
+        let message = [timestamp.as_bytes(), b".", body].concat();
+        hmac::verify(&key, &message, &signature).map_err(|_| "Invalid signature")?;
+
+        // Remember the signature until the timestamp is stale, the entry call checking and
+        // inserting at once so concurrent replays cannot both pass
+        let remaining = Duration::from_secs((timestamp_secs + max_age_secs as i64 - now).max(1) as u64);
+        let entry = self.seen.entry(signature).or_insert(remaining);
+        if !entry.is_fresh() {
+            return Err("Signature already used");
+        }
+        Ok(user)
+    }
+}
@@ -0,0 +1,83 @@
+// Copyright (c) 2025 Sébastien Campion, FOSS4. All rights reserved.
+//
+// This software is provided under the Commons Clause License Condition v1.0.
+// See the LICENSE file for full license details.
+
+use bytes::Bytes;
+use moka::sync::Cache;
+use moka::Expiry;
+use ring::digest;
+use serde_json::Value;
+use std::time::{Duration, Instant};
+
+pub const CACHE_STATUS_HEADER: &str = "X-Cache";
+
+/// Upstream response given again to identical requests
+#[derive(Clone)]
+pub struct CachedResponse {
+    pub status: u16,
+    pub content_type: Option<String>,
+    pub body: Bytes,
+}
+
+/// Entries expire after the `response_cache_ttl_secs` of the location that stored them
+struct LocationTtl;
+
+impl Expiry<[u8; 32], (CachedResponse, Duration)> for LocationTtl {
+    fn expire_after_create(&self, _key: &[u8; 32], (_, ttl): &(CachedResponse, Duration), _created_at: Instant) -> Option<Duration> {
+        Some(*ttl)
+    }
+}
+
+/// Responses of the locations with a `response_cache_ttl_secs`, by location and request body,
+/// bounded by the total size of their bodies
+pub struct ResponseCache {
+    responses: Cache<[u8; 32], (CachedResponse, Duration)>,
+}
+
+impl ResponseCache {
+    pub fn new(max_bytes: u64) -> Self {
+        Self {
+            responses: Cache::builder()
+                .max_capacity(max_bytes)
+                .weigher(|_key, (response, _): &(CachedResponse, Duration)| response.body.len().try_into().unwrap_or(u32::MAX))
+                .expire_after(LocationTtl)
+                .build(),
+        }
+    }
+
+    pub fn get(&self, key: &[u8; 32]) -> Option<CachedResponse> {
+        self.responses.get(key).map(|(response, _)| response)
+    }
+
+    pub fn insert(&self, key: [u8; 32], response: CachedResponse, ttl: Duration) {
+        self.responses.insert(key, (response, ttl));
+    }
+}
+
+/// Key of a request, from the parts telling it apart: location, path and body sent upstream
+pub fn key(parts: &[&[u8]]) -> [u8; 32] {
+    let mut context = digest::Context::new(&digest::SHA256);
+    for part in parts {
+        // the length prefix keeps ("ab", "c") and ("a", "bc") apart
+        context.update(&(part.len() as u64).to_be_bytes());
+        context.update(part);
+    }
+    context.finish().as_ref().try_into().expect("SHA-256 digests are 32 bytes")
+}
+
+/// Whether the body asks for a completion that does not vary: a JSON object with a `temperature`
+/// of 0 that is not streamed
+pub fn is_deterministic(body: &[u8]) -> bool {
+    let Ok(Value::Object(object)) = serde_json::from_slice::<Value>(body) else {
+        return false;
+    };
+    object.get("temperature").and_then(Value::as_f64) == Some(0.0)
+        && object.get("stream").and_then(Value::as_bool) != Some(true)
+}
+
+/// Whether the body asks for a streamed response with `"stream": true`
+pub fn is_streamed(body: &[u8]) -> bool {
+    serde_json::from_slice::<Value>(body)
+        .is_ok_and(|json| json.get("stream").and_then(Value::as_bool) == Some(true))
+}
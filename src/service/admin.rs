@@ -5,8 +5,13 @@
 
 use crate::app::admin::HttpAdminApp;
 use pingora::services::listening::Service;
+use crate::cache::AuthCache;
+use crate::config::ServerConf;
+use crate::maintenance_mode::MaintenanceMode;
+use crate::disabled_models::DisabledModels;
+use arc_swap::ArcSwap;
 use std::sync::Arc;
 
-pub fn admin_service_http(db: Arc<redb::Database>) -> Service<HttpAdminApp> {
-    Service::new("Admin Service HTTP".to_string(), HttpAdminApp{db})
+pub fn admin_service_http(db: Arc<redb::Database>, auth_cache: Arc<AuthCache>, conf: Arc<ArcSwap<ServerConf>>, maintenance_mode: Arc<MaintenanceMode>, disabled_models: Arc<DisabledModels>) -> Service<HttpAdminApp> {
+    Service::new("Admin Service HTTP".to_string(), HttpAdminApp{db, auth_cache, conf, maintenance_mode, disabled_models})
 }
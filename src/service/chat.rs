@@ -6,13 +6,18 @@
 use crate::app::admin::HttpAdminApp;
 use crate::app::chat::HttpChatApp;
 use pingora::services::listening::Service;
+use crate::cache::AuthCache;
+use crate::config::ServerConf;
+use crate::maintenance_mode::MaintenanceMode;
+use crate::disabled_models::DisabledModels;
+use arc_swap::ArcSwap;
 use std::sync::Arc;
 
-pub fn chat_service_http(db: Arc<redb::Database>) -> Service<HttpChatApp> {
+pub fn chat_service_http(db: Arc<redb::Database>, auth_cache: Arc<AuthCache>, conf: Arc<ArcSwap<ServerConf>>, maintenance_mode: Arc<MaintenanceMode>, disabled_models: Arc<DisabledModels>) -> Service<HttpChatApp> {
     Service::new(
         "Chat HTTP Service".to_string(),
         HttpChatApp {
-            admin: HttpAdminApp { db }
+            admin: HttpAdminApp { db, auth_cache, conf, maintenance_mode, disabled_models }
         },
     )
 }
\ No newline at end of file
@@ -0,0 +1,14 @@
+// Copyright (c) 2025 Sébastien Campion, FOSS4. All rights reserved.
+//
+// This software is provided under the Commons Clause License Condition v1.0.
+// See the LICENSE file for full license details.
+
+use crate::app::health::HttpHealthApp;
+use crate::config::ServerConf;
+use arc_swap::ArcSwap;
+use pingora::services::listening::Service;
+use std::sync::Arc;
+
+pub fn health_service_http(db: Arc<redb::Database>, conf: Arc<ArcSwap<ServerConf>>) -> Service<HttpHealthApp> {
+    Service::new("Health Service HTTP".to_string(), HttpHealthApp { db, conf })
+}
@@ -0,0 +1,14 @@
+// Copyright (c) 2025 Sébastien Campion, FOSS4. All rights reserved.
+//
+// This software is provided under the Commons Clause License Condition v1.0.
+// See the LICENSE file for full license details.
+
+use crate::app::keepalive::KeepAlive;
+use crate::config::ServerConf;
+use arc_swap::ArcSwap;
+use pingora::services::background::{background_service, GenBackgroundService};
+use std::sync::Arc;
+
+pub fn keepalive_service(conf: Arc<ArcSwap<ServerConf>>) -> GenBackgroundService<KeepAlive> {
+    background_service("Upstream Keep-Alive", KeepAlive { conf })
+}
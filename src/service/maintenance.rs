@@ -0,0 +1,15 @@
+// Copyright (c) 2025 Sébastien Campion, FOSS4. All rights reserved.
+//
+// This software is provided under the Commons Clause License Condition v1.0.
+// See the LICENSE file for full license details.
+
+use crate::app::maintenance::DbMaintenance;
+use crate::config::ServerConf;
+use arc_swap::ArcSwap;
+use pingora::services::background::{background_service, GenBackgroundService};
+use redb::Database;
+use std::sync::Arc;
+
+pub fn maintenance_service(db: Arc<Database>, conf: Arc<ArcSwap<ServerConf>>) -> GenBackgroundService<DbMaintenance> {
+    background_service("Database Maintenance", DbMaintenance { db, conf })
+}
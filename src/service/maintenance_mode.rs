@@ -0,0 +1,13 @@
+// Copyright (c) 2025 Sébastien Campion, FOSS4. All rights reserved.
+//
+// This software is provided under the Commons Clause License Condition v1.0.
+// See the LICENSE file for full license details.
+
+use crate::app::maintenance_mode::MaintenanceSignal;
+use crate::maintenance_mode::MaintenanceMode;
+use pingora::services::background::{background_service, GenBackgroundService};
+use std::sync::Arc;
+
+pub fn maintenance_signal_service(maintenance_mode: Arc<MaintenanceMode>) -> GenBackgroundService<MaintenanceSignal> {
+    background_service("Maintenance Mode Signal", MaintenanceSignal { maintenance_mode })
+}
@@ -5,4 +5,10 @@
 
 pub mod echo;
 pub mod admin;
-pub mod chat;
\ No newline at end of file
+pub mod chat;
+pub mod reload;
+pub mod keepalive;
+pub mod maintenance;
+pub mod maintenance_mode;
+pub mod shutdown;
+pub mod health;
\ No newline at end of file
@@ -0,0 +1,15 @@
+// Copyright (c) 2025 Sébastien Campion, FOSS4. All rights reserved.
+//
+// This software is provided under the Commons Clause License Condition v1.0.
+// See the LICENSE file for full license details.
+
+use crate::app::reload::ConfigReloader;
+use crate::config::ServerConf;
+use crate::disabled_models::DisabledModels;
+use arc_swap::ArcSwap;
+use pingora::services::background::{background_service, GenBackgroundService};
+use std::sync::Arc;
+
+pub fn reload_service(conf_path: String, conf: Arc<ArcSwap<ServerConf>>, disabled_models: Arc<DisabledModels>) -> GenBackgroundService<ConfigReloader> {
+    background_service("Config Reload", ConfigReloader { conf_path, conf, disabled_models })
+}
@@ -0,0 +1,16 @@
+// Copyright (c) 2025 Sébastien Campion, FOSS4. All rights reserved.
+//
+// This software is provided under the Commons Clause License Condition v1.0.
+// See the LICENSE file for full license details.
+
+use crate::app::shutdown::GracefulShutdown;
+use crate::audit::AuditLog;
+use crate::concurrency::ActiveRequests;
+use crate::usage_writer::UsageWriter;
+use pingora::services::background::{background_service, GenBackgroundService};
+use std::sync::Arc;
+use std::time::Duration;
+
+pub fn shutdown_service(requests: ActiveRequests, usage_writer: Arc<UsageWriter>, audit_log: Option<Arc<AuditLog>>, grace_period: Duration) -> GenBackgroundService<GracefulShutdown> {
+    background_service("Graceful Shutdown", GracefulShutdown { requests, usage_writer, audit_log, grace_period })
+}
@@ -0,0 +1,87 @@
+// Copyright (c) 2025 Sébastien Campion, FOSS4. All rights reserved.
+//
+// This software is provided under the Commons Clause License Condition v1.0.
+// See the LICENSE file for full license details.
+
+use crate::config::ModelConfig;
+use crate::errors::REQUEST_ID_HEADER;
+use crate::parsers::{parse, SseUsageParser};
+use anyhow::{anyhow, Result};
+use bytes::Bytes;
+use log::{info, warn};
+use once_cell::sync::Lazy;
+use prometheus::{register_int_counter, IntCounter, IntCounterVec};
+use std::sync::Arc;
+use std::time::Duration;
+
+const SHADOW_TIMEOUT: Duration = Duration::from_secs(120);
+
+static CLIENT: Lazy<reqwest::Client> = Lazy::new(reqwest::Client::new);
+
+static SHADOW_ERRORS: Lazy<IntCounter> = Lazy::new(|| {
+    register_int_counter!("shadow_errors", "Mirrored requests that failed or returned unparsable responses").unwrap()
+});
+
+/// Token counters the shadow usage is added to, with the labels of the request
+pub struct ShadowUsage {
+    pub input_tokens: IntCounterVec,
+    pub output_tokens: IntCounterVec,
+    /// `shadow:<location>`, then the user when the counters are labeled by user
+    pub labels: Vec<String>,
+}
+
+/// Send a copy of the request to the `shadow_proxy_pass` of the model in the background.
+/// Its response is only parsed for token usage; nothing about it reaches the client.
+pub fn mirror(model: Arc<ModelConfig>, body: Bytes, request_id: String, usage: ShadowUsage) {
+    tokio::spawn(async move {
+        match send(&model, body, &request_id).await {
+            Ok((input_tokens, output_tokens)) => {
+                info!("{} Shadow {} used {} input / {} output tokens", request_id, model.shadow_proxy_pass, input_tokens, output_tokens);
+                let labels: Vec<&str> = usage.labels.iter().map(String::as_str).collect();
+                usage.input_tokens.with_label_values(&labels).inc_by(input_tokens);
+                usage.output_tokens.with_label_values(&labels).inc_by(output_tokens);
+            }
+            Err(e) => {
+                SHADOW_ERRORS.inc();
+                warn!("{} Shadow request to {} failed: {}", request_id, model.shadow_proxy_pass, e);
+            }
+        }
+    });
+}
+
+async fn send(model: &ModelConfig, body: Bytes, request_id: &str) -> Result<(u64, u64)> {
+    let api_key = model.shadow_api_key.as_ref().or(model.api_keys.first()).unwrap_or(&model.api_key);
+    let auth_value = if model.auth_scheme.is_empty() {
+        api_key.clone()
+    } else {
+        format!("{} {}", model.auth_scheme, api_key)
+    };
+    let response = CLIENT
+        .post(&model.shadow_proxy_pass)
+        .header(model.auth_header_name.as_str(), auth_value)
+        .header(http::header::CONTENT_TYPE.as_str(), "application/json")
+        .header(REQUEST_ID_HEADER, request_id)
+        .timeout(SHADOW_TIMEOUT)
+        .body(body)
+        .send()
+        .await?;
+    if !response.status().is_success() {
+        return Err(anyhow!("status {}", response.status()));
+    }
+
+    let content_type = response.headers().get(reqwest::header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or_default();
+    let is_ndjson = content_type.starts_with("application/x-ndjson");
+    let is_event_stream = is_ndjson || content_type.starts_with("text/event-stream");
+    let body = response.bytes().await?;
+    if is_event_stream {
+        let mut events = SseUsageParser::default();
+        events.ndjson = is_ndjson;
+        events.feed(&body, &model.parser);
+        events.finish(&model.parser);
+        return Ok((events.input_tokens, events.output_tokens));
+    }
+    let json_body = serde_json::from_slice::<serde_json::Value>(&body)?;
+    parse(&json_body, &model.parser)
+}
@@ -0,0 +1,130 @@
+// Copyright (c) 2025 Sébastien Campion, FOSS4. All rights reserved.
+//
+// This software is provided under the Commons Clause License Condition v1.0.
+// See the LICENSE file for full license details.
+
+//! AWS Signature Version 4 for requests sent to AWS upstreams such as Bedrock.
+//! See https://docs.aws.amazon.com/IAM/latest/UserGuide/create-signed-request.html
+
+use chrono::{DateTime, Utc};
+use ring::{digest, hmac};
+
+const ALGORITHM: &str = "AWS4-HMAC-SHA256";
+
+/// Signs requests for one service and region with the credentials of an IAM identity
+#[derive(Clone)]
+pub struct AwsSigner {
+    pub access_key_id: String,
+    pub secret_access_key: String,
+    /// Set for temporary credentials
+    pub session_token: Option<String>,
+    pub region: String,
+    pub service: String,
+}
+
+impl std::fmt::Debug for AwsSigner {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "AwsSigner({}, {}/{})", self.access_key_id, self.region, self.service)
+    }
+}
+
+/// Hex SHA-256 of a request body, the `x-amz-content-sha256` of the signed request
+pub fn payload_hash(body: &[u8]) -> String {
+    hex::encode(digest::digest(&digest::SHA256, body))
+}
+
+impl AwsSigner {
+    /// Headers to add to a request so AWS accepts it: `x-amz-date`, `x-amz-content-sha256`,
+    /// `x-amz-security-token` for temporary credentials, and `authorization`.
+    /// `host` must be the Host header sent, and `uri` the path and query sent.
+    pub fn sign(&self, method: &str, uri: &http::Uri, host: &str, payload_hash: &str, time: DateTime<Utc>) -> Vec<(&'static str, String)> {
+        let amz_date = time.format("%Y%m%dT%H%M%SZ").to_string();
+        let date = time.format("%Y%m%d").to_string();
+
+        let mut headers = vec![
+            ("host", host.to_string()),
+            ("x-amz-content-sha256", payload_hash.to_string()),
+            ("x-amz-date", amz_date.clone()),
+        ];
+        if let Some(token) = &self.session_token {
+            headers.push(("x-amz-security-token", token.clone()));
+        }
+        let signed_headers = headers.iter().map(|(name, _)| *name).collect::<Vec<_>>().join(";");
+
+        let request = canonical_request(method, uri, &headers, &signed_headers, payload_hash);
+        let scope = format!("{}/{}/{}/aws4_request", date, self.region, self.service);
+        let string_to_sign = format!(
+            "{}\n{}\n{}\n{}",
+            ALGORITHM,
+            amz_date,
+            scope,
+            hex::encode(digest::digest(&digest::SHA256, request.as_bytes()))
+        );
+
+        let key = [date.as_str(), &self.region, &self.service, "aws4_request"].iter().fold(
+            format!("AWS4{}", self.secret_access_key).into_bytes(),
+            |key, part| hmac_sha256(&key, part.as_bytes()),
+        );
+        let signature = hex::encode(hmac_sha256(&key, string_to_sign.as_bytes()));
+
+        // host is already set on the request
+        headers.remove(0);
+        headers.push((
+            "authorization",
+            format!(
+                "{} Credential={}/{}, SignedHeaders={}, Signature={}",
+                ALGORITHM, self.access_key_id, scope, signed_headers, signature
+            ),
+        ));
+        headers
+    }
+}
+
+/// Canonical request of SigV4, `headers` sorted by lowercase name
+fn canonical_request(
+    method: &str,
+    uri: &http::Uri,
+    headers: &[(&str, String)],
+    signed_headers: &str,
+    payload_hash: &str,
+) -> String {
+    let headers: String = headers.iter().map(|(name, value)| format!("{}:{}\n", name, value.trim())).collect();
+    format!(
+        "{}\n{}\n{}\n{}\n{}\n{}",
+        method,
+        canonical_uri(uri.path()),
+        canonical_query(uri.query().unwrap_or_default()),
+        headers,
+        signed_headers,
+        payload_hash
+    )
+}
+
+/// Path with each segment encoded again, as done for every service but S3
+fn canonical_uri(path: &str) -> String {
+    if path.is_empty() {
+        return "/".to_string();
+    }
+    path.split('/').map(uri_encode).collect::<Vec<_>>().join("/")
+}
+
+/// Parameters decoded, encoded the SigV4 way and sorted
+fn canonical_query(query: &str) -> String {
+    let mut pairs: Vec<(String, String)> = url::form_urlencoded::parse(query.as_bytes())
+        .map(|(name, value)| (uri_encode(&name), uri_encode(&value)))
+        .collect();
+    pairs.sort();
+    pairs.iter().map(|(name, value)| format!("{}={}", name, value)).collect::<Vec<_>>().join("&")
+}
+
+/// Percent-encode everything but the unreserved characters of RFC 3986
+fn uri_encode(value: &str) -> String {
+    value.bytes().map(|b| match b {
+        b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => (b as char).to_string(),
+        _ => format!("%{:02X}", b),
+    }).collect()
+}
+
+fn hmac_sha256(key: &[u8], data: &[u8]) -> Vec<u8> {
+    hmac::sign(&hmac::Key::new(hmac::HMAC_SHA256, key), data).as_ref().to_vec()
+}
@@ -0,0 +1,68 @@
+// Copyright (c) 2025 Sébastien Campion, FOSS4. All rights reserved.
+//
+// This software is provided under the Commons Clause License Condition v1.0.
+// See the LICENSE file for full license details.
+
+use aho_corasick::AhoCorasick;
+use bytes::Bytes;
+
+/// Finds the `blacklist_words` of a location in the text generated along an event stream.
+///
+/// The text is scanned as the events arrive, after the end of the text scanned before, as long as
+/// the longest word less one, so a word split across events or chunks is found too. The lines of
+/// the stream are forwarded once complete, so the line completing a word is held back until it is
+/// scanned.
+#[derive(Debug)]
+pub struct StreamBlacklist {
+    matcher: AhoCorasick,
+    /// Bytes of text scanned again with the next one, one less than the longest word
+    overlap: usize,
+    /// End of the text scanned
+    tail: String,
+    /// Length of the text of the events already scanned
+    scanned: usize,
+    pending: Vec<u8>,
+}
+
+impl StreamBlacklist {
+    pub fn new(matcher: &AhoCorasick) -> Self {
+        StreamBlacklist {
+            matcher: matcher.clone(),
+            overlap: matcher.max_pattern_len().saturating_sub(1),
+            tail: String::new(),
+            scanned: 0,
+            pending: Vec::new(),
+        }
+    }
+
+    /// The first blacklisted word in the text of the events added since the last scan, as it was
+    /// generated. The text of each event ends with a newline, left out to join them.
+    pub fn scan(&mut self, text: &str) -> Option<String> {
+        let mut new_text = std::mem::take(&mut self.tail);
+        new_text.extend(text[self.scanned..].split('\n'));
+        self.scanned = text.len();
+        if let Some(found) = self.matcher.find(&new_text) {
+            return Some(new_text[found.range()].to_string());
+        }
+        let mut start = new_text.len().saturating_sub(self.overlap);
+        while !new_text.is_char_boundary(start) {
+            start -= 1;
+        }
+        self.tail = new_text.split_off(start);
+        None
+    }
+
+    /// The complete lines of the chunk, the rest waits for the next one
+    pub fn filter(&mut self, chunk: &[u8]) -> Bytes {
+        self.pending.extend_from_slice(chunk);
+        match self.pending.iter().rposition(|b| *b == b'\n') {
+            Some(end) => Bytes::from(self.pending.drain(..=end).collect::<Vec<u8>>()),
+            None => Bytes::new(),
+        }
+    }
+
+    /// The line left at the end of the stream, without its newline
+    pub fn finish(&mut self) -> Bytes {
+        Bytes::from(std::mem::take(&mut self.pending))
+    }
+}
@@ -0,0 +1,87 @@
+// Copyright (c) 2025 Sébastien Campion, FOSS4. All rights reserved.
+//
+// This software is provided under the Commons Clause License Condition v1.0.
+// See the LICENSE file for full license details.
+
+use bytes::Bytes;
+use serde_json::{json, Value};
+
+const DONE_EVENT: &[u8] = b"data: [DONE]";
+
+/// Whether the request body asks for the usage of a streamed completion, with
+/// `"stream_options": {"include_usage": true}`
+pub fn is_requested(body: &[u8]) -> bool {
+    serde_json::from_slice::<Value>(body)
+        .is_ok_and(|json| json["stream_options"]["include_usage"].as_bool() == Some(true))
+}
+
+/// Adds a final event with the usage counted by the gateway to an event stream, for upstreams
+/// that do not send the OpenAI usage chunk themselves.
+///
+/// Lines are forwarded once complete, and the `data: [DONE]` event is held back so that the
+/// usage event goes before it.
+#[derive(Debug, Default)]
+pub struct UsageEventWriter {
+    pending: Vec<u8>,
+    done: bool,
+}
+
+impl UsageEventWriter {
+    /// The complete lines of the chunk, but for `data: [DONE]`
+    pub fn filter(&mut self, chunk: &[u8]) -> Bytes {
+        self.pending.extend_from_slice(chunk);
+        let Some(end) = self.pending.iter().rposition(|b| *b == b'\n') else {
+            return Bytes::new();
+        };
+        let lines: Vec<u8> = self.pending.drain(..=end).collect();
+        self.without_done(&lines)
+    }
+
+    /// The rest of the stream, followed by the usage event when given and `data: [DONE]` when the
+    /// upstream sent it
+    pub fn finish(&mut self, usage: Option<(u64, u64)>) -> Bytes {
+        let rest = std::mem::take(&mut self.pending);
+        let mut out = self.without_done(&rest).to_vec();
+        if !out.is_empty() && !out.ends_with(b"\n\n") {
+            out.extend_from_slice(if out.ends_with(b"\n") { b"\n" } else { b"\n\n" });
+        }
+        if let Some((input_tokens, output_tokens)) = usage {
+            let event = json!({
+                "object": "chat.completion.chunk",
+                "choices": [],
+                "usage": {
+                    "prompt_tokens": input_tokens,
+                    "completion_tokens": output_tokens,
+                    "total_tokens": input_tokens + output_tokens,
+                },
+            });
+            out.extend_from_slice(format!("data: {}\n\n", event).as_bytes());
+        }
+        if self.done {
+            out.extend_from_slice(DONE_EVENT);
+            out.extend_from_slice(b"\n\n");
+        }
+        Bytes::from(out)
+    }
+
+    fn without_done(&mut self, lines: &[u8]) -> Bytes {
+        let mut out = Vec::with_capacity(lines.len());
+        let mut skip_blank = false;
+        for line in lines.split_inclusive(|b| *b == b'\n') {
+            let trimmed = line.trim_ascii();
+            if trimmed == DONE_EVENT || trimmed == b"data:[DONE]" {
+                self.done = true;
+                skip_blank = true;
+                continue;
+            }
+            // the blank line ending the held back event goes with it
+            if skip_blank && trimmed.is_empty() {
+                skip_blank = false;
+                continue;
+            }
+            skip_blank = false;
+            out.extend_from_slice(line);
+        }
+        Bytes::from(out)
+    }
+}
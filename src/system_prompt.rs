@@ -0,0 +1,86 @@
+// Copyright (c) 2025 Sébastien Campion, FOSS4. All rights reserved.
+//
+// This software is provided under the Commons Clause License Condition v1.0.
+// See the LICENSE file for full license details.
+
+use bytes::Bytes;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+
+/// How the `system_prompt` of a location combines with the one of the client
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum SystemPromptMode {
+    /// Put it before the system prompt of the client, if any
+    #[default]
+    Prepend,
+    /// Replace the system prompt of the client
+    Override,
+}
+
+/// Body with the system prompt applied, or why it was not: bodies that are not chat requests
+/// are left to the caller to send untouched.
+///
+/// OpenAI chat bodies carry it as the first message of `messages`, with the `system` role.
+/// Anthropic bodies (`top_level` set) carry it in the `system` field, a string or text blocks.
+pub fn apply(prompt: &str, mode: SystemPromptMode, top_level: bool, body: &[u8]) -> Result<Bytes, String> {
+    let mut json = serde_json::from_slice::<Value>(body).map_err(|e| e.to_string())?;
+    let object = json.as_object_mut().ok_or("body is not a JSON object")?;
+    if top_level {
+        apply_top_level(prompt, mode, object)?;
+    } else {
+        apply_messages(prompt, mode, object)?;
+    }
+    serde_json::to_vec(&json).map(Bytes::from).map_err(|e| e.to_string())
+}
+
+fn apply_messages(prompt: &str, mode: SystemPromptMode, object: &mut Map<String, Value>) -> Result<(), String> {
+    let messages = object.get_mut("messages")
+        .and_then(Value::as_array_mut)
+        .ok_or("body has no messages array")?;
+    let is_system = |message: &Value| message["role"] == "system";
+    match mode {
+        SystemPromptMode::Override => messages.retain(|message| !is_system(message)),
+        SystemPromptMode::Prepend => {
+            if let Some(first) = messages.first_mut().filter(|message| is_system(message)) {
+                if prepend_text(prompt, &mut first["content"]) {
+                    return Ok(());
+                }
+            }
+        }
+    }
+    messages.insert(0, json!({"role": "system", "content": prompt}));
+    Ok(())
+}
+
+fn apply_top_level(prompt: &str, mode: SystemPromptMode, object: &mut Map<String, Value>) -> Result<(), String> {
+    if !object.get("messages").is_some_and(Value::is_array) {
+        return Err("body has no messages array".to_string());
+    }
+    match object.get_mut("system").filter(|system| !system.is_null()) {
+        Some(system) if mode == SystemPromptMode::Prepend => {
+            if !prepend_text(prompt, system) {
+                return Err("system is neither a string nor text blocks".to_string());
+            }
+        }
+        _ => {
+            object.insert("system".to_string(), Value::from(prompt));
+        }
+    }
+    Ok(())
+}
+
+/// Put the prompt before a string or an array of content parts, false for other values
+fn prepend_text(prompt: &str, content: &mut Value) -> bool {
+    match content {
+        Value::String(text) => {
+            *text = format!("{}\n\n{}", prompt, text);
+            true
+        }
+        Value::Array(parts) => {
+            parts.insert(0, json!({"type": "text", "text": prompt}));
+            true
+        }
+        _ => false,
+    }
+}
@@ -0,0 +1,141 @@
+// Copyright (c) 2025 Sébastien Campion, FOSS4. All rights reserved.
+//
+// This software is provided under the Commons Clause License Condition v1.0.
+// See the LICENSE file for full license details.
+
+use anyhow::{anyhow, Context as _, Result};
+use opentelemetry::propagation::{Extractor, Injector, TextMapPropagator};
+use opentelemetry::trace::TracerProvider as _;
+use opentelemetry_otlp::WithExportConfig;
+use opentelemetry_sdk::propagation::TraceContextPropagator;
+use opentelemetry_sdk::trace::{Sampler, SdkTracerProvider};
+use opentelemetry_sdk::Resource;
+use pingora::http::RequestHeader;
+use serde::{Deserialize, Serialize};
+use std::sync::OnceLock;
+use std::time::Duration;
+use tracing::Span;
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+use tracing_subscriber::filter::filter_fn;
+use tracing_subscriber::{Layer, Registry};
+use url::Url;
+
+/// Spans of the proxied requests exported to an OpenTelemetry collector over OTLP/HTTP
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct TracingConf {
+    /// Collector URL, e.g. `http://otel-collector:4318`. `/v1/traces` is added when it has no path.
+    pub otlp_endpoint: String,
+    #[serde(default = "default_service_name")]
+    pub service_name: String,
+    /// Share of the traces started by the gateway that are exported, from 0 to 1. Requests that
+    /// carry a `traceparent` follow the sampling decision of their caller.
+    #[serde(default = "default_sample_ratio")]
+    pub sample_ratio: f64,
+    #[serde(default = "default_export_timeout_ms")]
+    pub export_timeout_ms: u64,
+}
+
+fn default_service_name() -> String {
+    "burgonet-gw".to_string()
+}
+
+fn default_sample_ratio() -> f64 {
+    1.0
+}
+
+fn default_export_timeout_ms() -> u64 {
+    10_000
+}
+
+impl TracingConf {
+    pub fn validate(&self) -> Result<()> {
+        self.traces_url()?;
+        if !(0.0..=1.0).contains(&self.sample_ratio) {
+            return Err(anyhow!("tracing: sample_ratio must be between 0 and 1"));
+        }
+        Ok(())
+    }
+
+    fn traces_url(&self) -> Result<String> {
+        let mut url = Url::parse(&self.otlp_endpoint)
+            .with_context(|| format!("tracing: invalid otlp_endpoint {:?}", self.otlp_endpoint))?;
+        if url.path() == "/" {
+            url.set_path("/v1/traces");
+        }
+        Ok(url.to_string())
+    }
+}
+
+/// Provider of the exporter, flushed on shutdown
+static PROVIDER: OnceLock<SdkTracerProvider> = OnceLock::new();
+
+/// Layer exporting the spans of the gateway. Only spans are exported: log lines stay in the logs.
+pub fn layer(conf: &TracingConf) -> Result<Box<dyn Layer<Registry> + Send + Sync>> {
+    let exporter = opentelemetry_otlp::SpanExporter::builder()
+        .with_http()
+        .with_endpoint(conf.traces_url()?)
+        .with_timeout(Duration::from_millis(conf.export_timeout_ms))
+        .build()
+        .context("tracing: unable to build the OTLP exporter")?;
+    let provider = SdkTracerProvider::builder()
+        .with_batch_exporter(exporter)
+        .with_sampler(Sampler::ParentBased(Box::new(Sampler::TraceIdRatioBased(conf.sample_ratio))))
+        .with_resource(Resource::builder().with_service_name(conf.service_name.clone()).build())
+        .build();
+    let tracer = provider.tracer("burgonet-gw");
+    let _ = PROVIDER.set(provider);
+
+    Ok(tracing_opentelemetry::layer()
+        .with_tracer(tracer)
+        .with_filter(filter_fn(|meta| meta.is_span() && meta.target().starts_with("burgonet_gw")))
+        .boxed())
+}
+
+/// Export the spans still buffered
+pub fn shutdown() {
+    if let Some(provider) = PROVIDER.get() {
+        if let Err(e) = provider.shutdown() {
+            log::warn!("Failed to flush the trace exporter: {}", e);
+        }
+    }
+}
+
+struct HeaderExtractor<'a>(&'a RequestHeader);
+
+impl Extractor for HeaderExtractor<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.headers.get(key).and_then(|v| v.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.headers.keys().map(|name| name.as_str()).collect()
+    }
+}
+
+struct HeaderInjector<'a>(&'a mut RequestHeader);
+
+impl Injector for HeaderInjector<'_> {
+    fn set(&mut self, key: &str, value: String) {
+        // an empty tracestate says nothing
+        if value.is_empty() {
+            return;
+        }
+        if let Err(e) = self.0.insert_header(key.to_string(), value) {
+            log::warn!("Failed to set the {} header: {}", key, e);
+        }
+    }
+}
+
+/// Continue the trace of the caller, given by the `traceparent` header of the request
+pub fn set_parent(span: &Span, req: &RequestHeader) {
+    if PROVIDER.get().is_some() {
+        span.set_parent(TraceContextPropagator::new().extract(&HeaderExtractor(req)));
+    }
+}
+
+/// Pass the span to the upstream as the `traceparent` header of its request
+pub fn inject(span: &Span, req: &mut RequestHeader) {
+    if PROVIDER.get().is_some() {
+        TraceContextPropagator::new().inject_context(&span.context(), &mut HeaderInjector(req));
+    }
+}
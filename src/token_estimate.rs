@@ -0,0 +1,68 @@
+// Copyright (c) 2025 Sébastien Campion, FOSS4. All rights reserved.
+//
+// This software is provided under the Commons Clause License Condition v1.0.
+// See the LICENSE file for full license details.
+
+use serde_json::Value;
+
+/// Fields holding the text of requests: chat messages and system prompts, completion prompts
+/// and embedding inputs
+const REQUEST_TEXT_FIELDS: [&str; 5] = ["content", "text", "prompt", "system", "input"];
+
+/// Fields holding the text generated in the responses and events of the supported APIs
+const RESPONSE_TEXT_FIELDS: [&str; 4] = ["content", "text", "response", "reasoning_content"];
+
+/// Tokens of the text with the `cl100k_base` encoding, an estimate for other tokenizers
+pub fn count(text: &str) -> u64 {
+    tiktoken_rs::cl100k_base_singleton().encode_ordinary(text).len() as u64
+}
+
+/// Text of a request body, its raw bytes when it is not JSON
+pub fn request_text(body: &[u8]) -> String {
+    match serde_json::from_slice::<Value>(body) {
+        Ok(json) => {
+            let mut text = String::new();
+            collect_text(&json, &REQUEST_TEXT_FIELDS, &mut text);
+            text
+        }
+        Err(_) => String::from_utf8_lossy(body).into_owned(),
+    }
+}
+
+/// Add the generated text of a response or event to `text`
+pub fn collect_response_text(json: &Value, text: &mut String) {
+    collect_text(json, &RESPONSE_TEXT_FIELDS, text);
+}
+
+/// Strings found under the fields, in arrays and objects at any depth
+fn collect_text(json: &Value, fields: &[&str], text: &mut String) {
+    match json {
+        Value::Object(object) => {
+            for (key, value) in object {
+                match value {
+                    Value::String(s) if fields.contains(&key.as_str()) => {
+                        text.push_str(s);
+                        text.push('\n');
+                    }
+                    Value::Array(items) if fields.contains(&key.as_str()) => {
+                        for item in items {
+                            if let Value::String(s) = item {
+                                text.push_str(s);
+                                text.push('\n');
+                            } else {
+                                collect_text(item, fields, text);
+                            }
+                        }
+                    }
+                    _ => collect_text(value, fields, text),
+                }
+            }
+        }
+        Value::Array(items) => {
+            for item in items {
+                collect_text(item, fields, text);
+            }
+        }
+        _ => {}
+    }
+}
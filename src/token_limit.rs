@@ -3,12 +3,15 @@
 // This software is provided under the Commons Clause License Condition v1.0.
 // See the LICENSE file for full license details.
 
-use crate::config::{QuotaPeriod, ModelConfig};
-use redb::{ReadTransaction, WriteTransaction, TableDefinition};
-use std::collections::HashMap;
+use crate::config::{Quota, QuotaPeriod, UsagePeriod};
+use chrono::{DateTime, Utc};
+use redb::{Database, ReadTransaction, ReadableTable, TableDefinition, WriteTransaction};
+use std::collections::BTreeMap;
 use anyhow::Result;
 use crate::app::gateway::GatewayContext;
-use log::{info, error};
+use crate::errors::insert_request_id;
+use crate::maintenance::{delete_in_batches, oldest_kept_month};
+use log::error;
 use pingora::Error;
 use pingora::http::ResponseHeader;
 use pingora::HTTPStatus;
@@ -16,47 +19,118 @@ use pingora_proxy::Session;
 
 const USAGE: TableDefinition<&str, u64> = TableDefinition::new("usage");
 
-pub fn extract_usage_keys(user: &str, current_time: chrono::DateTime<chrono::Utc>) -> HashMap<String, String> {
-    let mut keys = HashMap::new();
+/// Prefix and time format of the usage keys of a period, `<prefix>:<time>:<user>:<in|out>`.
+/// Times are fixed width, so the periods of one prefix sort as strings.
+fn key_format(period: UsagePeriod) -> (&'static str, &'static str) {
+    match period {
+        UsagePeriod::Minute => ("M", "%Y%m%d%H%M"),
+        UsagePeriod::Hour => ("H", "%Y%m%d%H"),
+        UsagePeriod::Day => ("d", "%Y%m%d"),
+        UsagePeriod::Week => ("W", "%Y%W"),
+        UsagePeriod::Month => ("m", "%Y%m"),
+    }
+}
 
-    let current_minute = current_time.format("%Y%m%d%H%M").to_string();
-    let current_hour = current_time.format("%Y%m%d%H").to_string();
-    let current_day = current_time.format("%Y%m%d").to_string();
-    let current_week = current_time.format("%Y%W").to_string();
-    let current_month = current_time.format("%Y%m").to_string();
+/// Period containing `time` as written in the usage keys, e.g. `202503` for March 2025
+pub fn usage_window(period: UsagePeriod, time: DateTime<Utc>) -> String {
+    time.format(key_format(period).1).to_string()
+}
 
-    keys.insert("input_key_minute".to_string(), format!("M:{}:{}:in", current_minute, user));
-    keys.insert("output_key_minute".to_string(), format!("M:{}:{}:out", current_minute, user));
-    keys.insert("input_key_hour".to_string(), format!("H:{}:{}:in", current_hour, user));
-    keys.insert("output_key_hour".to_string(), format!("H:{}:{}:out", current_hour, user));
-    keys.insert("input_key_day".to_string(), format!("d:{}:{}:in", current_day, user));
-    keys.insert("output_key_day".to_string(), format!("d:{}:{}:out", current_day, user));
-    keys.insert("input_key_week".to_string(), format!("W:{}:{}:in", current_week, user));
-    keys.insert("output_key_week".to_string(), format!("W:{}:{}:out", current_week, user));
-    keys.insert("input_key_month".to_string(), format!("m:{}:{}:in", current_month, user));
-    keys.insert("output_key_month".to_string(), format!("m:{}:{}:out", current_month, user));
+/// Start of the keys of every user in the period containing `time`
+fn usage_key_prefix(period: UsagePeriod, time: DateTime<Utc>) -> String {
+    format!("{}:{}:", key_format(period).0, usage_window(period, time))
+}
 
-    keys
+/// Key of the input (`in`) or output (`out`) tokens of a user in the period containing `time`
+pub fn usage_key(period: UsagePeriod, user: &str, time: DateTime<Utc>, direction: &str) -> String {
+    format!("{}{}:{}", usage_key_prefix(period, time), user, direction)
+}
+
+/// Input and output tokens of each user in the period containing `time`
+pub fn usage_by_user(read_txn: &ReadTransaction, period: UsagePeriod, time: DateTime<Utc>) -> Result<BTreeMap<String, (u64, u64)>> {
+    let prefix = usage_key_prefix(period, time);
+    let table = read_txn.open_table(USAGE)?;
+    let mut totals: BTreeMap<String, (u64, u64)> = BTreeMap::new();
+    for entry in table.range(prefix.as_str()..)? {
+        let (key, value) = entry?;
+        let Some(rest) = key.value().strip_prefix(prefix.as_str()) else {
+            break;
+        };
+        match rest.rsplit_once(':') {
+            Some((user, "in")) => totals.entry(user.to_string()).or_default().0 += value.value(),
+            Some((user, "out")) => totals.entry(user.to_string()).or_default().1 += value.value(),
+            _ => {}
+        }
+    }
+    Ok(totals)
 }
 
 
+/// Seconds until each usage period of `current_time` ends, at least 1.
+/// Periods follow the calendar boundaries of the usage keys, with weeks starting on Monday.
+fn seconds_until_reset(current_time: chrono::DateTime<chrono::Utc>) -> QuotaPeriod {
+    use chrono::{Datelike, Duration, NaiveDate, Timelike};
+
+    let until = |next: chrono::NaiveDateTime| (next - current_time.naive_utc()).num_seconds().max(1) as u64;
+    let today = current_time.date_naive();
+    let minute_start = today.and_hms_opt(current_time.hour(), current_time.minute(), 0).unwrap();
+    let hour_start = today.and_hms_opt(current_time.hour(), 0, 0).unwrap();
+    let day_start = today.and_hms_opt(0, 0, 0).unwrap();
+    let days_to_monday = 7 - current_time.weekday().num_days_from_monday() as i64;
+    let (year, month) = if current_time.month() == 12 { (current_time.year() + 1, 1) } else { (current_time.year(), current_time.month() + 1) };
+    let month_start = NaiveDate::from_ymd_opt(year, month, 1).unwrap().and_hms_opt(0, 0, 0).unwrap();
+
+    QuotaPeriod {
+        second: 1,
+        minute: until(minute_start + Duration::minutes(1)),
+        hour: until(hour_start + Duration::hours(1)),
+        day: until(day_start + Duration::days(1)),
+        week: until(day_start + Duration::days(days_to_monday)),
+        month: until(month_start),
+    }
+}
+
+/// Periods in the order they are checked, with the name used in the 429 error
+const PERIOD_LABELS: [(UsagePeriod, &str); 5] = [
+    (UsagePeriod::Minute, "Minutely"),
+    (UsagePeriod::Hour, "Hourly"),
+    (UsagePeriod::Day, "Daily"),
+    (UsagePeriod::Week, "Weekly"),
+    (UsagePeriod::Month, "Monthly"),
+];
+
+/// Token quota of the current request the closest to being exhausted, returned in the
+/// `X-Quota-*` response headers
+#[derive(Debug, Clone)]
+pub struct QuotaStatus {
+    pub limit: u64,
+    /// Tokens used in the period before this request
+    pub used: u64,
+    pub reset_seconds: u64,
+}
+
 struct TokenLimitConfig {
     limit: u64,
     remaining: u64,
     reset_seconds: u64,
 }
 
-pub async fn check_token_limits(
-    ctx: &mut GatewayContext,
-    session: &mut Session
-) -> pingora::Result<()> {
+/// Token quota of the location the user went over
+pub struct TokenLimitExceeded {
+    config: TokenLimitConfig,
+    /// Name of the period in the 429 error, e.g. `Hourly`
+    label: &'static str,
+    /// Tokens the quota counts, `total`, `input` or `output`
+    budget: &'static str,
+}
 
+/// Read the usage of the user and find the first token quota of the location it exceeds.
+/// Sets the usage and the quota closest to being exhausted in the context.
+pub fn find_exceeded_token_limit(db: &Database, ctx: &mut GatewayContext) -> pingora::Result<Option<TokenLimitExceeded>> {
     let current_time = chrono::Utc::now();
-    let read_txn = ctx.read_txn.as_ref().ok_or_else(|| {
-        error!("No read transaction available");
-        Error::explain(HTTPStatus(500), "Internal server error")
-    })?;
-    match get_usage_periods(read_txn, &ctx.user.as_ref().unwrap(), current_time) {
+    let usage = db.begin_read().map_err(anyhow::Error::from)
+        .and_then(|read_txn| get_usage_periods(&read_txn, ctx.user.as_ref().unwrap(), current_time, &ctx.conf.usage_periods));
+    match usage {
         Ok((usage_input, usage_output)) => {
             ctx.usage_input = usage_input;
             ctx.usage_output = usage_output;
@@ -67,41 +141,39 @@ pub async fn check_token_limits(
         }
     }
 
-    let usage_input = &ctx.usage_input;
-    let usage_output = &ctx.usage_output;
-
+    let reset = seconds_until_reset(current_time);
+    ctx.token_quota = None;
     if let Some(quotas) = &ctx.model.as_ref().unwrap().quotas {
-        for quota in quotas {
-            if let Some(max_tokens) = &quota.max_tokens {
-                if max_tokens.minute > 0 && usage_input.minute + usage_output.minute > max_tokens.minute {
-                    let config = get_token_limit_config(max_tokens.minute, usage_input.minute + usage_output.minute, 60).unwrap();
-                    handle_token_limit_exceeded(session, config).await?;
-                    return Err(Error::explain(HTTPStatus(429), "Minutely Token limit exceeded"));
-                }
-                if max_tokens.hour > 0 && usage_input.hour + usage_output.hour > max_tokens.hour {
-                    let config = get_token_limit_config(max_tokens.hour, usage_input.hour + usage_output.hour, 3600).unwrap();
-                    handle_token_limit_exceeded(session, config).await?;
-                    return Err(Error::explain(HTTPStatus(429), "Hourly Token limit exceeded"));
+        for (name, max_tokens) in quotas.iter().flat_map(Quota::token_limits) {
+            for (period, label) in PERIOD_LABELS {
+                let limit = period.of(max_tokens);
+                let (used, budget) = match name {
+                    "max_input_tokens" => (period.of(&ctx.usage_input), "input"),
+                    "max_output_tokens" => (period.of(&ctx.usage_output), "output"),
+                    _ => (period.of(&ctx.usage_input) + period.of(&ctx.usage_output), "total"),
+                };
+                if let Some(config) = get_token_limit_config(limit, used, period.of(&reset)) {
+                    return Ok(Some(TokenLimitExceeded { config, label, budget }));
                 }
-                if max_tokens.day > 0 && usage_input.day + usage_output.day > max_tokens.day {
-                    let config = get_token_limit_config(max_tokens.day, usage_input.day + usage_output.day, 86400).unwrap();
-                    handle_token_limit_exceeded(session, config).await?;
-                    return Err(Error::explain(HTTPStatus(429), "Daily Token limit exceeded"));
-                }
-                if max_tokens.week > 0 && usage_input.week + usage_output.week > max_tokens.week {
-                    let config = get_token_limit_config(max_tokens.week, usage_input.week + usage_output.week, 604800).unwrap();
-                    handle_token_limit_exceeded(session, config).await?;
-                    return Err(Error::explain(HTTPStatus(429), "Weekly Token limit exceeded"));
-                }
-                if max_tokens.month > 0 && usage_input.month + usage_output.month > max_tokens.month {
-                    let config = get_token_limit_config(max_tokens.month, usage_input.month + usage_output.month, 2592000).unwrap();
-                    handle_token_limit_exceeded(session, config).await?;
-                    return Err(Error::explain(HTTPStatus(429), "Monthly Token limit exceeded"));
+                // report the quota closest to being exhausted
+                let remaining = |quota: &QuotaStatus| quota.limit.saturating_sub(quota.used);
+                if limit > 0 && ctx.token_quota.as_ref().is_none_or(|quota| limit.saturating_sub(used) < remaining(quota)) {
+                    ctx.token_quota = Some(QuotaStatus { limit, used, reset_seconds: period.of(&reset) });
                 }
             }
         }
     }
-    Ok(())
+    Ok(None)
+}
+
+/// Answer the request with a 429 for the exceeded quota
+pub async fn reject_token_limit(session: &mut Session, exceeded: TokenLimitExceeded) -> pingora::Result<bool> {
+    handle_token_limit_exceeded(session, exceeded.config, exceeded.budget).await?;
+    let message = match exceeded.budget {
+        "total" => format!("{} Token limit exceeded", exceeded.label),
+        budget => format!("{} {} token limit exceeded", exceeded.label, budget),
+    };
+    Err(Error::explain(HTTPStatus(429), message))
 }
 
 
@@ -118,7 +190,7 @@ fn get_token_limit_config(limit: u64, current: u64, reset_seconds: u64) -> Optio
 }
 
 
-async fn handle_token_limit_exceeded(session: &mut Session, config: TokenLimitConfig) -> pingora::Result<bool> {
+async fn handle_token_limit_exceeded(session: &mut Session, config: TokenLimitConfig, budget: &'static str) -> pingora::Result<bool> {
     let mut header = ResponseHeader::build(429, None).unwrap();
     header
         .insert_header("X-Token-Limit-Limit", config.limit.to_string())
@@ -129,6 +201,14 @@ async fn handle_token_limit_exceeded(session: &mut Session, config: TokenLimitCo
     header
         .insert_header("X-Token-Limit-Reset", config.reset_seconds.to_string())
         .unwrap();
+    header
+        .insert_header("Retry-After", config.reset_seconds.to_string())
+        .unwrap();
+    header
+        .insert_header("X-Token-Limit-Budget", budget)
+        .unwrap();
+    header.insert_header("Content-Length", "0").unwrap();
+    insert_request_id(session, &mut header)?;
 
     session.set_keepalive(None);
     session.write_response_header(Box::new(header), true).await?;
@@ -137,118 +217,78 @@ async fn handle_token_limit_exceeded(session: &mut Session, config: TokenLimitCo
 
 
 
+/// Input and output tokens of the user in the current `periods`, the others are left at 0
 pub fn get_usage_periods(
     read_txn: &ReadTransaction,
     user: &str,
-    current_time: chrono::DateTime<chrono::Utc>
+    current_time: DateTime<Utc>,
+    periods: &[UsagePeriod],
 ) -> Result<(QuotaPeriod, QuotaPeriod)> {
     let mut usage_input = QuotaPeriod::new();
     let mut usage_output = QuotaPeriod::new();
-    
-    let keys = extract_usage_keys(user, current_time);
     let table = read_txn.open_table(USAGE)?;
+    let get = |key: String| table.get(key.as_str()).unwrap_or(None).map(|v| v.value()).unwrap_or(0);
 
-    usage_input.minute = table.get(keys.get("input_key_minute").unwrap().as_str())
-        .unwrap_or(None)
-        .map(|v| v.value())
-        .unwrap_or(0);
-
-    usage_output.minute = table.get(keys.get("output_key_minute").unwrap().as_str())
-        .unwrap_or(None)
-        .map(|v| v.value())
-        .unwrap_or(0);
-
-    usage_input.hour = table.get(keys.get("input_key_hour").unwrap().as_str())
-        .unwrap_or(None)
-        .map(|v| v.value())
-        .unwrap_or(0);
-
-    usage_output.hour = table.get(keys.get("output_key_hour").unwrap().as_str())
-        .unwrap_or(None)
-        .map(|v| v.value())
-        .unwrap_or(0);
-
-    usage_input.day = table.get(keys.get("input_key_day").unwrap().as_str())
-        .unwrap_or(None)
-        .map(|v| v.value())
-        .unwrap_or(0);
-
-    usage_output.day = table.get(keys.get("output_key_day").unwrap().as_str())
-        .unwrap_or(None)
-        .map(|v| v.value())
-        .unwrap_or(0);
-
-    usage_input.week = table.get(keys.get("input_key_week").unwrap().as_str())
-        .unwrap_or(None)
-        .map(|v| v.value())
-        .unwrap_or(0);
-
-    usage_output.week = table.get(keys.get("output_key_week").unwrap().as_str())
-        .unwrap_or(None)
-        .map(|v| v.value())
-        .unwrap_or(0);
-
-    usage_input.month = table.get(keys.get("input_key_month").unwrap().as_str())
-        .unwrap_or(None)
-        .map(|v| v.value())
-        .unwrap_or(0);
-
-    usage_output.month = table.get(keys.get("output_key_month").unwrap().as_str())
-        .unwrap_or(None)
-        .map(|v| v.value())
-        .unwrap_or(0);
-
+    for &period in periods {
+        *period.of_mut(&mut usage_input) = get(usage_key(period, user, current_time, "in"));
+        *period.of_mut(&mut usage_output) = get(usage_key(period, user, current_time, "out"));
+    }
     Ok((usage_input, usage_output))
 }
 
-pub fn update_usage_periods(ctx: &mut GatewayContext) -> Result<()> {
-    let user = ctx.user.as_ref().ok_or_else(|| {
-        error!("No user in context");
-        anyhow::anyhow!("No user in context")
-    })?;
-
-    let keys = extract_usage_keys(user, ctx.time);
-
-    ctx.usage_input.minute = ctx.usage_input.minute + ctx.input_tokens;
-    ctx.usage_output.minute = ctx.usage_output.minute + ctx.output_tokens;
-    ctx.usage_input.hour = ctx.usage_input.hour + ctx.input_tokens;
-    ctx.usage_output.hour = ctx.usage_output.hour + ctx.output_tokens;
-    ctx.usage_input.day = ctx.usage_input.day + ctx.input_tokens;
-    ctx.usage_output.day = ctx.usage_output.day + ctx.output_tokens;
-    ctx.usage_input.week = ctx.usage_input.week + ctx.input_tokens;
-    ctx.usage_output.week = ctx.usage_output.week + ctx.output_tokens;
-    ctx.usage_input.month = ctx.usage_input.month + ctx.input_tokens;
-    ctx.usage_output.month = ctx.usage_output.month + ctx.output_tokens;
-
-    let write_txn = ctx.write_txn.take().ok_or_else(|| {
-        error!("No write transaction available");
-        Error::explain(HTTPStatus(500), "Internal server error")
-    })?;
-    {
-        let mut table = write_txn.open_table(USAGE)?;
-        
-        // Create a vector of key-value pairs to insert
-        let updates = vec![
-            (keys.get("input_key_minute").unwrap().as_str(), ctx.usage_input.minute),
-            (keys.get("output_key_minute").unwrap().as_str(), ctx.usage_output.minute),
-            (keys.get("input_key_hour").unwrap().as_str(), ctx.usage_input.hour),
-            (keys.get("output_key_hour").unwrap().as_str(), ctx.usage_output.hour),
-            (keys.get("input_key_day").unwrap().as_str(), ctx.usage_input.day),
-            (keys.get("output_key_day").unwrap().as_str(), ctx.usage_output.day),
-            (keys.get("input_key_week").unwrap().as_str(), ctx.usage_input.week),
-            (keys.get("output_key_week").unwrap().as_str(), ctx.usage_output.week),
-            (keys.get("input_key_month").unwrap().as_str(), ctx.usage_input.month),
-            (keys.get("output_key_month").unwrap().as_str(), ctx.usage_output.month),
-        ];
-
-        // Perform all inserts in a single transaction
-        for (key, value) in updates {
-            table.insert(key, value)?;
+/// Add the tokens of a request to the user's totals of the current `periods`. The totals are
+/// read in the write transaction, so concurrent requests add up.
+/// The caller is responsible for committing the transaction.
+pub fn update_usage_periods(
+    write_txn: &WriteTransaction,
+    user: &str,
+    time: DateTime<Utc>,
+    periods: &[UsagePeriod],
+    input_tokens: u64,
+    output_tokens: u64,
+) -> Result<()> {
+    let mut table = write_txn.open_table(USAGE)?;
+    for &period in periods {
+        for (direction, tokens) in [("in", input_tokens), ("out", output_tokens)] {
+            let key = usage_key(period, user, time, direction);
+            let total = table.get(key.as_str())?.map(|v| v.value()).unwrap_or(0) + tokens;
+            table.insert(key.as_str(), total)?;
         }
     }
-
-    write_txn.commit()?;
-    info!("Updated usage periods for user {}", user);
     Ok(())
 }
 
+/// Remove the usage totals of ended periods and of the periods missing from `periods`.
+/// Monthly totals are kept for `retention_months` past months. Returns the number removed.
+pub fn prune_usage(db: &Database, periods: &[UsagePeriod], retention_months: u32, now: DateTime<Utc>) -> Result<u64> {
+    // oldest period kept for each key prefix, None to remove them all
+    let oldest_month = oldest_kept_month(now, retention_months)?;
+    let oldest: Vec<(&str, Option<String>)> = UsagePeriod::ALL.into_iter().map(|period| {
+        let (prefix, format) = key_format(period);
+        let kept = periods.contains(&period).then(|| match period {
+            UsagePeriod::Month => oldest_month.format(format).to_string(),
+            _ => now.format(format).to_string(),
+        });
+        (prefix, kept)
+    }).collect();
+
+    // found with a read transaction, which does not wait for the writers
+    let mut expired = Vec::new();
+    for entry in db.begin_read()?.open_table(USAGE)?.iter()? {
+        let (key, _) = entry?;
+        let key = key.value();
+        let mut parts = key.splitn(3, ':');
+        let (Some(prefix), Some(time)) = (parts.next(), parts.next()) else {
+            continue;
+        };
+        let keep = match oldest.iter().find(|(p, _)| *p == prefix) {
+            Some((_, Some(oldest))) => time >= oldest.as_str(),
+            Some((_, None)) => false,
+            None => true,
+        };
+        if !keep {
+            expired.push(key.to_string());
+        }
+    }
+    delete_in_batches(db, USAGE, &expired)
+}
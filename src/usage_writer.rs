@@ -0,0 +1,155 @@
+// Copyright (c) 2025 Sébastien Campion, FOSS4. All rights reserved.
+//
+// This software is provided under the Commons Clause License Condition v1.0.
+// See the LICENSE file for full license details.
+
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use log::{error, warn};
+use redb::Database;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{sync_channel, Receiver, RecvTimeoutError, SyncSender, TrySendError};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::config::UsagePeriod;
+use crate::cost::add_cost;
+use crate::decisions::{write_decision, Decision};
+use crate::token_limit::update_usage_periods;
+
+/// Usages waiting to be written before the requests logging them wait
+const QUEUE_SIZE: usize = 10_000;
+
+/// Tokens and cost of one request, added to the user's totals
+pub struct UsageDelta {
+    pub user: String,
+    /// Location of the request, for its daily cost on the location
+    pub location: String,
+    pub time: DateTime<Utc>,
+    pub periods: Vec<UsagePeriod>,
+    pub input_tokens: u64,
+    pub output_tokens: u64,
+    pub cents: f64,
+}
+
+/// Written by the usage writer
+enum Record {
+    Usage(UsageDelta),
+    Decision(Decision),
+}
+
+/// Adds the usage of the requests, and the decisions of the refused ones, to the database from a
+/// dedicated thread, committing the records received within `flush_interval`, at most
+/// `batch_size`, in one write transaction.
+pub struct UsageWriter {
+    sender: SyncSender<Record>,
+    /// Usages queued and not yet committed
+    pending: Arc<AtomicUsize>,
+}
+
+impl UsageWriter {
+    /// Start the writer thread
+    pub fn start(db: Arc<Database>, flush_interval: Duration, batch_size: usize) -> Result<Self> {
+        let (sender, receiver) = sync_channel(QUEUE_SIZE);
+        let pending = Arc::new(AtomicUsize::new(0));
+        let committed = pending.clone();
+        std::thread::Builder::new()
+            .name("usage-writer".to_string())
+            .spawn(move || write_usage(&db, receiver, flush_interval, batch_size, &committed))?;
+        Ok(Self { sender, pending })
+    }
+
+    /// Number of usages not yet committed
+    pub fn pending(&self) -> usize {
+        self.pending.load(Ordering::SeqCst)
+    }
+
+    /// Queue the usage, waiting for room when the writer fell behind rather than losing it
+    pub async fn record(&self, delta: UsageDelta) {
+        self.send(Record::Usage(delta)).await;
+    }
+
+    /// Queue the decision of a refused request, like a usage
+    pub async fn record_decision(&self, decision: Decision) {
+        self.send(Record::Decision(decision)).await;
+    }
+
+    async fn send(&self, record: Record) {
+        self.pending.fetch_add(1, Ordering::SeqCst);
+        let sent = match self.sender.try_send(record) {
+            Ok(()) => true,
+            // wait off the runtime, the other requests of the worker go on meanwhile
+            Err(TrySendError::Full(record)) => {
+                warn!("Usage queue full, waiting for the writer");
+                let sender = self.sender.clone();
+                tokio::task::spawn_blocking(move || sender.send(record).map_err(|e| lost(&e.0)).is_ok())
+                    .await
+                    .unwrap_or_else(|e| {
+                        error!("Usage queue wait failed, losing a usage or decision: {}", e);
+                        false
+                    })
+            }
+            Err(TrySendError::Disconnected(record)) => {
+                lost(&record);
+                false
+            }
+        };
+        if !sent {
+            self.pending.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+}
+
+fn lost(record: &Record) {
+    error!("Usage writer stopped, losing {}", describe(record));
+}
+
+/// Commit the records in batches as they come
+fn write_usage(db: &Database, receiver: Receiver<Record>, flush_interval: Duration, batch_size: usize, pending: &AtomicUsize) {
+    while let Ok(first) = receiver.recv() {
+        let deadline = Instant::now() + flush_interval;
+        let mut batch = vec![first];
+        while batch.len() < batch_size {
+            match receiver.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+                Ok(record) => batch.push(record),
+                Err(RecvTimeoutError::Timeout) | Err(RecvTimeoutError::Disconnected) => break,
+            }
+        }
+        // one failing record must not take the others of its batch down with it
+        if let Err(e) = commit_batch(db, &batch) {
+            warn!("Failed to record the usage or decision of {} requests at once, retrying one by one: {}", batch.len(), e);
+            for record in &batch {
+                if let Err(e) = commit_batch(db, std::slice::from_ref(record)) {
+                    error!("Failed to record {}: {}", describe(record), e);
+                }
+            }
+        }
+        pending.fetch_sub(batch.len(), Ordering::SeqCst);
+    }
+}
+
+/// What is lost with the record, for the logs
+fn describe(record: &Record) -> String {
+    match record {
+        Record::Usage(delta) => format!("{} input and {} output tokens of user {}",
+            delta.input_tokens, delta.output_tokens, delta.user),
+        Record::Decision(decision) => format!("the decision of request {}", decision.request_id),
+    }
+}
+
+fn commit_batch(db: &Database, batch: &[Record]) -> Result<()> {
+    let write_txn = db.begin_write()?;
+    for record in batch {
+        match record {
+            Record::Usage(delta) => {
+                update_usage_periods(&write_txn, &delta.user, delta.time, &delta.periods, delta.input_tokens, delta.output_tokens)?;
+                if delta.cents > 0.0 {
+                    add_cost(&write_txn, &delta.user, &delta.location, delta.time, delta.cents)?;
+                }
+            }
+            Record::Decision(decision) => write_decision(&write_txn, decision)?,
+        }
+    }
+    write_txn.commit()?;
+    Ok(())
+}
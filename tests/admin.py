@@ -1,6 +1,8 @@
 
 import logging
+import time
 import uuid
+from concurrent.futures import ThreadPoolExecutor
 import requests
 import yaml
 
@@ -11,6 +13,8 @@ with open('conf.yml') as f:
     config = yaml.safe_load(f)
 
 ADMIN_URL = f"http://{config['admin_host']}:{config['admin_port']}"
+ADMIN_HEADERS = {'Authorization': f"Bearer {config['admin_secret']}"}
+GATEWAY_URL = f"http://{config['host']}:{config['port']}"
 TEST_TOKENS = {
     str(uuid.uuid4()): "test_user1",
     str(uuid.uuid4()): "test_user2"
@@ -20,7 +24,7 @@ def setup_module():
     """Setup module-level test fixtures."""
     # Create test tokens
     token_data = {"tokens": TEST_TOKENS}
-    response = requests.post(f'{ADMIN_URL}/tokens', json=token_data)
+    response = requests.post(f'{ADMIN_URL}/tokens', headers=ADMIN_HEADERS, json=token_data)
     log.debug("Setup response: %s", response.text)
     assert response.status_code == 200, "Failed to create test tokens"
 
@@ -28,7 +32,7 @@ def teardown_module():
     """Teardown module-level test fixtures."""
     # Delete test tokens
     token_data = {"tokens": list(TEST_TOKENS.keys())}
-    response = requests.delete(f'{ADMIN_URL}/tokens', json=token_data)
+    response = requests.delete(f'{ADMIN_URL}/tokens', headers=ADMIN_HEADERS, json=token_data)
     assert response.status_code == 200, "Failed to delete test tokens"
 
 def test_create_tokens():
@@ -36,11 +40,11 @@ def test_create_tokens():
     new_token = str(uuid.uuid4())
     token_data = {"tokens": {new_token: "new_user"}}
     
-    response = requests.post(f'{ADMIN_URL}/tokens', json=token_data)
+    response = requests.post(f'{ADMIN_URL}/tokens', headers=ADMIN_HEADERS, json=token_data)
     assert response.status_code == 200, "Failed to create new token"
     
     # Verify token was created
-    response = requests.get(f'{ADMIN_URL}/tokens')
+    response = requests.get(f'{ADMIN_URL}/tokens', headers=ADMIN_HEADERS)
     assert response.status_code == 200
     tokens = response.json()
     all_tokens = [list(d.keys())[0] for d in tokens]
@@ -50,22 +54,137 @@ def test_delete_tokens():
     """Test deleting tokens."""
     # Create a token to delete
     temp_token = str(uuid.uuid4())
-    requests.post(f'{ADMIN_URL}/tokens', json={"tokens": {temp_token: "temp_user"}})
+    requests.post(f'{ADMIN_URL}/tokens', headers=ADMIN_HEADERS, json={"tokens": {temp_token: "temp_user"}})
     
     # Delete the token
-    response = requests.delete(f'{ADMIN_URL}/tokens', json={"tokens": [temp_token]})
+    response = requests.delete(f'{ADMIN_URL}/tokens', headers=ADMIN_HEADERS, json={"tokens": [temp_token]})
     assert response.status_code == 200, "Failed to delete token"
     
     # Verify token was deleted
-    response = requests.get(f'{ADMIN_URL}/tokens')
+    response = requests.get(f'{ADMIN_URL}/tokens', headers=ADMIN_HEADERS)
     assert response.status_code == 200
     tokens = response.json()
     all_tokens = [list(d.keys())[0] for d in tokens]
     assert temp_token not in all_tokens,  "Token was not deleted"
 
+def test_deleted_token_rejected():
+    """Test a deleted token is rejected even after being cached by the gateway."""
+    temp_token = str(uuid.uuid4())
+    requests.post(f'{ADMIN_URL}/tokens', headers=ADMIN_HEADERS, json={"tokens": {temp_token: "temp_user"}})
+    headers = {'Authorization': f'Bearer {temp_token}'}
+
+    response = requests.post(f'{GATEWAY_URL}/echo/balanced', headers=headers, json={"hello": "world"})
+    assert response.status_code == 200, "Token should be accepted"
+
+    requests.delete(f'{ADMIN_URL}/tokens', headers=ADMIN_HEADERS, json={"tokens": [temp_token]})
+    response = requests.post(f'{GATEWAY_URL}/echo/balanced', headers=headers, json={"hello": "world"})
+    assert response.status_code == 401, "Deleted token should be rejected"
+
+def test_token_expiry():
+    """Test expired tokens are rejected while unexpired ones still work."""
+    expired, valid = str(uuid.uuid4()), str(uuid.uuid4())
+    token_data = {"tokens": {
+        expired: {"user": "temp_user", "expires_at": "2020-01-01T00:00:00Z"},
+        valid: {"user": "temp_user", "expires_at": "2999-01-01T00:00:00Z"},
+    }}
+    response = requests.post(f'{ADMIN_URL}/tokens', headers=ADMIN_HEADERS, json=token_data)
+    assert response.status_code == 200, "Failed to create tokens with expiry"
+    try:
+        for token, status in ((expired, 401), (valid, 200)):
+            headers = {'Authorization': f'Bearer {token}'}
+            response = requests.post(f'{GATEWAY_URL}/echo/balanced', headers=headers, json={"hello": "world"})
+            assert response.status_code == status
+    finally:
+        requests.delete(f'{ADMIN_URL}/tokens', headers=ADMIN_HEADERS, json={"tokens": [expired, valid]})
+
+def test_invalid_token_expiry():
+    """Test an unparsable expiry is rejected."""
+    token_data = {"tokens": {str(uuid.uuid4()): {"user": "temp_user", "expires_at": "tomorrow"}}}
+    response = requests.post(f'{ADMIN_URL}/tokens', headers=ADMIN_HEADERS, json=token_data)
+    assert response.status_code == 400
+
+def test_generate_and_revoke_token():
+    """Test generating a random token for a user and revoking it by path."""
+    response = requests.post(f'{ADMIN_URL}/tokens', headers=ADMIN_HEADERS, json={"user": "generated_user"})
+    assert response.status_code == 200, "Failed to generate token"
+    token = response.json()["token"]
+    assert response.json()["user"] == "generated_user"
+
+    headers = {'Authorization': f'Bearer {token}'}
+    response = requests.post(f'{GATEWAY_URL}/echo/balanced', headers=headers, json={"hello": "world"})
+    assert response.status_code == 200, "Generated token should be accepted"
+
+    response = requests.delete(f'{ADMIN_URL}/tokens/{token}', headers=ADMIN_HEADERS)
+    assert response.status_code == 200, "Failed to revoke token"
+    response = requests.delete(f'{ADMIN_URL}/tokens/{token}', headers=ADMIN_HEADERS)
+    assert response.status_code == 404, "Revoking twice should report a missing token"
+
+    response = requests.post(f'{GATEWAY_URL}/echo/balanced', headers=headers, json={"hello": "world"})
+    assert response.status_code == 401, "Revoked token should be rejected"
+
+def test_rotate_tokens():
+    """Test listing a user's tokens and rotating them with a grace period."""
+    user = f"rotate_{uuid.uuid4().hex[:8]}"
+    old = requests.post(f'{ADMIN_URL}/tokens', headers=ADMIN_HEADERS, json={"user": user}).json()["token"]
+    other = requests.post(f'{ADMIN_URL}/tokens', headers=ADMIN_HEADERS, json={"user": user}).json()["token"]
+    old_headers = {'Authorization': f'Bearer {old}'}
+    # cache the old token in the gateway before rotating
+    assert requests.post(f'{GATEWAY_URL}/echo/balanced', headers=old_headers, json={}).status_code == 200
+
+    response = requests.get(f'{ADMIN_URL}/users/{user}/tokens', headers=ADMIN_HEADERS)
+    assert response.status_code == 200
+    assert {t["token"]: t["expires_at"] for t in response.json()["tokens"]} == {old: None, other: None}
+
+    response = requests.post(f'{ADMIN_URL}/users/{user}/tokens/rotate', headers=ADMIN_HEADERS, json={"grace_seconds": 3600})
+    assert response.status_code == 200
+    new = response.json()["token"]
+    assert {t["token"] for t in response.json()["rotated"]} == {old, other}
+
+    tokens = requests.get(f'{ADMIN_URL}/users/{user}/tokens', headers=ADMIN_HEADERS).json()["tokens"]
+    expiries = {t["token"]: t["expires_at"] for t in tokens}
+    assert expiries[new] is None
+    assert expiries[old] and expiries[other]
+    # both the new and the old tokens work during the grace period
+    for token in (new, old):
+        response = requests.post(f'{GATEWAY_URL}/echo/balanced', headers={'Authorization': f'Bearer {token}'}, json={})
+        assert response.status_code == 200
+
+    # without a grace period the previous tokens stop working at once
+    response = requests.post(f'{ADMIN_URL}/users/{user}/tokens/rotate', headers=ADMIN_HEADERS, json={"grace_seconds": 0})
+    assert response.status_code == 200
+    assert requests.post(f'{GATEWAY_URL}/echo/balanced', headers=old_headers, json={}).status_code == 401
+    newest = response.json()["token"]
+    response = requests.post(f'{GATEWAY_URL}/echo/balanced', headers={'Authorization': f'Bearer {newest}'}, json={})
+    assert response.status_code == 200
+
+    requests.delete(f'{ADMIN_URL}/tokens', headers=ADMIN_HEADERS, json={"tokens": [old, other, new, newest]})
+
+def test_rotate_invalid_grace():
+    response = requests.post(f'{ADMIN_URL}/users/someone/tokens/rotate', headers=ADMIN_HEADERS, json={"grace_seconds": "soon"})
+    assert response.status_code == 400
+
+def test_admin_secret_required():
+    """Test every admin endpoint rejects a missing or wrong admin secret."""
+    for headers in ({}, {'Authorization': 'Bearer wrong-secret'}):
+        assert requests.get(f'{ADMIN_URL}/usage', headers=headers).status_code == 401
+        assert requests.get(f'{ADMIN_URL}/usage/daily', headers=headers).status_code == 401
+        assert requests.get(f'{ADMIN_URL}/usage', headers=headers, params={"user": "all"}).status_code == 401
+        assert requests.get(f'{ADMIN_URL}/cost', headers=headers).status_code == 401
+        assert requests.get(f'{ADMIN_URL}/nonexistent', headers=headers).status_code == 401
+        assert requests.delete(f'{ADMIN_URL}/signing_clients', headers=headers, json={"clients": []}).status_code == 401
+        assert requests.get(f'{ADMIN_URL}/tokens', headers=headers).status_code == 401
+        assert requests.post(f'{ADMIN_URL}/tokens', headers=headers, json={"user": "intruder"}).status_code == 401
+        assert requests.delete(f'{ADMIN_URL}/tokens/{list(TEST_TOKENS)[0]}', headers=headers).status_code == 401
+        assert requests.post(f'{ADMIN_URL}/credentials', headers=headers, json={"credentials": {"intruder": "x"}}).status_code == 401
+        assert requests.get(f'{ADMIN_URL}/users/test_user1/tokens', headers=headers).status_code == 401
+        assert requests.post(f'{ADMIN_URL}/users/test_user1/tokens/rotate', headers=headers, json={}).status_code == 401
+    # the test token survived the unauthorized revoke
+    response = requests.get(f'{ADMIN_URL}/tokens', headers=ADMIN_HEADERS)
+    assert list(TEST_TOKENS)[0] in [list(d.keys())[0] for d in response.json()]
+
 def test_list_tokens():
     """Test listing all tokens."""
-    response = requests.get(f'{ADMIN_URL}/tokens')
+    response = requests.get(f'{ADMIN_URL}/tokens', headers=ADMIN_HEADERS)
     assert response.status_code == 200, "Failed to list tokens"
     
     tokens = response.json()
@@ -73,12 +192,29 @@ def test_list_tokens():
     all_tokens = [list(d.keys())[0] for d in tokens]
     assert all(token in all_tokens for token in TEST_TOKENS.keys()), "Test tokens missing from list"
 
+def test_login():
+    """Test exchanging credentials for a bearer token."""
+    username = f"login_{uuid.uuid4().hex[:8]}"
+    response = requests.post(f'{ADMIN_URL}/credentials', headers=ADMIN_HEADERS, json={"credentials": {username: "s3cret"}})
+    assert response.status_code == 200, "Failed to set credentials"
+
+    response = requests.post(f'{GATEWAY_URL}/login', json={"username": username, "password": "wrong"})
+    assert response.status_code == 401, "Wrong password should be rejected"
+
+    response = requests.post(f'{GATEWAY_URL}/login', json={"username": username, "password": "s3cret"})
+    assert response.status_code == 200, "Failed to login"
+    token = response.json()["token"]
+
+    response = requests.get(f'{ADMIN_URL}/tokens', headers=ADMIN_HEADERS)
+    all_tokens = {list(d.keys())[0]: list(d.values())[0] for d in response.json()}
+    assert all_tokens.get(token) == username, "Minted token not found in token list"
+
 def test_usage_stats():
     """Test retrieving usage statistics."""
     periods = ["minutely", "hourly", "daily", "weekly", "monthly"]
     
     for period in periods:
-        response = requests.get(f'{ADMIN_URL}/usage/{period}')
+        response = requests.get(f'{ADMIN_URL}/usage/{period}', headers=ADMIN_HEADERS)
         assert response.status_code == 200, f"Failed to get {period} usage stats"
         
         stats = response.json()
@@ -89,17 +225,17 @@ def test_usage_stats():
 def test_invalid_token_operations():
     """Test invalid token operations."""
     # Test creating invalid token
-    response = requests.post(f'{ADMIN_URL}/tokens', json={"tokens": {"": "invalid"}})
+    response = requests.post(f'{ADMIN_URL}/tokens', headers=ADMIN_HEADERS, json={"tokens": {"": "invalid"}})
     assert response.status_code != 200, "Should reject empty token"
     
     # Test token that's too short
     short_token = "a" * 31  # 31 characters
-    response = requests.post(f'{ADMIN_URL}/tokens', json={"tokens": {short_token: "short_token_user"}})
+    response = requests.post(f'{ADMIN_URL}/tokens', headers=ADMIN_HEADERS, json={"tokens": {short_token: "short_token_user"}})
     assert response.status_code == 400, "Should reject token shorter than 32 characters"
     assert "Token is too short" in response.text, "Error message should indicate token is too short"
     
     # Test deleting non-existent token
-    response = requests.delete(f'{ADMIN_URL}/tokens', json={"tokens": ["non-existent-token"]})
+    response = requests.delete(f'{ADMIN_URL}/tokens', headers=ADMIN_HEADERS, json={"tokens": ["non-existent-token"]})
     assert response.status_code == 200, "Deleting non-existent token should succeed"
 
 def test_static_assets():
@@ -119,12 +255,12 @@ def test_static_assets():
 def test_invalid_routes():
     """Test handling of invalid routes."""
     # Test non-existent route
-    response = requests.get(f'{ADMIN_URL}/nonexistent')
+    response = requests.get(f'{ADMIN_URL}/nonexistent', headers=ADMIN_HEADERS)
     assert response.status_code == 404
     assert response.json()['error'] == 'Not Found'
     
     # Test invalid method
-    response = requests.post(f'{ADMIN_URL}/usage')
+    response = requests.post(f'{ADMIN_URL}/usage', headers=ADMIN_HEADERS)
     assert response.status_code == 404
 
 def test_invalid_json():
@@ -133,20 +269,78 @@ def test_invalid_json():
     response = requests.post(
         f'{ADMIN_URL}/tokens',
         data='{"invalid": json}',
-        headers={'Content-Type': 'application/json'}
+        headers={**ADMIN_HEADERS, 'Content-Type': 'application/json'}
     )
     assert response.status_code == 400
     assert 'Invalid JSON' in response.text
 
+def test_usage_periods():
+    """Test a request is counted in each configured period, and each period filter finds it."""
+    token, user = next(iter(TEST_TOKENS.items()))
+    response = requests.post(f'{GATEWAY_URL}/echo/openai', headers={'Authorization': f'Bearer {token}'},
+                             json={"usage": {"prompt_tokens": 2, "completion_tokens": 1}})
+    assert response.status_code == 200
+    time.sleep(0.5)  # usage is written once the response has been sent
+
+    prefixes = {"minutely": "M", "hourly": "H", "daily": "d", "weekly": "W", "monthly": "m"}
+    periods = config.get('usage_periods', ["minute", "hour", "day", "week", "month"])
+    for (period, prefix), name in zip(prefixes.items(), ["minute", "hour", "day", "week", "month"]):
+        keys = [key for entry in requests.get(f'{ADMIN_URL}/usage/{period}', headers=ADMIN_HEADERS).json() for key in entry]
+        assert all(key.startswith(f"{prefix}:") for key in keys)
+        user_keys = [key for key in keys if key.endswith(f":{user}:in")]
+        assert bool(user_keys) == (name in periods), f"{period}: {keys}"
+
+def test_usage_totals():
+    """Test the totals of a user for the current period, with the monthly cost."""
+    token, user = list(TEST_TOKENS.items())[1]
+    usage = {"prompt_tokens": 1000, "completion_tokens": 1000}
+    response = requests.post(f'{GATEWAY_URL}/echo/openai', headers={'Authorization': f'Bearer {token}'}, json={"usage": usage})
+    assert response.status_code == 200
+    time.sleep(0.5)  # usage is written once the response has been sent
+
+    response = requests.get(f'{ADMIN_URL}/usage', headers=ADMIN_HEADERS, params={"user": user, "period": "day"})
+    assert response.status_code == 200
+    totals = response.json()
+    assert totals['user'] == user
+    assert (totals['input_tokens'], totals['output_tokens']) == (1000, 1000)
+    assert 'cost' not in totals
+
+    # /echo/openai costs 0.5 and 1.5 USD per 1k input and output tokens
+    totals = requests.get(f'{ADMIN_URL}/usage', headers=ADMIN_HEADERS, params={"user": user}).json()
+    assert totals['period'] == "month"
+    assert totals['cost'] == 2.0
+
+    response = requests.get(f'{ADMIN_URL}/usage', headers=ADMIN_HEADERS, params={"user": "all", "period": "day"})
+    assert response.json()['users'][user] == {"input_tokens": 1000, "output_tokens": 1000}
+
+    unknown = requests.get(f'{ADMIN_URL}/usage', headers=ADMIN_HEADERS, params={"user": "nobody", "period": "day"}).json()
+    assert (unknown['input_tokens'], unknown['output_tokens']) == (0, 0)
+    assert requests.get(f'{ADMIN_URL}/usage', headers=ADMIN_HEADERS, params={"period": "year"}).status_code == 400
+
+def test_parallel_usage():
+    """Test the usage of requests running in parallel adds up."""
+    token, user = str(uuid.uuid4()), "parallel_user"
+    assert requests.post(f'{ADMIN_URL}/tokens', headers=ADMIN_HEADERS, json={"tokens": {token: user}}).status_code == 200
+    usage = {"prompt_tokens": 3, "completion_tokens": 2}
+    send = lambda _: requests.post(f'{GATEWAY_URL}/echo/openai', headers={'Authorization': f'Bearer {token}'}, json={"usage": usage})
+    try:
+        with ThreadPoolExecutor(10) as pool:
+            assert [r.status_code for r in pool.map(send, range(20))] == [200] * 20
+        time.sleep(0.5)  # usage is written once the response has been sent
+        totals = requests.get(f'{ADMIN_URL}/usage', headers=ADMIN_HEADERS, params={"user": user, "period": "day"}).json()
+        assert (totals['input_tokens'], totals['output_tokens']) == (60, 40)
+    finally:
+        requests.delete(f'{ADMIN_URL}/tokens', headers=ADMIN_HEADERS, json={"tokens": [token]})
+
 def test_usage_stats_edge_cases():
     """Test edge cases for usage statistics."""
     # Test empty usage stats
-    response = requests.get(f'{ADMIN_URL}/usage')
+    response = requests.get(f'{ADMIN_URL}/usage', headers=ADMIN_HEADERS)
     assert response.status_code == 200
     assert isinstance(response.json(), list)
     
     # Test invalid period
-    response = requests.get(f'{ADMIN_URL}/usage/invalid')
+    response = requests.get(f'{ADMIN_URL}/usage/invalid', headers=ADMIN_HEADERS)
     assert response.status_code == 404
 
 
@@ -175,3 +369,16 @@ def test_usage_stats_edge_cases():
 #         pass  # Expected behavior
 #
 # [2025-01-23T12:06:36Z ERROR pingora_core::apps::http_app] HTTP server fails to read from downstream:  InvalidHTTPHeader context: buf: : \"user\", \"4a6ded51-06ae-45e6-b5d7-185364ea71c1\": \"user\", \"0c3f6e51-dab6-433a-8e39-0117876fa5a1\": \"user\", \"e9de0ff1-1359-44d9-95fe-454acd02f6cc\": \"user\", \"3d9a7f01-ca08-4fc5-b247-f3fee7dca23d\": \"user\", \"2aba677b-84d3-49f6-b581-b7f6050fd0c0\": \"user\", \"ba06d8e4-cc1b-4247-8e7d-d7fe3f361c8c\": \"user\", \"ad6e9d66-8522-4449-8f78-cdcdb993a86f\": \"user\", \"67fd8f87-8c16-4a0f-a91f-90f409f607cb\": \"user\", \"7fa84435-b2e7-4b6d-8757-8eb127614e62\": \"user\", \"4bfefe96-12a2-419a-9ab1-1874101d757b\": \"user\", \"bfadbdb0-f848-46f4-b16b-6a22c563add7\": \"user\", \"d00202c3-f84b-4546-b342-d6ae895c64b2\": \"user\", \"60c736ab-fd2f-44e7-8375-9af3186e0bd3\": \"user\", \"7a840fc7-62f9-4ebf-829c-306add182861\": \"user\", \"916a4fe3-dc49-431d-a32f-1a83d7b870df\": \"user\", \"70afd305-0194-4976-ade6-7e1b16b6410c\": \"user\", \"9b494da0-7b1c-4fbf-864c-bfd3a0880d56\": \"user\", \"faec0859-1f65-4836-981e-3f42e9fe249b\": \"user\", \"eac0833b-a2a1-441e-9531-4ffc2fb909a6\": \"user\"}} cause: invalid HTTP version
+
+def test_metrics_json():
+    """Test the Prometheus metrics are exposed as JSON by the admin API."""
+    requests.post(f'{GATEWAY_URL}/echo/openai', headers={'Authorization': f'Bearer {list(TEST_TOKENS)[0]}'},
+                  json={"object": "chat.completion", "choices": [], "usage": {"prompt_tokens": 1, "completion_tokens": 1}})
+    response = requests.get(f'{ADMIN_URL}/metrics-json', headers=ADMIN_HEADERS)
+    assert response.status_code == 200
+    metrics = response.json()
+    assert metrics['maintenance_mode']['type'] == 'gauge'
+    assert metrics['maintenance_mode']['values'] == [{'labels': {}, 'value': 0.0}]
+    assert metrics['req_counter']['type'] == 'counter'
+    assert metrics['req_counter']['values'][0]['value'] >= 1
+    assert requests.get(f'{ADMIN_URL}/metrics-json').status_code == 401
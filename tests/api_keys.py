@@ -0,0 +1,90 @@
+"""Rotation of the API keys of a model, setting aside the keys the provider rate limits."""
+import json
+import threading
+import time
+import uuid
+from http.server import BaseHTTPRequestHandler, HTTPServer
+
+import requests
+import yaml
+
+# Load configuration
+with open('conf.yml') as f:
+    config = yaml.safe_load(f)
+
+ADMIN_URL = f"http://{config['admin_host']}:{config['admin_port']}"
+ADMIN_HEADERS = {'Authorization': f"Bearer {config['admin_secret']}"}
+GATEWAY_URL = f"http://{config['host']}:{config['port']}"
+PROMETHEUS_URL = f"http://{config['prometheus_host']}:{config['prometheus_port']}"
+UPSTREAM_PORT = 6228
+LOCATION = "/echo/api-keys"
+MODEL = next(m for m in config['models'] if m['location'] == LOCATION)
+KEYS = MODEL['api_keys']
+TEST_TOKEN = str(uuid.uuid4())
+HEADERS = {'Authorization': f'Bearer {TEST_TOKEN}'}
+
+
+class KeysHandler(BaseHTTPRequestHandler):
+    """Upstream answering with the key it received, and a 429 to the keys listed in `limited`."""
+
+    def do_POST(self):
+        request = json.loads(self.rfile.read(int(self.headers.get('Content-Length', 0))))
+        key = self.headers['Authorization'].removeprefix('Bearer ')
+        status = 429 if key in request.get('limited', []) else 200
+        body = json.dumps({"key": key}).encode()
+        self.send_response(status)
+        self.send_header('Content-Type', 'application/json')
+        self.send_header('Content-Length', str(len(body)))
+        self.end_headers()
+        self.wfile.write(body)
+
+    def log_message(self, *args):
+        pass
+
+
+server = HTTPServer(('127.0.0.1', UPSTREAM_PORT), KeysHandler)
+
+
+def setup_module():
+    threading.Thread(target=server.serve_forever, daemon=True).start()
+    response = requests.post(f'{ADMIN_URL}/tokens', headers=ADMIN_HEADERS, json={"tokens": {TEST_TOKEN: "api_keys_user"}})
+    assert response.status_code == 200
+
+def teardown_module():
+    server.shutdown()
+    requests.delete(f'{ADMIN_URL}/tokens', headers=ADMIN_HEADERS, json={"tokens": [TEST_TOKEN]})
+
+def send(limited=()):
+    response = requests.post(f"{GATEWAY_URL}{LOCATION}", headers=HEADERS, json={"limited": list(limited)})
+    return response.status_code, response.json()['key']
+
+def test_keys_taken_in_turn():
+    time.sleep(MODEL['api_key_cooldown_secs'])
+    keys = [send()[1] for _ in range(2 * len(KEYS))]
+    start = KEYS.index(keys[0])
+    assert keys == (KEYS[start:] + KEYS[:start]) * 2
+
+def test_rate_limited_key_set_aside():
+    """Test a key answered a 429 is skipped until its cooldown is over."""
+    time.sleep(MODEL['api_key_cooldown_secs'])
+    while send()[1] != KEYS[-1]:
+        pass
+    status, key = send(limited=[KEYS[0]])
+    assert (status, key) == (429, KEYS[0])
+    keys = [send()[1] for _ in range(4)]
+    assert keys == [KEYS[1], KEYS[2], KEYS[1], KEYS[2]]
+    time.sleep(MODEL['api_key_cooldown_secs'])
+    assert KEYS[0] in [send()[1] for _ in range(len(KEYS))]
+
+def test_all_keys_rate_limited():
+    """Test the key whose cooldown ends first is used when they are all set aside."""
+    time.sleep(MODEL['api_key_cooldown_secs'])
+    statuses = [send(limited=KEYS)[0] for _ in range(len(KEYS) + 1)]
+    assert statuses == [429] * (len(KEYS) + 1)
+
+def test_key_metrics():
+    send()
+    metrics = requests.get(f'{PROMETHEUS_URL}/metrics').text
+    assert f'api_key_requests{{key="0",location="{LOCATION}"}}' in metrics
+    assert f'api_key_rate_limited{{key="0",location="{LOCATION}"}}' in metrics
+    assert 'sk-first' not in metrics
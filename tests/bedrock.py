@@ -0,0 +1,146 @@
+"""SigV4 signing of requests sent to a Bedrock upstream."""
+import hashlib
+import hmac
+import json
+import re
+import threading
+import time
+import uuid
+from http.server import BaseHTTPRequestHandler, HTTPServer
+from urllib.parse import parse_qsl, quote, urlsplit
+
+import requests
+import yaml
+
+# Load configuration
+with open('conf.yml') as f:
+    config = yaml.safe_load(f)
+
+ADMIN_URL = f"http://{config['admin_host']}:{config['admin_port']}"
+ADMIN_HEADERS = {'Authorization': f"Bearer {config['admin_secret']}"}
+GATEWAY_URL = f"http://{config['host']}:{config['port']}"
+UPSTREAM_PORT = 6218
+LOCATION = "/echo/bedrock"
+BEDROCK = next(m for m in config['models'] if m['location'] == LOCATION)
+TEST_TOKEN = str(uuid.uuid4())
+HEADERS = {'Authorization': f'Bearer {TEST_TOKEN}'}
+
+
+def canonical_request(method, path, query, headers, signed_headers, payload_hash):
+    """Canonical request of SigV4, path segments encoded again as for every service but S3."""
+    canonical_uri = '/'.join(quote(segment, safe='-_.~') for segment in path.split('/')) or '/'
+    canonical_query = '&'.join(f"{quote(k, safe='-_.~')}={quote(v, safe='-_.~')}"
+                               for k, v in sorted(parse_qsl(query, keep_blank_values=True)))
+    canonical_headers = ''.join(f"{name}:{headers[name].strip()}\n" for name in signed_headers)
+    return f"{method}\n{canonical_uri}\n{canonical_query}\n{canonical_headers}\n{';'.join(signed_headers)}\n{payload_hash}"
+
+def signature(secret, amz_date, region, service, request):
+    date = amz_date[:8]
+    string_to_sign = (f"AWS4-HMAC-SHA256\n{amz_date}\n{date}/{region}/{service}/aws4_request\n"
+                      f"{hashlib.sha256(request.encode()).hexdigest()}")
+    key = ('AWS4' + secret).encode()
+    for part in (date, region, service, 'aws4_request'):
+        key = hmac.new(key, part.encode(), hashlib.sha256).digest()
+    return hmac.new(key, string_to_sign.encode(), hashlib.sha256).hexdigest()
+
+
+class BedrockHandler(BaseHTTPRequestHandler):
+    """Upstream checking the signature the way AWS does, answering with its verdict."""
+
+    def do_POST(self):
+        body = self.rfile.read(int(self.headers.get('Content-Length', 0)))
+        headers = {k.lower(): v for k, v in self.headers.items()}
+        match = re.fullmatch(r"AWS4-HMAC-SHA256 Credential=([^/]+)/(\d{8})/([^/]+)/([^/]+)/aws4_request, "
+                             r"SignedHeaders=([^,]+), Signature=([0-9a-f]{64})", headers.get('authorization', ''))
+        verdict = {"valid": False, "body_length": len(body), "headers": headers}
+        if match:
+            key_id, _, region, service, signed_headers, sent = match.groups()
+            url = urlsplit(self.path)
+            request = canonical_request('POST', url.path, url.query, headers, signed_headers.split(';'),
+                                        hashlib.sha256(body).hexdigest())
+            expected = signature(BEDROCK['aws_secret_access_key'], headers['x-amz-date'], region, service, request)
+            verdict.update(valid=hmac.compare_digest(expected, sent), key_id=key_id, region=region,
+                           service=service, signed_headers=signed_headers, path=self.path)
+        response = json.dumps(verdict).encode()
+        self.send_response(200)
+        self.send_header('Content-Type', 'application/json')
+        self.send_header('Content-Length', str(len(response)))
+        self.end_headers()
+        self.wfile.write(response)
+
+    def log_message(self, *args):
+        pass
+
+
+server = HTTPServer(('127.0.0.1', UPSTREAM_PORT), BedrockHandler)
+
+
+def setup_module():
+    threading.Thread(target=server.serve_forever, daemon=True).start()
+    response = requests.post(f'{ADMIN_URL}/tokens', headers=ADMIN_HEADERS, json={"tokens": {TEST_TOKEN: "bedrock_user"}})
+    assert response.status_code == 200
+
+def teardown_module():
+    server.shutdown()
+    requests.delete(f'{ADMIN_URL}/tokens', headers=ADMIN_HEADERS, json={"tokens": [TEST_TOKEN]})
+
+def test_aws_test_vector():
+    """Test the verifier against the signed IAM ListUsers example of the AWS documentation."""
+    headers = {"content-type": "application/x-www-form-urlencoded; charset=utf-8",
+               "host": "iam.amazonaws.com", "x-amz-date": "20150830T123600Z"}
+    request = canonical_request("GET", "/", "Action=ListUsers&Version=2010-05-08", headers,
+                                ["content-type", "host", "x-amz-date"], hashlib.sha256(b"").hexdigest())
+    assert hashlib.sha256(request.encode()).hexdigest() == "f536975d06c0309214f805bb90ccff089219ecd68b2577efef23edd43b7e1a59"
+    assert signature("wJalrXUtnFEMI/K7MDENG+bPxRfiCYEXAMPLEKEY", "20150830T123600Z", "us-east-1", "iam", request) \
+        == "5d672d79c15b13162d9279b0855cfba6789a8edb4c82c400e06b5924a6f2b5d7"
+
+def test_signed_request():
+    """Test the upstream gets a valid signature over the body, and no gateway token."""
+    body = {"anthropic_version": "bedrock-2023-05-31", "max_tokens": 10, "messages": [{"role": "user", "content": "Hi"}]}
+    response = requests.post(f"{GATEWAY_URL}{LOCATION}", headers=HEADERS, json=body)
+    assert response.status_code == 200
+    verdict = response.json()
+    assert verdict['valid'], verdict
+    assert verdict['key_id'] == "AKIDEXAMPLE"
+    assert (verdict['region'], verdict['service']) == ("us-east-1", "bedrock")
+    assert verdict['signed_headers'] == "host;x-amz-content-sha256;x-amz-date"
+    assert verdict['path'] == "/model/anthropic.claude-3-haiku-20240307-v1:0/invoke"
+    assert verdict['body_length'] == len(json.dumps(body))
+    assert TEST_TOKEN not in json.dumps(verdict['headers'])
+
+def test_signed_body_too_large():
+    """Test bodies over the 64 KiB replay buffer are rejected before reaching the upstream."""
+    response = requests.post(f"{GATEWAY_URL}{LOCATION}", headers=HEADERS, data=b"x" * (65 * 1024))
+    assert response.status_code == 413
+    assert response.json()['error']['message'] == "Request body too large for a signed upstream"
+
+def test_signed_body_within_limit():
+    """Test a body just under the limit is read before signing and still forwarded whole."""
+    body = json.dumps({"prompt": "x" * (60 * 1024)})
+    response = requests.post(f"{GATEWAY_URL}{LOCATION}", headers=HEADERS, data=body)
+    verdict = response.json()
+    assert verdict['valid'], verdict
+    assert verdict['body_length'] == len(body)
+
+def test_signed_client_request():
+    """Test a body read to check the client signature is the one signed for the upstream too."""
+    client_id, secret = f"svc-{uuid.uuid4()}", uuid.uuid4().hex * 2
+    clients = {"clients": {client_id: {"user": "bedrock_user", "secret": secret}}}
+    assert requests.post(f'{ADMIN_URL}/signing_clients', headers=ADMIN_HEADERS, json=clients).status_code == 200
+    body = json.dumps({"max_tokens": 10, "messages": [{"role": "user", "content": "Hi"}]}).encode()
+    timestamp = str(int(time.time()))
+    headers = {'Content-Type': 'application/json', 'X-Client-Id': client_id, 'X-Timestamp': timestamp,
+               'X-Signature': hmac.new(secret.encode(), timestamp.encode() + b"." + body, hashlib.sha256).hexdigest()}
+    response = requests.post(f"{GATEWAY_URL}{LOCATION}", headers=headers, data=body)
+    verdict = response.json()
+    assert verdict['valid'], verdict
+    assert verdict['body_length'] == len(body)
+
+def test_transformed_body_signed():
+    """Test the signature covers the transformed body, sent with its new length."""
+    body = {"max_tokens": 10, "messages": [{"role": "user", "content": "Hi"}]}
+    response = requests.post(f"{GATEWAY_URL}{LOCATION}/transform", headers=HEADERS, json=body)
+    verdict = response.json()
+    assert verdict['valid'], verdict
+    sent = {"anthropic_version": "bedrock-2023-05-31", **body}
+    assert verdict['body_length'] == len(json.dumps(sent, separators=(',', ':')))
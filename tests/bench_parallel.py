@@ -0,0 +1,47 @@
+"""Measure gateway throughput with requests sent in parallel.
+
+Run against a running gateway: python tests/bench_parallel.py [requests] [workers]
+Each request records its token usage, so this also measures the database writes.
+"""
+import sys
+import time
+import uuid
+from concurrent.futures import ThreadPoolExecutor
+
+import requests
+import yaml
+
+with open('conf.yml') as f:
+    config = yaml.safe_load(f)
+
+ADMIN_URL = f"http://{config['admin_host']}:{config['admin_port']}"
+ADMIN_HEADERS = {'Authorization': f"Bearer {config['admin_secret']}"}
+API_URL = f"http://{config['host']}:{config['port']}/echo/openai"
+BODY = {"usage": {"prompt_tokens": 10, "completion_tokens": 10}}
+
+
+def main(count, workers):
+    token = str(uuid.uuid4())
+    assert requests.post(f'{ADMIN_URL}/tokens', headers=ADMIN_HEADERS, json={"tokens": {token: "bench_user"}}).status_code == 200
+    headers = {'Authorization': f'Bearer {token}'}
+    sessions = [requests.Session() for _ in range(workers)]
+
+    def send(i):
+        return sessions[i % workers].post(API_URL, headers=headers, json=BODY).status_code
+
+    try:
+        send(0)  # fill the auth cache
+        start = time.perf_counter()
+        with ThreadPoolExecutor(workers) as pool:
+            statuses = list(pool.map(send, range(count)))
+        elapsed = time.perf_counter() - start
+        assert statuses == [200] * count, set(statuses)
+        print(f"requests: {count}, workers: {workers}")
+        print(f"elapsed:    {elapsed:.3f} s")
+        print(f"throughput: {count / elapsed:.1f} req/s")
+    finally:
+        requests.delete(f'{ADMIN_URL}/tokens', headers=ADMIN_HEADERS, json={"tokens": [token]})
+
+
+if __name__ == '__main__':
+    main(int(sys.argv[1]) if len(sys.argv) > 1 else 1000, int(sys.argv[2]) if len(sys.argv) > 2 else 16)
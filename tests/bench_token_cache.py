@@ -0,0 +1,45 @@
+"""Measure per-request gateway latency with a valid bearer token.
+
+Run against a running gateway: python tests/bench_token_cache.py [requests]
+The first request for a token misses the auth cache; the following ones hit it.
+"""
+import statistics
+import sys
+import time
+import uuid
+
+import requests
+import yaml
+
+with open('conf.yml') as f:
+    config = yaml.safe_load(f)
+
+ADMIN_URL = f"http://{config['admin_host']}:{config['admin_port']}"
+ADMIN_HEADERS = {'Authorization': f"Bearer {config['admin_secret']}"}
+API_URL = f"http://{config['host']}:{config['port']}/echo/balanced"
+
+
+def main(count):
+    token = str(uuid.uuid4())
+    assert requests.post(f'{ADMIN_URL}/tokens', headers=ADMIN_HEADERS, json={"tokens": {token: "bench_user"}}).status_code == 200
+    session = requests.Session()
+    headers = {'Authorization': f'Bearer {token}'}
+    try:
+        latencies = []
+        for _ in range(count):
+            start = time.perf_counter()
+            response = session.post(API_URL, headers=headers, json={"hello": "world"})
+            latencies.append((time.perf_counter() - start) * 1000)
+            assert response.status_code == 200, response.status_code
+        latencies.sort()
+        print(f"requests: {count}")
+        print(f"mean:   {statistics.mean(latencies):.3f} ms")
+        print(f"median: {statistics.median(latencies):.3f} ms")
+        print(f"p99:    {latencies[int(count * 0.99) - 1]:.3f} ms")
+        print(f"max:    {latencies[-1]:.3f} ms")
+    finally:
+        requests.delete(f'{ADMIN_URL}/tokens', headers=ADMIN_HEADERS, json={"tokens": [token]})
+
+
+if __name__ == '__main__':
+    main(int(sys.argv[1]) if len(sys.argv) > 1 else 1000)
@@ -0,0 +1,172 @@
+"""Request bodies rewritten by the body_transform of /echo/transform, whose echo upstream returns what it got."""
+import json
+import time
+import uuid
+
+import requests
+import yaml
+
+# Load configuration
+with open('conf.yml') as f:
+    config = yaml.safe_load(f)
+
+ADMIN_URL = f"http://{config['admin_host']}:{config['admin_port']}"
+ADMIN_HEADERS = {'Authorization': f"Bearer {config['admin_secret']}"}
+GATEWAY_URL = f"http://{config['host']}:{config['port']}"
+API_URL = f"{GATEWAY_URL}/echo/transform"
+TEST_TOKEN = str(uuid.uuid4())
+HEADERS = {'Authorization': f'Bearer {TEST_TOKEN}'}
+
+
+def setup_module():
+    response = requests.post(f'{ADMIN_URL}/tokens', headers=ADMIN_HEADERS, json={"tokens": {TEST_TOKEN: "transform_user"}})
+    assert response.status_code == 200
+
+def teardown_module():
+    requests.delete(f'{ADMIN_URL}/tokens', headers=ADMIN_HEADERS, json={"tokens": [TEST_TOKEN]})
+
+def test_defaults_and_set():
+    """Test missing fields get their default and set fields are overwritten."""
+    response = requests.post(API_URL, headers=HEADERS, json={"messages": [], "stream": True})
+    assert response.status_code == 200
+    assert response.json() == {"messages": [], "max_tokens": 256, "temperature": 0.2, "stream": False}
+
+def test_client_values_kept():
+    """Test defaults do not replace the fields sent by the client."""
+    response = requests.post(API_URL, headers=HEADERS, json={"max_tokens": 10, "temperature": 1})
+    assert response.json() == {"max_tokens": 10, "temperature": 1, "stream": False}
+
+def test_rename():
+    """Test renamed fields take precedence over the default of their new name."""
+    response = requests.post(API_URL, headers=HEADERS, json={"max_completion_tokens": 42})
+    assert response.json() == {"max_tokens": 42, "temperature": 0.2, "stream": False}
+
+def test_invalid_json_untouched():
+    """Test bodies that are not JSON objects are forwarded as sent."""
+    for body in [b"not json {", b"[1, 2]"]:
+        response = requests.post(API_URL, headers=HEADERS, data=body)
+        assert response.status_code == 200
+        assert response.content == body
+
+def test_chunked_request():
+    """Test a chunked client body is transformed too."""
+    chunks = iter([b'{"messages":', b' []}'])
+    response = requests.post(API_URL, headers=HEADERS, data=chunks)
+    assert response.status_code == 200
+    assert json.loads(response.content)["max_tokens"] == 256
+
+def test_force_non_stream():
+    """Test force_non_stream turns streaming off, and sets it off when the client did not say."""
+    url = f"{GATEWAY_URL}/echo/nostream"
+    response = requests.post(url, headers=HEADERS, json={"messages": [], "stream": True})
+    assert response.status_code == 200
+    assert response.json() == {"messages": [], "stream": False}
+    response = requests.post(url, headers=HEADERS, json={"messages": []})
+    assert response.json() == {"messages": [], "stream": False}
+
+def test_max_output_tokens_cap():
+    """Test max_output_tokens_cap lowers the limits asking for more and fills in a missing one."""
+    url = f"{GATEWAY_URL}/echo/capped"
+    response = requests.post(url, headers=HEADERS, json={"messages": [], "max_tokens": 4096})
+    assert response.status_code == 200
+    assert response.json() == {"messages": [], "max_tokens": 100}
+    response = requests.post(url, headers=HEADERS, json={"max_tokens": 10})
+    assert response.json() == {"max_tokens": 10}
+    response = requests.post(url, headers=HEADERS, json={"max_completion_tokens": 500})
+    assert response.json() == {"max_completion_tokens": 100}
+    response = requests.post(url, headers=HEADERS, json={"messages": []})
+    assert response.json() == {"messages": [], "max_tokens": 100}
+    response = requests.post(url, headers=HEADERS, data=b"not json {")
+    assert response.status_code == 200
+    assert response.content == b"not json {"
+
+def test_parameter_limits_clamp():
+    """Test parameters out of range are clamped and missing ones get their default."""
+    url = f"{GATEWAY_URL}/echo/params"
+    response = requests.post(url, headers=HEADERS, json={"temperature": 1.8, "top_p": 0.99})
+    assert response.status_code == 200
+    assert response.json() == {"temperature": 1.0, "top_p": 0.9}
+    response = requests.post(url, headers=HEADERS, json={"temperature": -1, "top_p": 0.5})
+    assert response.json() == {"temperature": 0.0, "top_p": 0.5}
+    response = requests.post(url, headers=HEADERS, json={"messages": []})
+    assert response.json() == {"messages": [], "temperature": 0.7}
+    response = requests.post(url, headers=HEADERS, json={"temperature": "hot"})
+    assert response.status_code == 400
+    assert response.json()["error"]["message"] == "temperature must be a number"
+
+def test_parameter_limits_reject():
+    """Test reject mode answers 400 naming the parameter out of range."""
+    url = f"{GATEWAY_URL}/echo/params-strict"
+    response = requests.post(url, headers=HEADERS, json={"temperature": 2})
+    assert response.status_code == 400
+    assert response.json()["error"]["message"] == "temperature must be between 0 and 1"
+    response = requests.post(url, headers=HEADERS, json={"temperature": 0.5, "top_p": 1})
+    assert response.status_code == 200
+    assert response.json() == {"temperature": 0.5, "top_p": 1}
+
+def test_system_prompt_prepend():
+    """Test the system prompt goes before the client's, or first when the client sent none."""
+    url = f"{GATEWAY_URL}/echo/system-prompt"
+    user = {"role": "user", "content": "Hello"}
+    response = requests.post(url, headers=HEADERS, json={"messages": [user]})
+    assert response.json()["messages"] == [{"role": "system", "content": "Answer in French."}, user]
+
+    response = requests.post(url, headers=HEADERS, json={"messages": [{"role": "system", "content": "Be brief."}, user]})
+    assert response.json()["messages"] == [{"role": "system", "content": "Answer in French.\n\nBe brief."}, user]
+
+    parts = [{"type": "text", "text": "Be brief."}]
+    response = requests.post(url, headers=HEADERS, json={"messages": [{"role": "system", "content": parts}, user]})
+    assert response.json()["messages"][0]["content"] == [{"type": "text", "text": "Answer in French."}] + parts
+
+def test_system_prompt_override():
+    """Test the system prompt replaces every system message of the client."""
+    user = {"role": "user", "content": "Hello"}
+    body = {"messages": [{"role": "system", "content": "Answer in English."}, user, {"role": "system", "content": "Be rude."}]}
+    response = requests.post(f"{GATEWAY_URL}/echo/system-prompt/override", headers=HEADERS, json=body)
+    assert response.json()["messages"] == [{"role": "system", "content": "Answer in French."}, user]
+
+def test_system_prompt_anthropic():
+    """Test Anthropic bodies get the system prompt in their system field."""
+    url = f"{GATEWAY_URL}/echo/system-prompt/anthropic"
+    usage = {"input_tokens": 1, "output_tokens": 1}
+    messages = [{"role": "user", "content": "Hello"}]
+    response = requests.post(url, headers=HEADERS, json={"messages": messages, "usage": usage})
+    assert response.json() == {"system": "Answer in French.", "messages": messages, "usage": usage}
+
+    response = requests.post(url, headers=HEADERS, json={"system": "Be brief.", "messages": messages, "usage": usage})
+    assert response.json()["system"] == "Answer in French.\n\nBe brief."
+
+def test_system_prompt_non_chat_untouched():
+    """Test bodies without a messages array are forwarded as sent."""
+    for body in [{"prompt": "Once upon a time"}, {"input": "embed me"}]:
+        response = requests.post(f"{GATEWAY_URL}/echo/system-prompt", headers=HEADERS, json=body)
+        assert response.status_code == 200
+        assert response.json() == body
+
+COMPLETION = {
+    "object": "chat.completion",
+    "choices": [{"index": 0, "message": {"role": "assistant", "content": "Hello"}, "finish_reason": "stop"}],
+    "usage": {"prompt_tokens": 7, "completion_tokens": 2, "total_tokens": 9},
+}
+
+def usage_totals():
+    time.sleep(0.5)  # usage is written once the response has been sent
+    response = requests.get(f'{ADMIN_URL}/usage', headers=ADMIN_HEADERS, params={"user": "transform_user", "period": "day"})
+    return response.json()["input_tokens"], response.json()["output_tokens"]
+
+def test_response_transform():
+    """Test the response is rewrapped in the envelope of /echo/simple, its tokens still counted."""
+    before_in, before_out = usage_totals()
+    response = requests.post(f"{GATEWAY_URL}/echo/simple", headers=HEADERS, json=COMPLETION)
+    assert response.status_code == 200
+    assert response.json() == {"text": "Hello", "finish_reason": "stop", "input_tokens": 7, "output_tokens": 2}
+    assert usage_totals() == (before_in + 7, before_out + 2)
+
+def test_response_transform_missing_field():
+    usage = {"prompt_tokens": 1, "completion_tokens": 1}
+    response = requests.post(f"{GATEWAY_URL}/echo/simple", headers=HEADERS, json={"choices": [], "usage": usage})
+    assert response.json() == {"text": None, "finish_reason": None, "input_tokens": 1, "output_tokens": 1}
+
+def test_response_transform_invalid_json_untouched():
+    response = requests.post(f"{GATEWAY_URL}/echo/simple", headers=HEADERS, data=b'{"choices": [')
+    assert response.content == b'{"choices": ['
@@ -0,0 +1,58 @@
+"""Validation of a configuration file by --check-config, without starting the gateway."""
+import os
+import subprocess
+import tempfile
+
+import yaml
+
+# Load configuration
+with open('conf.yml') as f:
+    config = yaml.safe_load(f)
+
+GATEWAY_BIN = os.environ.get('BURGONET_BIN', 'target/debug/burgonet-gw')
+MODEL = {"location": "/checked", "model_name": "echo", "parser": "echo", "proxy_pass": "http://127.0.0.1:6193/echo"}
+
+
+def check(*models):
+    """Run --check-config on the configuration with the given models."""
+    with tempfile.TemporaryDirectory(dir='.') as directory:
+        conf_path = os.path.join(directory, 'conf.yml')
+        with open(conf_path, 'w') as f:
+            yaml.safe_dump({**config, 'models': [{**MODEL, **model} for model in models]}, f)
+        result = subprocess.run([GATEWAY_BIN, '-c', conf_path, '--check-config'], capture_output=True, text=True, timeout=10)
+    return result.returncode, result.stdout.splitlines(), conf_path
+
+def test_valid():
+    status, report, conf_path = check({})
+    assert status == 0
+    assert report == [f"Configuration {conf_path} is valid"]
+
+def test_invalid():
+    """Test the errors stopping the gateway at startup are reported."""
+    status, report, conf_path = check({"proxy_pass": "ftp://example.com"})
+    assert status == 1
+    assert report[0].startswith(f"Configuration {conf_path} is invalid: ")
+    assert "/checked" in report[0]
+
+def test_invalid_regex():
+    status, report, _ = check({"blacklist_regex": ["("]})
+    assert status == 1
+    assert "invalid blacklist_regex" in report[0]
+
+def test_unknown_parser():
+    """Test a parser name that is not known stops the gateway, with the known ones listed."""
+    status, report, conf_path = check({"parser": "gpt"})
+    assert status == 1
+    assert report[0].startswith(f'Configuration {conf_path} is invalid: Location /checked: unknown parser "gpt", expected one of ')
+    assert report[0].endswith(", ".join(["echo", "ollama", "deepseek", "llamacpp", "openai", "anthropic", "embeddings"]))
+
+def test_parser_not_set():
+    status, report, conf_path = check({"parser": ""})
+    assert status == 1
+    assert report[0].startswith(f'Configuration {conf_path} is invalid: Location /checked: parser not set, expected one of echo, ')
+
+def test_missing_api_key_variable():
+    """Test an `api_key` from a variable that is not set stops the gateway like `api_key_env`."""
+    status, report, conf_path = check({"api_key": "$BURGONET_NO_SUCH_KEY"})
+    assert status == 1
+    assert report == [f"Configuration {conf_path} is invalid: Location /checked: environment variable BURGONET_NO_SUCH_KEY for api_key not found"]
@@ -0,0 +1,122 @@
+"""Circuit breaker of a model whose upstream fails."""
+import json
+import threading
+import time
+import uuid
+from http.server import BaseHTTPRequestHandler, HTTPServer
+
+import requests
+import yaml
+
+# Load configuration
+with open('conf.yml') as f:
+    config = yaml.safe_load(f)
+
+ADMIN_URL = f"http://{config['admin_host']}:{config['admin_port']}"
+ADMIN_HEADERS = {'Authorization': f"Bearer {config['admin_secret']}"}
+GATEWAY_URL = f"http://{config['host']}:{config['port']}"
+PROMETHEUS_URL = f"http://{config['prometheus_host']}:{config['prometheus_port']}"
+UPSTREAM_PORT = 6214
+LOCATION = "/echo/breaker"
+BREAKER = next(m for m in config['models'] if m['location'] == LOCATION)
+TEST_TOKEN = str(uuid.uuid4())
+HEADERS = {'Authorization': f'Bearer {TEST_TOKEN}'}
+
+upstream = {'status': 200, 'calls': 0}
+
+
+class FlakyHandler(BaseHTTPRequestHandler):
+    """Upstream answering with the status currently set in `upstream`."""
+
+    def do_POST(self):
+        self.rfile.read(int(self.headers.get('Content-Length', 0)))
+        upstream['calls'] += 1
+        body = json.dumps({"status": upstream['status']}).encode()
+        self.send_response(upstream['status'])
+        self.send_header('Content-Type', 'application/json')
+        self.send_header('Content-Length', str(len(body)))
+        self.end_headers()
+        self.wfile.write(body)
+
+    def log_message(self, *args):
+        pass
+
+
+server = HTTPServer(('127.0.0.1', UPSTREAM_PORT), FlakyHandler)
+
+
+def setup_module():
+    threading.Thread(target=server.serve_forever, daemon=True).start()
+    response = requests.post(f'{ADMIN_URL}/tokens', headers=ADMIN_HEADERS, json={"tokens": {TEST_TOKEN: "breaker_user"}})
+    assert response.status_code == 200
+
+def teardown_module():
+    server.shutdown()
+    requests.delete(f'{ADMIN_URL}/tokens', headers=ADMIN_HEADERS, json={"tokens": [TEST_TOKEN]})
+
+def call():
+    return requests.post(f"{GATEWAY_URL}{LOCATION}", headers=HEADERS, json={})
+
+def breaker_state():
+    response = requests.get(f'{PROMETHEUS_URL}/metrics')
+    for line in response.text.splitlines():
+        if line.startswith(f'circuit_breaker_state{{location="{LOCATION}"}} '):
+            return int(float(line.split()[1]))
+    return None
+
+def close_circuit():
+    """Let a successful probe through if a previous test left the circuit open."""
+    upstream['status'] = 200
+    if breaker_state() in (1, 2):
+        time.sleep(BREAKER['circuit_breaker_cooldown_secs'] + 0.2)
+        assert call().status_code == 200
+
+def open_circuit():
+    close_circuit()
+
+    upstream['status'] = 500
+    for _ in range(BREAKER['circuit_breaker_threshold']):
+        assert call().status_code == 500
+
+def test_opens_after_consecutive_failures():
+    """Test the circuit opens after the threshold and rejects without calling the upstream."""
+    open_circuit()
+    assert breaker_state() == 1
+    calls = upstream['calls']
+    response = call()
+    assert response.status_code == 503
+    assert response.json()['error']['message'] == "Model temporarily unavailable after repeated upstream failures"
+    assert upstream['calls'] == calls
+
+def test_probe_success_closes():
+    """Test a successful probe after the cooldown closes the circuit."""
+    open_circuit()
+    upstream['status'] = 200
+    time.sleep(BREAKER['circuit_breaker_cooldown_secs'] + 0.2)
+    assert call().status_code == 200
+    assert breaker_state() == 0
+    assert call().status_code == 200
+
+def test_probe_failure_reopens():
+    """Test a failed probe opens the circuit again for another cooldown."""
+    open_circuit()
+    time.sleep(BREAKER['circuit_breaker_cooldown_secs'] + 0.2)
+    assert call().status_code == 500
+    assert breaker_state() == 1
+    assert call().status_code == 503
+    upstream['status'] = 200
+    time.sleep(BREAKER['circuit_breaker_cooldown_secs'] + 0.2)
+    assert call().status_code == 200
+
+def test_success_resets_failures():
+    """Test failures must be consecutive to open the circuit."""
+    close_circuit()
+    upstream['status'] = 500
+    assert call().status_code == 500
+    upstream['status'] = 200
+    assert call().status_code == 200
+    upstream['status'] = 500
+    assert call().status_code == 500
+    upstream['status'] = 200
+    assert call().status_code == 200
+    assert breaker_state() == 0
@@ -0,0 +1,114 @@
+"""Identical requests in flight sharing one upstream call, with coalesce_requests."""
+import json
+import threading
+import time
+import uuid
+from concurrent.futures import ThreadPoolExecutor
+from http.server import BaseHTTPRequestHandler, ThreadingHTTPServer
+
+import requests
+import yaml
+
+# Load configuration
+with open('conf.yml') as f:
+    config = yaml.safe_load(f)
+
+ADMIN_URL = f"http://{config['admin_host']}:{config['admin_port']}"
+ADMIN_HEADERS = {'Authorization': f"Bearer {config['admin_secret']}"}
+GATEWAY_URL = f"http://{config['host']}:{config['port']}"
+UPSTREAM_PORT = 6221
+LOCATION = "/echo/coalesced"
+TEST_TOKEN = str(uuid.uuid4())
+HEADERS = {'Authorization': f'Bearer {TEST_TOKEN}'}
+CALLERS = 5
+
+# upstream calls by request marker
+calls = {}
+calls_lock = threading.Lock()
+
+
+class SlowHandler(BaseHTTPRequestHandler):
+    """Upstream answering with `status` after the `delay` seconds of the request body."""
+
+    def do_POST(self):
+        request = json.loads(self.rfile.read(int(self.headers.get('Content-Length', 0))))
+        with calls_lock:
+            calls[request['marker']] = calls.get(request['marker'], 0) + 1
+        time.sleep(request['delay'])
+        body = json.dumps(request).encode()
+        try:
+            self.send_response(request.get('status', 200))
+            self.send_header('Content-Type', 'application/json')
+            self.send_header('Content-Length', str(len(body)))
+            self.end_headers()
+            self.wfile.write(body)
+        except OSError:
+            pass  # the client went away
+
+    def log_message(self, *args):
+        pass
+
+
+server = ThreadingHTTPServer(('127.0.0.1', UPSTREAM_PORT), SlowHandler)
+
+
+def setup_module():
+    threading.Thread(target=server.serve_forever, daemon=True).start()
+    response = requests.post(f'{ADMIN_URL}/tokens', headers=ADMIN_HEADERS, json={"tokens": {TEST_TOKEN: "coalesced_user"}})
+    assert response.status_code == 200
+
+def teardown_module():
+    server.shutdown()
+    requests.delete(f'{ADMIN_URL}/tokens', headers=ADMIN_HEADERS, json={"tokens": [TEST_TOKEN]})
+
+def post(body):
+    return requests.post(f"{GATEWAY_URL}{LOCATION}", headers=HEADERS, json=body, timeout=10)
+
+def post_together(bodies):
+    """Send the bodies at once, the first one slightly ahead so that it leads."""
+    with ThreadPoolExecutor(len(bodies)) as pool:
+        first = pool.submit(post, bodies[0])
+        time.sleep(0.2)
+        others = [pool.submit(post, body) for body in bodies[1:]]
+        return [first.result()] + [f.result() for f in others]
+
+def test_identical_requests_share_one_call():
+    """Test identical requests in flight get the response of a single upstream call."""
+    body = {"marker": str(uuid.uuid4()), "delay": 1}
+    responses = post_together([body] * CALLERS)
+    assert [r.status_code for r in responses] == [200] * CALLERS
+    assert all(r.json() == body for r in responses)
+    assert calls[body['marker']] == 1
+
+    leader = responses[0]
+    assert "X-Coalesced-With" not in leader.headers
+    for response in responses[1:]:
+        assert response.headers["X-Coalesced-With"] == leader.headers["X-Request-ID"]
+        assert response.headers["X-Request-ID"] != leader.headers["X-Request-ID"]
+        assert response.headers["Content-Type"] == "application/json"
+
+    # the flight is over, the next identical request calls the upstream again
+    assert post(body).status_code == 200
+    assert calls[body['marker']] == 2
+
+def test_errors_shared():
+    """Test the error response of the upstream is given to every waiting request."""
+    body = {"marker": str(uuid.uuid4()), "delay": 1, "status": 500}
+    responses = post_together([body] * 3)
+    assert [r.status_code for r in responses] == [500] * 3
+    assert calls[body['marker']] == 1
+
+def test_different_bodies_not_coalesced():
+    """Test requests with different bodies each call the upstream."""
+    bodies = [{"marker": str(uuid.uuid4()), "delay": 0.5} for _ in range(3)]
+    responses = post_together(bodies)
+    assert [r.json() for r in responses] == bodies
+    assert all(calls[body['marker']] == 1 for body in bodies)
+    assert all("X-Coalesced-With" not in r.headers for r in responses)
+
+def test_streamed_requests_not_coalesced():
+    """Test requests asking for a stream each call the upstream."""
+    body = {"marker": str(uuid.uuid4()), "delay": 0.5, "stream": True}
+    responses = post_together([body] * 3)
+    assert [r.status_code for r in responses] == [200] * 3
+    assert calls[body['marker']] == 3
@@ -0,0 +1,167 @@
+"""Compressed request and upstream response bodies, decoded by the gateway to be checked and counted."""
+import base64
+import gzip
+import json
+import threading
+import time
+import uuid
+from http.server import BaseHTTPRequestHandler, HTTPServer
+
+import requests
+import yaml
+
+# Load configuration
+with open('conf.yml') as f:
+    config = yaml.safe_load(f)
+
+ADMIN_URL = f"http://{config['admin_host']}:{config['admin_port']}"
+ADMIN_HEADERS = {'Authorization': f"Bearer {config['admin_secret']}"}
+GATEWAY_URL = f"http://{config['host']}:{config['port']}"
+UPSTREAM_PORT = 6229
+TEST_TOKEN = str(uuid.uuid4())
+HEADERS = {'Authorization': f'Bearer {TEST_TOKEN}', 'Content-Type': 'application/json'}
+
+COMPLETION = {"object": "chat.completion", "choices": [], "usage": {"prompt_tokens": 9, "completion_tokens": 2, "total_tokens": 11}}
+# COMPLETION in br, Python has no brotli encoder in its standard library
+COMPLETION_BR = base64.b64decode(
+    "G3YAAETdlupZFuE2n6qIQQwP+qM2USfHB9UObUEWyDGhs7bcxobjqkLhjubhNYAghC47Xgus2kKgD9M+DoRKBXd4IfTg26bf1kRYKgcybhoSpzZazP8D")
+EVENTS = b'data: {"choices": [], "usage": {"prompt_tokens": 5, "completion_tokens": 7}}\n\ndata: [DONE]\n\n'
+# Accept-Encoding of the last request the stub upstream got
+received = {}
+
+
+class CompressedHandler(BaseHTTPRequestHandler):
+    """Upstream answering COMPLETION, or EVENTS when `stream` is set, in the `encoding` of the request.
+    COMPLETION gets `padding` characters more in gzip."""
+
+    def do_POST(self):
+        request = json.loads(self.rfile.read(int(self.headers['Content-Length'])))
+        received['accept_encoding'] = self.headers.get('Accept-Encoding')
+        if request['encoding'] == 'br':
+            body = COMPLETION_BR
+        else:
+            completion = {**COMPLETION, "padding": "x" * request['padding']} if 'padding' in request else COMPLETION
+            body = gzip.compress(EVENTS if request.get('stream') else json.dumps(completion).encode())
+        self.send_response(200)
+        self.send_header('Content-Type', 'text/event-stream' if request.get('stream') else 'application/json')
+        self.send_header('Content-Encoding', request['encoding'])
+        self.send_header('Content-Length', str(len(body)))
+        self.end_headers()
+        self.wfile.write(body)
+
+    def log_message(self, *args):
+        pass
+
+
+server = HTTPServer(('127.0.0.1', UPSTREAM_PORT), CompressedHandler)
+
+
+def setup_module():
+    threading.Thread(target=server.serve_forever, daemon=True).start()
+    response = requests.post(f'{ADMIN_URL}/tokens', headers=ADMIN_HEADERS, json={"tokens": {TEST_TOKEN: "compression_user"}})
+    assert response.status_code == 200
+
+def teardown_module():
+    server.shutdown()
+    requests.delete(f'{ADMIN_URL}/tokens', headers=ADMIN_HEADERS, json={"tokens": [TEST_TOKEN]})
+
+def usage_totals():
+    """Return the (input, output) tokens recorded for the test user today."""
+    time.sleep(0.5)  # usage is written once the response has been sent
+    response = requests.get(f'{ADMIN_URL}/usage/daily', headers=ADMIN_HEADERS)
+    totals = {"in": 0, "out": 0}
+    for entry in response.json():
+        for key, value in entry.items():
+            _, _, user, direction = key.split(":")
+            if user == "compression_user":
+                totals[direction] += value
+    return totals["in"], totals["out"]
+
+def post(location, body, encoding, accept_encoding='identity'):
+    headers = {**HEADERS, 'Content-Encoding': encoding, 'Accept-Encoding': accept_encoding}
+    return requests.post(f"{GATEWAY_URL}{location}", headers=headers, data=body)
+
+def test_gzip_request_body():
+    """Test a gzipped request body reaches the upstream decoded, and its usage is counted."""
+    before_in, before_out = usage_totals()
+    response = post("/echo/openai", gzip.compress(json.dumps(COMPLETION).encode()), 'gzip')
+    assert response.status_code == 200
+    assert response.json() == COMPLETION
+    assert usage_totals() == (before_in + 9, before_out + 2)
+
+def test_br_request_body():
+    response = post("/echo/openai", COMPLETION_BR, 'br')
+    assert response.status_code == 200
+    assert response.json() == COMPLETION
+
+def test_unsupported_request_encoding():
+    response = post("/echo/openai", b'{}', 'zstd')
+    assert response.status_code == 415
+    assert "gzip or br" in response.json()['error']['message']
+
+def test_invalid_gzip_request_body():
+    response = post("/echo/openai", b'not gzip', 'gzip')
+    assert response.status_code == 400
+
+def test_decoded_request_body_too_large():
+    """Test a small body decoding past max_request_bytes is rejected."""
+    body = gzip.compress(b' ' * (config['max_request_bytes'] + 1))
+    assert len(body) < 64 * 1024
+    response = post("/echo/openai", body, 'gzip')
+    assert response.status_code == 413
+
+def test_gzip_response_decoded():
+    """Test a gzipped upstream response is decoded for the client and counted."""
+    before_in, before_out = usage_totals()
+    response = requests.post(f"{GATEWAY_URL}/echo/compressed", headers={**HEADERS, 'Accept-Encoding': 'identity'},
+                             json={"encoding": "gzip"})
+    assert response.status_code == 200
+    assert 'Content-Encoding' not in response.headers
+    assert response.json() == COMPLETION
+    assert received['accept_encoding'] == "gzip, br"
+    assert response.headers['X-Input-Tokens'] == '9'
+    assert usage_totals() == (before_in + 9, before_out + 2)
+
+def test_decoded_response_too_large():
+    """Test a held response decoded over max_response_buffer_bytes fails, its headers are not sent yet."""
+    response = requests.post(f"{GATEWAY_URL}/echo/compressed", headers={**HEADERS, 'Accept-Encoding': 'identity'},
+                             json={"encoding": "gzip", "padding": config['max_response_buffer_bytes']})
+    assert response.status_code == 502
+
+def test_br_response_decoded():
+    before_in, before_out = usage_totals()
+    response = requests.post(f"{GATEWAY_URL}/echo/compressed", headers={**HEADERS, 'Accept-Encoding': 'identity'},
+                             json={"encoding": "br"})
+    assert response.status_code == 200
+    assert 'Content-Encoding' not in response.headers
+    assert response.json() == COMPLETION
+    assert usage_totals() == (before_in + 9, before_out + 2)
+
+def test_gzip_event_stream_counted():
+    before_in, before_out = usage_totals()
+    response = requests.post(f"{GATEWAY_URL}/echo/compressed", headers={**HEADERS, 'Accept-Encoding': 'identity'},
+                             json={"encoding": "gzip", "stream": True})
+    assert response.status_code == 200
+    assert response.content == EVENTS
+    assert usage_totals() == (before_in + 5, before_out + 7)
+
+def test_response_compressed_for_client():
+    """Test the response is compressed again for a client accepting gzip, with response_compression_level."""
+    response = requests.post(f"{GATEWAY_URL}/echo/compressed", headers={**HEADERS, 'Accept-Encoding': 'gzip'},
+                             json={"encoding": "br"}, stream=True)
+    assert response.headers['Content-Encoding'] == 'gzip'
+    assert json.loads(gzip.decompress(response.raw.read())) == COMPLETION
+
+def test_event_stream_not_compressed_for_client():
+    """Test event streams are sent as they come, without a compressor holding them back."""
+    response = requests.post(f"{GATEWAY_URL}/echo/compressed", headers={**HEADERS, 'Accept-Encoding': 'gzip'},
+                             json={"encoding": "gzip", "stream": True})
+    assert 'Content-Encoding' not in response.headers
+    assert response.content == EVENTS
+
+def test_error_compressed_for_client():
+    """Test an error raised once the body is read still ends its compressed body."""
+    response = post("/echo/compressed", b'not gzip', 'gzip', accept_encoding='gzip')
+    assert response.status_code == 400
+    assert response.headers['Content-Encoding'] == 'gzip'
+    assert response.json()['error']['message'] == "Invalid compressed request body"
@@ -0,0 +1,98 @@
+"""Requests of a user in flight at once, limited by max_concurrent_requests."""
+import json
+import threading
+import time
+import uuid
+from concurrent.futures import ThreadPoolExecutor
+from http.server import BaseHTTPRequestHandler, ThreadingHTTPServer
+
+import requests
+import yaml
+
+# Load configuration
+with open('conf.yml') as f:
+    config = yaml.safe_load(f)
+
+ADMIN_URL = f"http://{config['admin_host']}:{config['admin_port']}"
+ADMIN_HEADERS = {'Authorization': f"Bearer {config['admin_secret']}"}
+GATEWAY_URL = f"http://{config['host']}:{config['port']}"
+UPSTREAM_PORT = 6220
+LOCATION = "/echo/concurrent"
+LIMIT = next(m for m in config['models'] if m['location'] == LOCATION)['max_concurrent_requests']
+TEST_TOKEN = str(uuid.uuid4())
+HEADERS = {'Authorization': f'Bearer {TEST_TOKEN}'}
+OTHER_TOKEN = str(uuid.uuid4())
+
+
+class SlowHandler(BaseHTTPRequestHandler):
+    """Upstream answering with `status` after the `delay` seconds of the request body."""
+
+    def do_POST(self):
+        request = json.loads(self.rfile.read(int(self.headers.get('Content-Length', 0))))
+        time.sleep(request['delay'])
+        body = json.dumps(request).encode()
+        try:
+            self.send_response(request.get('status', 200))
+            self.send_header('Content-Type', 'application/json')
+            self.send_header('Content-Length', str(len(body)))
+            self.end_headers()
+            self.wfile.write(body)
+        except OSError:
+            pass  # the client went away
+
+    def log_message(self, *args):
+        pass
+
+
+server = ThreadingHTTPServer(('127.0.0.1', UPSTREAM_PORT), SlowHandler)
+
+
+def setup_module():
+    threading.Thread(target=server.serve_forever, daemon=True).start()
+    response = requests.post(f'{ADMIN_URL}/tokens', headers=ADMIN_HEADERS, json={"tokens": {TEST_TOKEN: "concurrent_user", OTHER_TOKEN: "other_concurrent_user"}})
+    assert response.status_code == 200
+
+def teardown_module():
+    server.shutdown()
+    requests.delete(f'{ADMIN_URL}/tokens', headers=ADMIN_HEADERS, json={"tokens": [TEST_TOKEN, OTHER_TOKEN]})
+
+def post(body, timeout=10, headers=HEADERS):
+    return requests.post(f"{GATEWAY_URL}{LOCATION}", headers=headers, json=body, timeout=timeout)
+
+def test_limit_exceeded():
+    """Test the request over the limit gets a 429 while the others are in flight."""
+    with ThreadPoolExecutor(LIMIT + 1) as pool:
+        slow = [pool.submit(post, {"delay": 1}) for _ in range(LIMIT)]
+        time.sleep(0.3)
+        response = post({"delay": 0})
+        assert response.status_code == 429
+        assert response.json()['error']['message'] == "Too many concurrent requests"
+        assert [f.result().status_code for f in slow] == [200] * LIMIT
+    assert post({"delay": 0}).status_code == 200
+
+def test_limit_per_user():
+    """Test the requests of one user do not count against another."""
+    with ThreadPoolExecutor(LIMIT) as pool:
+        slow = [pool.submit(post, {"delay": 1}) for _ in range(LIMIT)]
+        time.sleep(0.3)
+        assert post({"delay": 0}, headers={'Authorization': f'Bearer {OTHER_TOKEN}'}).status_code == 200
+        assert [f.result().status_code for f in slow] == [200] * LIMIT
+
+def test_released_on_completion():
+    """Test more requests than the limit pass one after the other."""
+    for _ in range(LIMIT + 1):
+        response = post({"delay": 0})
+        assert response.status_code == 200
+        assert response.json() == {"delay": 0}
+
+def test_released_on_errors():
+    """Test failed requests and clients giving up release their slot."""
+    for _ in range(LIMIT + 1):
+        assert post({"delay": 0, "status": 500}).status_code == 500
+    for _ in range(LIMIT + 1):
+        try:
+            post({"delay": 0.5}, timeout=0.1)
+        except requests.exceptions.ReadTimeout:
+            pass
+    time.sleep(1)
+    assert post({"delay": 0}).status_code == 200
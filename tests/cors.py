@@ -0,0 +1,68 @@
+"""CORS headers for the browser origins allowed in the cors section of conf.yml."""
+import uuid
+
+import requests
+import yaml
+
+# Load configuration
+with open('conf.yml') as f:
+    config = yaml.safe_load(f)
+
+ADMIN_URL = f"http://{config['admin_host']}:{config['admin_port']}"
+ADMIN_HEADERS = {'Authorization': f"Bearer {config['admin_secret']}"}
+GATEWAY_URL = f"http://{config['host']}:{config['port']}"
+ALLOWED_ORIGIN = config['cors']['allowed_origins'][0]
+OTHER_ORIGIN = "https://evil.example.com"
+TEST_TOKEN = str(uuid.uuid4())
+HEADERS = {'Authorization': f'Bearer {TEST_TOKEN}'}
+
+
+def setup_module():
+    response = requests.post(f'{ADMIN_URL}/tokens', headers=ADMIN_HEADERS, json={"tokens": {TEST_TOKEN: "cors_user"}})
+    assert response.status_code == 200
+
+def teardown_module():
+    requests.delete(f'{ADMIN_URL}/tokens', headers=ADMIN_HEADERS, json={"tokens": [TEST_TOKEN]})
+
+def preflight(origin):
+    return requests.options(f"{GATEWAY_URL}/echo/openai", headers={
+        'Origin': origin,
+        'Access-Control-Request-Method': 'POST',
+        'Access-Control-Request-Headers': 'authorization, content-type',
+    })
+
+def test_preflight_allowed_origin():
+    """Test a preflight from an allowed origin is answered with the configured policy."""
+    response = preflight(ALLOWED_ORIGIN)
+    assert response.status_code == 204
+    assert response.headers['Access-Control-Allow-Origin'] == ALLOWED_ORIGIN
+    assert 'POST' in response.headers['Access-Control-Allow-Methods']
+    assert 'Authorization' in response.headers['Access-Control-Allow-Headers']
+    assert response.headers['Access-Control-Max-Age'] == str(config['cors']['max_age_secs'])
+    assert 'Origin' in response.headers['Vary']
+
+def test_preflight_other_origin():
+    """Test origins missing from the allowlist get no CORS headers."""
+    response = preflight(OTHER_ORIGIN)
+    assert response.status_code == 204
+    assert not [name for name in response.headers if name.lower().startswith('access-control-')]
+
+def test_proxied_response_headers():
+    """Test proxied responses allow the origin and expose the gateway headers."""
+    response = requests.post(f"{GATEWAY_URL}/echo/openai", headers={**HEADERS, 'Origin': ALLOWED_ORIGIN}, json={})
+    assert response.status_code == 200
+    assert response.headers['Access-Control-Allow-Origin'] == ALLOWED_ORIGIN
+    assert 'X-Request-Id' in response.headers['Access-Control-Expose-Headers']
+
+    response = requests.post(f"{GATEWAY_URL}/echo/openai", headers={**HEADERS, 'Origin': OTHER_ORIGIN}, json={})
+    assert response.status_code == 200
+    assert 'Access-Control-Allow-Origin' not in response.headers
+
+def test_gateway_responses():
+    """Test the responses written by the gateway itself allow the origin too."""
+    response = requests.get(f"{GATEWAY_URL}/models", headers={**HEADERS, 'Origin': ALLOWED_ORIGIN})
+    assert response.status_code == 200
+    assert response.headers['Access-Control-Allow-Origin'] == ALLOWED_ORIGIN
+    response = requests.get(f"{GATEWAY_URL}/", headers={'Origin': OTHER_ORIGIN})
+    assert response.status_code == 200
+    assert 'Access-Control-Allow-Origin' not in response.headers
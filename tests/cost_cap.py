@@ -0,0 +1,81 @@
+"""Daily spend caps of the users, global, per location, and set per user through the admin API."""
+import time
+import uuid
+
+import requests
+import yaml
+
+# Load configuration
+with open('conf.yml') as f:
+    config = yaml.safe_load(f)
+
+ADMIN_URL = f"http://{config['admin_host']}:{config['admin_port']}"
+ADMIN_HEADERS = {'Authorization': f"Bearer {config['admin_secret']}"}
+GATEWAY_URL = f"http://{config['host']}:{config['port']}"
+# Users of a previous run have already spent their cap for the day
+TEST_TOKEN = str(uuid.uuid4())
+TEST_USER = f"capped_{uuid.uuid4().hex[:8]}"
+HEADERS = {'Authorization': f'Bearer {TEST_TOKEN}'}
+OTHER_TOKEN = str(uuid.uuid4())
+OTHER_USER = f"capped_{uuid.uuid4().hex[:8]}"
+OTHER_HEADERS = {'Authorization': f'Bearer {OTHER_TOKEN}'}
+# Echoed back as the response, 1k input tokens cost 0.5 USD on both locations
+BODY = {"object": "chat.completion", "choices": [], "usage": {"prompt_tokens": 1000, "completion_tokens": 0}}
+
+
+def setup_module():
+    response = requests.post(f'{ADMIN_URL}/tokens', headers=ADMIN_HEADERS, json={"tokens": {TEST_TOKEN: TEST_USER, OTHER_TOKEN: OTHER_USER}})
+    assert response.status_code == 200
+
+def teardown_module():
+    requests.delete(f'{ADMIN_URL}/tokens', headers=ADMIN_HEADERS, json={"tokens": [TEST_TOKEN, OTHER_TOKEN]})
+    requests.delete(f'{ADMIN_URL}/users/{OTHER_USER}/cost_cap', headers=ADMIN_HEADERS)
+
+def post(location, headers):
+    response = requests.post(f"{GATEWAY_URL}{location}", headers=headers, json=BODY)
+    # the cost is committed by the usage writer
+    time.sleep(0.5)
+    return response
+
+def test_location_cap():
+    """Test the requests on a location stop once the user spent its daily_cost_cap_usd there."""
+    assert post("/echo/spend-capped", HEADERS).status_code == 200
+    assert post("/echo/spend-capped", HEADERS).status_code == 200
+    response = post("/echo/spend-capped", HEADERS)
+    assert response.status_code == 402
+    assert response.json()['error'] == {
+        "message": "Daily spend cap of this model reached",
+        "type": "insufficient_quota",
+        "code": "spend_cap_reached",
+    }
+    # the other locations and the other users are not capped
+    assert post("/echo/openai", HEADERS).status_code == 200
+    assert post("/echo/spend-capped", OTHER_HEADERS).status_code == 200
+
+def test_user_cap():
+    """Test a cap set for a user through the admin API stops its requests on every location."""
+    url = f'{ADMIN_URL}/users/{OTHER_USER}/cost_cap'
+    spent = requests.get(url, headers=ADMIN_HEADERS).json()['spent_today_usd']
+    # room for one more request
+    assert requests.put(url, headers=ADMIN_HEADERS, json={"daily_cost_cap_usd": spent + 0.3}).status_code == 200
+    assert post("/echo/openai", OTHER_HEADERS).status_code == 200
+    response = post("/echo/openai", OTHER_HEADERS)
+    assert response.status_code == 402
+    assert response.json()['error']['message'] == "Daily spend cap reached"
+
+    cap = requests.get(url, headers=ADMIN_HEADERS).json()
+    assert cap['daily_cost_cap_usd'] == spent + 0.3
+    assert cap['overridden'] is True
+    assert abs(cap['spent_today_usd'] - spent - 0.5) < 1e-9
+
+    # back to the global cap, unset in conf.yml
+    assert requests.delete(url, headers=ADMIN_HEADERS).status_code == 200
+    assert requests.get(url, headers=ADMIN_HEADERS).json()['overridden'] is False
+    assert post("/echo/openai", OTHER_HEADERS).status_code == 200
+
+def test_invalid_cap():
+    """Test the admin API rejects negative caps and reports the users without a cap."""
+    url = f'{ADMIN_URL}/users/{OTHER_USER}/cost_cap'
+    assert requests.put(url, headers=ADMIN_HEADERS, json={"daily_cost_cap_usd": -1}).status_code == 400
+    assert requests.put(url, headers=ADMIN_HEADERS, json={}).status_code == 400
+    assert requests.delete(f'{ADMIN_URL}/users/nobody_{uuid.uuid4().hex}/cost_cap', headers=ADMIN_HEADERS).status_code == 404
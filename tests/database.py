@@ -0,0 +1,78 @@
+"""Startup errors of the database, reported before the gateway exits (run next to a running gateway)."""
+import os
+import subprocess
+import tempfile
+
+import yaml
+
+# Load configuration
+with open('conf.yml') as f:
+    config = yaml.safe_load(f)
+
+GATEWAY_BIN = os.environ.get('BURGONET_BIN', 'target/debug/burgonet-gw')
+
+
+def start(conf_path, env=None):
+    """Start a second gateway, it should exit at once."""
+    return subprocess.run([GATEWAY_BIN, '-c', conf_path], capture_output=True, text=True, timeout=10, env=env)
+
+def test_locked_database():
+    """Test the database of the running gateway is reported as locked."""
+    result = start('conf.yml')
+    assert result.returncode == 1
+    assert f"Database {config['database_path']} is locked by another process" in result.stdout + result.stderr
+    assert 'panicked' not in result.stderr
+
+def test_deprecated_db_filepath():
+    """Test `db_filepath`, the former name of `database_path`, still sets the database file."""
+    conf = {key: value for key, value in config.items() if key != 'database_path'}
+    with tempfile.TemporaryDirectory(dir='.') as directory:
+        db_path = os.path.join(directory, 'tenant.redb')
+        with open(db_path, 'wb') as f:
+            f.write(os.urandom(4096))
+        conf_path = os.path.join(directory, 'conf.yml')
+        with open(conf_path, 'w') as f:
+            yaml.safe_dump({**conf, 'db_filepath': db_path}, f)
+        result = start(conf_path, env={**os.environ, 'RUST_LOG': 'warn'})
+    assert result.returncode == 1
+    output = result.stdout + result.stderr
+    assert "db_filepath is deprecated, rename it to database_path" in output
+    assert f"Database {db_path} is not a redb database" in output
+
+def test_both_names():
+    """Test setting both names of the database file is refused."""
+    with tempfile.TemporaryDirectory(dir='.') as directory:
+        conf_path = os.path.join(directory, 'conf.yml')
+        with open(conf_path, 'w') as f:
+            yaml.safe_dump({**config, 'database_path': os.path.join(directory, 'tenant.redb'),
+                            'db_filepath': os.path.join(directory, 'other.redb')}, f)
+        result = start(conf_path)
+    assert result.returncode == 1
+    assert "db_filepath and database_path are the same setting" in result.stdout + result.stderr
+
+def test_both_names_with_default_path():
+    """Test db_filepath is refused even when database_path is explicitly set to its default."""
+    with tempfile.TemporaryDirectory(dir='.') as directory:
+        conf_path = os.path.join(directory, 'conf.yml')
+        with open(conf_path, 'w') as f:
+            yaml.safe_dump({**config, 'database_path': 'database.redb',
+                            'db_filepath': os.path.join(directory, 'other.redb')}, f)
+        result = start(conf_path)
+    assert result.returncode == 1
+    assert "db_filepath and database_path are the same setting" in result.stdout + result.stderr
+
+def test_corrupted_database():
+    """Test a file that is not a database is reported with what to do about it."""
+    with tempfile.TemporaryDirectory(dir='.') as directory:
+        db_path = os.path.join(directory, 'corrupted.redb')
+        with open(db_path, 'wb') as f:
+            f.write(os.urandom(4096))
+        conf_path = os.path.join(directory, 'conf.yml')
+        with open(conf_path, 'w') as f:
+            yaml.safe_dump({**config, 'database_path': db_path}, f)
+        result = start(conf_path)
+    assert result.returncode == 1
+    output = result.stdout + result.stderr
+    assert f"Database {db_path} is not a redb database" in output
+    assert 'move it aside' in output
+    assert 'panicked' not in result.stderr
@@ -0,0 +1,80 @@
+"""Models taken out of rotation at runtime with the admin API."""
+import subprocess
+import time
+import uuid
+
+import requests
+import yaml
+
+# Load configuration
+with open('conf.yml') as f:
+    config = yaml.safe_load(f)
+
+ADMIN_URL = f"http://{config['admin_host']}:{config['admin_port']}"
+ADMIN_HEADERS = {'Authorization': f"Bearer {config['admin_secret']}"}
+GATEWAY_URL = f"http://{config['host']}:{config['port']}"
+LOCATION = "/echo/toggle"
+TEST_TOKEN = str(uuid.uuid4())
+HEADERS = {'Authorization': f'Bearer {TEST_TOKEN}'}
+
+
+def setup_module():
+    response = requests.post(f'{ADMIN_URL}/tokens', headers=ADMIN_HEADERS, json={"tokens": {TEST_TOKEN: "disabled_models_user"}})
+    assert response.status_code == 200
+
+def teardown_module():
+    requests.post(f'{ADMIN_URL}/models{LOCATION}/enable', headers=ADMIN_HEADERS)
+    requests.delete(f'{ADMIN_URL}/tokens', headers=ADMIN_HEADERS, json={"tokens": [TEST_TOKEN]})
+
+def set_disabled(disabled):
+    action = "disable" if disabled else "enable"
+    response = requests.post(f'{ADMIN_URL}/models{LOCATION}/{action}', headers=ADMIN_HEADERS)
+    assert response.status_code == 200
+    assert response.json() == {"location": LOCATION, "disabled": disabled}
+
+def post():
+    body = {"object": "chat.completion", "choices": [], "usage": {"prompt_tokens": 1, "completion_tokens": 1}}
+    return requests.post(f"{GATEWAY_URL}{LOCATION}", headers=HEADERS, json=body)
+
+def listed_models():
+    return [model['id'] for model in requests.get(f"{GATEWAY_URL}/models", headers=HEADERS).json()['data']]
+
+def test_disable_and_enable():
+    """Test a disabled location gets a 503 and leaves the model list, until enabled again."""
+    set_disabled(True)
+    response = post()
+    assert response.status_code == 503
+    assert response.json()['error']['message'] == "Model disabled by the administrator"
+    assert LOCATION not in listed_models()
+    assert requests.get(f'{ADMIN_URL}/models/disabled', headers=ADMIN_HEADERS).json() == {"disabled": [LOCATION]}
+    # the other locations are still served
+    assert requests.post(f"{GATEWAY_URL}/echo/openai", headers=HEADERS, json={"usage": {"prompt_tokens": 1, "completion_tokens": 1}}).status_code == 200
+
+    set_disabled(False)
+    assert post().status_code == 200
+    assert LOCATION in listed_models()
+    assert requests.get(f'{ADMIN_URL}/models/disabled', headers=ADMIN_HEADERS).json() == {"disabled": []}
+
+def test_location_with_leading_slash():
+    """Test the location may also be given with its leading slash, after the one of the path."""
+    response = requests.post(f'{ADMIN_URL}/models/{LOCATION}/disable', headers=ADMIN_HEADERS)
+    assert response.status_code == 200
+    assert response.json() == {"location": LOCATION, "disabled": True}
+    set_disabled(False)
+
+def test_unknown_location():
+    response = requests.post(f'{ADMIN_URL}/models/echo/nowhere/disable', headers=ADMIN_HEADERS)
+    assert response.status_code == 404
+
+def test_requires_admin_secret():
+    response = requests.post(f'{ADMIN_URL}/models{LOCATION}/disable')
+    assert response.status_code == 401
+    assert post().status_code == 200
+
+def test_reload_enables():
+    """Test a configuration reload puts the disabled locations back in rotation."""
+    set_disabled(True)
+    assert post().status_code == 503
+    subprocess.run(['pkill', '-HUP', '-x', 'burgonet-gw'], check=True)
+    time.sleep(0.5)
+    assert post().status_code == 200
@@ -0,0 +1,486 @@
+import json
+import logging
+import select
+import socket
+import time
+import uuid
+
+import requests
+import yaml
+
+log = logging.getLogger(__name__)
+# Load configuration
+with open('conf.yml') as f:
+    config = yaml.safe_load(f)
+
+# Test configuration
+ADMIN_URL = f"http://{config['admin_host']}:{config['admin_port']}"
+ADMIN_HEADERS = {'Authorization': f"Bearer {config['admin_secret']}"}
+GATEWAY_URL = f"http://{config['host']}:{config['port']}"
+PROMETHEUS_URL = f"http://{config['prometheus_host']}:{config['prometheus_port']}"
+API_URL = f"{GATEWAY_URL}/echo/balanced"
+TEST_TOKEN = str(uuid.uuid4())
+HEADERS = {
+    'Authorization': f'Bearer {TEST_TOKEN}',
+}
+
+
+def setup_module():
+    """Setup module-level test fixtures.
+
+    Creates a test token used by all test functions. The echo upstream
+    returns the request body as its response, so each test sends the
+    upstream response it wants the gateway to handle.
+    """
+    token_data = {
+        "tokens": {TEST_TOKEN: "echo_user"}
+    }
+    response = requests.post(f'{ADMIN_URL}/tokens', headers=ADMIN_HEADERS, json=token_data)
+    assert response.status_code == 200, "Failed to create test token"
+
+def teardown_module():
+    """Teardown module-level test fixtures."""
+    token_data = {
+        "tokens": [TEST_TOKEN]
+    }
+    response = requests.delete(f'{ADMIN_URL}/tokens', headers=ADMIN_HEADERS, json=token_data)
+    assert response.status_code == 200, "Failed to delete test token"
+
+def metric_value(name):
+    """Return the value of an unlabeled Prometheus metric, 0 if absent."""
+    response = requests.get(f'{PROMETHEUS_URL}/metrics')
+    for line in response.text.splitlines():
+        if line.startswith(f"{name} "):
+            return float(line.split()[1])
+    return 0
+
+def usage_totals(user):
+    """Return the (input, output) tokens recorded for the user today."""
+    time.sleep(0.5)  # usage is written once the response has been sent
+    response = requests.get(f'{ADMIN_URL}/usage/daily', headers=ADMIN_HEADERS)
+    assert response.status_code == 200
+    totals = {"in": 0, "out": 0}
+    for entry in response.json():
+        for key, value in entry.items():
+            _, _, key_user, direction = key.split(":")
+            if key_user == user:
+                totals[direction] += value
+    return totals["in"], totals["out"]
+
+def test_echo():
+    """Test a JSON body is proxied through the echo upstream."""
+    response = requests.post(API_URL, headers=HEADERS, json={"hello": "world"})
+    assert response.status_code == 200, response.text
+    assert response.json() == {"hello": "world"}
+
+def test_upstream_protocol_auto():
+    """Test upstream_protocol auto falls back to HTTP/1.1 on a plain http upstream."""
+    response = requests.post(f"{GATEWAY_URL}/echo/auto-protocol", headers=HEADERS, json={"hello": "world"})
+    assert response.status_code == 200, response.text
+    assert response.json() == {"hello": "world"}
+
+def test_invalid_json_response():
+    """Test an upstream answering with non-JSON is forwarded unchanged.
+
+    The gateway must not crash; it skips token accounting and counts
+    the failure in response_parse_errors.
+    """
+    before = metric_value("response_parse_errors")
+    html = b"<html><body>502 Bad Gateway</body></html>"
+    response = requests.post(API_URL, headers=HEADERS, data=html)
+    assert response.status_code == 200, response.text
+    assert response.content == html
+    assert metric_value("response_parse_errors") == before + 1
+
+    truncated = b'{"usage": {"prompt_tokens": 1'
+    response = requests.post(API_URL, headers=HEADERS, data=truncated)
+    assert response.status_code == 200, response.text
+    assert response.content == truncated
+
+def test_response_without_usage():
+    """Test valid JSON without usage, like an upstream error body, is forwarded unchanged."""
+    before = metric_value("response_parse_errors")
+    error = {"error": {"message": "Rate limit reached", "type": "rate_limit_error"}}
+    response = requests.post(f"{GATEWAY_URL}/echo/anthropic", headers=HEADERS, json=error)
+    assert response.status_code == 200, response.text
+    assert response.json() == error
+    assert metric_value("response_parse_errors") == before + 1
+
+def test_openai_usage():
+    """Test OpenAI usage is counted as input/output tokens."""
+    before_in, before_out = usage_totals("echo_user")
+    completion = {
+        "object": "chat.completion",
+        "choices": [{"index": 0, "message": {"role": "assistant", "content": "Hi"}}],
+        "usage": {"prompt_tokens": 12, "completion_tokens": 3, "total_tokens": 15},
+    }
+    response = requests.post(f"{GATEWAY_URL}/echo/openai", headers=HEADERS, json=completion)
+    assert response.status_code == 200, response.text
+    after_in, after_out = usage_totals("echo_user")
+    assert (after_in - before_in, after_out - before_out) == (12, 3)
+
+def test_usage_headers():
+    """Test a response that is not streamed tells its tokens and cost in its headers."""
+    completion = {
+        "object": "chat.completion",
+        "choices": [{"index": 0, "message": {"role": "assistant", "content": "Hi"}}],
+        "usage": {"prompt_tokens": 12, "completion_tokens": 3, "total_tokens": 15},
+    }
+    response = requests.post(f"{GATEWAY_URL}/echo/openai", headers=HEADERS, json=completion)
+    assert response.status_code == 200, response.text
+    assert response.json() == completion
+    assert response.headers['X-Input-Tokens'] == '12'
+    assert response.headers['X-Output-Tokens'] == '3'
+    # 12 input tokens at 0.5 per 1k and 3 output tokens at 1.5 per 1k
+    assert response.headers['X-Request-Cost-Usd'] == '0.010500'
+    assert int(response.headers['X-Upstream-Latency-Ms']) >= 0
+
+def test_openai_null_usage():
+    """Test a null usage block counts zero tokens instead of failing."""
+    chunk = {"object": "chat.completion.chunk", "choices": [], "usage": None}
+    response = requests.post(f"{GATEWAY_URL}/echo/openai", headers=HEADERS, json=chunk)
+    assert response.status_code == 200, response.text
+
+ANTHROPIC_MESSAGE = {
+    "id": "msg_01XFDUDYJgAACzvnptvVoYEL",
+    "type": "message",
+    "role": "assistant",
+    "model": "claude-3-5-sonnet-20241022",
+    "content": [{"type": "text", "text": "Hello! How can I help you today?"}],
+    "stop_reason": "end_turn",
+    "stop_sequence": None,
+    "usage": {"input_tokens": 10, "output_tokens": 12},
+}
+
+def test_anthropic_usage():
+    """Test a recorded Anthropic Messages API response is counted."""
+    before_in, before_out = usage_totals("echo_user")
+    response = requests.post(f"{GATEWAY_URL}/echo/anthropic", headers=HEADERS, json=ANTHROPIC_MESSAGE)
+    assert response.status_code == 200, response.text
+    assert response.json() == ANTHROPIC_MESSAGE
+    after_in, after_out = usage_totals("echo_user")
+    assert (after_in - before_in, after_out - before_out) == (10, 12)
+
+def test_anthropic_message_delta():
+    """Test the final message_delta streaming event carries output tokens."""
+    before_in, before_out = usage_totals("echo_user")
+    delta = {
+        "type": "message_delta",
+        "delta": {"stop_reason": "end_turn", "stop_sequence": None},
+        "usage": {"output_tokens": 15},
+    }
+    response = requests.post(f"{GATEWAY_URL}/echo/anthropic", headers=HEADERS, json=delta)
+    assert response.status_code == 200, response.text
+    after_in, after_out = usage_totals("echo_user")
+    assert (after_in - before_in, after_out - before_out) == (0, 15)
+
+EMBEDDINGS = {
+    "object": "list",
+    "data": [{"object": "embedding", "index": 0, "embedding": [0.0023064255, -0.009327292, -0.0028842222]}],
+    "model": "text-embedding-3-small",
+    "usage": {"prompt_tokens": 8, "total_tokens": 8},
+}
+
+def test_embeddings_usage():
+    """Test a recorded embeddings response counts its prompt tokens as input only."""
+    before_in, before_out = usage_totals("echo_user")
+    before_cost = monthly_cost("echo_user")
+    response = requests.post(f"{GATEWAY_URL}/echo/embeddings", headers=HEADERS, json=EMBEDDINGS)
+    assert response.status_code == 200, response.text
+    assert response.json() == EMBEDDINGS
+    after_in, after_out = usage_totals("echo_user")
+    assert (after_in - before_in, after_out - before_out) == (8, 0)
+    # 8 input tokens at 0.02 per 1k, the output price never applies
+    assert abs(monthly_cost("echo_user") - before_cost - 0.00016) < 1e-9
+
+def monthly_cost(user):
+    time.sleep(0.5)
+    response = requests.get(f"{ADMIN_URL}/cost", headers=ADMIN_HEADERS)
+    assert response.status_code == 200
+    return sum(v for k, v in response.json().items() if k.endswith(f":{user}"))
+
+def test_monthly_cost():
+    """Test the request cost is accumulated from the model prices."""
+    before = monthly_cost("echo_user")
+    body = {"object": "chat.completion", "choices": [], "usage": {"prompt_tokens": 1000, "completion_tokens": 2000}}
+    response = requests.post(f"{GATEWAY_URL}/echo/openai", headers=HEADERS, json=body)
+    assert response.status_code == 200
+    # 1k input at 0.5 + 2k output at 1.5
+    assert abs(monthly_cost("echo_user") - before - 3.5) < 1e-9
+    metrics = requests.get(f'{PROMETHEUS_URL}/metrics').text
+    assert 'user_monthly_cost_usd{user="echo_user"}' in metrics
+
+def assert_json_error(response, status, error_type, code):
+    assert response.status_code == status
+    assert response.headers['Content-Type'] == 'application/json'
+    error = response.json()["error"]
+    assert error["type"] == error_type
+    assert error["code"] == code
+    assert error["message"]
+
+def test_invalid_token_error_body():
+    """Test an invalid API key gets an OpenAI style JSON error."""
+    response = requests.post(API_URL, headers={'Authorization': 'Bearer not-a-token'}, json={})
+    assert_json_error(response, 401, "invalid_request_error", "invalid_api_key")
+
+def test_unknown_location_error_body():
+    response = requests.post(f"{GATEWAY_URL}/echo/unknown", headers=HEADERS, json={})
+    assert_json_error(response, 404, "invalid_request_error", "not_found")
+
+def test_blacklist_error_body():
+    """Test a blacklisted word is rejected with a JSON 403."""
+    body = {"messages": [{"role": "user", "content": "This is Confidential"}]}
+    response = requests.post(f"{GATEWAY_URL}/echo/blacklist", headers=HEADERS, json=body)
+    assert_json_error(response, 403, "invalid_request_error", "forbidden")
+    assert response.json()["error"]["message"] == "Blacklisted word found in request body"
+
+def test_request_too_large():
+    """Test a body over the location's max_request_bytes gets a JSON 413."""
+    response = requests.post(f"{GATEWAY_URL}/echo/small", headers=HEADERS, data=b"x" * 2048)
+    assert_json_error(response, 413, "invalid_request_error", "request_too_large")
+    response = requests.post(f"{GATEWAY_URL}/echo/small", headers=HEADERS, data=b"x" * 512)
+    assert response.status_code == 200
+
+def test_content_type_allowlist():
+    """Test a Content-Type missing from allowed_content_types gets a JSON 415."""
+    response = requests.post(API_URL, headers=HEADERS, files={"file": ("a.txt", b"hello")})
+    assert_json_error(response, 415, "invalid_request_error", "unsupported_media_type")
+    json_utf8 = {**HEADERS, 'Content-Type': 'Application/JSON; charset=utf-8'}
+    assert requests.post(API_URL, headers=json_utf8, data=b'{}').status_code == 200
+    assert requests.post(API_URL, headers=HEADERS, data=b'{}').status_code == 200  # no Content-Type
+    text = {**HEADERS, 'Content-Type': 'text/plain'}
+    assert requests.post(API_URL, headers=text, data=b'hello').status_code == 415
+    assert requests.post(f"{GATEWAY_URL}/echo/text", headers=text, data=b'hello').status_code == 200
+
+def test_content_length_preserved():
+    """Test a fixed-length upstream response keeps its Content-Length."""
+    body = {"object": "chat.completion", "choices": [], "usage": {"prompt_tokens": 1, "completion_tokens": 1}}
+    response = requests.post(f"{GATEWAY_URL}/echo/openai", headers=HEADERS, json=body)
+    assert response.status_code == 200
+    assert "Transfer-Encoding" not in response.headers
+    assert response.headers["Content-Length"] == str(len(response.content))
+    assert response.json() == body
+
+def test_request_too_large_streamed():
+    """Test a chunked upload is rejected as soon as it goes over the limit."""
+    chunk, total_chunks = b"x" * 512, 200
+    sock = socket.create_connection((config['host'], config['port']), timeout=5)
+    sock.sendall((
+        "POST /echo/small HTTP/1.1\r\n"
+        f"Host: {config['host']}\r\n"
+        f"Authorization: Bearer {TEST_TOKEN}\r\n"
+        "Transfer-Encoding: chunked\r\n\r\n"
+    ).encode())
+    sent = 0
+    try:
+        while sent < total_chunks and not select.select([sock], [], [], 0.05)[0]:
+            sock.sendall(b"%x\r\n%s\r\n" % (len(chunk), chunk))
+            sent += 1
+    except OSError:
+        pass  # the gateway may close the connection while we are still sending
+    status_line = sock.recv(4096).split(b"\r\n", 1)[0]
+    sock.close()
+    assert status_line == b"HTTP/1.1 413 Payload Too Large", status_line
+    assert sent < total_chunks, "the gateway waited for the whole body"
+
+def test_large_response_passthrough():
+    """Test a response over max_response_buffer_bytes is forwarded whole but not counted."""
+    before_in, before_out = usage_totals("echo_user")
+    padding = "x" * (config['max_response_buffer_bytes'] + 1)
+    body = {"object": "chat.completion", "choices": [], "usage": {"prompt_tokens": 7, "completion_tokens": 7}, "padding": padding}
+    response = requests.post(f"{GATEWAY_URL}/echo/openai", headers=HEADERS, json=body)
+    assert response.status_code == 200
+    assert response.json() == body
+    assert usage_totals("echo_user") == (before_in, before_out)
+
+def test_blacklist_regex():
+    """Test a body matching a blacklist_regex pattern is rejected."""
+    body = {"messages": [{"role": "user", "content": "My card is 4111 1111 1111 1111"}]}
+    response = requests.post(f"{GATEWAY_URL}/echo/blacklist", headers=HEADERS, json=body)
+    assert_json_error(response, 403, "invalid_request_error", "forbidden")
+    assert response.json()["error"]["message"] == "Blacklisted pattern found in request body"
+    body = {"messages": [{"role": "user", "content": "Order 4111 is on its way"}]}
+    response = requests.post(f"{GATEWAY_URL}/echo/blacklist", headers=HEADERS, json=body)
+    assert response.status_code == 200
+
+def test_pii_service_down_fail_open():
+    """Test an unreachable PII service lets requests through in open mode."""
+    before = metric_value("pii_service_errors")
+    response = requests.post(f"{GATEWAY_URL}/echo/pii/open", headers=HEADERS, json={"hello": "world"})
+    assert response.status_code == 200
+    assert metric_value("pii_service_errors") == before + 1
+
+def test_pii_service_down_fail_closed():
+    """Test an unreachable PII service blocks requests in closed mode, without counting a detection."""
+    before = metric_value("pii_detections")
+    response = requests.post(f"{GATEWAY_URL}/echo/pii/closed", headers=HEADERS, json={"hello": "world"})
+    assert response.status_code == 503
+    assert response.json()["error"]["message"] == "PII protection service unavailable"
+    assert metric_value("pii_detections") == before
+
+def test_audit_log():
+    """Test each request is appended to the audit log with its bodies."""
+    marker = str(uuid.uuid4())
+    response = requests.post(f"{GATEWAY_URL}/echo/openai", headers=HEADERS, json={"marker": marker, "usage": {"prompt_tokens": 2, "completion_tokens": 1}})
+    assert response.status_code == 200
+    time.sleep(0.5)  # records are written by a background thread
+    with open(config['audit_log_path']) as f:
+        records = [json.loads(line) for line in f if marker in line]
+    assert len(records) == 1
+    record = records[0]
+    assert record["user"] == "echo_user"
+    assert record["model"] == "echo"
+    assert record["location"] == "/echo/openai"
+    assert record["status"] == 200
+    assert (record["input_tokens"], record["output_tokens"]) == (2, 1)
+    assert marker in record["request_body"] and marker in record["response_body"]
+
+def test_audit_log_redaction():
+    """Test spans matching redact_regex are masked in the logged bodies."""
+    marker = str(uuid.uuid4())
+    response = requests.post(f"{GATEWAY_URL}/echo/openai", headers=HEADERS, json={"marker": marker, "content": "mail jane.doe@example.com"})
+    assert response.status_code == 200
+    assert "jane.doe@example.com" in response.text, "only the logs are redacted"
+    time.sleep(0.5)
+    with open(config['audit_log_path']) as f:
+        record = next(json.loads(line) for line in f if marker in line)
+    for body in (record["request_body"], record["response_body"]):
+        assert "jane.doe@example.com" not in body
+        assert "mail [REDACTED]" in body
+
+def labeled_metric_value(name, **labels):
+    """Return the value of the Prometheus series with exactly these labels, 0 if absent."""
+    series = name + "{" + ",".join(f'{k}="{v}"' for k, v in sorted(labels.items())) + "}"
+    response = requests.get(f'{PROMETHEUS_URL}/metrics')
+    for line in response.text.splitlines():
+        if line.startswith(series + " "):
+            return float(line.split()[1])
+    return 0
+
+def test_response_cache():
+    """Test identical requests with a temperature of 0 are answered from the response cache."""
+    url = f"{GATEWAY_URL}/echo/cached"
+    completion = {
+        "temperature": 0,
+        "marker": str(uuid.uuid4()),
+        "usage": {"prompt_tokens": 3, "completion_tokens": 5},
+    }
+    hits = labeled_metric_value("response_cache_hits", location="/echo/cached")
+    first = requests.post(url, headers=HEADERS, json=completion)
+    assert first.status_code == 200, first.text
+    assert first.headers["X-Cache"] == "MISS"
+
+    second = requests.post(url, headers=HEADERS, json=completion)
+    assert second.status_code == 200, second.text
+    assert second.headers["X-Cache"] == "HIT"
+    assert second.content == first.content
+    assert second.headers["Content-Type"] == first.headers["Content-Type"]
+    assert second.headers["X-Request-ID"] != first.headers["X-Request-ID"]
+    assert labeled_metric_value("response_cache_hits", location="/echo/cached") == hits + 1
+
+    # another body is another entry, a sampled request is never cached
+    other = requests.post(url, headers=HEADERS, json=dict(completion, marker=str(uuid.uuid4())))
+    assert other.headers["X-Cache"] == "MISS"
+    sampled = dict(completion, temperature=0.7)
+    for _ in range(2):
+        response = requests.post(url, headers=HEADERS, json=sampled)
+        assert response.status_code == 200, response.text
+        assert "X-Cache" not in response.headers
+
+def test_quota_headers():
+    """Test responses report the token quota closest to being exhausted, before the request."""
+    body = {"usage": {"prompt_tokens": 3, "completion_tokens": 2}}
+    first = requests.post(f"{GATEWAY_URL}/echo/quota", headers=HEADERS, json=body)
+    assert first.status_code == 200
+    assert first.headers['X-Quota-Limit'] == "100000"
+    assert 0 < int(first.headers['X-Quota-Reset']) <= 3600
+    time.sleep(0.5)  # usage is written once the response has been sent
+    second = requests.post(f"{GATEWAY_URL}/echo/quota", headers=HEADERS, json=body)
+    assert int(second.headers['X-Quota-Used']) == int(first.headers['X-Quota-Used']) + 5
+
+    response = requests.post(API_URL, headers=HEADERS, json=body)
+    assert 'X-Quota-Limit' not in response.headers
+
+def test_quota_fallback():
+    """Test a user over the quota of /echo/premium is served by its fallback /echo/economy."""
+    token = str(uuid.uuid4())
+    response = requests.post(f'{ADMIN_URL}/tokens', headers=ADMIN_HEADERS, json={"tokens": {token: f"fallback_{token[:8]}"}})
+    assert response.status_code == 200
+    headers = {'Authorization': f'Bearer {token}'}
+    labels = {"location": "/echo/premium", "fallback": "/echo/economy"}
+    before = labeled_metric_value("model_fallbacks", **labels)
+
+    body = {"usage": {"prompt_tokens": 150, "completion_tokens": 0}}
+    first = requests.post(f"{GATEWAY_URL}/echo/premium", headers=headers, json=body)
+    assert first.status_code == 200
+    assert "served_by" not in first.json()
+    time.sleep(0.5)  # usage is written once the response has been sent
+    second = requests.post(f"{GATEWAY_URL}/echo/premium", headers=headers, json=body)
+    assert second.status_code == 200
+    assert second.json()["served_by"] == "economy"
+    assert labeled_metric_value("model_fallbacks", **labels) == before + 1
+
+def test_split_token_quotas():
+    """Test input and output token quotas are enforced apart, each naming its budget in the 429."""
+    for budget, usage in [("input", {"prompt_tokens": 150, "completion_tokens": 0}),
+                          ("output", {"prompt_tokens": 0, "completion_tokens": 30})]:
+        token = str(uuid.uuid4())
+        response = requests.post(f'{ADMIN_URL}/tokens', headers=ADMIN_HEADERS, json={"tokens": {token: f"split_{token[:8]}"}})
+        assert response.status_code == 200
+        headers = {'Authorization': f'Bearer {token}'}
+        first = requests.post(f"{GATEWAY_URL}/echo/split-quota", headers=headers, json={"usage": usage})
+        assert first.status_code == 200
+        time.sleep(0.5)  # usage is written once the response has been sent
+        second = requests.post(f"{GATEWAY_URL}/echo/split-quota", headers=headers, json={"usage": usage})
+        assert second.status_code == 429
+        assert second.headers['X-Token-Limit-Budget'] == budget
+        assert second.headers['X-Token-Limit-Limit'] == ("100" if budget == "input" else "20")
+
+def test_token_metrics_labels():
+    """Test token counters are labeled by location and user."""
+    labels = {"location": "/echo/openai", "user": "echo_user"}
+    before_in, before_out = labeled_metric_value("input_tokens", **labels), labeled_metric_value("output_tokens", **labels)
+    body = {"object": "chat.completion", "choices": [], "usage": {"prompt_tokens": 12, "completion_tokens": 3}}
+    assert requests.post(f"{GATEWAY_URL}/echo/openai", headers=HEADERS, json=body).status_code == 200
+    time.sleep(0.2)
+    assert labeled_metric_value("input_tokens", **labels) == before_in + 12
+    assert labeled_metric_value("output_tokens", **labels) == before_out + 3
+
+def test_token_weights():
+    """Test weighted tokens count towards the usage totals and raw tokens feed the metrics."""
+    labels = {"location": "/echo/weighted", "user": "echo_user"}
+    before_metrics = labeled_metric_value("input_tokens", **labels), labeled_metric_value("output_tokens", **labels)
+    before_in, before_out = usage_totals("echo_user")
+    body = {"object": "chat.completion", "choices": [], "usage": {"prompt_tokens": 12, "completion_tokens": 3}}
+    assert requests.post(f"{GATEWAY_URL}/echo/weighted", headers=HEADERS, json=body).status_code == 200
+    after_in, after_out = usage_totals("echo_user")
+    assert (after_in - before_in, after_out - before_out) == (24, 2)
+    assert labeled_metric_value("input_tokens", **labels) == before_metrics[0] + 12
+    assert labeled_metric_value("output_tokens", **labels) == before_metrics[1] + 3
+
+def test_estimate_tokens():
+    """Test tokens are estimated from the request and response text when the upstream reports no usage."""
+    before_in, before_out = usage_totals("echo_user")
+    body = {"messages": [{"role": "user", "content": "Hello world, how are you today?"}]}
+    response = requests.post(f"{GATEWAY_URL}/echo/estimated", headers=HEADERS, json=body)
+    assert response.status_code == 200, response.text
+    after_in, after_out = usage_totals("echo_user")
+    # the echo upstream answers with the request, so both sides hold the same text
+    assert after_in - before_in == after_out - before_out > 5
+
+def histogram_count(location, status):
+    """Return the number of requests observed by request_duration_seconds for the labels."""
+    response = requests.get(f'{PROMETHEUS_URL}/metrics')
+    for line in response.text.splitlines():
+        if line.startswith('request_duration_seconds_count{') and f'location="{location}"' in line and f'status="{status}"' in line:
+            return float(line.split()[1])
+    return 0
+
+def test_request_duration_histogram():
+    """Test request latency is recorded per location and status class."""
+    before_ok, before_missing = histogram_count("/echo/balanced", "2xx"), histogram_count("none", "4xx")
+    assert requests.post(API_URL, headers=HEADERS, json={}).status_code == 200
+    assert requests.post(f"{GATEWAY_URL}/echo/unknown", headers=HEADERS, json={}).status_code == 404
+    time.sleep(0.2)
+    assert histogram_count("/echo/balanced", "2xx") == before_ok + 1
+    assert histogram_count("none", "4xx") == before_missing + 1
@@ -0,0 +1,58 @@
+import uuid
+
+import requests
+import yaml
+
+# Load configuration
+with open('conf.yml') as f:
+    config = yaml.safe_load(f)
+
+GATEWAY_URL = f"http://{config['host']}:{config['port']}"
+ADMIN_URL = f"http://{config['admin_host']}:{config['admin_port']}"
+ADMIN_HEADERS = {'Authorization': f"Bearer {config['admin_secret']}"}
+# Seeded by BURGONET_MODE=dev: alice is in the admin, it and hr groups
+ALICE = {'Authorization': 'Bearer your_token_here'}
+BODY = {"hello": "world"}
+
+
+def post(location, headers=ALICE):
+    return requests.post(f'{GATEWAY_URL}{location}', headers=headers, json=BODY)
+
+def test_empty_allowed_groups():
+    """Test a location without allowed_groups is open to every group."""
+    assert post('/echo/balanced').status_code == 200
+
+def test_allowed_group_member():
+    """Test a user in one of the allowed groups is accepted."""
+    assert post('/echo/allowed').status_code == 200
+
+def test_not_in_allowed_groups():
+    """Test a user outside every allowed group is forbidden."""
+    assert post('/echo/finance').status_code == 403
+
+def test_conflicting_allowed_and_disabled_groups():
+    """Test disabled_groups still applies to a user admitted by allowed_groups."""
+    assert post('/echo/allowed-disabled').status_code == 401
+
+def test_user_without_groups():
+    """Test a user with no groups is forbidden when allowed_groups is set."""
+    token = str(uuid.uuid4())
+    requests.post(f'{ADMIN_URL}/tokens', headers=ADMIN_HEADERS, json={"tokens": {token: "groupless_user"}})
+    try:
+        headers = {'Authorization': f'Bearer {token}'}
+        assert post('/echo/allowed', headers).status_code == 403
+        assert post('/echo/balanced', headers).status_code == 200
+    finally:
+        requests.delete(f'{ADMIN_URL}/tokens/{token}', headers=ADMIN_HEADERS)
+
+def test_models_list():
+    """Test GET /models lists the locations the groups of the user may use."""
+    response = requests.get(f'{GATEWAY_URL}/models', headers=ALICE)
+    assert response.status_code == 200
+    models = response.json()
+    assert models['object'] == "list"
+    ids = {model['id'] for model in models['data']}
+    assert {'/echo/balanced', '/echo/allowed'} <= ids
+    assert not {'/echo/finance', '/echo/allowed-disabled'} & ids
+    assert all(model['object'] == "model" for model in models['data'])
+    assert requests.get(f'{GATEWAY_URL}/models').status_code == 401
@@ -0,0 +1,35 @@
+import time
+
+import requests
+import yaml
+
+# Load configuration
+with open('conf.yml') as f:
+    config = yaml.safe_load(f)
+
+HEALTH_URL = f"http://{config['health_host']}:{config['health_port']}"
+ECHO_UPSTREAM = f"{config['echo_host']}:{config['echo_port']}"
+
+
+def test_healthz():
+    """Test liveness reports the database as available."""
+    response = requests.get(f'{HEALTH_URL}/healthz', timeout=2)
+    assert response.status_code == 200
+    assert response.json() == {"status": "ok", "database": True}
+
+def test_readyz():
+    """Test readiness checks every upstream and answers quickly."""
+    start = time.monotonic()
+    response = requests.get(f'{HEALTH_URL}/readyz', timeout=5)
+    assert time.monotonic() - start < 2
+    body = response.json()
+    assert body["database"] is True
+    assert body["upstreams"][ECHO_UPSTREAM] is True
+    # Some configured upstreams (ollama, public APIs) may be unreachable here
+    expected = 200 if all(body["upstreams"].values()) else 503
+    assert response.status_code == expected
+    assert body["status"] == ("ok" if expected == 200 else "unavailable")
+
+def test_unknown_path():
+    response = requests.get(f'{HEALTH_URL}/nope', timeout=2)
+    assert response.status_code == 404
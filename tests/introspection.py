@@ -0,0 +1,93 @@
+"""Opaque bearer tokens checked at a stub OAuth2 introspection endpoint."""
+import base64
+import json
+import threading
+import time
+import uuid
+from http.server import BaseHTTPRequestHandler, HTTPServer
+from urllib.parse import parse_qs
+
+import requests
+import yaml
+
+# Load configuration
+with open('conf.yml') as f:
+    config = yaml.safe_load(f)
+
+GATEWAY_URL = f"http://{config['host']}:{config['port']}"
+INTROSPECTION = config['introspection']
+INTROSPECTION_PORT = 6212
+CREDENTIALS = base64.b64encode(f"{INTROSPECTION['client_id']}:{INTROSPECTION['client_secret']}".encode()).decode()
+
+# token -> introspection answer
+ANSWERS = {}
+
+
+class StubIntrospectionHandler(BaseHTTPRequestHandler):
+    calls = []
+
+    def do_POST(self):
+        form = parse_qs(self.rfile.read(int(self.headers['Content-Length'])).decode())
+        token = form['token'][0]
+        StubIntrospectionHandler.calls.append(token)
+        if self.headers.get('Authorization') != f"Basic {CREDENTIALS}":
+            self.send_response(401)
+            self.send_header('Content-Length', '0')
+            self.end_headers()
+            return
+        body = json.dumps(ANSWERS.get(token, {"active": False})).encode()
+        self.send_response(200)
+        self.send_header('Content-Type', 'application/json')
+        self.send_header('Content-Length', str(len(body)))
+        self.end_headers()
+        self.wfile.write(body)
+
+    def log_message(self, *args):
+        pass
+
+
+server = HTTPServer(('127.0.0.1', INTROSPECTION_PORT), StubIntrospectionHandler)
+
+
+def setup_module():
+    threading.Thread(target=server.serve_forever, daemon=True).start()
+
+def teardown_module():
+    server.shutdown()
+
+def opaque_token(**answer):
+    token = f"opaque-{uuid.uuid4()}"
+    if answer:
+        ANSWERS[token] = answer
+    return token
+
+def post(location, token):
+    return requests.post(f"{GATEWAY_URL}{location}", headers={'Authorization': f'Bearer {token}'}, json={"hello": "world"})
+
+def test_active_token():
+    """Test an active token is accepted and its answer reused."""
+    token = opaque_token(active=True, username="oauth_client", scope="reports")
+    for _ in range(3):
+        assert post('/echo/balanced', token).status_code == 200
+    assert StubIntrospectionHandler.calls.count(token) == 1
+
+def test_inactive_token():
+    """Test an inactive token is rejected, and the rejection is cached too."""
+    token = opaque_token()
+    for _ in range(2):
+        response = post('/echo/balanced', token)
+        assert response.status_code == 401
+        assert response.json()["error"]["message"] == "Invalid API key"
+    assert StubIntrospectionHandler.calls.count(token) == 1
+
+def test_scopes_are_groups():
+    """Test the scopes of the token are checked like groups."""
+    assert post('/echo/allowed', opaque_token(active=True, username="oauth_client", scope="finance reports")).status_code == 200
+    assert post('/echo/allowed', opaque_token(active=True, username="oauth_client", scope="reports")).status_code == 403
+
+def test_expired_answer_not_reused():
+    """Test a cached active answer is not used past the token's exp."""
+    token = opaque_token(active=True, sub="oauth_client", exp=int(time.time()) + 1)
+    assert post('/echo/balanced', token).status_code == 200
+    time.sleep(1.5)
+    assert post('/echo/balanced', token).status_code == 401
@@ -0,0 +1,61 @@
+"""Client networks allowed and denied by the ip_allowlist and ip_denylist of conf.yml.
+
+The tests connect from 127.0.0.1, a trusted proxy, and give the client address in X-Forwarded-For.
+"""
+import uuid
+
+import requests
+import yaml
+
+# Load configuration
+with open('conf.yml') as f:
+    config = yaml.safe_load(f)
+
+ADMIN_URL = f"http://{config['admin_host']}:{config['admin_port']}"
+ADMIN_HEADERS = {'Authorization': f"Bearer {config['admin_secret']}"}
+GATEWAY_URL = f"http://{config['host']}:{config['port']}"
+TEST_TOKEN = str(uuid.uuid4())
+HEADERS = {'Authorization': f'Bearer {TEST_TOKEN}'}
+
+
+def setup_module():
+    response = requests.post(f'{ADMIN_URL}/tokens', headers=ADMIN_HEADERS, json={"tokens": {TEST_TOKEN: "ip_user"}})
+    assert response.status_code == 200
+
+def teardown_module():
+    requests.delete(f'{ADMIN_URL}/tokens', headers=ADMIN_HEADERS, json={"tokens": [TEST_TOKEN]})
+
+def status(location, forwarded_for=None, headers=HEADERS):
+    headers = dict(headers)
+    if forwarded_for is not None:
+        headers['X-Forwarded-For'] = forwarded_for
+    return requests.post(f"{GATEWAY_URL}{location}", headers=headers, json={}).status_code
+
+def test_global_denylist():
+    """Test denied IPv4 and IPv6 networks are rejected before authentication."""
+    for address in ["203.0.113.7", "2001:db8:dead::1", "2001:db8:dead:ffff::1"]:
+        assert status("/echo/openai", address, headers={}) == 403
+    assert status("/echo/openai", "203.0.114.1") == 200
+    assert status("/echo/openai", "2001:db8:beef::1") == 200
+
+def test_forwarded_for_spoofing():
+    """Test only the hop added by the trusted proxy counts, not those written by the client."""
+    assert status("/echo/openai", "203.0.113.7, 198.51.100.1") == 200
+    assert status("/echo/openai", "198.51.100.1, 203.0.113.7") == 403
+    # trusted proxies in the chain are skipped
+    assert status("/echo/openai", "203.0.113.7, 127.0.0.1") == 403
+
+def test_location_allowlist_ipv4():
+    """Test a location with an allowlist only serves its networks, minus its denylist."""
+    assert status("/echo/ip", "192.0.2.10") == 200
+    assert status("/echo/ip", "192.0.2.66") == 403
+    assert status("/echo/ip", "198.51.100.1") == 403
+    # the proxy itself is not in the allowlist
+    assert status("/echo/ip") == 403
+
+def test_location_allowlist_ipv6():
+    """Test IPv6 ranges, IPv4-mapped addresses being matched as IPv4."""
+    assert status("/echo/ip", "2001:db8::1") == 200
+    assert status("/echo/ip", "2001:db8:bad::1") == 403
+    assert status("/echo/ip", "2001:db9::1") == 403
+    assert status("/echo/ip", "::ffff:192.0.2.10") == 200
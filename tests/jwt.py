@@ -0,0 +1,106 @@
+"""JWT bearer tokens validated against the JWKS of a stub identity provider.
+
+The RSA key is generated with the openssl command line tool.
+"""
+import base64
+import json
+import subprocess
+import tempfile
+import threading
+import time
+from http.server import BaseHTTPRequestHandler, HTTPServer
+
+import requests
+import yaml
+
+# Load configuration
+with open('conf.yml') as f:
+    config = yaml.safe_load(f)
+
+GATEWAY_URL = f"http://{config['host']}:{config['port']}"
+JWT = config['jwt']
+JWKS_PORT = 6211
+KID = "test-key"
+
+key_file = tempfile.NamedTemporaryFile(suffix='.pem')
+subprocess.run(['openssl', 'genrsa', '-out', key_file.name, '2048'], check=True, capture_output=True)
+
+
+def b64url(data):
+    return base64.urlsafe_b64encode(data).rstrip(b'=').decode()
+
+def public_jwk():
+    text = subprocess.run(['openssl', 'rsa', '-in', key_file.name, '-noout', '-modulus'],
+                          check=True, capture_output=True, text=True).stdout
+    modulus = bytes.fromhex(text.strip().split('=', 1)[1])
+    return {"kty": "RSA", "kid": KID, "use": "sig", "alg": "RS256", "n": b64url(modulus), "e": b64url((65537).to_bytes(3, 'big'))}
+
+def sign(claims, kid=KID):
+    header = b64url(json.dumps({"alg": "RS256", "typ": "JWT", "kid": kid}).encode())
+    payload = b64url(json.dumps(claims).encode())
+    signing_input = f"{header}.{payload}".encode()
+    signature = subprocess.run(['openssl', 'dgst', '-sha256', '-sign', key_file.name],
+                               input=signing_input, check=True, capture_output=True).stdout
+    return f"{header}.{payload}.{b64url(signature)}"
+
+def claims(**overrides):
+    now = int(time.time())
+    return {"iss": JWT['issuer'], "aud": JWT['audience'], "email": "jwt_user@example.com",
+            "groups": ["finance"], "iat": now, "exp": now + 600, **overrides}
+
+def post(location, token):
+    return requests.post(f"{GATEWAY_URL}{location}", headers={'Authorization': f'Bearer {token}'}, json={"hello": "world"})
+
+
+class StubJwksHandler(BaseHTTPRequestHandler):
+    def do_GET(self):
+        body = json.dumps({"keys": [public_jwk()]}).encode()
+        self.send_response(200)
+        self.send_header('Content-Type', 'application/json')
+        self.send_header('Content-Length', str(len(body)))
+        self.end_headers()
+        self.wfile.write(body)
+
+    def log_message(self, *args):
+        pass
+
+
+server = HTTPServer(('127.0.0.1', JWKS_PORT), StubJwksHandler)
+
+
+def setup_module():
+    threading.Thread(target=server.serve_forever, daemon=True).start()
+
+def teardown_module():
+    server.shutdown()
+
+def test_valid_jwt():
+    """Test a JWT signed by a JWKS key is accepted."""
+    response = post('/echo/balanced', sign(claims()))
+    assert response.status_code == 200, response.text
+
+def test_jwt_groups_claim():
+    """Test the groups come from the groups claim."""
+    assert post('/echo/allowed', sign(claims(groups=["finance"]))).status_code == 200
+    assert post('/echo/allowed', sign(claims(groups=["sales"]))).status_code == 403
+
+def test_expired_jwt():
+    now = int(time.time())
+    response = post('/echo/balanced', sign(claims(iat=now - 7200, exp=now - 3600)))
+    assert response.status_code == 401
+    assert response.json()["error"]["message"] == "API key expired"
+
+def test_jwt_wrong_audience_or_issuer():
+    assert post('/echo/balanced', sign(claims(aud="someone-else"))).status_code == 401
+    assert post('/echo/balanced', sign(claims(iss="https://evil.example.com"))).status_code == 401
+
+def test_jwt_tampered_or_unknown_key():
+    header, payload, signature = sign(claims()).split('.')
+    forged = b64url(json.dumps(claims(email="admin@example.com")).encode())
+    assert post('/echo/balanced', f"{header}.{forged}.{signature}").status_code == 401
+    assert post('/echo/balanced', sign(claims(), kid="unknown-key")).status_code == 401
+
+def test_jwt_missing_user_claim():
+    token_claims = claims()
+    del token_claims["email"]
+    assert post('/echo/balanced', sign(token_claims)).status_code == 401
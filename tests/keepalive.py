@@ -0,0 +1,65 @@
+"""Keep-alive requests sent in the background to the upstreams of a model."""
+import json
+import threading
+import time
+from http.server import BaseHTTPRequestHandler, ThreadingHTTPServer
+
+import requests
+import yaml
+
+# Load configuration
+with open('conf.yml') as f:
+    config = yaml.safe_load(f)
+
+PROMETHEUS_URL = f"http://{config['prometheus_host']}:{config['prometheus_port']}"
+UPSTREAM_PORT = 6231
+LOCATION = "/echo/keepalive"
+MODEL = next(m for m in config['models'] if m['location'] == LOCATION)
+received = []
+
+
+class RecordingHandler(BaseHTTPRequestHandler):
+    """Records the requests it gets and answers a one token completion."""
+
+    def do_POST(self):
+        body = json.loads(self.rfile.read(int(self.headers['Content-Length'])))
+        received.append((time.monotonic(), self.path, self.headers, body))
+        answer = json.dumps({"choices": [{"message": {"content": "pong"}}], "usage": {"prompt_tokens": 1, "completion_tokens": 1}}).encode()
+        self.send_response(200)
+        self.send_header('Content-Type', 'application/json')
+        self.send_header('Content-Length', str(len(answer)))
+        self.end_headers()
+        self.wfile.write(answer)
+
+    def log_message(self, *args):
+        pass
+
+
+server = ThreadingHTTPServer(('127.0.0.1', UPSTREAM_PORT), RecordingHandler)
+
+
+def setup_module():
+    threading.Thread(target=server.serve_forever, daemon=True).start()
+    time.sleep(MODEL['keepalive_interval_secs'] * 3.5)
+
+def teardown_module():
+    server.shutdown()
+
+def test_requests_sent_each_interval():
+    assert len(received) >= 3
+    times = [t for t, _, _, _ in received]
+    gaps = [later - earlier for earlier, later in zip(times, times[1:])]
+    assert all(gap >= MODEL['keepalive_interval_secs'] * 0.8 for gap in gaps), gaps
+
+def test_request_content():
+    """Test the keep-alive request is a one token completion of the model, sent with its key."""
+    _, path, headers, body = received[-1]
+    assert path == "/v1/chat/completions"
+    assert headers['Authorization'] == f"Bearer {MODEL['api_key']}"
+    assert body == {"model": MODEL['model_name'], "messages": [{"role": "user", "content": "ping"}], "max_tokens": 1, "stream": False}
+
+def test_not_counted_as_traffic():
+    """Test the keep-alive requests only show in their own metric, not with the client requests."""
+    metrics = requests.get(f'{PROMETHEUS_URL}/metrics').text
+    assert f'keepalive_requests{{location="{LOCATION}",result="ok"}}' in metrics
+    assert not [line for line in metrics.splitlines() if f'location="{LOCATION}"' in line and not line.startswith('keepalive_requests')]
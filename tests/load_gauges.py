@@ -0,0 +1,86 @@
+"""Gauges of the active connections and of the requests in flight on each model."""
+import json
+import re
+import threading
+import time
+import uuid
+from concurrent.futures import ThreadPoolExecutor
+from http.server import BaseHTTPRequestHandler, ThreadingHTTPServer
+
+import requests
+import yaml
+
+# Load configuration
+with open('conf.yml') as f:
+    config = yaml.safe_load(f)
+
+ADMIN_URL = f"http://{config['admin_host']}:{config['admin_port']}"
+ADMIN_HEADERS = {'Authorization': f"Bearer {config['admin_secret']}"}
+GATEWAY_URL = f"http://{config['host']}:{config['port']}"
+PROMETHEUS_URL = f"http://{config['prometheus_host']}:{config['prometheus_port']}"
+UPSTREAM_PORT = 6232
+LOCATION = "/echo/inflight"
+READ_TIMEOUT = next(m for m in config['models'] if m['location'] == LOCATION)['read_timeout_ms'] / 1000
+TEST_TOKEN = str(uuid.uuid4())
+HEADERS = {'Authorization': f'Bearer {TEST_TOKEN}'}
+
+
+class SlowHandler(BaseHTTPRequestHandler):
+    """Upstream answering after the `delay` seconds of the request body."""
+
+    def do_POST(self):
+        request = json.loads(self.rfile.read(int(self.headers.get('Content-Length', 0))))
+        time.sleep(request['delay'])
+        body = json.dumps(request).encode()
+        try:
+            self.send_response(200)
+            self.send_header('Content-Type', 'application/json')
+            self.send_header('Content-Length', str(len(body)))
+            self.end_headers()
+            self.wfile.write(body)
+        except OSError:
+            pass  # the gateway gave up on us
+
+    def log_message(self, *args):
+        pass
+
+
+server = ThreadingHTTPServer(('127.0.0.1', UPSTREAM_PORT), SlowHandler)
+
+
+def setup_module():
+    threading.Thread(target=server.serve_forever, daemon=True).start()
+    response = requests.post(f'{ADMIN_URL}/tokens', headers=ADMIN_HEADERS, json={"tokens": {TEST_TOKEN: "load_gauges_user"}})
+    assert response.status_code == 200
+
+def teardown_module():
+    server.shutdown()
+    requests.delete(f'{ADMIN_URL}/tokens', headers=ADMIN_HEADERS, json={"tokens": [TEST_TOKEN]})
+
+def gauge(name, labels=""):
+    metrics = requests.get(f'{PROMETHEUS_URL}/metrics').text
+    match = re.search(rf'^{name}{re.escape(labels)} (\d+)$', metrics, re.MULTILINE)
+    return int(match.group(1)) if match else 0
+
+def in_flight():
+    return gauge("in_flight_requests", f'{{location="{LOCATION}"}}')
+
+def post(delay):
+    return requests.post(f"{GATEWAY_URL}{LOCATION}", headers=HEADERS, json={"delay": delay}).status_code
+
+def test_gauges_follow_the_load():
+    """Test the gauges count the requests in progress, and go back down once they complete."""
+    with ThreadPoolExecutor(max_workers=3) as pool:
+        statuses = [pool.submit(post, READ_TIMEOUT / 2) for _ in range(3)]
+        time.sleep(READ_TIMEOUT / 4)
+        assert in_flight() == 3
+        assert gauge("active_requests") >= 3
+        assert [status.result() for status in statuses] == [200] * 3
+    time.sleep(0.2)  # the requests are logged after their response
+    assert in_flight() == 0
+
+def test_released_on_error():
+    """Test a request failing on the upstream read timeout leaves the gauge."""
+    assert post(READ_TIMEOUT * 2) == 504
+    time.sleep(0.2)
+    assert in_flight() == 0
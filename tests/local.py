@@ -11,6 +11,7 @@ with open('conf.yml') as f:
 
 # Test configuration
 ADMIN_URL = f"http://{config['admin_host']}:{config['admin_port']}"
+ADMIN_HEADERS = {'Authorization': f"Bearer {config['admin_secret']}"}
 API_URL = f"http://{config['host']}:{config['port']}/ollama/gemma2/2b/"
 TEST_TOKEN = str(uuid.uuid4())
 HEADERS = {
@@ -41,7 +42,7 @@ def setup_module():
     token_data = {
         "tokens": {TEST_TOKEN : "test_user"}
     }
-    response = requests.post(f'{ADMIN_URL}/tokens', json=token_data)
+    response = requests.post(f'{ADMIN_URL}/tokens', headers=ADMIN_HEADERS, json=token_data)
     log.debug("Response setup test token: %s", response.text)
     assert response.status_code == 200, "Failed to create test token"
 
@@ -54,7 +55,7 @@ def teardown_module():
     token_data = {
         "tokens": [TEST_TOKEN]
     }
-    response = requests.delete(f'{ADMIN_URL}/tokens', json=token_data)
+    response = requests.delete(f'{ADMIN_URL}/tokens', headers=ADMIN_HEADERS, json=token_data)
     assert response.status_code == 200, "Failed to delete test token"
 
 def test_invalid_token():
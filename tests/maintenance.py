@@ -0,0 +1,66 @@
+"""Maintenance mode, rejecting the model requests while the gateway keeps running."""
+import subprocess
+import time
+import uuid
+
+import requests
+import yaml
+
+# Load configuration
+with open('conf.yml') as f:
+    config = yaml.safe_load(f)
+
+ADMIN_URL = f"http://{config['admin_host']}:{config['admin_port']}"
+ADMIN_HEADERS = {'Authorization': f"Bearer {config['admin_secret']}"}
+GATEWAY_URL = f"http://{config['host']}:{config['port']}"
+HEALTH_URL = f"http://{config['health_host']}:{config['health_port']}"
+TEST_TOKEN = str(uuid.uuid4())
+HEADERS = {'Authorization': f'Bearer {TEST_TOKEN}'}
+
+
+def setup_module():
+    response = requests.post(f'{ADMIN_URL}/tokens', headers=ADMIN_HEADERS, json={"tokens": {TEST_TOKEN: "maintenance_user"}})
+    assert response.status_code == 200
+
+def teardown_module():
+    set_maintenance(False)
+    requests.delete(f'{ADMIN_URL}/tokens', headers=ADMIN_HEADERS, json={"tokens": [TEST_TOKEN]})
+
+def set_maintenance(enabled):
+    response = requests.put(f'{ADMIN_URL}/maintenance', headers=ADMIN_HEADERS, json={"maintenance_mode": enabled})
+    assert response.status_code == 200
+    assert response.json() == {"maintenance_mode": enabled}
+
+def post():
+    body = {"object": "chat.completion", "choices": [], "usage": {"prompt_tokens": 1, "completion_tokens": 1}}
+    return requests.post(f"{GATEWAY_URL}/echo/openai", headers=HEADERS, json=body)
+
+def test_admin_toggle():
+    """Test model requests get a 503 with Retry-After during maintenance, and pass again after."""
+    set_maintenance(True)
+    response = post()
+    assert response.status_code == 503
+    assert response.headers['Retry-After'] == str(config.get('maintenance_retry_after_secs', 60))
+    assert response.json()['error']['message'] == "Gateway under maintenance, try again later"
+    assert requests.get(f'{ADMIN_URL}/maintenance', headers=ADMIN_HEADERS).json() == {"maintenance_mode": True}
+
+    # health checks and the admin API are still served
+    assert requests.get(f'{HEALTH_URL}/healthz').status_code == 200
+    assert requests.get(f'{ADMIN_URL}/tokens', headers=ADMIN_HEADERS).status_code == 200
+
+    set_maintenance(False)
+    assert post().status_code == 200
+
+def test_signal_toggle():
+    """Test SIGUSR1 switches the maintenance mode on and off."""
+    subprocess.run(['pkill', '-USR1', '-x', 'burgonet-gw'], check=True)
+    time.sleep(0.3)
+    assert post().status_code == 503
+    subprocess.run(['pkill', '-USR1', '-x', 'burgonet-gw'], check=True)
+    time.sleep(0.3)
+    assert post().status_code == 200
+
+def test_invalid_body():
+    """Test the admin API expects a boolean."""
+    response = requests.put(f'{ADMIN_URL}/maintenance', headers=ADMIN_HEADERS, json={"maintenance_mode": "yes"})
+    assert response.status_code == 400
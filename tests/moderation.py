@@ -0,0 +1,93 @@
+"""Request bodies scored by a moderation service, with moderation_url and moderation_thresholds."""
+import base64
+import json
+import threading
+import uuid
+from http.server import BaseHTTPRequestHandler, HTTPServer
+
+import requests
+import yaml
+
+# Load configuration
+with open('conf.yml') as f:
+    config = yaml.safe_load(f)
+
+ADMIN_URL = f"http://{config['admin_host']}:{config['admin_port']}"
+ADMIN_HEADERS = {'Authorization': f"Bearer {config['admin_secret']}"}
+GATEWAY_URL = f"http://{config['host']}:{config['port']}"
+PROMETHEUS_URL = f"http://{config['prometheus_host']}:{config['prometheus_port']}"
+API_URL = f"{GATEWAY_URL}/echo/moderated"
+TEST_TOKEN = str(uuid.uuid4())
+HEADERS = {'Authorization': f'Bearer {TEST_TOKEN}'}
+SERVICE_PORT = 6223
+
+
+class StubModerationHandler(BaseHTTPRequestHandler):
+    """Scores the decoded text with the `scores` of its JSON body, or answers its `status`."""
+
+    def do_POST(self):
+        payload = json.loads(self.rfile.read(int(self.headers['Content-Length'])))
+        request = json.loads(base64.b64decode(payload["text"]))
+        body = json.dumps({"categories": request.get("scores", {})}).encode()
+        self.send_response(request.get("status", 200))
+        self.send_header('Content-Type', 'application/json')
+        self.send_header('Content-Length', str(len(body)))
+        self.end_headers()
+        self.wfile.write(body)
+
+    def log_message(self, *args):
+        pass
+
+
+server = HTTPServer(('127.0.0.1', SERVICE_PORT), StubModerationHandler)
+
+
+def setup_module():
+    threading.Thread(target=server.serve_forever, daemon=True).start()
+    response = requests.post(f'{ADMIN_URL}/tokens', headers=ADMIN_HEADERS, json={"tokens": {TEST_TOKEN: "moderated_user"}})
+    assert response.status_code == 200, "Failed to create test token"
+
+def teardown_module():
+    server.shutdown()
+    requests.delete(f'{ADMIN_URL}/tokens', headers=ADMIN_HEADERS, json={"tokens": [TEST_TOKEN]})
+
+def labeled_metric_value(name, **labels):
+    """Return the value of the Prometheus series with exactly these labels, 0 if absent."""
+    series = name + "{" + ",".join(f'{k}="{v}"' for k, v in sorted(labels.items())) + "}"
+    response = requests.get(f'{PROMETHEUS_URL}/metrics')
+    for line in response.text.splitlines():
+        if line.startswith(series + " "):
+            return float(line.split()[1])
+    return 0
+
+def test_scores_under_thresholds():
+    """Test a body scored under every threshold reaches the upstream."""
+    body = {"scores": {"hate": 0.5, "violence": 0.3, "sexual": 0.99}}
+    response = requests.post(API_URL, headers=HEADERS, json=body)
+    assert response.status_code == 200
+    assert response.json() == body
+
+def test_category_over_threshold():
+    """Test a body over a threshold is rejected naming the category, and counted."""
+    before = labeled_metric_value("moderation_flags", category="violence")
+    response = requests.post(API_URL, headers=HEADERS, json={"scores": {"hate": 0.1, "violence": 0.95}})
+    assert response.status_code == 403
+    assert response.json()["error"]["message"] == "Request body flagged for violence"
+    assert labeled_metric_value("moderation_flags", category="violence") == before + 1
+
+def test_highest_excess_reported():
+    """Test the category furthest over its threshold is reported when several are."""
+    response = requests.post(API_URL, headers=HEADERS, json={"scores": {"hate": 0.6, "self-harm": 0.9}})
+    assert response.status_code == 403
+    assert response.json()["error"]["message"] == "Request body flagged for self-harm"
+
+def test_service_error_fail_closed():
+    """Test a failing service blocks requests by default."""
+    response = requests.post(API_URL, headers=HEADERS, json={"status": 500})
+    assert response.status_code == 503
+    assert response.json()["error"]["message"] == "Moderation service unavailable"
+
+def test_service_down_fail_open():
+    """Test an unreachable service lets requests through in open mode."""
+    response = requests.post(f"{GATEWAY_URL}/echo/moderated/open", headers=HEADERS, json={"hello": "world"})
+    assert response.status_code == 200
@@ -0,0 +1,137 @@
+"""Token counting of Ollama /api/chat and /api/generate responses, single JSON and NDJSON streams."""
+import json
+import threading
+import time
+import uuid
+from http.server import BaseHTTPRequestHandler, ThreadingHTTPServer
+
+import requests
+import yaml
+
+# Load configuration
+with open('conf.yml') as f:
+    config = yaml.safe_load(f)
+
+ADMIN_URL = f"http://{config['admin_host']}:{config['admin_port']}"
+ADMIN_HEADERS = {'Authorization': f"Bearer {config['admin_secret']}"}
+GATEWAY_URL = f"http://{config['host']}:{config['port']}"
+API_URL = f"{GATEWAY_URL}/echo/ollama"
+UPSTREAM_PORT = 6225
+TEST_TOKEN = str(uuid.uuid4())
+USER = "ollama_user"
+HEADERS = {'Authorization': f'Bearer {TEST_TOKEN}'}
+
+# Responses recorded from Ollama 0.5
+CHAT = {
+    "model": "llama3.2", "created_at": "2025-01-14T10:02:31.719213Z",
+    "message": {"role": "assistant", "content": "Hello! How can I help you today?"},
+    "done_reason": "stop", "done": True, "total_duration": 1095138292, "load_duration": 28461792,
+    "prompt_eval_count": 26, "prompt_eval_duration": 398000000, "eval_count": 10, "eval_duration": 665000000,
+}
+GENERATE = {
+    "model": "llama3.2", "created_at": "2025-01-14T10:04:12.104633Z",
+    "response": "The sky is blue because of Rayleigh scattering.",
+    "done": True, "done_reason": "stop", "context": [128006, 882, 128007, 271, 791, 13180],
+    "total_duration": 5043500667, "load_duration": 5025959,
+    "prompt_eval_count": 31, "prompt_eval_duration": 325953000, "eval_count": 11, "eval_duration": 4709213000,
+}
+CHAT_STREAM = [
+    {"model": "llama3.2", "created_at": "2025-01-14T10:05:01.1Z", "message": {"role": "assistant", "content": "Hello"}, "done": False},
+    {"model": "llama3.2", "created_at": "2025-01-14T10:05:01.2Z", "message": {"role": "assistant", "content": "!"}, "done": False},
+    {"model": "llama3.2", "created_at": "2025-01-14T10:05:01.3Z", "message": {"role": "assistant", "content": ""},
+     "done_reason": "stop", "done": True, "total_duration": 4883583458, "load_duration": 1334875,
+     "prompt_eval_count": 26, "prompt_eval_duration": 342546000, "eval_count": 2, "eval_duration": 4535599000},
+]
+GENERATE_STREAM = [
+    {"model": "llama3.2", "created_at": "2025-01-14T10:06:00.1Z", "response": "The", "done": False},
+    {"model": "llama3.2", "created_at": "2025-01-14T10:06:00.2Z", "response": " sky", "done": False},
+    {"model": "llama3.2", "created_at": "2025-01-14T10:06:00.3Z", "response": "", "done": True, "done_reason": "stop",
+     "context": [1, 2, 3], "total_duration": 10706818083, "load_duration": 6338219291,
+     "prompt_eval_count": 12, "prompt_eval_duration": 130079000, "eval_count": 3, "eval_duration": 4232710000},
+]
+
+
+class ReplayHandler(BaseHTTPRequestHandler):
+    """Answers with the `lines` of the request body, one chunk each, as `content_type`."""
+    protocol_version = "HTTP/1.1"
+
+    def do_POST(self):
+        request = json.loads(self.rfile.read(int(self.headers['Content-Length'])))
+        self.send_response(200)
+        self.send_header('Content-Type', request['content_type'])
+        self.send_header('Transfer-Encoding', 'chunked')
+        self.end_headers()
+        for line in request['lines']:
+            chunk = (json.dumps(line) + "\n").encode()
+            self.wfile.write(b"%x\r\n%s\r\n" % (len(chunk), chunk))
+            self.wfile.flush()
+            time.sleep(0.05)
+        self.wfile.write(b"0\r\n\r\n")
+
+    def log_message(self, *args):
+        pass
+
+
+server = ThreadingHTTPServer(('127.0.0.1', UPSTREAM_PORT), ReplayHandler)
+
+
+def setup_module():
+    threading.Thread(target=server.serve_forever, daemon=True).start()
+    response = requests.post(f'{ADMIN_URL}/tokens', headers=ADMIN_HEADERS, json={"tokens": {TEST_TOKEN: USER}})
+    assert response.status_code == 200
+
+def teardown_module():
+    server.shutdown()
+    requests.delete(f'{ADMIN_URL}/tokens', headers=ADMIN_HEADERS, json={"tokens": [TEST_TOKEN]})
+
+def usage_totals():
+    """Return the (input, output) tokens recorded for the user today."""
+    time.sleep(0.5)  # usage is written once the response has been sent
+    response = requests.get(f'{ADMIN_URL}/usage/daily', headers=ADMIN_HEADERS)
+    assert response.status_code == 200
+    totals = {"in": 0, "out": 0}
+    for entry in response.json():
+        for key, value in entry.items():
+            _, _, key_user, direction = key.split(":")
+            if key_user == USER:
+                totals[direction] += value
+    return totals["in"], totals["out"]
+
+def replay(lines, content_type):
+    """Return the response lines and the tokens counted for them."""
+    before_in, before_out = usage_totals()
+    response = requests.post(API_URL, headers=HEADERS, json={"content_type": content_type, "lines": lines})
+    assert response.status_code == 200, response.text
+    after_in, after_out = usage_totals()
+    return [json.loads(line) for line in response.text.splitlines()], (after_in - before_in, after_out - before_out)
+
+def test_chat():
+    """Test /api/chat responses count prompt_eval_count and eval_count."""
+    lines, tokens = replay([CHAT], "application/json; charset=utf-8")
+    assert lines == [CHAT]
+    assert tokens == (26, 10)
+
+def test_generate():
+    """Test /api/generate responses count prompt_eval_count and eval_count."""
+    lines, tokens = replay([GENERATE], "application/json; charset=utf-8")
+    assert lines == [GENERATE]
+    assert tokens == (31, 11)
+
+def test_chat_stream():
+    """Test /api/chat NDJSON streams are forwarded as sent and counted from their final message."""
+    lines, tokens = replay(CHAT_STREAM, "application/x-ndjson")
+    assert lines == CHAT_STREAM
+    assert tokens == (26, 2)
+
+def test_generate_stream():
+    """Test /api/generate NDJSON streams are counted from their final message."""
+    lines, tokens = replay(GENERATE_STREAM, "application/x-ndjson")
+    assert lines == GENERATE_STREAM
+    assert tokens == (12, 3)
+
+def test_cached_prompt():
+    """Test a final message without prompt_eval_count, left out by Ollama for a cached prompt, counts its output."""
+    final = {k: v for k, v in CHAT_STREAM[-1].items() if not k.startswith("prompt_eval")}
+    lines, tokens = replay(CHAT_STREAM[:-1] + [final], "application/x-ndjson")
+    assert lines[-1] == final
+    assert tokens == (0, 2)
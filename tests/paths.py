@@ -0,0 +1,104 @@
+"""Client path and query forwarded to the upstream, and the Host header it gets."""
+import json
+import threading
+import uuid
+from http.server import BaseHTTPRequestHandler, HTTPServer
+
+import requests
+import yaml
+
+# Load configuration
+with open('conf.yml') as f:
+    config = yaml.safe_load(f)
+
+ADMIN_URL = f"http://{config['admin_host']}:{config['admin_port']}"
+ADMIN_HEADERS = {'Authorization': f"Bearer {config['admin_secret']}"}
+GATEWAY_URL = f"http://{config['host']}:{config['port']}"
+UPSTREAM_PORT = 6216
+TEST_TOKEN = str(uuid.uuid4())
+HEADERS = {'Authorization': f'Bearer {TEST_TOKEN}'}
+
+
+class PathHandler(BaseHTTPRequestHandler):
+    """Upstream answering with the path, query and Host header it received."""
+
+    def do_POST(self):
+        self.rfile.read(int(self.headers.get('Content-Length', 0)))
+        body = json.dumps({"path": self.path, "host": self.headers["Host"]}).encode()
+        self.send_response(200)
+        self.send_header('Content-Type', 'application/json')
+        self.send_header('Content-Length', str(len(body)))
+        self.end_headers()
+        self.wfile.write(body)
+
+    def log_message(self, *args):
+        pass
+
+
+server = HTTPServer(('127.0.0.1', UPSTREAM_PORT), PathHandler)
+
+
+def setup_module():
+    threading.Thread(target=server.serve_forever, daemon=True).start()
+    response = requests.post(f'{ADMIN_URL}/tokens', headers=ADMIN_HEADERS, json={"tokens": {TEST_TOKEN: "paths_user"}})
+    assert response.status_code == 200
+
+def teardown_module():
+    server.shutdown()
+    requests.delete(f'{ADMIN_URL}/tokens', headers=ADMIN_HEADERS, json={"tokens": [TEST_TOKEN]})
+
+def upstream_path(path):
+    response = requests.post(f"{GATEWAY_URL}{path}", headers=HEADERS, json={})
+    assert response.status_code == 200, response.text
+    return response.json()['path']
+
+def test_prefix_location():
+    """Test the path after a prefix location is joined to a base with a trailing slash."""
+    assert upstream_path("/echo/prefix/chat/completions") == "/v1/chat/completions"
+    assert upstream_path("/echo/prefix/") == "/v1/"
+
+def test_prefix_location_base_without_slash():
+    assert upstream_path("/echo/prefix-noslash/chat/completions") == "/v1/chat/completions"
+    assert upstream_path("/echo/prefix-noslash/") == "/v1"
+
+def test_client_query():
+    """Test the client query string is forwarded."""
+    assert upstream_path("/echo/prefix/chat/completions?foo=bar&n=2") == "/v1/chat/completions?foo=bar&n=2"
+
+def test_client_query_after_proxy_pass_query():
+    """Test the client query follows the query of the proxy_pass."""
+    assert upstream_path("/echo/query") == "/v1/chat?api-version=1"
+    assert upstream_path("/echo/query?foo=bar") == "/v1/chat?api-version=1&foo=bar"
+
+def test_exact_location_no_suffix():
+    """Test a location without trailing slash only serves its exact path."""
+    response = requests.post(f"{GATEWAY_URL}/echo/query/more", headers=HEADERS, json={})
+    assert response.status_code == 404
+
+def test_host_header_from_proxy_pass():
+    response = requests.post(f"{GATEWAY_URL}/echo/query", headers=HEADERS, json={})
+    assert response.json()['host'] == "127.0.0.1"
+
+def test_host_header_override():
+    """Test upstream_host_header replaces the host of the proxy_pass."""
+    response = requests.post(f"{GATEWAY_URL}/echo/vhost", headers={**HEADERS, 'Host': 'client.example'}, json={})
+    assert response.status_code == 200
+    assert response.json() == {"path": "/v1/chat", "host": "llm.internal.example"}
+
+def test_regex_location():
+    """Test a regex location serves every path it matches, whole, and keeps the rest of the path
+    after its literal prefix."""
+    assert upstream_path("/echo/routed/completions") == "/v1/completions"
+    assert upstream_path("/echo/routed/completions?n=1") == "/v1/completions?n=1"
+    for path in ["/echo/routed/embeddings", "/echo/routed/completions/more", "/x/echo/routed/completions"]:
+        assert requests.post(f"{GATEWAY_URL}{path}", headers=HEADERS, json={}).status_code == 404
+
+def test_longest_literal_prefix_wins():
+    """Test the prefix location with a longer literal prefix wins over the regex matching too."""
+    assert upstream_path("/echo/routed/chat/completions") == "/chat-only/completions"
+    assert upstream_path("/echo/routed/chat") == "/chat-only"
+
+def test_prefix_location_segments():
+    """Test a prefix location without trailing slash matches whole path segments only."""
+    response = requests.post(f"{GATEWAY_URL}/echo/routed/chatbot", headers=HEADERS, json={})
+    assert response.status_code == 404
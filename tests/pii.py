@@ -0,0 +1,69 @@
+import base64
+import json
+import threading
+import uuid
+from http.server import BaseHTTPRequestHandler, HTTPServer
+
+import requests
+import yaml
+
+# Load configuration
+with open('conf.yml') as f:
+    config = yaml.safe_load(f)
+
+ADMIN_URL = f"http://{config['admin_host']}:{config['admin_port']}"
+ADMIN_HEADERS = {'Authorization': f"Bearer {config['admin_secret']}"}
+GATEWAY_URL = f"http://{config['host']}:{config['port']}"
+API_URL = f"{GATEWAY_URL}/echo/pii/stub"
+TEST_TOKEN = str(uuid.uuid4())
+HEADERS = {'Authorization': f'Bearer {TEST_TOKEN}'}
+PII_PORT = 6210
+
+
+class StubPiiHandler(BaseHTTPRequestHandler):
+    """Answers 400 when the decoded text contains "SSN", 200 otherwise, and counts the calls."""
+    calls = 0
+
+    def do_POST(self):
+        StubPiiHandler.calls += 1
+        payload = json.loads(self.rfile.read(int(self.headers['Content-Length'])))
+        text = base64.b64decode(payload["text"])
+        self.send_response(400 if b"SSN" in text else 200)
+        self.send_header('Content-Length', '0')
+        self.end_headers()
+
+    def log_message(self, *args):
+        pass
+
+
+server = HTTPServer(('127.0.0.1', PII_PORT), StubPiiHandler)
+
+
+def setup_module():
+    threading.Thread(target=server.serve_forever, daemon=True).start()
+    response = requests.post(f'{ADMIN_URL}/tokens', headers=ADMIN_HEADERS, json={"tokens": {TEST_TOKEN: "pii_user"}})
+    assert response.status_code == 200, "Failed to create test token"
+
+def teardown_module():
+    server.shutdown()
+    requests.delete(f'{ADMIN_URL}/tokens', headers=ADMIN_HEADERS, json={"tokens": [TEST_TOKEN]})
+
+def test_identical_bodies_are_checked_once():
+    """Test the PII answer for a body is reused for the same body."""
+    body = {"prompt": f"hello {uuid.uuid4()}"}
+    before = StubPiiHandler.calls
+    for _ in range(3):
+        assert requests.post(API_URL, headers=HEADERS, json=body).status_code == 200
+    assert StubPiiHandler.calls == before + 1
+    assert requests.post(API_URL, headers=HEADERS, json={"prompt": "something else"}).status_code == 200
+    assert StubPiiHandler.calls == before + 2
+
+def test_detections_are_cached():
+    """Test a body with PII stays rejected when answered from the cache."""
+    body = {"prompt": f"my SSN is secret {uuid.uuid4()}"}
+    before = StubPiiHandler.calls
+    for _ in range(2):
+        response = requests.post(API_URL, headers=HEADERS, json=body)
+        assert response.status_code == 403
+        assert response.json()["error"]["message"] == "PII found in request body"
+    assert StubPiiHandler.calls == before + 1
@@ -0,0 +1,124 @@
+import time
+from concurrent.futures import ThreadPoolExecutor
+import uuid
+
+import requests
+import yaml
+
+# Load configuration
+with open('conf.yml') as f:
+    config = yaml.safe_load(f)
+
+ADMIN_URL = f"http://{config['admin_host']}:{config['admin_port']}"
+ADMIN_HEADERS = {'Authorization': f"Bearer {config['admin_secret']}"}
+GATEWAY_URL = f"http://{config['host']}:{config['port']}"
+LIMIT = 5
+
+
+def new_user_headers():
+    """Rate limits are per user, each test gets its own token."""
+    token = str(uuid.uuid4())
+    response = requests.post(f'{ADMIN_URL}/tokens', headers=ADMIN_HEADERS, json={"tokens": {token: f"rl_{token[:8]}"}})
+    assert response.status_code == 200
+    return {'Authorization': f'Bearer {token}'}
+
+def burst(location, headers, count=LIMIT):
+    return [requests.post(f'{GATEWAY_URL}{location}', headers=headers, json={}).status_code for _ in range(count)]
+
+def boundary_bursts(location):
+    """Fill the limit at the end of a window and send it again just after the boundary."""
+    headers = new_user_headers()
+    # the window starts on the first request after two windows without traffic
+    time.sleep(2.1)
+    start = time.monotonic()
+    before = burst(location, headers, 1)
+    time.sleep(0.85 - (time.monotonic() - start))
+    before += burst(location, headers, LIMIT - 1)
+    time.sleep(1.05 - (time.monotonic() - start))
+    after = burst(location, headers)
+    return before, after
+
+def test_fixed_window_allows_boundary_burst():
+    before, after = boundary_bursts('/echo/ratelimit/fixed')
+    assert before == [200] * LIMIT
+    assert after == [200] * LIMIT, "Fixed window resets and lets twice the limit through"
+
+def test_sliding_window_smooths_boundary_burst():
+    before, after = boundary_bursts('/echo/ratelimit/sliding')
+    assert before == [200] * LIMIT
+    # most of the previous second still counts against the limit
+    assert after.count(429) >= LIMIT - 1, after
+
+def test_sliding_window_recovers():
+    headers = new_user_headers()
+    assert burst('/echo/ratelimit/sliding', headers) == [200] * LIMIT
+    time.sleep(2)
+    assert burst('/echo/ratelimit/sliding', headers) == [200] * LIMIT
+
+def test_user_limit_scope():
+    """Test the 429 response tells the user limit was hit."""
+    headers = new_user_headers()
+    responses = [requests.post(f'{GATEWAY_URL}/echo/ratelimit/fixed', headers=headers, json={}) for _ in range(LIMIT + 1)]
+    assert responses[-1].status_code == 429
+    assert responses[-1].headers['X-Rate-Limit-Scope'] == 'user'
+
+def test_group_limit():
+    """Test a group budget is enforced and reported (needs BURGONET_MODE=dev for alice's groups)."""
+    alice = {'Authorization': 'Bearer your_token_here'}
+    time.sleep(2.1)
+    responses = [requests.post(f'{GATEWAY_URL}/echo/ratelimit/group', headers=alice, json={}) for _ in range(4)]
+    assert [r.status_code for r in responses] == [200, 200, 200, 429]
+    assert responses[-1].headers['X-Rate-Limit-Scope'] == 'group:admin'
+    assert responses[-1].headers['X-Rate-Limit-Limit'] == '3'
+
+def test_group_limit_per_location():
+    """Test a group spending its budget at one location keeps it at another."""
+    alice = {'Authorization': 'Bearer your_token_here'}
+    time.sleep(2.1)
+    responses = [requests.post(f'{GATEWAY_URL}/echo/ratelimit/group', headers=alice, json={}) for _ in range(4)]
+    assert responses[-1].status_code == 429
+    response = requests.post(f'{GATEWAY_URL}/echo/ratelimit/group-other', headers=alice, json={})
+    assert response.status_code == 200, response.text
+
+def test_retry_after():
+    """Test the 429 response tells the client when the window resets."""
+    headers = new_user_headers()
+    responses = [requests.post(f'{GATEWAY_URL}/echo/ratelimit/fixed', headers=headers, json={}) for _ in range(LIMIT + 1)]
+    assert responses[-1].status_code == 429
+    assert responses[-1].headers['Retry-After'] == '1'
+    assert responses[-1].headers['X-Rate-Limit-Reset'] == '1'
+
+def concurrent_burst(location, headers, count):
+    with ThreadPoolExecutor(count) as pool:
+        return list(pool.map(lambda _: requests.post(f'{GATEWAY_URL}{location}', headers=headers, json={}).status_code, range(count)))
+
+def test_queue_holds_requests_over_limit():
+    """Test requests over the limit wait for the next window instead of a 429."""
+    headers = new_user_headers()
+    start = time.monotonic()
+    statuses = concurrent_burst('/echo/ratelimit/queued', headers, 4)
+    assert statuses == [200] * 4
+    assert time.monotonic() - start < 2
+
+def test_queue_full():
+    """Test requests beyond the queue size get a 429 at once."""
+    headers = new_user_headers()
+    statuses = concurrent_burst('/echo/ratelimit/queued', headers, 5)
+    assert statuses.count(200) == 4
+    assert statuses.count(429) == 1
+
+def test_queue_timeout():
+    """Test a window resetting after the queue timeout is rejected without waiting."""
+    headers = new_user_headers()
+    for _ in range(3):
+        assert burst('/echo/ratelimit/queued', headers, 2) == [200, 200]
+        time.sleep(1.1)
+    start = time.monotonic()
+    response = requests.post(f'{GATEWAY_URL}/echo/ratelimit/queued', headers=headers, json={})
+    assert response.status_code == 429
+    assert time.monotonic() - start < 1
+
+def test_queue_depth_gauge():
+    concurrent_burst('/echo/ratelimit/queued', new_user_headers(), 3)
+    metrics = requests.get(f"http://{config['prometheus_host']}:{config['prometheus_port']}/metrics").text
+    assert 'rate_limit_queue_depth{location="/echo/ratelimit/queued"}' in metrics
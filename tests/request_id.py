@@ -0,0 +1,72 @@
+"""X-Request-Id correlation between the client, the gateway and the upstream."""
+import json
+import threading
+import uuid
+from http.server import BaseHTTPRequestHandler, HTTPServer
+
+import requests
+import yaml
+
+# Load configuration
+with open('conf.yml') as f:
+    config = yaml.safe_load(f)
+
+ADMIN_URL = f"http://{config['admin_host']}:{config['admin_port']}"
+ADMIN_HEADERS = {'Authorization': f"Bearer {config['admin_secret']}"}
+GATEWAY_URL = f"http://{config['host']}:{config['port']}"
+UPSTREAM_PORT = 6213
+TEST_TOKEN = str(uuid.uuid4())
+HEADERS = {'Authorization': f'Bearer {TEST_TOKEN}'}
+
+
+class HeadersHandler(BaseHTTPRequestHandler):
+    """Upstream answering with the headers it received."""
+
+    def do_POST(self):
+        self.rfile.read(int(self.headers.get('Content-Length', 0)))
+        body = json.dumps({k.lower(): v for k, v in self.headers.items()}).encode()
+        self.send_response(200)
+        self.send_header('Content-Type', 'application/json')
+        self.send_header('Content-Length', str(len(body)))
+        self.end_headers()
+        self.wfile.write(body)
+
+    def log_message(self, *args):
+        pass
+
+
+server = HTTPServer(('127.0.0.1', UPSTREAM_PORT), HeadersHandler)
+
+
+def setup_module():
+    threading.Thread(target=server.serve_forever, daemon=True).start()
+    response = requests.post(f'{ADMIN_URL}/tokens', headers=ADMIN_HEADERS, json={"tokens": {TEST_TOKEN: "request_id_user"}})
+    assert response.status_code == 200
+
+def teardown_module():
+    server.shutdown()
+    requests.delete(f'{ADMIN_URL}/tokens', headers=ADMIN_HEADERS, json={"tokens": [TEST_TOKEN]})
+
+def test_generated_request_id():
+    """Test a request without id gets one, sent upstream and echoed back."""
+    response = requests.post(f"{GATEWAY_URL}/echo/headers", headers=HEADERS, json={})
+    assert response.status_code == 200
+    request_id = response.headers['X-Request-Id']
+    uuid.UUID(request_id)
+    assert response.json()['x-request-id'] == request_id
+
+def test_incoming_request_id():
+    """Test the caller's id is kept end to end."""
+    response = requests.post(f"{GATEWAY_URL}/echo/headers", headers={**HEADERS, 'X-Request-Id': 'client-trace-42'}, json={})
+    assert response.headers['X-Request-Id'] == 'client-trace-42'
+    assert response.json()['x-request-id'] == 'client-trace-42'
+
+def test_invalid_request_id_replaced():
+    response = requests.post(f"{GATEWAY_URL}/echo/headers", headers={**HEADERS, 'X-Request-Id': 'x' * 200}, json={})
+    uuid.UUID(response.headers['X-Request-Id'])
+
+def test_request_id_on_gateway_errors():
+    """Test error responses written by the gateway carry the id too."""
+    response = requests.post(f"{GATEWAY_URL}/echo/headers", headers={'Authorization': 'Bearer invalid', 'X-Request-Id': 'failed-call'}, json={})
+    assert response.status_code == 401
+    assert response.headers['X-Request-Id'] == 'failed-call'
@@ -0,0 +1,98 @@
+"""Requests signed with HMAC-SHA256 by the signing clients registered at the admin API."""
+import hashlib
+import hmac
+import json
+import time
+import uuid
+
+import requests
+import yaml
+
+# Load configuration
+with open('conf.yml') as f:
+    config = yaml.safe_load(f)
+
+ADMIN_URL = f"http://{config['admin_host']}:{config['admin_port']}"
+ADMIN_HEADERS = {'Authorization': f"Bearer {config['admin_secret']}"}
+GATEWAY_URL = f"http://{config['host']}:{config['port']}"
+MAX_AGE = config.get('signature_max_age_secs', 300)
+CLIENT_ID = f"svc-{uuid.uuid4()}"
+SECRET = uuid.uuid4().hex + uuid.uuid4().hex
+
+
+def setup_module():
+    clients = {"clients": {CLIENT_ID: {"user": "signed_user", "secret": SECRET}}}
+    response = requests.post(f'{ADMIN_URL}/signing_clients', headers=ADMIN_HEADERS, json=clients)
+    assert response.status_code == 200
+
+def teardown_module():
+    requests.delete(f'{ADMIN_URL}/signing_clients', headers=ADMIN_HEADERS, json={"clients": [CLIENT_ID]})
+
+def signed_headers(body, timestamp=None, client_id=CLIENT_ID, secret=SECRET):
+    timestamp = str(int(time.time()) if timestamp is None else timestamp)
+    signature = hmac.new(secret.encode(), timestamp.encode() + b"." + body, hashlib.sha256).hexdigest()
+    return {'Content-Type': 'application/json', 'X-Client-Id': client_id,
+            'X-Timestamp': timestamp, 'X-Signature': signature}
+
+def post(body, headers, location="/echo/openai"):
+    return requests.post(f"{GATEWAY_URL}{location}", headers=headers, data=body)
+
+def error_message(response):
+    return response.json()['error']['message']
+
+def test_signed_request():
+    """Test a signed request is served for the user of its client, with its body forwarded."""
+    body = json.dumps({"messages": [], "marker": str(uuid.uuid4())}).encode()
+    response = post(body, signed_headers(body))
+    assert response.status_code == 200
+    assert response.content == body
+
+def test_bad_signature():
+    """Test a signature over another body, or with another secret, is rejected."""
+    body = b'{"messages": []}'
+    response = post(b'{"messages": [1]}', signed_headers(body))
+    assert response.status_code == 401
+    assert error_message(response) == "Invalid signature"
+    assert post(body, signed_headers(body, secret="x" * 64)).status_code == 401
+    assert post(body, signed_headers(body, client_id="unknown-client")).status_code == 401
+    headers = signed_headers(body)
+    headers['X-Signature'] = "not hex"
+    assert post(body, headers).status_code == 401
+
+def test_stale_timestamp():
+    """Test timestamps outside signature_max_age_secs are rejected, even when signed."""
+    body = b'{"messages": []}'
+    for timestamp in [int(time.time()) - MAX_AGE - 60, int(time.time()) + MAX_AGE + 60]:
+        response = post(body, signed_headers(body, timestamp))
+        assert response.status_code == 401
+        assert error_message(response) == "Stale request timestamp"
+
+def test_replay():
+    """Test a signed request is only accepted once."""
+    body = json.dumps({"messages": [], "marker": str(uuid.uuid4())}).encode()
+    headers = signed_headers(body)
+    assert post(body, headers).status_code == 200
+    response = post(body, headers)
+    assert response.status_code == 401
+    assert error_message(response) == "Signature already used"
+
+def test_missing_headers():
+    """Test a signature without its client id or timestamp is rejected."""
+    body = b'{"messages": []}'
+    headers = signed_headers(body)
+    del headers['X-Timestamp']
+    assert post(body, headers).status_code == 401
+
+def test_body_too_large():
+    """Test signed bodies over the 64 KiB replay buffer are rejected."""
+    body = json.dumps({"prompt": "x" * (65 * 1024)}).encode()
+    response = post(body, signed_headers(body))
+    assert response.status_code == 413
+    assert error_message(response) == "Request body too large for a signed request"
+
+def test_admin_validation():
+    """Test the admin API requires its secret and rejects short client secrets."""
+    clients = {"clients": {"short": {"user": "signed_user", "secret": "too-short"}}}
+    assert requests.post(f'{ADMIN_URL}/signing_clients', json=clients).status_code == 401
+    response = requests.post(f'{ADMIN_URL}/signing_clients', headers=ADMIN_HEADERS, json=clients)
+    assert response.status_code == 400
@@ -0,0 +1,68 @@
+"""API keys read from the environment or a file, missing ones stopping the gateway at startup."""
+import os
+import subprocess
+import tempfile
+
+import yaml
+
+# Load configuration
+with open('conf.yml') as f:
+    config = yaml.safe_load(f)
+
+GATEWAY_BIN = os.environ.get('BURGONET_BIN', 'target/debug/burgonet-gw')
+MODEL = {"location": "/secret", "model_name": "echo", "parser": "echo", "proxy_pass": "http://127.0.0.1:6193/echo"}
+
+
+def start(directory, env=None, **model):
+    """Start a gateway with one location, it should exit at once."""
+    conf_path = os.path.join(directory, 'conf.yml')
+    with open(conf_path, 'w') as f:
+        yaml.safe_dump({**config, 'models': [{**MODEL, **model}]}, f)
+    return subprocess.run([GATEWAY_BIN, '-c', conf_path], capture_output=True, text=True, timeout=10, env=env)
+
+def test_missing_env():
+    with tempfile.TemporaryDirectory(dir='.') as directory:
+        result = start(directory, api_key_env="BURGONET_NO_SUCH_KEY")
+    assert result.returncode == 1
+    assert "Location /secret: environment variable BURGONET_NO_SUCH_KEY for api_key_env not found" in result.stdout + result.stderr
+
+def test_missing_legacy_env():
+    """Test an `api_key` written `$VAR` stops the gateway too when the variable is not set."""
+    with tempfile.TemporaryDirectory(dir='.') as directory:
+        result = start(directory, api_key="$BURGONET_NO_SUCH_KEY")
+    assert result.returncode == 1
+    assert "Location /secret: environment variable BURGONET_NO_SUCH_KEY for api_key not found" in result.stdout + result.stderr
+
+def test_missing_file():
+    with tempfile.TemporaryDirectory(dir='.') as directory:
+        path = os.path.join(directory, 'missing')
+        result = start(directory, api_key_file=path)
+    assert result.returncode == 1
+    assert f"Location /secret: unable to read api_key_file {path}" in result.stdout + result.stderr
+
+def test_empty_file():
+    with tempfile.TemporaryDirectory(dir='.') as directory:
+        path = os.path.join(directory, 'api_key')
+        with open(path, 'w') as f:
+            f.write('\n')
+        result = start(directory, api_key_file=path)
+    assert result.returncode == 1
+    assert f"Location /secret: api_key_file {path} is empty" in result.stdout + result.stderr
+
+def test_exclusive_sources():
+    with tempfile.TemporaryDirectory(dir='.') as directory:
+        result = start(directory, api_key="sk-plain", api_key_env="HOME")
+    assert result.returncode == 1
+    assert "api_key, api_key_env, api_key_file and api_keys are exclusive" in result.stdout + result.stderr
+
+def test_key_never_logged():
+    """Test a key read from a file does not show in the trace logs of the startup."""
+    with tempfile.TemporaryDirectory(dir='.') as directory:
+        path = os.path.join(directory, 'api_key')
+        with open(path, 'w') as f:
+            f.write('sk-from-file\n')
+        # the configuration loads, then the database of the running gateway stops the startup
+        result = start(directory, api_key_file=path, env={**os.environ, 'RUST_LOG': 'trace'})
+    assert result.returncode == 1
+    assert 'is locked by another process' in result.stdout + result.stderr
+    assert 'sk-from-file' not in result.stdout + result.stderr
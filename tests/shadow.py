@@ -0,0 +1,119 @@
+"""Requests mirrored to a shadow upstream whose responses never reach the client."""
+import json
+import threading
+import time
+import uuid
+from http.server import BaseHTTPRequestHandler, ThreadingHTTPServer
+
+import requests
+import yaml
+
+# Load configuration
+with open('conf.yml') as f:
+    config = yaml.safe_load(f)
+
+ADMIN_URL = f"http://{config['admin_host']}:{config['admin_port']}"
+ADMIN_HEADERS = {'Authorization': f"Bearer {config['admin_secret']}"}
+GATEWAY_URL = f"http://{config['host']}:{config['port']}"
+PROMETHEUS_URL = f"http://{config['prometheus_host']}:{config['prometheus_port']}"
+UPSTREAM_PORT = 6219
+LOCATION = "/echo/shadow"
+TEST_TOKEN = str(uuid.uuid4())
+TEST_USER = "shadow_user"
+HEADERS = {'Authorization': f'Bearer {TEST_TOKEN}'}
+
+shadow = {'status': 200, 'delay': 0, 'requests': []}
+
+
+class ShadowHandler(BaseHTTPRequestHandler):
+    """Shadow upstream recording what it receives and reporting 7 input and 5 output tokens."""
+
+    def do_POST(self):
+        body = self.rfile.read(int(self.headers.get('Content-Length', 0)))
+        time.sleep(shadow['delay'])
+        shadow['requests'].append({"path": self.path, "headers": {k.lower(): v for k, v in self.headers.items()}, "body": body})
+        response = json.dumps({"object": "chat.completion", "choices": [], "usage": {"prompt_tokens": 7, "completion_tokens": 5}}).encode()
+        self.send_response(shadow['status'])
+        self.send_header('Content-Type', 'application/json')
+        self.send_header('Content-Length', str(len(response)))
+        self.end_headers()
+        self.wfile.write(response)
+
+    def log_message(self, *args):
+        pass
+
+
+server = ThreadingHTTPServer(('127.0.0.1', UPSTREAM_PORT), ShadowHandler)
+
+
+def setup_module():
+    threading.Thread(target=server.serve_forever, daemon=True).start()
+    response = requests.post(f'{ADMIN_URL}/tokens', headers=ADMIN_HEADERS, json={"tokens": {TEST_TOKEN: TEST_USER}})
+    assert response.status_code == 200
+
+def teardown_module():
+    server.shutdown()
+    requests.delete(f'{ADMIN_URL}/tokens', headers=ADMIN_HEADERS, json={"tokens": [TEST_TOKEN]})
+
+def metric_value(series):
+    for line in requests.get(f'{PROMETHEUS_URL}/metrics').text.splitlines():
+        if line.startswith(series + " "):
+            return float(line.split()[1])
+    return 0
+
+def shadow_series(name):
+    labels = f'location="shadow:{LOCATION}"'
+    if config.get('token_metrics_by_user'):
+        labels += f',user="{TEST_USER}"'
+    return f'{name}{{{labels}}}'
+
+def wait_for_shadow(count, timeout=3):
+    deadline = time.monotonic() + timeout
+    while len(shadow['requests']) < count and time.monotonic() < deadline:
+        time.sleep(0.05)
+    return len(shadow['requests']) >= count
+
+def reset(status=200, delay=0):
+    shadow.update(status=status, delay=delay, requests=[])
+
+def primary_body():
+    return {"object": "chat.completion", "choices": [], "usage": {"prompt_tokens": 2, "completion_tokens": 1}}
+
+def test_mirrored_request():
+    """Test the client gets the primary response while the shadow gets a copy of the request."""
+    reset()
+    before_in, before_out = metric_value(shadow_series("input_tokens")), metric_value(shadow_series("output_tokens"))
+    response = requests.post(f"{GATEWAY_URL}{LOCATION}", headers=HEADERS, json=primary_body())
+    assert response.status_code == 200
+    assert response.json() == primary_body()
+
+    assert wait_for_shadow(1)
+    mirrored = shadow['requests'][0]
+    assert mirrored['path'] == "/v1/chat/completions"
+    assert json.loads(mirrored['body']) == primary_body()
+    assert mirrored['headers']['authorization'] == "Bearer sk-shadow"
+    assert mirrored['headers']['x-request-id'] == response.headers['X-Request-Id']
+
+    time.sleep(0.2)
+    assert metric_value(shadow_series("input_tokens")) == before_in + 7
+    assert metric_value(shadow_series("output_tokens")) == before_out + 5
+
+def test_shadow_failure_ignored():
+    """Test a failing shadow neither changes the response nor counts tokens."""
+    reset(status=500)
+    before_errors, before_in = metric_value("shadow_errors"), metric_value(shadow_series("input_tokens"))
+    response = requests.post(f"{GATEWAY_URL}{LOCATION}", headers=HEADERS, json=primary_body())
+    assert response.status_code == 200
+    assert response.json() == primary_body()
+    assert wait_for_shadow(1)
+    time.sleep(0.2)
+    assert metric_value("shadow_errors") == before_errors + 1
+    assert metric_value(shadow_series("input_tokens")) == before_in
+
+def test_slow_shadow_does_not_delay():
+    reset(delay=2)
+    start = time.monotonic()
+    response = requests.post(f"{GATEWAY_URL}{LOCATION}", headers=HEADERS, json=primary_body())
+    assert response.status_code == 200
+    assert time.monotonic() - start < 1
+    assert wait_for_shadow(1)
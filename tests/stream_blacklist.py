@@ -0,0 +1,112 @@
+"""Event streams stopped when the generated text holds a blacklisted word."""
+import base64
+import json
+import threading
+import time
+import uuid
+from http.server import BaseHTTPRequestHandler, ThreadingHTTPServer
+
+import requests
+import yaml
+
+# Load configuration
+with open('conf.yml') as f:
+    config = yaml.safe_load(f)
+
+ADMIN_URL = f"http://{config['admin_host']}:{config['admin_port']}"
+ADMIN_HEADERS = {'Authorization': f"Bearer {config['admin_secret']}"}
+GATEWAY_URL = f"http://{config['host']}:{config['port']}"
+UPSTREAM_PORT = 6230
+LOCATION = "/echo/sse/blacklist"
+TEST_TOKEN = str(uuid.uuid4())
+TEST_USER = "stream_blacklist_user"
+HEADERS = {'Authorization': f'Bearer {TEST_TOKEN}'}
+
+
+class ChunkedStreamHandler(BaseHTTPRequestHandler):
+    """Streams the base64 `chunks` of the request body as they are, one HTTP chunk each. They are
+    encoded so the request itself holds no blacklisted word."""
+    protocol_version = "HTTP/1.1"
+
+    def do_POST(self):
+        request = json.loads(self.rfile.read(int(self.headers['Content-Length'])))
+        self.send_response(200)
+        self.send_header('Content-Type', 'text/event-stream')
+        self.send_header('Transfer-Encoding', 'chunked')
+        self.end_headers()
+        try:
+            for chunk in request['chunks']:
+                chunk = base64.b64decode(chunk)
+                self.wfile.write(b"%x\r\n%s\r\n" % (len(chunk), chunk))
+                self.wfile.flush()
+                time.sleep(0.05)
+            self.wfile.write(b"0\r\n\r\n")
+        except OSError:
+            pass  # the gateway stopped the stream
+
+    def log_message(self, *args):
+        pass
+
+
+server = ThreadingHTTPServer(('127.0.0.1', UPSTREAM_PORT), ChunkedStreamHandler)
+
+
+def setup_module():
+    threading.Thread(target=server.serve_forever, daemon=True).start()
+    response = requests.post(f'{ADMIN_URL}/tokens', headers=ADMIN_HEADERS, json={"tokens": {TEST_TOKEN: TEST_USER}})
+    assert response.status_code == 200
+
+def teardown_module():
+    server.shutdown()
+    requests.delete(f'{ADMIN_URL}/tokens', headers=ADMIN_HEADERS, json={"tokens": [TEST_TOKEN]})
+
+def event(content):
+    return f'data: {json.dumps({"choices": [{"delta": {"content": content}}]})}\n\n'
+
+def stream(chunks):
+    """Return what the client received of the stream of the chunks, and whether it ended cleanly."""
+    body = {"stream": True, "chunks": [base64.b64encode(chunk.encode()).decode() for chunk in chunks]}
+    response = requests.post(f"{GATEWAY_URL}{LOCATION}", headers=HEADERS, json=body, stream=True, timeout=10)
+    assert response.status_code == 200
+    received = b""
+    try:
+        for data in response.iter_content(chunk_size=None):
+            received += data
+    except requests.exceptions.ChunkedEncodingError:
+        return received.decode(), False
+    return received.decode(), True
+
+def test_clean_stream():
+    """Test a stream without blacklisted words is forwarded unchanged."""
+    chunks = [event("The report"), event(" is public"), "data: [DONE]\n\n"]
+    received, complete = stream(chunks)
+    assert complete
+    assert received == "".join(chunks)
+
+def test_word_in_event():
+    """Test the stream stops before the event holding a blacklisted word."""
+    received, complete = stream([event("The report"), event(" is Confidential"), event(" indeed"), "data: [DONE]\n\n"])
+    assert not complete
+    assert received == event("The report")
+
+def test_word_split_across_events():
+    """Test a word generated over two events is found, the second is not forwarded."""
+    received, complete = stream([event("The report is conf"), event("idential"), "data: [DONE]\n\n"])
+    assert not complete
+    assert received == event("The report is conf")
+
+def test_word_split_across_chunks():
+    """Test the line completing a word split across chunks is held back and never forwarded."""
+    line = event("Ask mycorp")
+    received, complete = stream([event("Hello"), line[:-8], line[-8:], "data: [DONE]\n\n"])
+    assert not complete
+    assert received == event("Hello")
+
+def test_decision_recorded():
+    """Test the stopped stream is in the refused requests of the user."""
+    stream([event("mycorp")])
+    time.sleep(0.5)  # decisions are written once the response has been sent
+    response = requests.get(f'{ADMIN_URL}/decisions', headers=ADMIN_HEADERS, params={"user": TEST_USER})
+    assert response.status_code == 200
+    reasons = [decision['reason'] for decision in response.json()]
+    assert "Blacklisted word found in response" in reasons
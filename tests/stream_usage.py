@@ -0,0 +1,105 @@
+"""Usage event added to event streams for clients asking for stream_options.include_usage."""
+import json
+import threading
+import time
+import uuid
+from http.server import BaseHTTPRequestHandler, ThreadingHTTPServer
+
+import requests
+import yaml
+
+# Load configuration
+with open('conf.yml') as f:
+    config = yaml.safe_load(f)
+
+ADMIN_URL = f"http://{config['admin_host']}:{config['admin_port']}"
+ADMIN_HEADERS = {'Authorization': f"Bearer {config['admin_secret']}"}
+GATEWAY_URL = f"http://{config['host']}:{config['port']}"
+UPSTREAM_PORT = 6224
+TEST_TOKEN = str(uuid.uuid4())
+HEADERS = {'Authorization': f'Bearer {TEST_TOKEN}'}
+INCLUDE_USAGE = {"stream": True, "stream_options": {"include_usage": True}}
+
+ANTHROPIC_EVENTS = [
+    {"type": "message_start", "message": {"usage": {"input_tokens": 12, "output_tokens": 1}}},
+    {"type": "content_block_delta", "delta": {"type": "text_delta", "text": "Hello"}},
+    {"type": "message_delta", "usage": {"output_tokens": 7}},
+]
+
+
+class EventStreamHandler(BaseHTTPRequestHandler):
+    """Streams the `events` of the request body, one chunk each, then `data: [DONE]`."""
+    protocol_version = "HTTP/1.1"
+
+    def do_POST(self):
+        request = json.loads(self.rfile.read(int(self.headers['Content-Length'])))
+        self.send_response(200)
+        self.send_header('Content-Type', 'text/event-stream')
+        self.send_header('Transfer-Encoding', 'chunked')
+        self.end_headers()
+        chunks = [f"data: {json.dumps(event)}\n\n".encode() for event in request['events']]
+        chunks.append(b"data: [DONE]\n\n")
+        for chunk in chunks:
+            self.wfile.write(b"%x\r\n%s\r\n" % (len(chunk), chunk))
+            self.wfile.flush()
+            time.sleep(0.05)
+        self.wfile.write(b"0\r\n\r\n")
+
+    def log_message(self, *args):
+        pass
+
+
+server = ThreadingHTTPServer(('127.0.0.1', UPSTREAM_PORT), EventStreamHandler)
+
+
+def setup_module():
+    threading.Thread(target=server.serve_forever, daemon=True).start()
+    response = requests.post(f'{ADMIN_URL}/tokens', headers=ADMIN_HEADERS, json={"tokens": {TEST_TOKEN: "stream_user"}})
+    assert response.status_code == 200
+
+def teardown_module():
+    server.shutdown()
+    requests.delete(f'{ADMIN_URL}/tokens', headers=ADMIN_HEADERS, json={"tokens": [TEST_TOKEN]})
+
+def stream(location, body):
+    """Return the data of the events received, in order."""
+    response = requests.post(f"{GATEWAY_URL}{location}", headers=HEADERS, json=body, timeout=10)
+    assert response.status_code == 200, response.text
+    assert response.headers['Content-Type'] == 'text/event-stream'
+    return [line[len("data: "):] for line in response.text.split("\n") if line.startswith("data: ")]
+
+def test_usage_event_added():
+    """Test the usage counted by the gateway is sent as an OpenAI usage chunk before [DONE]."""
+    events = stream("/echo/sse/anthropic", dict(INCLUDE_USAGE, events=ANTHROPIC_EVENTS))
+    assert [json.loads(e) for e in events[:3]] == ANTHROPIC_EVENTS
+    assert json.loads(events[3]) == {
+        "object": "chat.completion.chunk",
+        "choices": [],
+        "usage": {"prompt_tokens": 12, "completion_tokens": 7, "total_tokens": 19},
+    }
+    assert events[4:] == ["[DONE]"]
+
+def test_not_requested():
+    """Test the stream is forwarded unchanged when the client did not ask for the usage."""
+    events = stream("/echo/sse/anthropic", {"stream": True, "events": ANTHROPIC_EVENTS})
+    assert [json.loads(e) for e in events[:3]] == ANTHROPIC_EVENTS
+    assert events[3:] == ["[DONE]"]
+
+def test_upstream_usage_kept():
+    """Test no usage event is added when the upstream already sent one."""
+    chunks = [
+        {"choices": [{"delta": {"content": "Hi"}}], "usage": None},
+        {"choices": [], "usage": {"prompt_tokens": 4, "completion_tokens": 2, "total_tokens": 6}},
+    ]
+    events = stream("/echo/sse/openai", dict(INCLUDE_USAGE, events=chunks))
+    assert [json.loads(e) for e in events[:2]] == chunks
+    assert events[2:] == ["[DONE]"]
+
+def test_estimated_usage():
+    """Test the usage event carries estimated tokens for an upstream reporting none."""
+    chunks = [{"choices": [{"delta": {"content": "Bonjour, comment allez-vous ?"}}]}]
+    body = dict(INCLUDE_USAGE, events=chunks, messages=[{"role": "user", "content": "Say hello in French"}])
+    events = stream("/echo/sse/estimated", body)
+    usage = json.loads(events[1])["usage"]
+    assert usage["prompt_tokens"] > 0 and usage["completion_tokens"] > 0
+    assert events[2:] == ["[DONE]"]
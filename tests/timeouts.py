@@ -0,0 +1,129 @@
+"""Upstream read timeout and request deadline of a model."""
+import json
+import re
+import threading
+import time
+import uuid
+from http.server import BaseHTTPRequestHandler, ThreadingHTTPServer
+
+import requests
+import yaml
+
+# Load configuration
+with open('conf.yml') as f:
+    config = yaml.safe_load(f)
+
+ADMIN_URL = f"http://{config['admin_host']}:{config['admin_port']}"
+ADMIN_HEADERS = {'Authorization': f"Bearer {config['admin_secret']}"}
+GATEWAY_URL = f"http://{config['host']}:{config['port']}"
+PROMETHEUS_URL = f"http://{config['prometheus_host']}:{config['prometheus_port']}"
+UPSTREAM_PORT = 6215
+LOCATION = "/echo/slow"
+READ_TIMEOUT = next(m for m in config['models'] if m['location'] == LOCATION)['read_timeout_ms'] / 1000
+DEADLINE_LOCATION = "/echo/deadline"
+DEADLINE = next(m for m in config['models'] if m['location'] == DEADLINE_LOCATION)['request_deadline_ms'] / 1000
+TEST_TOKEN = str(uuid.uuid4())
+HEADERS = {'Authorization': f'Bearer {TEST_TOKEN}'}
+
+
+class SlowHandler(BaseHTTPRequestHandler):
+    """Upstream answering after the `delay` seconds of the request body, with its `status`,
+    and sending the body `body_delay` seconds after the headers."""
+
+    def do_POST(self):
+        request = json.loads(self.rfile.read(int(self.headers.get('Content-Length', 0))))
+        time.sleep(request['delay'])
+        body = json.dumps(request).encode()
+        try:
+            self.send_response(request.get('status', 200))
+            self.send_header('Content-Type', 'application/json')
+            self.send_header('Content-Length', str(len(body)))
+            self.end_headers()
+            self.wfile.flush()
+            time.sleep(request.get('body_delay', 0))
+            self.wfile.write(body)
+        except OSError:
+            pass  # the gateway gave up on us
+
+    def log_message(self, *args):
+        pass
+
+
+server = ThreadingHTTPServer(('127.0.0.1', UPSTREAM_PORT), SlowHandler)
+
+
+def setup_module():
+    threading.Thread(target=server.serve_forever, daemon=True).start()
+    response = requests.post(f'{ADMIN_URL}/tokens', headers=ADMIN_HEADERS, json={"tokens": {TEST_TOKEN: "timeout_user"}})
+    assert response.status_code == 200
+
+def teardown_module():
+    server.shutdown()
+    requests.delete(f'{ADMIN_URL}/tokens', headers=ADMIN_HEADERS, json={"tokens": [TEST_TOKEN]})
+
+def test_response_within_timeout():
+    response = requests.post(f"{GATEWAY_URL}{LOCATION}", headers=HEADERS, json={"delay": READ_TIMEOUT / 5})
+    assert response.status_code == 200
+    assert response.json() == {"delay": READ_TIMEOUT / 5}
+
+def test_read_timeout():
+    """Test a slow upstream gets a 504 with a JSON error once the read timeout fires."""
+    start = time.monotonic()
+    response = requests.post(f"{GATEWAY_URL}{LOCATION}", headers=HEADERS, json={"delay": READ_TIMEOUT * 4})
+    elapsed = time.monotonic() - start
+    assert response.status_code == 504
+    error = response.json()['error']
+    assert error['type'] == "api_error"
+    assert error['code'] == "upstream_error"
+    assert elapsed < READ_TIMEOUT * 3, elapsed
+
+def test_upstream_latency_header():
+    """Test the response tells how long the upstream took to answer."""
+    delay = READ_TIMEOUT / 5
+    response = requests.post(f"{GATEWAY_URL}{LOCATION}", headers=HEADERS, json={"delay": delay})
+    assert response.status_code == 200
+    latency_ms = int(response.headers['X-Upstream-Latency-Ms'])
+    assert delay * 1000 <= latency_ms < delay * 1000 + 500
+
+def test_upstream_latency_header_to_last_byte():
+    """Test the latency header of a response that is not streamed counts its body too."""
+    delay = READ_TIMEOUT / 5
+    response = requests.post(f"{GATEWAY_URL}{LOCATION}", headers=HEADERS, json={"delay": 0, "body_delay": delay})
+    assert response.status_code == 200
+    latency_ms = int(response.headers['X-Upstream-Latency-Ms'])
+    assert delay * 1000 <= latency_ms < delay * 1000 + 500
+
+def latency_sum():
+    metrics = requests.get(f'{PROMETHEUS_URL}/metrics').text
+    match = re.search(rf'^upstream_latency_seconds_sum{{location="{LOCATION}"}} (\S+)$', metrics, re.MULTILINE)
+    return float(match.group(1)) if match else 0.0
+
+def test_upstream_latency_histogram():
+    """Test the histogram counts the upstream time up to the end of the response."""
+    before = latency_sum()
+    delay = READ_TIMEOUT / 5
+    response = requests.post(f"{GATEWAY_URL}{LOCATION}", headers=HEADERS, json={"delay": delay})
+    assert response.status_code == 200
+    time.sleep(0.2)  # the requests are logged after their response
+    assert delay <= latency_sum() - before < delay + 0.5
+
+def test_response_within_deadline():
+    response = requests.post(f"{GATEWAY_URL}{DEADLINE_LOCATION}", headers=HEADERS, json={"delay": DEADLINE / 5})
+    assert response.status_code == 200
+
+def test_deadline_exceeded():
+    """Test an upstream slower than the deadline, but within the read timeout, gets a 504."""
+    start = time.monotonic()
+    response = requests.post(f"{GATEWAY_URL}{DEADLINE_LOCATION}", headers=HEADERS, json={"delay": DEADLINE * 3})
+    elapsed = time.monotonic() - start
+    assert response.status_code == 504
+    assert DEADLINE <= elapsed < DEADLINE * 2, elapsed
+
+def test_deadline_spans_retries():
+    """Test the retries of failing upstream responses stop at the deadline."""
+    start = time.monotonic()
+    response = requests.post(f"{GATEWAY_URL}{DEADLINE_LOCATION}", headers=HEADERS, json={"delay": DEADLINE * 0.4, "status": 503})
+    elapsed = time.monotonic() - start
+    # 4 attempts would take 1.6 deadlines, the third is cut short
+    assert response.status_code == 504
+    assert DEADLINE <= elapsed < DEADLINE * 1.4, elapsed
@@ -0,0 +1,108 @@
+"""Requests sent to the upstreams of a location at once, limited by upstream_max_connections."""
+import json
+import threading
+import time
+import uuid
+from concurrent.futures import ThreadPoolExecutor
+from http.server import BaseHTTPRequestHandler, ThreadingHTTPServer
+
+import requests
+import yaml
+
+# Load configuration
+with open('conf.yml') as f:
+    config = yaml.safe_load(f)
+
+ADMIN_URL = f"http://{config['admin_host']}:{config['admin_port']}"
+ADMIN_HEADERS = {'Authorization': f"Bearer {config['admin_secret']}"}
+GATEWAY_URL = f"http://{config['host']}:{config['port']}"
+PROMETHEUS_URL = f"http://{config['prometheus_host']}:{config['prometheus_port']}"
+UPSTREAM_PORT = 6226
+LOCATION = "/echo/pooled"
+TEST_TOKEN = str(uuid.uuid4())
+HEADERS = {'Authorization': f'Bearer {TEST_TOKEN}'}
+
+# Most requests the stub upstream had in flight at once
+in_flight = 0
+max_in_flight = 0
+lock = threading.Lock()
+
+
+class SlowHandler(BaseHTTPRequestHandler):
+    """Upstream answering after the `delay` seconds of the request body."""
+
+    def do_POST(self):
+        global in_flight, max_in_flight
+        request = json.loads(self.rfile.read(int(self.headers.get('Content-Length', 0))))
+        with lock:
+            in_flight += 1
+            max_in_flight = max(max_in_flight, in_flight)
+        time.sleep(request['delay'])
+        with lock:
+            in_flight -= 1
+        body = json.dumps(request).encode()
+        try:
+            self.send_response(200)
+            self.send_header('Content-Type', 'application/json')
+            self.send_header('Content-Length', str(len(body)))
+            self.end_headers()
+            self.wfile.write(body)
+        except OSError:
+            pass  # the client went away
+
+    def log_message(self, *args):
+        pass
+
+
+server = ThreadingHTTPServer(('127.0.0.1', UPSTREAM_PORT), SlowHandler)
+
+
+def setup_module():
+    threading.Thread(target=server.serve_forever, daemon=True).start()
+    response = requests.post(f'{ADMIN_URL}/tokens', headers=ADMIN_HEADERS, json={"tokens": {TEST_TOKEN: "pooled_user"}})
+    assert response.status_code == 200
+
+def teardown_module():
+    server.shutdown()
+    requests.delete(f'{ADMIN_URL}/tokens', headers=ADMIN_HEADERS, json={"tokens": [TEST_TOKEN]})
+
+def post(body, timeout=10):
+    return requests.post(f"{GATEWAY_URL}{LOCATION}", headers=HEADERS, json=body, timeout=timeout)
+
+def gauge(name):
+    metrics = requests.get(f'{PROMETHEUS_URL}/metrics').text
+    line = next(l for l in metrics.splitlines() if l.startswith(f'{name}{{location="{LOCATION}"}}'))
+    return int(line.split()[-1])
+
+def test_queued():
+    """Test requests over the limit wait for the upstream instead of being sent along."""
+    global max_in_flight
+    max_in_flight = 0
+    with ThreadPoolExecutor(3) as pool:
+        start = time.time()
+        responses = [pool.submit(post, {"delay": 0.4, "n": n}) for n in range(3)]
+        assert [f.result().status_code for f in responses] == [200] * 3
+        assert [f.result().json()['n'] for f in responses] == list(range(3))
+    assert time.time() - start >= 1.2
+    assert max_in_flight == 1
+
+def test_queue_timeout():
+    """Test a request waiting longer than upstream_queue_timeout_ms gets a 503."""
+    with ThreadPoolExecutor(1) as pool:
+        slow = pool.submit(post, {"delay": 2.5})
+        time.sleep(0.3)
+        response = post({"delay": 0})
+        assert response.status_code == 503
+        assert response.json()['error']['message'] == "Upstream connection limit reached"
+        assert slow.result().status_code == 200
+    assert post({"delay": 0}).status_code == 200
+
+def test_gauges():
+    """Test the gauges report the connections in use and the limit."""
+    with ThreadPoolExecutor(1) as pool:
+        slow = pool.submit(post, {"delay": 1})
+        time.sleep(0.3)
+        assert gauge('upstream_connections_in_use') == 1
+        assert gauge('upstream_connections_max') == 1
+        assert slow.result().status_code == 200
+    assert gauge('upstream_connections_in_use') == 0
@@ -0,0 +1,87 @@
+"""Headers added, removed and the API key header sent to the upstream, and Azure addressing."""
+import json
+import os
+import threading
+import uuid
+from http.server import BaseHTTPRequestHandler, HTTPServer
+
+import requests
+import yaml
+
+# Load configuration
+with open('conf.yml') as f:
+    config = yaml.safe_load(f)
+
+ADMIN_URL = f"http://{config['admin_host']}:{config['admin_port']}"
+ADMIN_HEADERS = {'Authorization': f"Bearer {config['admin_secret']}"}
+GATEWAY_URL = f"http://{config['host']}:{config['port']}"
+UPSTREAM_PORT = 6217
+TEST_TOKEN = str(uuid.uuid4())
+HEADERS = {'Authorization': f'Bearer {TEST_TOKEN}'}
+
+
+class HeadersHandler(BaseHTTPRequestHandler):
+    """Upstream answering with the path and headers it received."""
+
+    def do_POST(self):
+        self.rfile.read(int(self.headers.get('Content-Length', 0)))
+        body = json.dumps({"path": self.path, "headers": {k.lower(): v for k, v in self.headers.items()}}).encode()
+        self.send_response(200)
+        self.send_header('Content-Type', 'application/json')
+        self.send_header('Content-Length', str(len(body)))
+        self.end_headers()
+        self.wfile.write(body)
+
+    def log_message(self, *args):
+        pass
+
+
+server = HTTPServer(('127.0.0.1', UPSTREAM_PORT), HeadersHandler)
+
+
+def setup_module():
+    threading.Thread(target=server.serve_forever, daemon=True).start()
+    response = requests.post(f'{ADMIN_URL}/tokens', headers=ADMIN_HEADERS, json={"tokens": {TEST_TOKEN: "headers_user"}})
+    assert response.status_code == 200
+
+def teardown_module():
+    server.shutdown()
+    requests.delete(f'{ADMIN_URL}/tokens', headers=ADMIN_HEADERS, json={"tokens": [TEST_TOKEN]})
+
+def upstream_request(location):
+    response = requests.post(f"{GATEWAY_URL}{location}", headers=HEADERS, json={})
+    assert response.status_code == 200, response.text
+    return response.json()
+
+def upstream_headers(location):
+    return upstream_request(location)['headers']
+
+def test_default_bearer():
+    """Test the API key replaces the gateway token as a bearer by default."""
+    headers = upstream_headers("/echo/upstream-headers/default")
+    assert headers['authorization'] == "Bearer sk-upstream"
+    assert headers['content-type'] == "application/json"
+
+def test_api_key_env():
+    """Test the API key is read from the environment variable named by api_key_env."""
+    headers = upstream_headers("/echo/upstream-headers/env-key")
+    assert headers['authorization'] == f"Bearer {os.environ['HOME']}"
+
+def test_custom_auth_header():
+    """Test the API key goes bare in api-key and the gateway token is not forwarded."""
+    headers = upstream_headers("/echo/upstream-headers/custom")
+    assert headers['api-key'] == "sk-upstream"
+    assert 'authorization' not in headers
+
+def test_headers_added_and_removed():
+    headers = upstream_headers("/echo/upstream-headers/custom")
+    assert headers['anthropic-version'] == "2023-06-01"
+    assert 'accept' not in headers
+    assert 'accept' in upstream_headers("/echo/upstream-headers/default")
+
+def test_azure_provider():
+    """Test an Azure location gets the deployment path, api-version and api-key header."""
+    request = upstream_request("/echo/upstream-headers/azure")
+    assert request['path'] == "/openai/deployments/gpt-4o/chat/completions?api-version=2024-10-21"
+    assert request['headers']['api-key'] == "sk-azure"
+    assert 'authorization' not in request['headers']
@@ -0,0 +1,157 @@
+"""WebSocket connections of the locations with websocket: true, bridged without buffering."""
+import base64
+import hashlib
+import os
+import socket
+import socketserver
+import struct
+import threading
+import uuid
+
+import requests
+import yaml
+
+# Load configuration
+with open('conf.yml') as f:
+    config = yaml.safe_load(f)
+
+ADMIN_URL = f"http://{config['admin_host']}:{config['admin_port']}"
+ADMIN_HEADERS = {'Authorization': f"Bearer {config['admin_secret']}"}
+UPSTREAM_PORT = 6227
+LOCATION = "/echo/realtime"
+TEST_TOKEN = str(uuid.uuid4())
+WS_GUID = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11"
+
+# Request headers of the last handshake the stub upstream accepted
+upstream_headers = {}
+
+
+def accept_key(key):
+    return base64.b64encode(hashlib.sha1((key + WS_GUID).encode()).digest()).decode()
+
+def read_head(sock):
+    """Status or request line and headers, then the bytes read past them."""
+    data = b''
+    while b'\r\n\r\n' not in data:
+        chunk = sock.recv(4096)
+        if not chunk:
+            break
+        data += chunk
+    head, _, rest = data.partition(b'\r\n\r\n')
+    lines = head.decode().split('\r\n')
+    headers = {k.strip().lower(): v.strip() for k, v in (line.split(':', 1) for line in lines[1:])}
+    return lines[0], headers, rest
+
+def frame(payload, mask):
+    """Text frame, masked as the client must send them."""
+    header = bytes([0x81])
+    length = len(payload)
+    mask_bit = 0x80 if mask else 0
+    if length < 126:
+        header += bytes([mask_bit | length])
+    else:
+        header += bytes([mask_bit | 126]) + struct.pack('!H', length)
+    if not mask:
+        return header + payload
+    key = os.urandom(4)
+    return header + key + bytes(b ^ key[i % 4] for i, b in enumerate(payload))
+
+def read_frame(sock, buffer):
+    """Payload of the next frame and the bytes left after it."""
+    def need(n):
+        nonlocal buffer
+        while len(buffer) < n:
+            chunk = sock.recv(4096)
+            if not chunk:
+                raise ConnectionError("closed")
+            buffer += chunk
+    need(2)
+    length, offset = buffer[1] & 0x7f, 2
+    if length == 126:
+        need(4)
+        length, offset = struct.unpack('!H', buffer[2:4])[0], 4
+    masked = buffer[1] & 0x80
+    key_len = 4 if masked else 0
+    need(offset + key_len + length)
+    key = buffer[offset:offset + key_len]
+    payload = buffer[offset + key_len:offset + key_len + length]
+    if masked:
+        payload = bytes(b ^ key[i % 4] for i, b in enumerate(payload))
+    return payload, buffer[offset + key_len + length:]
+
+
+class EchoHandler(socketserver.BaseRequestHandler):
+    """Upstream accepting the handshake then echoing text frames in upper case."""
+
+    def handle(self):
+        _, headers, buffer = read_head(self.request)
+        upstream_headers.clear()
+        upstream_headers.update(headers)
+        self.request.sendall((
+            "HTTP/1.1 101 Switching Protocols\r\n"
+            "Upgrade: websocket\r\nConnection: Upgrade\r\n"
+            f"Sec-WebSocket-Accept: {accept_key(headers['sec-websocket-key'])}\r\n\r\n"
+        ).encode())
+        try:
+            while True:
+                payload, buffer = read_frame(self.request, buffer)
+                self.request.sendall(frame(payload.upper(), mask=False))
+        except (ConnectionError, OSError):
+            pass
+
+
+class Server(socketserver.ThreadingMixIn, socketserver.TCPServer):
+    daemon_threads = True
+    allow_reuse_address = True
+
+
+server = Server(('127.0.0.1', UPSTREAM_PORT), EchoHandler)
+
+
+def setup_module():
+    threading.Thread(target=server.serve_forever, daemon=True).start()
+    response = requests.post(f'{ADMIN_URL}/tokens', headers=ADMIN_HEADERS, json={"tokens": {TEST_TOKEN: "websocket_user"}})
+    assert response.status_code == 200
+
+def teardown_module():
+    server.shutdown()
+    requests.delete(f'{ADMIN_URL}/tokens', headers=ADMIN_HEADERS, json={"tokens": [TEST_TOKEN]})
+
+def handshake(location, token=TEST_TOKEN):
+    sock = socket.create_connection((config['host'], config['port']), timeout=5)
+    key = base64.b64encode(os.urandom(16)).decode()
+    sock.sendall((
+        f"GET {location} HTTP/1.1\r\nHost: {config['host']}\r\n"
+        "Upgrade: websocket\r\nConnection: Upgrade\r\n"
+        f"Sec-WebSocket-Key: {key}\r\nSec-WebSocket-Version: 13\r\n"
+        f"Authorization: Bearer {token}\r\n\r\n"
+    ).encode())
+    status, headers, rest = read_head(sock)
+    return sock, key, status, headers, rest
+
+def test_bridged():
+    """Test frames cross the gateway both ways, one at a time."""
+    sock, key, status, headers, buffer = handshake(LOCATION)
+    with sock:
+        assert status.startswith("HTTP/1.1 101")
+        assert headers['sec-websocket-accept'] == accept_key(key)
+        for message in [b'{"type": "session.update"}', b'x' * 300]:
+            sock.sendall(frame(message, mask=True))
+            payload, buffer = read_frame(sock, buffer)
+            assert payload == message.upper()
+    # the client token never reaches the upstream
+    assert upstream_headers['authorization'] != f"Bearer {TEST_TOKEN}"
+    assert upstream_headers['upgrade'] == "websocket"
+
+def test_unauthenticated():
+    """Test the handshake needs a valid token like any request."""
+    sock, _, status, _, _ = handshake(LOCATION, token="invalid")
+    sock.close()
+    assert status.startswith("HTTP/1.1 401")
+
+def test_not_enabled():
+    """Test the locations without websocket: true refuse the upgrade."""
+    sock, _, status, _, rest = handshake("/echo")
+    sock.close()
+    assert status.startswith("HTTP/1.1 400")
+    assert b"WebSocket not enabled for this location" in rest